@@ -25,4 +25,25 @@ for canister in $canister_names; do
     dfx deploy $canister
 done
 
+ledger_canister_id=$(dfx canister id icrc1_ledger_canister)
+
+for canister in course grindarena storage towntalk; do
+    dfx canister call $canister set_ledger_canister "(principal \"$ledger_canister_id\")"
+done
+
+# Let course, grindarena and towntalk emit notifications.
+notifications_canister_id=$(dfx canister id notifications)
+
+for canister in course grindarena towntalk; do
+    dfx canister call notifications register_emitter "(principal \"$(dfx canister id $canister)\")"
+    dfx canister call $canister set_notifications_canister "(principal \"$notifications_canister_id\")"
+done
+
+storage_canister_id=$(dfx canister id storage)
+
+for canister in grindarena towntalk; do
+    dfx canister call storage register_subscriber "(principal \"$(dfx canister id $canister)\", variant { Cascade })"
+    dfx canister call $canister set_storage_canister "(principal \"$storage_canister_id\")"
+done
+
 # dfx canister install --all
\ No newline at end of file
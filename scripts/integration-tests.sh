@@ -0,0 +1,38 @@
+#!/bin/bash
+
+# Builds every canister wasm and runs the PocketIC integration tests.
+# Uses POCKET_IC_BIN when set, otherwise downloads the PocketIC server.
+
+PROJECT_ROOT="$(cd "$(dirname "${BASH_SOURCE[0]}")/../" && pwd)"
+POCKET_IC_VERSION="16.1.0"
+
+cd "$PROJECT_ROOT" || exit 1
+
+canisters=(shared user storage storage_index course towntalk grindarena notifications local_ledger)
+
+for canister in "${canisters[@]}"; do
+  cargo build --target wasm32-unknown-unknown --release --package "$canister"
+
+  if [ $? -ne 0 ]; then
+    echo "Error: Failed to build Wasm for canister $canister"
+    exit 1
+  fi
+done
+
+if [ -z "$POCKET_IC_BIN" ]; then
+  case "$(uname -s)" in
+    Darwin) os="darwin" ;;
+    *) os="linux" ;;
+  esac
+
+  POCKET_IC_BIN="$PROJECT_ROOT/target/pocket-ic-$POCKET_IC_VERSION"
+
+  if [ ! -x "$POCKET_IC_BIN" ]; then
+    echo "Downloading PocketIC server $POCKET_IC_VERSION..."
+    curl -sSL "https://github.com/dfinity/pocketic/releases/download/$POCKET_IC_VERSION/pocket-ic-x86_64-$os.gz" \
+      | gunzip > "$POCKET_IC_BIN" || exit 1
+    chmod +x "$POCKET_IC_BIN"
+  fi
+fi
+
+POCKET_IC_BIN="$POCKET_IC_BIN" cargo test --package integration_tests "$@" -- --ignored
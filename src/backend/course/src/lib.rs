@@ -1,9 +1,15 @@
 use candid::CandidType;
 use ic_cdk::{api, export_candid};
 use ic_principal::Principal;
+use paginator::PaginatorResponse;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use utilities::audit::{self, AuditEntry};
+use utilities::escrow::{self, Escrow, EscrowBook, EscrowEngine, EscrowPurpose, EscrowState};
+use utilities::ledger::{Account as LedgerAccount, IcrcLedger};
+use utilities::notifications::{NotificationEvent, notify};
+use utilities::rewards::{self, Payout, PayoutBook, pay};
 
 // pub mod user;
 
@@ -26,6 +32,7 @@ pub struct Course {
     pub completed: bool,
     pub image: String,
     pub category: String,
+    pub completion_reward: u64, // Amount of CRY-tokens paid on completion
 }
 
 #[derive(Serialize, Deserialize, CandidType)]
@@ -39,6 +46,7 @@ pub struct CreateCourseInput {
     pub completed: bool,
     pub image: String,
     pub category: String,
+    pub completion_reward: Option<u64>,
 }
 
 #[derive(Clone, Serialize, Deserialize, CandidType)]
@@ -157,6 +165,8 @@ pub struct CanisterState {
 
 thread_local! {
     pub static STATE: RefCell<CanisterState> = RefCell::new(CanisterState::default());
+    pub static PAYOUTS: RefCell<PayoutBook> = RefCell::new(PayoutBook::default());
+    pub static ESCROWS: RefCell<EscrowBook> = RefCell::new(EscrowBook::default());
 }
 
 pub fn generate_id<T>(map: &std::collections::HashMap<u64, T>) -> u64 {
@@ -167,22 +177,72 @@ fn now() -> u64 {
     api::time() / 1_000_000
 }
 
+// Enrolls the caller in a free course. Paid courses go through
+// `purchase_course`; controllers can enroll anyone in any course.
 #[ic_cdk::update]
-pub fn create_enrollment(input: CreateEnrollmentInput) -> Enrollment {
+pub fn create_enrollment(input: CreateEnrollmentInput) -> Result<Enrollment, String> {
+    let caller = api::msg_caller();
+    let is_controller = api::is_controller(&caller);
+
+    if input.user_id != caller && !is_controller {
+        return Err("You can only enroll yourself.".to_string());
+    }
+
+    let course = STATE
+        .with(|state| state.borrow().courses.get(&input.course_id).cloned())
+        .ok_or("Course not found.")?;
+
+    if price_in_e8s(course.price) > 0 && !is_controller {
+        return Err("Paid courses must be purchased.".to_string());
+    }
+
+    Ok(enroll(input.user_id, input.course_id))
+}
+
+// Returns the user's enrollment in the course, creating it if needed.
+fn enroll(user_id: Principal, course_id: u64) -> Enrollment {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
+
+        if let Some(enrollment) = state
+            .enrollments
+            .values()
+            .find(|e| e.course_id == course_id && e.user_id == user_id)
+        {
+            return enrollment.clone();
+        }
+
         let temp_id = generate_id(&state.enrollments);
         let timestamp = now();
 
         let enrollment = Enrollment {
             enrollment_id: temp_id,
-            user_id: input.user_id,
-            course_id: input.course_id,
+            user_id,
+            course_id,
             enrolled_at: timestamp,
             progress: 0.0,
         };
 
         state.enrollments.insert(temp_id, enrollment.clone());
+
+        // The student and the course instructor both hear about a new enrollment.
+        let mut recipients = vec![enrollment.user_id];
+        if let Some(instructor) = state
+            .courses
+            .get(&enrollment.course_id)
+            .and_then(|course| state.instructors.get(&course.instructor_id))
+        {
+            recipients.push(instructor.user_id);
+        }
+
+        notify(
+            recipients,
+            NotificationEvent::CourseEnrollment {
+                course_id: enrollment.course_id,
+                enrollment_id: enrollment.enrollment_id,
+            },
+        );
+
         enrollment
     })
 }
@@ -195,9 +255,15 @@ pub fn get_all_enrollments() -> Vec<Enrollment> {
     })
 }
 
+// Completion rewards are paid from the treasury, so only controllers can
+// attach one to a course.
 #[ic_cdk::update]
-pub fn create_course(input: CreateCourseInput) -> Course {
-    STATE.with(|state| {
+pub fn create_course(input: CreateCourseInput) -> Result<Course, String> {
+    if input.completion_reward.unwrap_or(0) > 0 && !api::is_controller(&api::msg_caller()) {
+        return Err("Only controllers can set a completion reward.".to_string());
+    }
+
+    Ok(STATE.with(|state| {
         let mut state = state.borrow_mut();
         let course_id = generate_id(&state.courses);
         let timestamp = now();
@@ -216,11 +282,12 @@ pub fn create_course(input: CreateCourseInput) -> Course {
             completed: false,
             image: input.image,
             category: input.category,
+            completion_reward: input.completion_reward.unwrap_or(0),
         };
 
         state.courses.insert(course_id, course.clone());
         course
-    })
+    }))
 }
 
 #[ic_cdk::query]
@@ -299,13 +366,21 @@ pub fn get_all_modules() -> Vec<Module> {
 
 #[ic_cdk::update]
 pub fn create_instructor(input: CreateInstructorInput) -> Instructor {
+    // Only controllers can register an instructor on someone else's behalf.
+    let caller = api::msg_caller();
+    let user_id = if api::is_controller(&caller) {
+        input.user_id
+    } else {
+        caller
+    };
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let temp_id = generate_id(&state.instructors);
 
         let instructor = Instructor {
             instructor_id: temp_id,
-            user_id: input.user_id,
+            user_id,
             full_name: input.full_name,
             email: input.email,
             phone: input.phone,
@@ -488,6 +563,277 @@ pub fn get_course_with_instructor_and_modules(course_id: u64) -> Option<CourseOv
     })
 }
 
+#[ic_cdk::update]
+pub fn set_ledger_canister(ledger_canister_id: Principal) -> Result<(), String> {
+    if !api::is_controller(&api::msg_caller()) {
+        return Err("Only controllers can configure the ledger.".to_string());
+    }
+
+    utilities::ledger::configure(ledger_canister_id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+pub fn set_notifications_canister(notifications_canister_id: Principal) -> Result<(), String> {
+    if !api::is_controller(&api::msg_caller()) {
+        return Err("Only controllers can configure notifications.".to_string());
+    }
+
+    utilities::notifications::configure(notifications_canister_id);
+    Ok(())
+}
+
+// Marks an enrollment as completed and pays the course completion reward.
+// Only the course instructor or a controller can confirm a completion.
+#[ic_cdk::update]
+pub async fn complete_course(enrollment_id: u64) -> Result<Enrollment, String> {
+    let caller = api::msg_caller();
+
+    let (enrollment, course) = STATE.with(|state| {
+        let state = state.borrow();
+        let enrollment = state
+            .enrollments
+            .get(&enrollment_id)
+            .cloned()
+            .ok_or("Enrollment not found.")?;
+        let course = state
+            .courses
+            .get(&enrollment.course_id)
+            .cloned()
+            .ok_or("Course not found.")?;
+
+        let is_instructor = state
+            .instructors
+            .get(&course.instructor_id)
+            .is_some_and(|instructor| instructor.user_id == caller);
+
+        if !is_instructor && !api::is_controller(&caller) {
+            return Err("Only the course instructor can confirm a completion.");
+        }
+
+        Ok((enrollment, course))
+    })?;
+
+    STATE.with(|state| {
+        if let Some(enrollment) = state.borrow_mut().enrollments.get_mut(&enrollment_id) {
+            enrollment.progress = 100.0;
+        }
+    });
+
+    if enrollment.progress < 100.0 {
+        notify(
+            vec![enrollment.user_id],
+            NotificationEvent::CourseCompleted {
+                course_id: course.id,
+                enrollment_id,
+            },
+        );
+    }
+
+    if course.completion_reward > 0 {
+        let ledger = IcrcLedger::configured().map_err(|_| "Ledger is not configured.")?;
+        let payout = Payout::course_completion(
+            course.id,
+            enrollment_id,
+            LedgerAccount::of(enrollment.user_id),
+            course.completion_reward,
+            api::time(),
+        );
+
+        // A reward is charged to the budget once, retries included.
+        if PAYOUTS.with_borrow(|book| book.get(&payout.id)).is_none() {
+            rewards::spend_reward_budget(course.completion_reward)?;
+        }
+
+        let payout = pay(&PAYOUTS, &ledger, payout, api::time()).await;
+
+        if !payout.is_paid() {
+            return Err(format!(
+                "Completion reward could not be paid: {:?}",
+                payout.status
+            ));
+        }
+    }
+
+    Ok(Enrollment {
+        progress: 100.0,
+        ..enrollment
+    })
+}
+
+// Course prices are expressed in CRY, the ledger counts in e8s.
+const E8S_PER_CRY: f64 = 100_000_000.0;
+
+fn price_in_e8s(price: f32) -> u64 {
+    (price as f64 * E8S_PER_CRY).round() as u64
+}
+
+fn purchase_escrow_id(course_id: u64, buyer: Principal, attempt: u64) -> String {
+    match attempt {
+        0 => format!("course:{course_id}:{buyer}"),
+        _ => format!("course:{course_id}:{buyer}:{attempt}"),
+    }
+}
+
+// The escrow of the buyer's current purchase, and its id. Refunded and
+// cancelled purchases are closed, so buying again opens a new escrow.
+fn current_purchase(course_id: u64, buyer: Principal) -> (String, Option<Escrow>) {
+    ESCROWS.with_borrow(|book| {
+        let mut attempt = 0;
+        loop {
+            let id = purchase_escrow_id(course_id, buyer, attempt);
+            match book.get(&id) {
+                Some(escrow)
+                    if matches!(escrow.state, EscrowState::Refunded | EscrowState::Cancelled) =>
+                {
+                    attempt += 1
+                }
+                escrow => return (id, escrow),
+            }
+        }
+    })
+}
+
+fn escrow_engine() -> Result<EscrowEngine<IcrcLedger>, String> {
+    Ok(EscrowEngine {
+        escrows: &ESCROWS,
+        payouts: &PAYOUTS,
+        ledger: IcrcLedger::configured().map_err(|_| "Ledger is not configured.")?,
+        canister: api::canister_self(),
+    })
+}
+
+// Buys access to a course. The price is locked in escrow from the caller (who
+// must first `icrc2_approve` this canister for the price plus the ledger fee)
+// and released to the instructor, and only then is the enrollment granted.
+// Calling it again resumes an interrupted purchase.
+#[ic_cdk::update]
+pub async fn purchase_course(course_id: u64) -> Result<Enrollment, String> {
+    let buyer = api::msg_caller();
+
+    let (course, instructor, enrollment) = STATE.with(|state| {
+        let state = state.borrow();
+        let course = state.courses.get(&course_id).cloned();
+        let instructor = course
+            .as_ref()
+            .and_then(|c| state.instructors.get(&c.instructor_id))
+            .map(|i| i.user_id);
+        let enrollment = state
+            .enrollments
+            .values()
+            .find(|e| e.course_id == course_id && e.user_id == buyer)
+            .cloned();
+        (course, instructor, enrollment)
+    });
+
+    let course = course.ok_or("Course not found.")?;
+    let (id, escrow) = current_purchase(course_id, buyer);
+
+    if let Some(enrollment) = &enrollment
+        && escrow
+            .as_ref()
+            .is_none_or(|escrow| escrow.state == EscrowState::Released)
+    {
+        return Ok(enrollment.clone());
+    }
+
+    let amount = price_in_e8s(course.price);
+    if amount == 0 {
+        return Ok(enroll(buyer, course_id));
+    }
+
+    let engine = escrow_engine()?;
+
+    let escrow = engine
+        .lock(
+            id.clone(),
+            EscrowPurpose::CourseAccess { course_id, buyer },
+            LedgerAccount::of(buyer),
+            amount,
+            api::time(),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+
+    // A refund may have closed the escrow while the lock was in flight.
+    if !matches!(
+        escrow.state,
+        EscrowState::Funded | EscrowState::Releasing | EscrowState::Released
+    ) {
+        return Err(format!("Purchase is {:?}.", escrow.state));
+    }
+
+    let Some(instructor) = instructor else {
+        engine
+            .refund(&id, api::time())
+            .await
+            .map_err(|err| err.to_string())?;
+        return Err("Course has no instructor, the purchase was refunded.".to_string());
+    };
+
+    if escrow.state != EscrowState::Released {
+        engine
+            .release(
+                &id,
+                vec![(LedgerAccount::of(instructor), amount)],
+                api::time(),
+            )
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    Ok(enroll(buyer, course_id))
+}
+
+// Refunds a purchase that was locked but not yet paid out, and revokes the
+// enrollment. Once paying the instructor started it can only be resumed,
+// through `purchase_course`. Controllers only.
+#[ic_cdk::update]
+pub async fn refund_course_purchase(course_id: u64, buyer: Principal) -> Result<Escrow, String> {
+    if !api::is_controller(&api::msg_caller()) {
+        return Err("Only controllers can refund purchases.".to_string());
+    }
+
+    let (id, _) = current_purchase(course_id, buyer);
+    let escrow = escrow_engine()?
+        .refund(&id, api::time())
+        .await
+        .map_err(|err| err.to_string())?;
+
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .enrollments
+            .retain(|_, e| !(e.course_id == course_id && e.user_id == buyer));
+    });
+
+    Ok(escrow)
+}
+
+#[ic_cdk::query]
+pub fn get_my_purchases() -> Vec<Escrow> {
+    let caller = api::msg_caller();
+
+    ESCROWS.with_borrow(|book| {
+        book.list()
+            .into_iter()
+            .filter(|escrow| escrow.depositor.owner == caller)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+pub fn get_my_payouts() -> Vec<Payout> {
+    let caller = api::msg_caller();
+
+    PAYOUTS.with_borrow(|book| {
+        book.list()
+            .into_iter()
+            .filter(|payout| payout.to.owner == caller)
+            .collect()
+    })
+}
+
 #[ic_cdk::update]
 fn seed_all() {
     let demo_courses = vec![
@@ -506,6 +852,7 @@ fn seed_all() {
             completed: false,
             image: "/images/placeholder/rust.png".to_string(),
             category: "Coding".to_string(),
+            completion_reward: 100,
         },
         Course {
             id: 2,
@@ -524,6 +871,7 @@ fn seed_all() {
             completed: false,
             image: "/images/placeholder/webdev.png".to_string(),
             category: "Web Development".to_string(),
+            completion_reward: 0,
         },
         Course {
             id: 3,
@@ -541,6 +889,7 @@ fn seed_all() {
             completed: false,
             image: "/images/placeholder/datascience.png".to_string(),
             category: "Data Science".to_string(),
+            completion_reward: 0,
         },
         Course {
             id: 4,
@@ -558,6 +907,7 @@ fn seed_all() {
             completed: false,
             image: "/images/placeholder/design.png".to_string(),
             category: "Design".to_string(),
+            completion_reward: 0,
         },
         Course {
             id: 5,
@@ -574,6 +924,7 @@ fn seed_all() {
             completed: false,
             image: "/images/placeholder/business.png".to_string(),
             category: "Business".to_string(),
+            completion_reward: 0,
         },
     ];
 
@@ -629,6 +980,67 @@ fn seed_all() {
             state.lectures.insert(lecture.lecture_id, lecture.clone());
         }
     });
+
+    audit::record(
+        "seed_all",
+        demo_courses
+            .iter()
+            .map(|course| course.id.to_string())
+            .collect(),
+        None,
+        Some(format!(
+            "seeded {} courses, {} modules, {} lectures",
+            demo_courses.len(),
+            demo_modules.len(),
+            demo_lectures.len()
+        )),
+    );
+}
+
+// Rewards
+#[ic_cdk::update]
+pub fn set_reward_budget(amount: u64) -> Result<(), String> {
+    if !api::is_controller(&api::msg_caller()) {
+        return Err("Only controllers can set the reward budget.".to_string());
+    }
+
+    let before = rewards::reward_budget();
+    rewards::set_reward_budget(amount);
+    audit::record(
+        "set_reward_budget",
+        vec![],
+        Some(before.to_string()),
+        Some(amount.to_string()),
+    );
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub fn get_reward_budget() -> u64 {
+    rewards::reward_budget()
+}
+
+// Audit
+#[ic_cdk::query]
+fn get_audit_log(page: usize, per_page: usize) -> Result<PaginatorResponse<AuditEntry>, String> {
+    if !api::is_controller(&api::msg_caller()) {
+        return Err("Only controllers can read the audit log.".to_string());
+    }
+
+    Ok(audit::page(page, per_page))
+}
+
+// Upgrades
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    rewards::save_book(&PAYOUTS);
+    escrow::save_book(&ESCROWS);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    rewards::restore_book(&PAYOUTS);
+    escrow::restore_book(&ESCROWS);
 }
 
 export_candid!();
@@ -1,9 +1,21 @@
 use candid::{CandidType, Principal};
 use ic_cdk::{api::msg_caller, export_candid};
+use paginator::PaginatorResponse;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::HashMap;
-use utilities::{StoredFile, generate_uuid, get_files, now};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use utilities::audit::{self, AuditEntry};
+use utilities::escrow::{self, Escrow, EscrowBook, EscrowEngine, EscrowPurpose, EscrowState};
+use utilities::ledger::{Account as LedgerAccount, IcrcLedger};
+use utilities::memory::{self, PRIZES_MEMORY_ID, RESOURCES_MEMORY_ID};
+use utilities::notifications::{NotificationEvent, notify};
+use utilities::rewards::{self, Payout, PayoutBook, RewardKind, pay};
+use utilities::{
+    StorageEvent, StoredFile, generate_uuid, get_files, now, now_as_datetime, reference_files,
+    unreference_files,
+};
 
 #[derive(Clone, Serialize, Deserialize, CandidType)]
 pub enum Difficulty {
@@ -118,6 +130,7 @@ pub struct Competition {
     pub rules: Vec<String>,
     pub started_at: String,
     pub ended_at: String,
+    pub finalized_at: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize, CandidType)]
@@ -186,6 +199,12 @@ thread_local! {
     static COORDINATORS: RefCell<HashMap<String, Coordinator>> = RefCell::new(HashMap::new());
     static PARTICIPANTS: RefCell<HashMap<String, Participant>> = RefCell::new(HashMap::new());
     static SUBMISSIONS: RefCell<HashMap<String, Submission>> = RefCell::new(HashMap::new());
+    static PRIZES: RefCell<HashMap<String, Vec<Prize>>> = RefCell::new(HashMap::new());
+    // Storage file ids of each competition's problem statements and guidelines.
+    static RESOURCES: RefCell<HashMap<String, Vec<String>>> = RefCell::new(HashMap::new());
+    static PAYOUTS: RefCell<PayoutBook> = RefCell::new(PayoutBook::default());
+    static ESCROWS: RefCell<EscrowBook> = RefCell::new(EscrowBook::default());
+    static DEADLINE_NOTIFIED: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
 }
 
 // SEEDERS
@@ -213,6 +232,7 @@ fn competition_seeders() {
             rules: vec!["Rule 1".to_string(), "Rule 2".to_string()],
             started_at: now(), // Example timestamp
             ended_at: now(),   // Example timestamp
+            finalized_at: None,
         },
         Competition {
             id: "comp2".to_string(),
@@ -225,15 +245,20 @@ fn competition_seeders() {
             rules: vec!["Rule A".to_string(), "Rule B".to_string()],
             started_at: now(), // Example timestamp
             ended_at: now(),   // Example timestamp
+            finalized_at: None,
         },
     ];
 
+    let ids: Vec<String> = demo_competitions.iter().map(|c| c.id.clone()).collect();
+
     COMPETITIONS.with(|state| {
         let mut state = state.borrow_mut();
         for competition in demo_competitions {
             state.insert(competition.id.clone(), competition);
         }
     });
+
+    record_seed("competition_seeders", ids);
 }
 
 #[ic_cdk::update]
@@ -247,9 +272,9 @@ fn account_seeders() {
         ("Frank", "ccccc-aa"),
     ];
     let now = now();
-    let mut idx = 1;
-    for (username, principal_str) in demo_users {
-        let id = format!("acc{idx}");
+    let mut ids = Vec::new();
+    for (idx, (username, principal_str)) in demo_users.into_iter().enumerate() {
+        let id = format!("acc{}", idx + 1);
         let user_id = Principal::from_text(principal_str).unwrap_or(Principal::anonymous());
         let account = Account {
             id: id.clone(),
@@ -262,25 +287,29 @@ fn account_seeders() {
         };
         // Insert into a global ACCOUNTS map (assume exists)
         ACCOUNTS.with(|state| state.borrow_mut().insert(id.clone(), account));
-        idx += 1;
+        ids.push(id);
     }
+
+    record_seed("account_seeders", ids);
 }
 
 #[ic_cdk::update]
 fn coordinator_seeders() {
     // Example: assign first two accounts as coordinators for the two demo competitions
     let demo_coordinators = vec![("acc1", "comp1"), ("acc2", "comp2")];
-    let mut idx = 1;
-    for (account_id, competition_id) in demo_coordinators {
-        let id = format!("coord{idx}");
+    let mut ids = Vec::new();
+    for (idx, (account_id, competition_id)) in demo_coordinators.into_iter().enumerate() {
+        let id = format!("coord{}", idx + 1);
         let coordinator = Coordinator {
             id: id.clone(),
             account_id: account_id.to_string(),
             competition_id: competition_id.to_string(),
         };
         COORDINATORS.with(|state| state.borrow_mut().insert(id.clone(), coordinator));
-        idx += 1;
+        ids.push(id);
     }
+
+    record_seed("coordinator_seeders", ids);
 }
 
 #[ic_cdk::update]
@@ -288,6 +317,7 @@ fn participant_seeders() {
     // Example: assign all accounts as participants in both competitions
     let account_ids = vec!["acc1", "acc2", "acc3", "acc4", "acc5"];
     let competition_ids = vec!["comp1", "comp2"];
+    let mut ids = Vec::new();
     let mut idx = 1;
     for account_id in &account_ids {
         for competition_id in &competition_ids {
@@ -299,14 +329,18 @@ fn participant_seeders() {
                 score: None,
             };
             PARTICIPANTS.with(|state| state.borrow_mut().insert(id.clone(), participant));
+            ids.push(id);
             idx += 1;
         }
     }
+
+    record_seed("participant_seeders", ids);
 }
 
 #[ic_cdk::update]
 fn submission_seeders() {
     // Example: each participant submits once
+    let mut ids = Vec::new();
     let mut idx = 1;
     PARTICIPANTS.with(|state| {
         for participant in state.borrow().values() {
@@ -317,9 +351,17 @@ fn submission_seeders() {
                 submitted_at: Some(now()),
             };
             SUBMISSIONS.with(|subs| subs.borrow_mut().insert(id.clone(), submission));
+            ids.push(id);
             idx += 1;
         }
     });
+
+    record_seed("submission_seeders", ids);
+}
+
+fn record_seed(endpoint: &str, ids: Vec<String>) {
+    let summary = format!("seeded {} records", ids.len());
+    audit::record(endpoint, ids, None, Some(summary));
 }
 
 async fn get_profile_picture(
@@ -338,7 +380,7 @@ fn get_all_accounts() -> Vec<Account> {
 }
 
 #[ic_cdk::update]
-async fn create_account(input: CreateAccountInput, storage_canister_id: Principal) -> String {
+async fn create_account(input: CreateAccountInput, _storage_canister_id: Principal) -> String {
     let principal = msg_caller();
 
     let account_id = generate_uuid();
@@ -445,8 +487,14 @@ fn get_all_competitions() -> Vec<CompetitionBriefInformation> {
     result
 }
 
+// Prizes may end up paid from the treasury, so only controllers can create
+// a competition that carries one.
 #[ic_cdk::update]
 async fn create_competition(input: CreateCompetitionInput) -> String {
+    if input.prize > 0 && !ic_cdk::api::is_controller(&msg_caller()) {
+        return "".to_string();
+    }
+
     let competition_id = generate_uuid();
     let new_competition = Competition {
         id: competition_id.clone(),
@@ -459,6 +507,7 @@ async fn create_competition(input: CreateCompetitionInput) -> String {
         rules: input.rules,
         started_at: input.started_at,
         ended_at: input.ended_at,
+        finalized_at: None,
     };
 
     COMPETITIONS.with(|state| {
@@ -484,6 +533,8 @@ fn get_all_coordinators(competition_id: String) -> Vec<Coordinator> {
     })
 }
 
+// Coordinators are appointed by controllers or by the competition's existing
+// coordinators.
 #[ic_cdk::update]
 fn create_coordinator(input: CreateCoordinatorInput) -> String {
     let competition_exist = COMPETITIONS.with(|state| {
@@ -496,6 +547,16 @@ fn create_coordinator(input: CreateCoordinatorInput) -> String {
         return "".to_string();
     };
 
+    let principal = msg_caller();
+    if !is_coordinator(&input.competition_id, principal) && !ic_cdk::api::is_controller(&principal)
+    {
+        return "".to_string();
+    }
+
+    if ACCOUNTS.with_borrow(|accounts| !accounts.contains_key(&input.account_id)) {
+        return "".to_string();
+    }
+
     let coordinator_id = generate_uuid();
     let new_coordinator = Coordinator {
         id: coordinator_id.clone(),
@@ -538,6 +599,17 @@ async fn create_participant(input: CreateParticipantInput) -> String {
         return "".to_string();
     }
 
+    // Accounts can only join on their owner's behalf.
+    let is_owner = ACCOUNTS.with_borrow(|accounts| {
+        accounts
+            .get(&input.account_id)
+            .is_some_and(|acc| acc.user_id == msg_caller())
+    });
+
+    if !is_owner {
+        return "".to_string();
+    }
+
     let particant_id = generate_uuid();
     let new_participant = Participant {
         id: particant_id.clone(),
@@ -600,4 +672,508 @@ async fn create_submission(input: CreateSubmissionInput) -> String {
     submission_id
 }
 
+// REWARDS
+
+fn is_coordinator(competition_id: &str, principal: Principal) -> bool {
+    COORDINATORS.with_borrow(|coordinators| {
+        coordinators
+            .values()
+            .filter(|c| c.competition_id == competition_id)
+            .any(|c| {
+                ACCOUNTS.with_borrow(|accounts| {
+                    accounts
+                        .get(&c.account_id)
+                        .is_some_and(|acc| acc.user_id == principal)
+                })
+            })
+    })
+}
+
+fn participant_owner(competition_id: &str, participant_id: &str) -> Option<Principal> {
+    let account_id = PARTICIPANTS.with_borrow(|participants| {
+        participants
+            .get(participant_id)
+            .filter(|p| p.competition_id == competition_id)
+            .map(|p| p.account_id.clone())
+    })?;
+
+    ACCOUNTS.with_borrow(|accounts| accounts.get(&account_id).map(|acc| acc.user_id))
+}
+
+#[ic_cdk::update]
+fn set_ledger_canister(ledger_canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure the ledger.".to_string());
+    }
+
+    utilities::ledger::configure(ledger_canister_id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_competition_prizes(competition_id: String, prizes: Vec<Prize>) -> Result<(), String> {
+    let competition = COMPETITIONS
+        .with_borrow(|state| state.get(&competition_id).cloned())
+        .ok_or("Competition not found.")?;
+
+    let principal = msg_caller();
+    if !is_coordinator(&competition_id, principal) && !ic_cdk::api::is_controller(&principal) {
+        return Err("You are not a coordinator of this competition.".to_string());
+    }
+
+    if competition.finalized_at.is_some() {
+        return Err("Competition is already finalized.".to_string());
+    }
+
+    let total: u64 = prizes.iter().map(|p| p.amount).sum();
+    if total > competition.prize {
+        return Err("Prizes exceed the competition prize pool.".to_string());
+    }
+
+    let prizes = prizes
+        .into_iter()
+        .map(|prize| Prize {
+            competition_id: competition_id.clone(),
+            ..prize
+        })
+        .collect();
+
+    PRIZES.with_borrow_mut(|state| state.insert(competition_id, prizes));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_competition_prizes(competition_id: String) -> Vec<Prize> {
+    PRIZES.with_borrow(|state| state.get(&competition_id).cloned().unwrap_or_default())
+}
+
+// RESOURCES
+
+// Replaces the competition's resources with `file_ids`, referencing them in
+// storage so they aren't deleted while the competition uses them.
+#[ic_cdk::update]
+fn set_competition_resources(competition_id: String, file_ids: Vec<String>) -> Result<(), String> {
+    if !COMPETITIONS.with_borrow(|state| state.contains_key(&competition_id)) {
+        return Err("Competition not found.".to_string());
+    }
+
+    let principal = msg_caller();
+    if !is_coordinator(&competition_id, principal) && !ic_cdk::api::is_controller(&principal) {
+        return Err("You are not a coordinator of this competition.".to_string());
+    }
+
+    let previous = RESOURCES
+        .with_borrow_mut(|state| state.insert(competition_id.clone(), file_ids.clone()))
+        .unwrap_or_default();
+
+    if let Some(storage_canister_id) = utilities::storage_canister_id() {
+        let dropped = previous
+            .into_iter()
+            .filter(|file_id| !file_ids.contains(file_id))
+            .collect();
+        unreference_files(storage_canister_id, dropped, competition_id.clone());
+        reference_files(storage_canister_id, file_ids, competition_id, principal);
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_competition_resources(competition_id: String) -> Vec<String> {
+    RESOURCES.with_borrow(|state| state.get(&competition_id).cloned().unwrap_or_default())
+}
+
+// STORAGE
+
+// Competitions reference their resources in the storage canister set here,
+// which then refuses or reports their deletion through `storage_event`.
+#[ic_cdk::update]
+fn set_storage_canister(storage_canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure storage.".to_string());
+    }
+
+    utilities::configure_storage(storage_canister_id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn storage_event(event: StorageEvent) {
+    if utilities::storage_canister_id() != Some(msg_caller()) {
+        return;
+    }
+
+    match event {
+        StorageEvent::FileDeleted { file_id, entities } => {
+            RESOURCES.with_borrow_mut(|state| {
+                for competition_id in entities.iter() {
+                    if let Some(resources) = state.get_mut(competition_id) {
+                        resources.retain(|resource| *resource != file_id);
+                    }
+                }
+            });
+        }
+        // Resources are read through storage, which checks permissions itself.
+        StorageEvent::PermissionsChanged { .. } => {}
+    }
+}
+
+fn prize_pool_id(competition_id: &str) -> String {
+    format!("prize-pool:{competition_id}")
+}
+
+fn escrow_engine() -> Result<EscrowEngine<IcrcLedger>, String> {
+    Ok(EscrowEngine {
+        escrows: &ESCROWS,
+        payouts: &PAYOUTS,
+        ledger: IcrcLedger::configured().map_err(|_| "Ledger is not configured.")?,
+        canister: ic_cdk::api::canister_self(),
+    })
+}
+
+// Locks the full competition prize from the caller into the competition's
+// prize pool escrow. The caller must first `icrc2_approve` this canister for
+// the prize plus the ledger fee.
+#[ic_cdk::update]
+async fn fund_prize_pool(competition_id: String) -> Result<Escrow, String> {
+    let competition = COMPETITIONS
+        .with_borrow(|state| state.get(&competition_id).cloned())
+        .ok_or("Competition not found.")?;
+
+    if competition.finalized_at.is_some() || competition.status == "Cancelled" {
+        return Err("Competition is closed.".to_string());
+    }
+
+    let sponsor = LedgerAccount::of(msg_caller());
+    let id = prize_pool_id(&competition_id);
+
+    if let Some(escrow) = ESCROWS.with_borrow(|book| book.get(&id))
+        && escrow.depositor != sponsor
+    {
+        return Err("Prize pool is already sponsored.".to_string());
+    }
+
+    escrow_engine()?
+        .lock(
+            id,
+            EscrowPurpose::PrizePool { competition_id },
+            sponsor,
+            competition.prize,
+            ic_cdk::api::time(),
+        )
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[ic_cdk::query]
+fn get_prize_pool(competition_id: String) -> Option<Escrow> {
+    ESCROWS.with_borrow(|book| book.get(&prize_pool_id(&competition_id)))
+}
+
+// Marks the competition finalized, once, and tells its participants.
+fn mark_finalized(competition: &Competition) {
+    let marked = COMPETITIONS.with_borrow_mut(|state| {
+        let comp = state.get_mut(&competition.id)?;
+        if comp.finalized_at.is_some() {
+            return None;
+        }
+
+        comp.finalized_at = Some(now());
+        Some(())
+    });
+
+    if marked.is_some() {
+        notify(
+            competition_participants(&competition.id),
+            NotificationEvent::CompetitionFinalized {
+                competition_id: competition.id.clone(),
+                title: competition.title.clone(),
+            },
+        );
+    }
+}
+
+// Pays every prize to the participant ranked at its order (1-based) in
+// `winners`, from the prize pool escrow when one is funded and otherwise,
+// for controllers only, from the canister treasury. Calling it again only
+// retries unpaid prizes.
+// A prize pool is only final once its release plan is fixed; what the plan
+// leaves over, such as prizes without a winner, goes back to the sponsor.
+#[ic_cdk::update]
+async fn finalize_competition(
+    competition_id: String,
+    winners: Vec<String>,
+) -> Result<Vec<Payout>, String> {
+    let competition = COMPETITIONS
+        .with_borrow(|state| state.get(&competition_id).cloned())
+        .ok_or("Competition not found.")?;
+
+    let principal = msg_caller();
+    if !is_coordinator(&competition_id, principal) && !ic_cdk::api::is_controller(&principal) {
+        return Err("You are not a coordinator of this competition.".to_string());
+    }
+
+    if competition.status == "Cancelled" {
+        return Err("Competition was cancelled.".to_string());
+    }
+
+    let ledger = IcrcLedger::configured().map_err(|_| "Ledger is not configured.")?;
+    let pool = ESCROWS.with_borrow(|book| book.get(&prize_pool_id(&competition_id)));
+    let from_pool = pool.as_ref().is_some_and(|escrow| {
+        matches!(
+            escrow.state,
+            EscrowState::Funded | EscrowState::Releasing | EscrowState::Released
+        )
+    });
+
+    if !from_pool && competition.prize > 0 && !ic_cdk::api::is_controller(&principal) {
+        return Err("Fund the prize pool before finalizing the competition.".to_string());
+    }
+
+    let mut ranked: Vec<(Prize, Principal)> = Vec::new();
+
+    if competition.finalized_at.is_none() {
+        let mut prizes = PRIZES
+            .with_borrow(|state| state.get(&competition_id).cloned())
+            .unwrap_or(vec![Prize {
+                order: 1,
+                competition_id: competition_id.clone(),
+                amount: competition.prize,
+            }]);
+        prizes.sort_by_key(|p| p.order);
+
+        for prize in prizes.into_iter().filter(|p| p.amount > 0) {
+            let Some(participant_id) = winners.get(prize.order.saturating_sub(1) as usize) else {
+                continue;
+            };
+
+            let owner = participant_owner(&competition_id, participant_id)
+                .ok_or(format!("{participant_id} is not a participant."))?;
+
+            ranked.push((prize, owner));
+        }
+    }
+
+    if from_pool {
+        let id = prize_pool_id(&competition_id);
+        let plan = ranked
+            .into_iter()
+            .map(|(prize, owner)| (LedgerAccount::of(owner), prize.amount))
+            .collect();
+
+        let released = pool.is_some_and(|escrow| escrow.state == EscrowState::Released);
+        let result = if released {
+            Ok(())
+        } else {
+            escrow_engine()?
+                .release(&id, plan, ic_cdk::api::time())
+                .await
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        };
+
+        // A plan that was refused leaves the pool funded, so the competition
+        // can still be finalized again or cancelled.
+        let fixed = ESCROWS
+            .with_borrow(|book| book.get(&id))
+            .is_some_and(|escrow| {
+                matches!(escrow.state, EscrowState::Releasing | EscrowState::Released)
+            });
+        if fixed {
+            mark_finalized(&competition);
+        }
+
+        result?;
+        return Ok(competition_payouts(&competition_id));
+    }
+
+    mark_finalized(&competition);
+
+    let payouts: Vec<Payout> = if ranked.is_empty() {
+        competition_payouts(&competition_id)
+    } else {
+        ranked
+            .into_iter()
+            .map(|(prize, owner)| {
+                Payout::competition_prize(
+                    competition_id.clone(),
+                    prize.order,
+                    LedgerAccount::of(owner),
+                    prize.amount,
+                    ic_cdk::api::time(),
+                )
+            })
+            .collect()
+    };
+
+    let mut result = Vec::new();
+    for payout in payouts {
+        result.push(pay(&PAYOUTS, &ledger, payout, ic_cdk::api::time()).await);
+    }
+
+    Ok(result)
+}
+
+// Cancels a competition that has not been finalized and refunds its sponsor.
+#[ic_cdk::update]
+async fn cancel_competition(competition_id: String) -> Result<Option<Escrow>, String> {
+    let competition = COMPETITIONS
+        .with_borrow(|state| state.get(&competition_id).cloned())
+        .ok_or("Competition not found.")?;
+
+    let principal = msg_caller();
+    if !is_coordinator(&competition_id, principal) && !ic_cdk::api::is_controller(&principal) {
+        return Err("You are not a coordinator of this competition.".to_string());
+    }
+
+    if competition.finalized_at.is_some() {
+        return Err("Competition is already finalized.".to_string());
+    }
+
+    COMPETITIONS.with_borrow_mut(|state| {
+        if let Some(comp) = state.get_mut(&competition_id) {
+            comp.status = "Cancelled".to_string();
+        }
+    });
+
+    let id = prize_pool_id(&competition_id);
+    if ESCROWS.with_borrow(|book| book.get(&id)).is_none() {
+        return Ok(None);
+    }
+
+    escrow_engine()?
+        .refund(&id, ic_cdk::api::time())
+        .await
+        .map(Some)
+        .map_err(|err| err.to_string())
+}
+
+fn competition_payouts(competition_id: &str) -> Vec<Payout> {
+    let pool_id = prize_pool_id(competition_id);
+
+    PAYOUTS.with_borrow(|book| {
+        book.list()
+            .into_iter()
+            .filter(|payout| match &payout.kind {
+                RewardKind::CompetitionPrize {
+                    competition_id: id, ..
+                } => id == competition_id,
+                RewardKind::EscrowRelease { escrow_id, .. } => *escrow_id == pool_id,
+                _ => false,
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_competition_payouts(competition_id: String) -> Vec<Payout> {
+    competition_payouts(&competition_id)
+}
+
+// NOTIFICATIONS
+
+const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEADLINE_NOTICE: time::Duration = time::Duration::hours(24);
+
+#[ic_cdk::init]
+fn init() {
+    start_deadline_timer();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    rewards::save_book(&PAYOUTS);
+    escrow::save_book(&ESCROWS);
+    PRIZES.with_borrow(|prizes| memory::save(PRIZES_MEMORY_ID, prizes));
+    RESOURCES.with_borrow(|resources| memory::save(RESOURCES_MEMORY_ID, resources));
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    rewards::restore_book(&PAYOUTS);
+    escrow::restore_book(&ESCROWS);
+    if let Some(prizes) = memory::restore(PRIZES_MEMORY_ID) {
+        PRIZES.set(prizes);
+    }
+    if let Some(resources) = memory::restore(RESOURCES_MEMORY_ID) {
+        RESOURCES.set(resources);
+    }
+    start_deadline_timer();
+}
+
+fn start_deadline_timer() {
+    ic_cdk_timers::set_timer_interval(DEADLINE_CHECK_INTERVAL, notify_upcoming_deadlines);
+}
+
+#[ic_cdk::update]
+fn set_notifications_canister(notifications_canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure notifications.".to_string());
+    }
+
+    utilities::notifications::configure(notifications_canister_id);
+    Ok(())
+}
+
+fn competition_participants(competition_id: &str) -> Vec<Principal> {
+    let account_ids: Vec<String> = PARTICIPANTS.with_borrow(|participants| {
+        participants
+            .values()
+            .filter(|p| p.competition_id == competition_id)
+            .map(|p| p.account_id.clone())
+            .collect()
+    });
+
+    ACCOUNTS.with_borrow(|accounts| {
+        account_ids
+            .iter()
+            .filter_map(|id| accounts.get(id).map(|acc| acc.user_id))
+            .collect()
+    })
+}
+
+// Warns participants once when a running competition ends within
+// `DEADLINE_NOTICE`. Competitions whose `ended_at` is not RFC 3339 are skipped.
+fn notify_upcoming_deadlines() {
+    let current = now_as_datetime();
+
+    let due: Vec<Competition> = COMPETITIONS.with_borrow(|state| {
+        state
+            .values()
+            .filter(|comp| comp.finalized_at.is_none() && comp.status != "Cancelled")
+            .filter(|comp| !DEADLINE_NOTIFIED.with_borrow(|notified| notified.contains(&comp.id)))
+            .filter(|comp| {
+                OffsetDateTime::parse(&comp.ended_at, &Rfc3339).is_ok_and(|ended_at| {
+                    ended_at > current && ended_at - current <= DEADLINE_NOTICE
+                })
+            })
+            .cloned()
+            .collect()
+    });
+
+    for comp in due {
+        DEADLINE_NOTIFIED.with_borrow_mut(|notified| notified.insert(comp.id.clone()));
+
+        notify(
+            competition_participants(&comp.id),
+            NotificationEvent::CompetitionDeadline {
+                competition_id: comp.id,
+                title: comp.title,
+                ended_at: comp.ended_at,
+            },
+        );
+    }
+}
+
+// AUDIT
+
+#[ic_cdk::query]
+fn get_audit_log(page: usize, per_page: usize) -> Result<PaginatorResponse<AuditEntry>, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can read the audit log.".to_string());
+    }
+
+    Ok(audit::page(page, per_page))
+}
+
 export_candid!();
@@ -0,0 +1,1045 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Principal};
+use paginator::Filter;
+use serde::Deserialize;
+use utilities::ledger::Account as LedgerAccount;
+use utilities::notifications::{NotificationEvent, NotificationType};
+use utilities::{Access, FileUploadResolveType, Group, StoredFile, UploadContext};
+
+use crate::TestEnv;
+
+// Mirrors of the canister interfaces. Replies only declare the fields the
+// tests look at, candid skips the rest.
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Page<T> {
+    pub total_data: u64,
+    pub curr_page: u64,
+    pub data: Vec<T>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Notification {
+    pub id: String,
+    pub kind: NotificationType,
+    pub event: NotificationEvent,
+    pub read: bool,
+}
+
+// Storage
+
+pub struct FileBuilder {
+    file: StoredFile,
+}
+
+impl FileBuilder {
+    pub fn new(name: &str) -> Self {
+        Self {
+            file: StoredFile {
+                id: String::new(),
+                name: name.to_string(),
+                mime_type: "text/plain".to_string(),
+                size: 0,
+                data: Vec::new(),
+                content_hash: String::new(),
+                parent_id: None,
+                owner: Principal::anonymous(),
+                groups: Vec::new(),
+                allowed_users: Vec::new(),
+                public: false,
+                tags: Vec::new(),
+                metadata: BTreeMap::new(),
+                uploaded_at: String::new(),
+            },
+        }
+    }
+
+    pub fn mime_type(mut self, mime_type: &str) -> Self {
+        self.file.mime_type = mime_type.to_string();
+        self
+    }
+
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.file.size = data.len();
+        self.file.data = data.to_vec();
+        self
+    }
+
+    pub fn public(mut self) -> Self {
+        self.file.public = true;
+        self
+    }
+
+    pub fn parent(mut self, folder_id: &str) -> Self {
+        self.file.parent_id = Some(folder_id.to_string());
+        self
+    }
+
+    pub fn allow(mut self, user: Principal, access: Access) -> Self {
+        self.file.allowed_users.push((user, access));
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.file.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn metadata(mut self, key: &str, value: &str) -> Self {
+        self.file
+            .metadata
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn build(self) -> StoredFile {
+        self.file
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileMetadata {
+    pub id: String,
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub content_hash: String,
+    pub chunk_count: u32,
+    pub parent_id: Option<String>,
+    pub owner: Principal,
+    pub public: bool,
+    pub encrypted: bool,
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadMetadata {
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub groups: Vec<Group>,
+    pub allowed_users: Vec<(Principal, Access)>,
+    pub public: bool,
+    pub parent_id: Option<String>,
+    pub context: Option<UploadContext>,
+}
+
+impl UploadMetadata {
+    pub fn new(name: &str, size: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            mime_type: "application/octet-stream".to_string(),
+            size: size as u64,
+            groups: Vec::new(),
+            allowed_users: Vec::new(),
+            public: false,
+            parent_id: None,
+            context: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadStatus {
+    pub upload_id: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u32,
+    pub missing_chunks: Vec<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum QuotaError {
+    BytesExceeded {
+        bytes_used: u64,
+        requested: u64,
+        max_bytes: u64,
+    },
+    FilesExceeded {
+        max_files: u64,
+    },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum UploadError {
+    QuotaExceeded(QuotaError),
+    Rejected(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Quota {
+    pub max_bytes: u64,
+    pub max_files: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Usage {
+    pub bytes_used: u64,
+    pub file_count: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UsageReport {
+    pub usage: Usage,
+    pub quota: Quota,
+    pub purchased_bytes: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileChunk {
+    pub index: u32,
+    pub chunk_count: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GarbageCollection {
+    pub removed_blobs: u64,
+    pub freed_bytes: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+    pub upgrade: Option<bool>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingToken {
+    pub file_id: String,
+    pub index: u32,
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum StreamingStrategy {
+    Callback { token: StreamingToken },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateShareLinkInput {
+    pub file_id: String,
+    pub access: Access,
+    pub expires_in_seconds: u64,
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ShareLink {
+    pub id: u64,
+    pub file_id: String,
+    pub access: Access,
+    pub uses: u32,
+    pub token: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingToken>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateFolderInput {
+    pub name: String,
+    pub parent_id: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Folder {
+    pub id: String,
+    pub name: String,
+    pub parent_id: Option<String>,
+    pub owner: Principal,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum FolderEntry {
+    Folder(Folder),
+    File(FileMetadata),
+}
+
+impl FolderEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            FolderEntry::Folder(folder) => &folder.name,
+            FolderEntry::File(file) => &file.name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct FolderDeletion {
+    pub deleted_folders: u64,
+    pub deleted_files: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileVersion {
+    pub version: u32,
+    pub content_hash: String,
+    pub size: u64,
+    pub uploaded_by: Principal,
+    pub comment: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TrashedFile {
+    pub file: FileMetadata,
+    pub deleted_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TrashedFolder {
+    pub folders: Vec<Folder>,
+    pub file_ids: Vec<String>,
+    pub deleted_by: Principal,
+    pub deleted_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Trash {
+    pub files: Vec<TrashedFile>,
+    pub folders: Vec<TrashedFolder>,
+}
+
+// Storage's `Group`, whose `utilities` twin keeps its fields private.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GroupRecord {
+    pub id: String,
+    pub name: String,
+    pub members: Vec<(Principal, Access)>,
+    pub owner: Principal,
+    pub public: bool,
+}
+
+impl GroupRecord {
+    pub fn new(name: &str, members: Vec<(Principal, Access)>) -> Self {
+        Self {
+            id: String::new(),
+            name: name.to_string(),
+            members,
+            owner: Principal::anonymous(),
+            public: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum GrantPath {
+    Owner,
+    Direct,
+    Public,
+    Folder { folder_id: String, name: String },
+    Group { group_id: String, name: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Grant {
+    pub access: Access,
+    pub path: GrantPath,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AccessExplanation {
+    pub file_id: String,
+    pub principal: Principal,
+    pub effective: Vec<Access>,
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum VariantSize {
+    Thumbnail,
+    Small,
+    Medium,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DeletePolicy {
+    Restrict,
+    Cascade,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct FileReference {
+    pub canister: Principal,
+    pub entity: String,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum SortField {
+    Name,
+    Size,
+    MimeType,
+    UploadedAt,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileSort {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+#[derive(Clone, CandidType)]
+pub struct FileQuery {
+    pub text: Option<String>,
+    pub filters: Vec<Filter>,
+    pub sort: Option<FileSort>,
+    pub page: u64,
+    pub per_page: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct WrappedKey {
+    pub key_version: u32,
+    pub wrapped_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FileKeyStatus {
+    pub key_version: u32,
+    pub holders: Vec<Principal>,
+    pub missing: Vec<Principal>,
+    pub rotation_pending: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Bucket {
+    pub canister_id: Principal,
+    pub bytes_used: u64,
+    pub file_count: u64,
+    pub created_at: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadPolicy {
+    pub allowed_types: Vec<String>,
+    pub max_size: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct FileStats {
+    pub downloads: u64,
+    pub last_accessed: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum GrowthPeriod {
+    Day,
+    Month,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OwnerStats {
+    pub owner: Principal,
+    pub usage: Usage,
+    pub downloads: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MimeTypeStats {
+    pub mime_type: String,
+    pub usage: Usage,
+    pub downloads: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GrowthBucket {
+    pub period: String,
+    pub bytes_added: u64,
+    pub bytes_removed: u64,
+    pub files_added: u64,
+    pub files_removed: u64,
+    pub usage: Usage,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PopularFile {
+    pub file_id: String,
+    pub name: String,
+    pub owner: Principal,
+    pub stats: FileStats,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StorageStats {
+    pub usage: Usage,
+    pub by_owner: Vec<OwnerStats>,
+    pub by_mime_type: Vec<MimeTypeStats>,
+    pub growth: Vec<GrowthBucket>,
+    pub most_downloaded: Vec<PopularFile>,
+}
+
+// TownTalk
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AccountProfileCreationPayload {
+    pub username: String,
+    pub about: String,
+    pub profile_picture: Option<StoredFile>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AccountCreationPayload {
+    pub profile: AccountProfileCreationPayload,
+    pub private: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TowntalkProfile {
+    pub username: String,
+    pub profile_picture: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TowntalkAccount {
+    pub id: String,
+    pub user_id: Principal,
+    pub profile: TowntalkProfile,
+    pub followers: Vec<(String, String)>,
+    pub blocked: Vec<(String, String)>,
+    pub private: bool,
+    pub deleted_at: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Comment {
+    pub id: String,
+    pub comment: String,
+    pub post_id: String,
+    pub poster_id: String,
+    pub replied_to: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Post {
+    pub id: String,
+    pub poster_id: String,
+    pub title: String,
+    pub caption: String,
+    pub medias: Vec<String>,
+    pub likes: Vec<String>,
+    pub shares: Vec<String>,
+    pub comments: Vec<Comment>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+pub struct AccountBuilder {
+    payload: AccountCreationPayload,
+}
+
+impl AccountBuilder {
+    pub fn new(username: &str) -> Self {
+        Self {
+            payload: AccountCreationPayload {
+                profile: AccountProfileCreationPayload {
+                    username: username.to_string(),
+                    about: String::new(),
+                    profile_picture: None,
+                },
+                private: false,
+            },
+        }
+    }
+
+    pub fn private(mut self) -> Self {
+        self.payload.private = true;
+        self
+    }
+
+    pub fn profile_picture(mut self, picture: StoredFile) -> Self {
+        self.payload.profile.profile_picture = Some(picture);
+        self
+    }
+
+    pub fn build(self) -> AccountCreationPayload {
+        self.payload
+    }
+}
+
+pub struct PostBuilder {
+    post: Post,
+}
+
+impl PostBuilder {
+    pub fn new(id: &str, poster_id: &str) -> Self {
+        Self {
+            post: Post {
+                id: id.to_string(),
+                poster_id: poster_id.to_string(),
+                title: String::new(),
+                caption: String::new(),
+                medias: Vec::new(),
+                likes: Vec::new(),
+                shares: Vec::new(),
+                comments: Vec::new(),
+                created_at: String::new(),
+                updated_at: String::new(),
+            },
+        }
+    }
+
+    pub fn title(mut self, title: &str) -> Self {
+        self.post.title = title.to_string();
+        self
+    }
+
+    pub fn caption(mut self, caption: &str) -> Self {
+        self.post.caption = caption.to_string();
+        self
+    }
+
+    pub fn medias(mut self, file_ids: &[&str]) -> Self {
+        self.post.medias = file_ids.iter().map(|id| id.to_string()).collect();
+        self
+    }
+
+    pub fn build(self) -> Post {
+        self.post
+    }
+}
+
+// Course
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateInstructorInput {
+    pub user_id: Principal,
+    pub full_name: String,
+    pub email: String,
+    pub phone: String,
+    pub country: String,
+    pub city: String,
+    pub profile_image: String,
+    pub bio: String,
+    pub expertise: String,
+    pub experience: String,
+    pub education: String,
+    pub portfolio: String,
+    pub linkedin: String,
+    pub video: String,
+    pub why: String,
+    pub ideas: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Instructor {
+    pub instructor_id: u64,
+    pub user_id: Principal,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateCourseInput {
+    pub instructor_id: u64,
+    pub category_id: u64,
+    pub title: String,
+    pub description: String,
+    pub price: f32,
+    pub language: String,
+    pub completed: bool,
+    pub image: String,
+    pub category: String,
+    pub completion_reward: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Course {
+    pub id: u64,
+    pub instructor_id: u64,
+    pub title: String,
+    pub price: f32,
+    pub completion_reward: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateEnrollmentInput {
+    pub user_id: Principal,
+    pub course_id: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Enrollment {
+    pub enrollment_id: u64,
+    pub user_id: Principal,
+    pub course_id: u64,
+    pub progress: f32,
+}
+
+pub fn instructor_input(user_id: Principal, full_name: &str) -> CreateInstructorInput {
+    CreateInstructorInput {
+        user_id,
+        full_name: full_name.to_string(),
+        email: String::new(),
+        phone: String::new(),
+        country: String::new(),
+        city: String::new(),
+        profile_image: String::new(),
+        bio: String::new(),
+        expertise: String::new(),
+        experience: String::new(),
+        education: String::new(),
+        portfolio: String::new(),
+        linkedin: String::new(),
+        video: String::new(),
+        why: String::new(),
+        ideas: String::new(),
+    }
+}
+
+pub struct CourseBuilder {
+    input: CreateCourseInput,
+}
+
+impl CourseBuilder {
+    pub fn new(instructor_id: u64, title: &str) -> Self {
+        Self {
+            input: CreateCourseInput {
+                instructor_id,
+                category_id: 1,
+                title: title.to_string(),
+                description: String::new(),
+                price: 0.0,
+                language: "English".to_string(),
+                completed: false,
+                image: String::new(),
+                category: "Coding".to_string(),
+                completion_reward: None,
+            },
+        }
+    }
+
+    pub fn price(mut self, price: f32) -> Self {
+        self.input.price = price;
+        self
+    }
+
+    pub fn completion_reward(mut self, amount: u64) -> Self {
+        self.input.completion_reward = Some(amount);
+        self
+    }
+
+    pub fn build(self) -> CreateCourseInput {
+        self.input
+    }
+}
+
+// GrindArena
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum Difficulty {
+    Beginner,
+    Intermediate,
+    Advanced,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateCompetitionInput {
+    pub title: String,
+    pub description: String,
+    pub difficulty: Difficulty,
+    pub prize: u64,
+    pub category: String,
+    pub status: String,
+    pub rules: Vec<String>,
+    pub started_at: String,
+    pub ended_at: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateArenaAccountInput {
+    pub username: String,
+    pub profile_picture: Option<StoredFile>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CreateParticipantInput {
+    pub account_id: String,
+    pub competition_id: String,
+}
+
+pub struct CompetitionBuilder {
+    input: CreateCompetitionInput,
+}
+
+impl CompetitionBuilder {
+    pub fn new(title: &str) -> Self {
+        Self {
+            input: CreateCompetitionInput {
+                title: title.to_string(),
+                description: String::new(),
+                difficulty: Difficulty::Beginner,
+                prize: 0,
+                category: "Coding".to_string(),
+                status: "Normal".to_string(),
+                rules: Vec::new(),
+                started_at: "2025-01-01T00:00:00Z".to_string(),
+                ended_at: "2025-02-01T00:00:00Z".to_string(),
+            },
+        }
+    }
+
+    pub fn prize(mut self, prize: u64) -> Self {
+        self.input.prize = prize;
+        self
+    }
+
+    pub fn ends_at(mut self, ended_at: &str) -> Self {
+        self.input.ended_at = ended_at.to_string();
+        self
+    }
+
+    pub fn build(self) -> CreateCompetitionInput {
+        self.input
+    }
+}
+
+// Shortcuts for the calls most tests start with.
+impl TestEnv {
+    pub fn upload_file(&self, owner: Principal, file: StoredFile) -> String {
+        self.upload_file_to(self.canisters.storage, owner, file)
+    }
+
+    // Uploads to another storage canister, such as a bucket of the index.
+    pub fn upload_file_to(&self, storage: Principal, owner: Principal, file: StoredFile) -> String {
+        self.advance();
+        let uploaded: Vec<(String, FileUploadResolveType, String)> = self.update(
+            storage,
+            owner,
+            "upload_files",
+            (vec![file], None::<UploadContext>),
+        );
+
+        match uploaded.as_slice() {
+            [(id, FileUploadResolveType::SuccessfullyUploaded, _)] => id.clone(),
+            other => panic!("upload failed: {other:?}"),
+        }
+    }
+
+    // Deletes files for good: into the trash, then out of it.
+    pub fn purge_files(&self, owner: Principal, file_ids: &[String]) {
+        let deleted: u64 = self.update(
+            self.canisters.storage,
+            owner,
+            "delete_files",
+            (file_ids.to_vec(),),
+        );
+        let purged: u64 = self.update(
+            self.canisters.storage,
+            owner,
+            "purge_files",
+            (file_ids.to_vec(),),
+        );
+        assert_eq!(
+            (deleted, purged),
+            (file_ids.len() as u64, file_ids.len() as u64)
+        );
+    }
+
+    pub fn create_folder(&self, owner: Principal, name: &str, parent_id: Option<&str>) -> String {
+        self.advance();
+        let created: Result<Folder, String> = self.update(
+            self.canisters.storage,
+            owner,
+            "create_folder",
+            (CreateFolderInput {
+                name: name.to_string(),
+                parent_id: parent_id.map(str::to_string),
+            },),
+        );
+
+        created.expect("create_folder failed").id
+    }
+
+    // GET through the storage canister's HTTP interface, as the gateway does,
+    // following an upgrade to `http_request_update`.
+    pub fn http_get(&self, url: &str, headers: &[(&str, &str)]) -> HttpResponse {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new(),
+        };
+
+        let response: HttpResponse = self.query(
+            self.canisters.storage,
+            Principal::anonymous(),
+            "http_request",
+            (request.clone(),),
+        );
+
+        if response.upgrade != Some(true) {
+            return response;
+        }
+
+        self.update(
+            self.canisters.storage,
+            Principal::anonymous(),
+            "http_request_update",
+            (request,),
+        )
+    }
+
+    pub fn create_towntalk_account(
+        &self,
+        owner: Principal,
+        payload: AccountCreationPayload,
+    ) -> TowntalkAccount {
+        self.advance();
+        self.update(
+            self.canisters.towntalk,
+            owner,
+            "create_account",
+            (payload, self.canisters.storage),
+        )
+    }
+
+    pub fn create_post(&self, owner: Principal, post: Post) {
+        self.advance();
+        let account_id = post.poster_id.clone();
+        self.update::<_, ()>(
+            self.canisters.towntalk,
+            owner,
+            "create_post",
+            (account_id, post),
+        );
+    }
+
+    pub fn create_instructor(&self, user_id: Principal, full_name: &str) -> Instructor {
+        self.update(
+            self.canisters.course,
+            user_id,
+            "create_instructor",
+            (instructor_input(user_id, full_name),),
+        )
+    }
+
+    pub fn create_course(&self, sender: Principal, input: CreateCourseInput) -> Course {
+        self.update::<_, Result<Course, String>>(
+            self.canisters.course,
+            sender,
+            "create_course",
+            (input,),
+        )
+        .expect("course is created")
+    }
+
+    pub fn create_arena_account(&self, owner: Principal, username: &str) -> String {
+        self.advance();
+        self.update(
+            self.canisters.grindarena,
+            owner,
+            "create_account",
+            (
+                CreateArenaAccountInput {
+                    username: username.to_string(),
+                    profile_picture: None,
+                },
+                self.canisters.storage,
+            ),
+        )
+    }
+
+    pub fn create_competition(&self, sender: Principal, input: CreateCompetitionInput) -> String {
+        self.advance();
+        self.update(
+            self.canisters.grindarena,
+            sender,
+            "create_competition",
+            (input,),
+        )
+    }
+
+    pub fn join_competition(
+        &self,
+        owner: Principal,
+        account_id: &str,
+        competition_id: &str,
+    ) -> String {
+        self.advance();
+        self.update(
+            self.canisters.grindarena,
+            owner,
+            "create_participant",
+            (CreateParticipantInput {
+                account_id: account_id.to_string(),
+                competition_id: competition_id.to_string(),
+            },),
+        )
+    }
+
+    pub fn notifications(&self, recipient: Principal, unread_only: bool) -> Page<Notification> {
+        self.query(
+            self.canisters.notifications,
+            recipient,
+            "get_notifications",
+            (1u64, 50u64, unread_only),
+        )
+    }
+
+    // Ledger
+
+    pub fn mint(&self, to: Principal, amount: u64) {
+        let minted: Result<candid::Nat, String> = self.update(
+            self.canisters.ledger,
+            self.controller,
+            "mint",
+            (LedgerAccount::of(to), amount),
+        );
+        minted.expect("mint failed");
+    }
+
+    pub fn balance(&self, owner: Principal) -> candid::Nat {
+        self.query(
+            self.canisters.ledger,
+            owner,
+            "icrc1_balance_of",
+            (LedgerAccount::of(owner),),
+        )
+    }
+
+    pub fn approve(&self, owner: Principal, spender: Principal, amount: u64) {
+        let approved: Result<candid::Nat, utilities::ledger::ApproveError> = self.update(
+            self.canisters.ledger,
+            owner,
+            "icrc2_approve",
+            (utilities::ledger::ApproveArgs {
+                from_subaccount: None,
+                spender: LedgerAccount::of(spender),
+                amount: amount.into(),
+                expected_allowance: None,
+                expires_at: None,
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            },),
+        );
+        approved.expect("approve failed");
+    }
+}
@@ -0,0 +1,252 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Principal, encode_args};
+use pocket_ic::{PocketIc, PocketIcBuilder};
+use serde::de::DeserializeOwned;
+
+pub mod fixtures;
+
+use fixtures::DeletePolicy;
+
+// PocketIC harness installing every workspace canister plus the
+// `local_ledger` stand-in, wired together the way `deploy.sh` wires them.
+//
+// Needs the PocketIC server (`POCKET_IC_BIN`) and the release wasm of every
+// canister, so the tests are `#[ignore]`d and `cargo test --workspace` stays
+// usable. `scripts/integration-tests.sh` sets both up and runs them with
+// `--ignored`.
+
+pub const CANISTERS: [&str; 9] = [
+    "shared",
+    "user",
+    "storage",
+    "storage_index",
+    "course",
+    "towntalk",
+    "grindarena",
+    "notifications",
+    "local_ledger",
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Canisters {
+    pub shared: Principal,
+    pub user: Principal,
+    pub storage: Principal,
+    pub storage_index: Principal,
+    pub course: Principal,
+    pub towntalk: Principal,
+    pub grindarena: Principal,
+    pub notifications: Principal,
+    pub ledger: Principal,
+}
+
+pub struct TestEnv {
+    pub pic: PocketIc,
+    pub controller: Principal,
+    pub canisters: Canisters,
+}
+
+fn wasm_dir() -> PathBuf {
+    std::env::var_os("CANISTER_WASM_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../../../target/wasm32-unknown-unknown/release")
+        })
+}
+
+fn read_wasm(name: &str) -> Vec<u8> {
+    let path = wasm_dir().join(format!("{name}.wasm"));
+    std::fs::read(&path)
+        .unwrap_or_else(|_| panic!("{} not found, build the canisters first.", path.display()))
+}
+
+fn pocket_ic_bin() -> Option<PathBuf> {
+    std::env::var_os("POCKET_IC_BIN")
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+}
+
+// Deterministic, distinct principal for a named test user.
+pub fn user(name: &str) -> Principal {
+    Principal::self_authenticating(name.as_bytes())
+}
+
+impl Default for TestEnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestEnv {
+    // Panics when the server or a wasm is missing.
+    pub fn new() -> Self {
+        let server = pocket_ic_bin().expect("Set POCKET_IC_BIN to the PocketIC server binary.");
+
+        let wasms: Vec<Vec<u8>> = CANISTERS.iter().map(|name| read_wasm(name)).collect();
+
+        let pic = PocketIcBuilder::new()
+            .with_application_subnet()
+            .with_server_binary(server)
+            .build();
+        let controller = user("controller");
+
+        let ids: Vec<Principal> = wasms
+            .into_iter()
+            .map(|wasm| {
+                let canister_id = pic.create_canister_with_settings(Some(controller), None);
+                pic.install_canister(
+                    canister_id,
+                    wasm,
+                    encode_args(()).unwrap(),
+                    Some(controller),
+                );
+                canister_id
+            })
+            .collect();
+
+        let env = Self {
+            pic,
+            controller,
+            canisters: Canisters {
+                shared: ids[0],
+                user: ids[1],
+                storage: ids[2],
+                storage_index: ids[3],
+                course: ids[4],
+                towntalk: ids[5],
+                grindarena: ids[6],
+                notifications: ids[7],
+                ledger: ids[8],
+            },
+        };
+
+        env.wire();
+        env
+    }
+
+    fn wire(&self) {
+        let Canisters {
+            storage,
+            course,
+            towntalk,
+            grindarena,
+            notifications,
+            ledger,
+            ..
+        } = self.canisters;
+
+        let configured: Result<(), String> =
+            self.update(storage, self.controller, "set_ledger_canister", (ledger,));
+        configured.expect("Failed to configure the ledger.");
+
+        for canister in [course, towntalk, grindarena] {
+            let configured: Result<(), String> =
+                self.update(canister, self.controller, "set_ledger_canister", (ledger,));
+            configured.expect("Failed to configure the ledger.");
+
+            let registered: Result<(), String> = self.update(
+                notifications,
+                self.controller,
+                "register_emitter",
+                (canister,),
+            );
+            registered.expect("Failed to register the emitter.");
+
+            let configured: Result<(), String> = self.update(
+                canister,
+                self.controller,
+                "set_notifications_canister",
+                (notifications,),
+            );
+            configured.expect("Failed to configure notifications.");
+        }
+
+        for canister in [towntalk, grindarena] {
+            let registered: Result<(), String> = self.update(
+                storage,
+                self.controller,
+                "register_subscriber",
+                (canister, DeletePolicy::Cascade),
+            );
+            registered.expect("Failed to register the subscriber.");
+
+            let configured: Result<(), String> = self.update(
+                canister,
+                self.controller,
+                "set_storage_canister",
+                (storage,),
+            );
+            configured.expect("Failed to configure storage.");
+        }
+    }
+
+    pub fn try_update<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+        &self,
+        canister_id: Principal,
+        sender: Principal,
+        method: &str,
+        args: A,
+    ) -> Result<R, String> {
+        let bytes = self
+            .pic
+            .update_call(canister_id, sender, method, encode_args(args).unwrap())
+            .map_err(|reject| reject.reject_message)?;
+
+        candid::decode_one(&bytes).map_err(|err| err.to_string())
+    }
+
+    pub fn update<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+        &self,
+        canister_id: Principal,
+        sender: Principal,
+        method: &str,
+        args: A,
+    ) -> R {
+        self.try_update(canister_id, sender, method, args)
+            .unwrap_or_else(|err| panic!("{method} failed: {err}"))
+    }
+
+    pub fn query<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+        &self,
+        canister_id: Principal,
+        sender: Principal,
+        method: &str,
+        args: A,
+    ) -> R {
+        let bytes = self
+            .pic
+            .query_call(canister_id, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|reject| panic!("{method} failed: {}", reject.reject_message));
+
+        candid::decode_one(&bytes).unwrap_or_else(|err| panic!("{method} failed: {err}"))
+    }
+
+    // Lets fire-and-forget calls (notifications) reach their target.
+    pub fn settle(&self) {
+        for _ in 0..5 {
+            self.pic.tick();
+        }
+    }
+
+    // Ids and uuids are derived from the canister time, so records created in
+    // separate calls need distinct timestamps.
+    pub fn advance(&self) {
+        self.pic.advance_time(Duration::from_millis(1));
+    }
+
+    // Reinstalls the same wasm, running the canister's upgrade hooks.
+    pub fn upgrade(&self, canister_id: Principal, name: &str) {
+        self.pic
+            .upgrade_canister(
+                canister_id,
+                read_wasm(name),
+                encode_args(()).unwrap(),
+                Some(self.controller),
+            )
+            .unwrap_or_else(|reject| panic!("upgrading {name} failed: {}", reject.reject_message));
+    }
+}
@@ -0,0 +1,221 @@
+use candid::Nat;
+use integration_tests::fixtures::{Course, CourseBuilder, CreateEnrollmentInput, Enrollment};
+use integration_tests::{TestEnv, user};
+use utilities::escrow::{Escrow, EscrowState};
+use utilities::notifications::NotificationType;
+
+fn enroll(env: &TestEnv, student: candid::Principal, course_id: u64) -> Enrollment {
+    env.update::<_, Result<Enrollment, String>>(
+        env.canisters.course,
+        student,
+        "create_enrollment",
+        (CreateEnrollmentInput {
+            user_id: student,
+            course_id,
+        },),
+    )
+    .expect("student enrolls")
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn enrolling_notifies_student_and_instructor() {
+    let env = TestEnv::new();
+    let (teacher, student) = (user("teacher"), user("student"));
+
+    let instructor = env.create_instructor(teacher, "Teacher");
+    let course = env.create_course(
+        teacher,
+        CourseBuilder::new(instructor.instructor_id, "Rust").build(),
+    );
+    enroll(&env, student, course.id);
+    env.settle();
+
+    for recipient in [teacher, student] {
+        let inbox = env.notifications(recipient, true);
+        assert_eq!(inbox.data.len(), 1);
+        assert_eq!(inbox.data[0].kind, NotificationType::CourseEnrollment);
+    }
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn only_the_instructor_can_complete_a_course_and_pay_its_reward() {
+    let env = TestEnv::new();
+    let (teacher, student) = (user("teacher"), user("student"));
+
+    let instructor = env.create_instructor(teacher, "Teacher");
+    let course = env.create_course(
+        env.controller,
+        CourseBuilder::new(instructor.instructor_id, "Rust")
+            .completion_reward(500)
+            .build(),
+    );
+    let enrollment = enroll(&env, student, course.id);
+    env.mint(env.canisters.course, 100_000);
+    let funded: Result<(), String> = env.update(
+        env.canisters.course,
+        env.controller,
+        "set_reward_budget",
+        (500u64,),
+    );
+    funded.expect("controller sets the budget");
+
+    let denied: Result<Enrollment, String> = env.update(
+        env.canisters.course,
+        student,
+        "complete_course",
+        (enrollment.enrollment_id,),
+    );
+    assert!(denied.is_err());
+
+    let completed: Result<Enrollment, String> = env.update(
+        env.canisters.course,
+        teacher,
+        "complete_course",
+        (enrollment.enrollment_id,),
+    );
+    assert_eq!(completed.expect("instructor completes").progress, 100.0);
+    assert_eq!(env.balance(student), Nat::from(500u64));
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn only_controllers_can_reward_completions_within_the_budget() {
+    let env = TestEnv::new();
+    let (teacher, first, second) = (user("teacher"), user("first"), user("second"));
+
+    let instructor = env.create_instructor(teacher, "Teacher");
+    let rewarded = CourseBuilder::new(instructor.instructor_id, "Rust")
+        .completion_reward(500)
+        .build();
+    let denied: Result<Course, String> = env.update(
+        env.canisters.course,
+        teacher,
+        "create_course",
+        (rewarded.clone(),),
+    );
+    assert!(denied.is_err());
+
+    let course = env.create_course(env.controller, rewarded);
+    env.mint(env.canisters.course, 100_000);
+    let funded: Result<(), String> = env.update(
+        env.canisters.course,
+        env.controller,
+        "set_reward_budget",
+        (500u64,),
+    );
+    funded.expect("controller sets the budget");
+
+    let complete = |student| {
+        let enrollment = enroll(&env, student, course.id);
+        env.update::<_, Result<Enrollment, String>>(
+            env.canisters.course,
+            teacher,
+            "complete_course",
+            (enrollment.enrollment_id,),
+        )
+    };
+
+    complete(first).expect("the budget covers one reward");
+    assert!(complete(second).is_err());
+    assert_eq!(env.balance(first), Nat::from(500u64));
+    assert_eq!(env.balance(second), Nat::from(0u64));
+}
+
+fn purchases(env: &TestEnv, buyer: candid::Principal) -> Vec<EscrowState> {
+    let mut escrows: Vec<Escrow> = env.query(env.canisters.course, buyer, "get_my_purchases", ());
+    escrows.sort_by_key(|escrow| escrow.created_at);
+    escrows.into_iter().map(|escrow| escrow.state).collect()
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn purchasing_pays_the_instructor_before_enrolling() {
+    let env = TestEnv::new();
+    let (teacher, student) = (user("teacher"), user("student"));
+
+    let instructor = env.create_instructor(teacher, "Teacher");
+    let course = env.create_course(
+        teacher,
+        CourseBuilder::new(instructor.instructor_id, "Rust")
+            .price(0.001)
+            .build(),
+    );
+
+    let unpaid: Result<Enrollment, String> = env.update(
+        env.canisters.course,
+        student,
+        "create_enrollment",
+        (CreateEnrollmentInput {
+            user_id: student,
+            course_id: course.id,
+        },),
+    );
+    assert!(unpaid.is_err(), "paid courses must be purchased");
+
+    env.mint(student, 1_000_000);
+    env.approve(student, env.canisters.course, 110_000);
+
+    let purchased: Result<Enrollment, String> = env.update(
+        env.canisters.course,
+        student,
+        "purchase_course",
+        (course.id,),
+    );
+    assert_eq!(purchased.expect("student buys").user_id, student);
+    assert_eq!(purchases(&env, student), vec![EscrowState::Released]);
+    assert_eq!(env.balance(teacher), Nat::from(90_000u64));
+
+    // A released purchase is final.
+    let refunded: Result<Escrow, String> = env.update(
+        env.canisters.course,
+        env.controller,
+        "refund_course_purchase",
+        (course.id, student),
+    );
+    assert!(refunded.is_err());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn a_refunded_purchase_grants_no_access_and_can_be_repeated() {
+    let env = TestEnv::new();
+    let (teacher, student) = (user("teacher"), user("student"));
+
+    // The course points at an instructor who only registers later.
+    let course = env.create_course(teacher, CourseBuilder::new(1, "Rust").price(0.001).build());
+    env.mint(student, 1_000_000);
+    env.approve(student, env.canisters.course, 110_000);
+
+    let refunded: Result<Enrollment, String> = env.update(
+        env.canisters.course,
+        student,
+        "purchase_course",
+        (course.id,),
+    );
+    assert!(refunded.is_err());
+    assert_eq!(purchases(&env, student), vec![EscrowState::Refunded]);
+    assert_eq!(env.balance(student), Nat::from(970_000u64));
+
+    let enrollments: Vec<Enrollment> =
+        env.query(env.canisters.course, student, "get_all_enrollments", ());
+    assert!(enrollments.is_empty());
+
+    env.create_instructor(teacher, "Teacher");
+    env.advance();
+    env.approve(student, env.canisters.course, 110_000);
+
+    let purchased: Result<Enrollment, String> = env.update(
+        env.canisters.course,
+        student,
+        "purchase_course",
+        (course.id,),
+    );
+    assert_eq!(purchased.expect("student buys again").course_id, course.id);
+    assert_eq!(
+        purchases(&env, student),
+        vec![EscrowState::Refunded, EscrowState::Released]
+    );
+    assert_eq!(env.balance(teacher), Nat::from(90_000u64));
+}
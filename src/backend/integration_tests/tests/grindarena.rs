@@ -0,0 +1,142 @@
+use candid::Nat;
+use integration_tests::fixtures::{CompetitionBuilder, FileBuilder};
+use integration_tests::{TestEnv, user};
+use utilities::escrow::{Escrow, EscrowState};
+use utilities::notifications::NotificationType;
+use utilities::rewards::Payout;
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn finalizing_pays_the_winner_and_notifies_participants() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let competition_id = env.create_competition(
+        env.controller,
+        CompetitionBuilder::new("Weekly challenge")
+            .prize(1_000)
+            .build(),
+    );
+    let alice_account = env.create_arena_account(alice, "alice");
+    let bob_account = env.create_arena_account(bob, "bob");
+    let winner = env.join_competition(alice, &alice_account, &competition_id);
+    env.join_competition(bob, &bob_account, &competition_id);
+
+    env.mint(env.canisters.grindarena, 100_000);
+
+    let denied: Result<Vec<Payout>, String> = env.update(
+        env.canisters.grindarena,
+        bob,
+        "finalize_competition",
+        (competition_id.clone(), vec![winner.clone()]),
+    );
+    assert!(denied.is_err(), "only coordinators finalize");
+
+    let payouts: Result<Vec<Payout>, String> = env.update(
+        env.canisters.grindarena,
+        env.controller,
+        "finalize_competition",
+        (competition_id.clone(), vec![winner]),
+    );
+    let payouts = payouts.expect("controller finalizes");
+    assert_eq!(payouts.len(), 1);
+    assert!(payouts[0].is_paid());
+    assert_eq!(env.balance(alice), Nat::from(1_000u64));
+    env.settle();
+
+    for participant in [alice, bob] {
+        let kinds: Vec<NotificationType> = env
+            .notifications(participant, false)
+            .data
+            .into_iter()
+            .map(|n| n.kind)
+            .collect();
+        assert_eq!(kinds, vec![NotificationType::CompetitionFinalized]);
+    }
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn competition_resources_are_referenced_until_purged() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let competition_id = env.create_competition(
+        env.controller,
+        CompetitionBuilder::new("Weekly challenge").build(),
+    );
+    // Resources are referenced for the coordinator, who must be able to read
+    // them.
+    let rules_id = env.upload_file(
+        alice,
+        FileBuilder::new("rules.txt")
+            .data(b"rules")
+            .public()
+            .build(),
+    );
+
+    let set_resources = |sender| -> Result<(), String> {
+        env.update(
+            env.canisters.grindarena,
+            sender,
+            "set_competition_resources",
+            (competition_id.clone(), vec![rules_id.clone()]),
+        )
+    };
+    assert!(
+        set_resources(bob).is_err(),
+        "only coordinators set resources"
+    );
+    assert!(set_resources(env.controller).is_ok());
+    env.settle();
+
+    let resources = || -> Vec<String> {
+        env.query(
+            env.canisters.grindarena,
+            alice,
+            "get_competition_resources",
+            (competition_id.clone(),),
+        )
+    };
+    assert_eq!(resources(), vec![rules_id.clone()]);
+
+    env.purge_files(alice, &[rules_id]);
+    env.settle();
+
+    assert!(resources().is_empty());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn cancelling_refunds_the_prize_pool_to_its_sponsor() {
+    let env = TestEnv::new();
+    let sponsor = user("sponsor");
+
+    let competition_id = env.create_competition(
+        env.controller,
+        CompetitionBuilder::new("Weekly challenge")
+            .prize(100_000)
+            .build(),
+    );
+    env.mint(sponsor, 1_000_000);
+    env.approve(sponsor, env.canisters.grindarena, 110_000);
+
+    let funded: Result<Escrow, String> = env.update(
+        env.canisters.grindarena,
+        sponsor,
+        "fund_prize_pool",
+        (competition_id.clone(),),
+    );
+    assert_eq!(funded.expect("sponsor funds").state, EscrowState::Funded);
+    assert_eq!(env.balance(sponsor), Nat::from(880_000u64));
+
+    let cancelled: Result<Option<Escrow>, String> = env.update(
+        env.canisters.grindarena,
+        env.controller,
+        "cancel_competition",
+        (competition_id.clone(),),
+    );
+    let pool = cancelled.expect("controller cancels").expect("pool exists");
+    assert_eq!(pool.state, EscrowState::Refunded);
+    assert_eq!(env.balance(sponsor), Nat::from(970_000u64));
+}
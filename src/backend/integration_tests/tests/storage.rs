@@ -0,0 +1,2505 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use candid::Principal;
+use integration_tests::fixtures::{
+    AccessExplanation, AccountBuilder, Bucket, CreateShareLinkInput, DeletePolicy, FileBuilder,
+    FileChunk, FileKeyStatus, FileMetadata, FileQuery, FileReference, FileSort, FileStats,
+    FileVersion, Folder, FolderDeletion, FolderEntry, GarbageCollection, GrantPath, GroupRecord,
+    GrowthPeriod, Page, Post, PostBuilder, Quota, QuotaError, ShareLink, SortField, StorageStats,
+    StreamingCallbackHttpResponse, StreamingStrategy, Trash, UploadError, UploadMetadata,
+    UploadPolicy, UploadStatus, Usage, UsageReport, VariantSize, WrappedKey,
+};
+use integration_tests::{TestEnv, user};
+use paginator::{Filter, Operator};
+use sha2::{Digest, Sha256};
+use utilities::audit::AuditEntry;
+use utilities::{Access, BucketReport, FileUploadResolveType, StoredFile, UploadContext};
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn private_files_are_only_visible_to_allowed_users() {
+    let env = TestEnv::new();
+    let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+
+    let file_id = env.upload_file(
+        alice,
+        FileBuilder::new("notes.txt")
+            .data(b"secret")
+            .allow(bob, Access::Read)
+            .build(),
+    );
+
+    let get_file = |sender| -> Option<StoredFile> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "get_file",
+            (file_id.clone(), None::<bool>),
+        )
+    };
+
+    let shared = get_file(bob).expect("bob was granted read access");
+    assert_eq!(shared.owner, alice);
+    assert_eq!(shared.data, b"secret");
+    assert!(get_file(carol).is_none());
+
+    let editable: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        bob,
+        "get_file",
+        (file_id.clone(), Some(true)),
+    );
+    assert!(editable.is_none(), "read access does not allow editing");
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn public_files_are_paginated() {
+    let env = TestEnv::new();
+    let (alice, carol) = (user("alice"), user("carol"));
+
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        env.upload_file(alice, FileBuilder::new(name).data(b"hi").public().build());
+    }
+    env.upload_file(alice, FileBuilder::new("private.txt").build());
+
+    let page = |page: u64| -> Page<FileMetadata> {
+        env.update(
+            env.canisters.storage,
+            carol,
+            "get_files",
+            (2u64, page, true, false),
+        )
+    };
+
+    let first = page(1);
+    let second = page(2);
+    assert_eq!(first.data.len(), 2);
+    assert_eq!(second.data.len(), 1);
+    assert!(first.data.iter().chain(&second.data).all(|f| f.public));
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn deleting_files_is_restricted_to_owners_and_audited() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let file_id = env.upload_file(alice, FileBuilder::new("report.pdf").build());
+
+    let deleted: u64 = env.update(
+        env.canisters.storage,
+        bob,
+        "delete_files",
+        (vec![file_id.clone()],),
+    );
+    assert_eq!(deleted, 0);
+
+    let deleted: u64 = env.update(
+        env.canisters.storage,
+        alice,
+        "delete_files",
+        (vec![file_id.clone()],),
+    );
+    assert_eq!(deleted, 1);
+
+    let log: Result<Page<AuditEntry>, String> = env.query(
+        env.canisters.storage,
+        env.controller,
+        "get_audit_log",
+        (1u64, 10u64),
+    );
+    let log = log.expect("controllers can read the audit log");
+    assert_eq!(log.data.len(), 1);
+    assert_eq!(log.data[0].endpoint, "delete_files");
+    assert_eq!(log.data[0].actor, alice);
+    assert_eq!(log.data[0].target_ids, vec![file_id]);
+
+    let denied: Result<Page<AuditEntry>, String> =
+        env.query(env.canisters.storage, alice, "get_audit_log", (1u64, 10u64));
+    assert!(denied.is_err());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn deleting_groups_is_audited() {
+    let env = TestEnv::new();
+    let alice = user("alice");
+
+    let created: Result<GroupRecord, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "create_group",
+        (GroupRecord::new("team", vec![]),),
+    );
+    let group = created.unwrap();
+
+    let deleted: u64 = env.update(
+        env.canisters.storage,
+        alice,
+        "delete_groups",
+        (vec![group.id.clone()],),
+    );
+    assert_eq!(deleted, 1);
+
+    let log: Result<Page<AuditEntry>, String> = env.query(
+        env.canisters.storage,
+        env.controller,
+        "get_audit_log",
+        (1u64, 10u64),
+    );
+    let log = log.unwrap();
+    assert_eq!(log.data.len(), 1);
+    assert_eq!(log.data[0].endpoint, "delete_groups");
+    assert_eq!(log.data[0].target_ids, vec![group.id]);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn chunked_uploads_resume_and_download_in_chunks() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let data: Vec<u8> = (0..(5 << 19)).map(|i| (i % 251) as u8).collect();
+    let checksum = hex::encode(Sha256::digest(&data));
+
+    let begun: Result<UploadStatus, UploadError> = env.update(
+        env.canisters.storage,
+        alice,
+        "begin_upload",
+        (UploadMetadata::new("lecture.mp4", data.len()),),
+    );
+    let status = begun.unwrap();
+    assert_eq!(status.chunk_count, 3);
+    assert_eq!(status.missing_chunks, vec![0, 1, 2]);
+
+    let chunk_size = status.chunk_size as usize;
+    let put_chunk = |sender, index: u32| -> Result<UploadStatus, String> {
+        let start = index as usize * chunk_size;
+        let end = (start + chunk_size).min(data.len());
+        env.update(
+            env.canisters.storage,
+            sender,
+            "put_chunk",
+            (status.upload_id, index, data[start..end].to_vec()),
+        )
+    };
+
+    assert!(put_chunk(bob, 0).is_err(), "sessions belong to their owner");
+    put_chunk(alice, 0).unwrap();
+    put_chunk(alice, 2).unwrap();
+
+    let commit = |checksum: &str| -> Result<String, UploadError> {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "commit_upload",
+            (status.upload_id, checksum.to_string()),
+        )
+    };
+
+    assert!(commit(&checksum).is_err(), "chunk 1 is still missing");
+
+    let resumed: Result<UploadStatus, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "get_upload_status",
+        (status.upload_id,),
+    );
+    assert_eq!(resumed.unwrap().missing_chunks, vec![1]);
+    put_chunk(alice, 1).unwrap();
+
+    assert!(commit(&"0".repeat(64)).is_err());
+    let file_id = commit(&checksum).unwrap();
+
+    let get_chunk = |sender, index: u32| -> Result<FileChunk, String> {
+        env.query(
+            env.canisters.storage,
+            sender,
+            "get_chunk",
+            (file_id.clone(), index),
+        )
+    };
+
+    let downloaded: Vec<u8> = (0..3)
+        .flat_map(|index| get_chunk(alice, index).unwrap().data)
+        .collect();
+    assert_eq!(downloaded, data);
+    assert!(get_chunk(alice, 3).is_err());
+    assert!(get_chunk(bob, 0).is_err());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn committing_an_upload_rechecks_folder_access() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let shared = env.create_folder(alice, "shared", None);
+    let set_access = |allowed_users: Vec<(Principal, Access)>| {
+        let updated: Result<Folder, String> = env.update(
+            env.canisters.storage,
+            alice,
+            "set_folder_access",
+            (shared.clone(), allowed_users, false),
+        );
+        updated.expect("alice shares her folder");
+    };
+    set_access(vec![(bob, Access::Write)]);
+
+    let data = b"notes".to_vec();
+    let begun: Result<UploadStatus, UploadError> = env.update(
+        env.canisters.storage,
+        bob,
+        "begin_upload",
+        (UploadMetadata {
+            parent_id: Some(shared.clone()),
+            ..UploadMetadata::new("notes.txt", data.len())
+        },),
+    );
+    let status = begun.expect("bob can write to the folder");
+    let put: Result<UploadStatus, String> = env.update(
+        env.canisters.storage,
+        bob,
+        "put_chunk",
+        (status.upload_id, 0u32, data.clone()),
+    );
+    put.unwrap();
+
+    set_access(Vec::new());
+
+    let committed: Result<String, UploadError> = env.update(
+        env.canisters.storage,
+        bob,
+        "commit_upload",
+        (status.upload_id, hex::encode(Sha256::digest(&data))),
+    );
+    assert!(committed.is_err(), "bob lost access before committing");
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn abandoned_uploads_are_cleaned_up() {
+    let env = TestEnv::new();
+    let alice = user("alice");
+
+    let begun: Result<UploadStatus, UploadError> = env.update(
+        env.canisters.storage,
+        alice,
+        "begin_upload",
+        (UploadMetadata::new("draft.bin", 10),),
+    );
+    let upload_id = begun.unwrap().upload_id;
+
+    env.pic.advance_time(Duration::from_secs(25 * 60 * 60));
+    env.settle();
+
+    let status: Result<UploadStatus, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "get_upload_status",
+        (upload_id,),
+    );
+    assert!(status.is_err());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn identical_content_is_stored_once_and_collected_when_unused() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+    let avatar = || {
+        FileBuilder::new("avatar.png")
+            .data(b"same pixels")
+            .public()
+            .build()
+    };
+
+    let first = env.upload_file(alice, avatar());
+
+    env.advance();
+    let again = env.upload_file(
+        alice,
+        FileBuilder::new("avatar-copy.png")
+            .data(b"same pixels")
+            .build(),
+    );
+    assert_ne!(again, first, "every upload gets its own file record");
+    let renamed: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_file",
+        (again.clone(), None::<bool>),
+    );
+    let renamed = renamed.expect("alice reads her copy");
+    assert_eq!(renamed.name, "avatar-copy.png");
+    assert!(!renamed.public);
+
+    let copy = env.upload_file(bob, avatar());
+    assert_ne!(copy, first, "other owners get their own file record");
+
+    let delete = |sender, file_id: &String| env.purge_files(sender, std::slice::from_ref(file_id));
+    let collect = || -> GarbageCollection {
+        let collected: Result<GarbageCollection, String> =
+            env.update(env.canisters.storage, env.controller, "collect_garbage", ());
+        collected.unwrap()
+    };
+
+    delete(alice, &first);
+    delete(alice, &again);
+    assert_eq!(collect().removed_blobs, 0, "bob still references the blob");
+
+    let kept: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        bob,
+        "get_file",
+        (copy.clone(), None::<bool>),
+    );
+    assert_eq!(kept.map(|f| f.data), Some(b"same pixels".to_vec()));
+
+    delete(bob, &copy);
+    let collected = collect();
+    assert_eq!(collected.removed_blobs, 1);
+    assert_eq!(collected.freed_bytes, 11);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn one_call_uploads_several_new_files() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let upload = |sender, names: &[&str]| -> Vec<(String, FileUploadResolveType, String)> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "upload_files",
+            (
+                names
+                    .iter()
+                    .map(|name| FileBuilder::new(name).data(name.as_bytes()).build())
+                    .collect::<Vec<_>>(),
+                None::<UploadContext>,
+            ),
+        )
+    };
+
+    let alices = upload(alice, &["a.txt", "b.txt", "c.txt"]);
+    let bobs = upload(bob, &["d.txt"]);
+
+    let ids: std::collections::HashSet<&String> =
+        alices.iter().chain(&bobs).map(|(id, _, _)| id).collect();
+    assert_eq!(ids.len(), 4);
+    assert!(
+        alices
+            .iter()
+            .chain(&bobs)
+            .all(|(_, resolved, _)| *resolved == FileUploadResolveType::SuccessfullyUploaded)
+    );
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn files_are_served_over_http() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let public_id = env.upload_file(
+        alice,
+        FileBuilder::new("scores.csv")
+            .mime_type("text/csv")
+            .data(b"name,score\n")
+            .public()
+            .build(),
+    );
+    let private_id = env.upload_file(alice, FileBuilder::new("notes.txt").data(b"secret").build());
+
+    let response = env.http_get(&format!("/files/{public_id}"), &[]);
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.body, b"name,score\n");
+    assert_eq!(response.header("Content-Type"), Some("text/csv"));
+    assert_eq!(response.header("Content-Length"), Some("11"));
+    assert_eq!(response.header("X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(response.header("Content-Disposition"), Some("attachment"));
+
+    let etag = response.header("ETag").unwrap().to_string();
+    let cached = env.http_get(&format!("/files/{public_id}"), &[("If-None-Match", &etag)]);
+    assert_eq!(cached.status_code, 304);
+
+    let range = env.http_get(&format!("/files/{public_id}"), &[("Range", "bytes=1-3")]);
+    assert_eq!(range.status_code, 206);
+    assert_eq!(range.body, b"ame");
+    assert_eq!(range.header("Content-Range"), Some("bytes 1-3/11"));
+
+    let beyond = env.http_get(&format!("/files/{public_id}"), &[("Range", "bytes=20-")]);
+    assert_eq!(beyond.status_code, 416);
+
+    assert_eq!(
+        env.http_get(&format!("/files/{private_id}"), &[])
+            .status_code,
+        403
+    );
+    assert_eq!(env.http_get("/files/missing", &[]).status_code, 404);
+
+    let token: Result<String, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "create_download_token",
+        (private_id.clone(), 60u64),
+    );
+    let token = token.unwrap();
+
+    let shared = env.http_get(&format!("/files/{private_id}?token={token}"), &[]);
+    assert_eq!(shared.status_code, 200);
+    assert_eq!(shared.body, b"secret");
+    assert_eq!(
+        shared.header("Content-Disposition"),
+        None,
+        "plain text is shown inline"
+    );
+
+    let other = env.upload_file(alice, FileBuilder::new("other.txt").data(b"other").build());
+    let reused = env.http_get(&format!("/files/{other}?token={token}"), &[]);
+    assert_eq!(reused.status_code, 403, "tokens are bound to one file");
+
+    env.pic.advance_time(Duration::from_secs(61));
+    let expired = env.http_get(&format!("/files/{private_id}?token={token}"), &[]);
+    assert_eq!(expired.status_code, 403);
+
+    let share = |access| {
+        let _: u64 = env.update(
+            env.canisters.storage,
+            alice,
+            "edit_allowed_users",
+            (private_id.clone(), vec![(bob, access)]),
+        );
+    };
+    share(Access::Admin);
+    let token: Result<String, String> = env.update(
+        env.canisters.storage,
+        bob,
+        "create_download_token",
+        (private_id.clone(), 60u64),
+    );
+    let url = format!("/files/{private_id}?token={}", token.unwrap());
+    assert_eq!(env.http_get(&url, &[]).status_code, 200);
+
+    share(Access::Removed);
+    assert_eq!(
+        env.http_get(&url, &[]).status_code,
+        403,
+        "tokens die with their minter's access"
+    );
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn large_files_are_streamed_over_http() {
+    let env = TestEnv::new();
+    let alice = user("alice");
+
+    let mut data: Vec<u8> = (0..(3 << 19)).map(|i| (i % 253) as u8).collect();
+    data[4..8].copy_from_slice(b"ftyp");
+    let file_id = env.upload_file(
+        alice,
+        FileBuilder::new("clip.mp4")
+            .mime_type("video/mp4")
+            .data(&data)
+            .public()
+            .build(),
+    );
+
+    let response = env.http_get(&format!("/files/{file_id}"), &[]);
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.header("Content-Length"), Some("1572864"));
+
+    let mut body = response.body;
+    let mut next = match response.streaming_strategy {
+        Some(StreamingStrategy::Callback { token }) => Some(token),
+        None => panic!("expected a streaming strategy"),
+    };
+
+    while let Some(token) = next {
+        let chunk: StreamingCallbackHttpResponse = env.query(
+            env.canisters.storage,
+            candid::Principal::anonymous(),
+            "http_request_streaming_callback",
+            (token,),
+        );
+        body.extend(chunk.body);
+        next = chunk.token;
+    }
+
+    assert_eq!(body, data);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn share_links_are_limited_expiring_and_revocable() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let file_id = env.upload_file(alice, FileBuilder::new("draft.txt").data(b"draft").build());
+
+    let share = |sender, access, max_uses| -> Result<ShareLink, String> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "create_share_link",
+            (CreateShareLinkInput {
+                file_id: file_id.clone(),
+                access,
+                expires_in_seconds: 3600,
+                max_uses,
+            },),
+        )
+    };
+    let redeem = |token: &str, mutable: bool| -> Result<StoredFile, String> {
+        env.update(
+            env.canisters.storage,
+            bob,
+            "get_file_with_token",
+            (token.to_string(), Some(mutable)),
+        )
+    };
+
+    assert!(
+        share(bob, Access::Read, None).is_err(),
+        "only owners and admins share"
+    );
+    assert!(share(alice, Access::Admin, None).is_err());
+
+    let once = share(alice, Access::Read, Some(1)).unwrap();
+    assert!(
+        redeem(&once.token, true).is_err(),
+        "read links do not allow editing"
+    );
+    assert_eq!(redeem(&once.token, false).unwrap().data, b"draft");
+    assert!(redeem(&once.token, false).is_err(), "the only use is spent");
+
+    let metered = share(alice, Access::Read, Some(1)).unwrap();
+    let url = format!("/files/{file_id}?token={}", metered.token);
+    assert_eq!(env.http_get(&url, &[]).status_code, 200);
+    assert_eq!(env.http_get(&url, &[]).status_code, 403);
+
+    let open = share(alice, Access::Write, None).unwrap();
+    let url = format!("/files/{file_id}?token={}", open.token);
+    assert_eq!(env.http_get(&url, &[]).status_code, 200);
+    assert!(redeem(&open.token, true).is_ok());
+
+    let links: Result<Vec<ShareLink>, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "list_share_links",
+        (file_id.clone(),),
+    );
+    assert_eq!(links.unwrap().len(), 3);
+
+    let revoked: Result<(), String> = env.update(
+        env.canisters.storage,
+        alice,
+        "revoke_share_link",
+        (open.id,),
+    );
+    revoked.unwrap();
+    assert!(redeem(&open.token, false).is_err());
+    assert_eq!(env.http_get(&url, &[]).status_code, 403);
+
+    let expiring = share(alice, Access::Read, None).unwrap();
+    let last = if expiring.token.ends_with('0') {
+        "1"
+    } else {
+        "0"
+    };
+    let tampered = format!("{}{last}", &expiring.token[..expiring.token.len() - 1]);
+    assert!(redeem(&tampered, false).is_err());
+    assert!(redeem(&expiring.token, false).is_ok());
+
+    env.pic.advance_time(Duration::from_secs(3601));
+    assert!(redeem(&expiring.token, false).is_err());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn listings_return_metadata_and_get_all_is_for_controllers() {
+    let env = TestEnv::new();
+    let alice = user("alice");
+
+    let file_id = env.upload_file(alice, FileBuilder::new("a.txt").data(b"hello").build());
+
+    let owned: Page<FileMetadata> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_files",
+        (10u64, 1u64, false, true),
+    );
+    assert_eq!(owned.data.len(), 1);
+    assert_eq!(owned.data[0].id, file_id);
+    assert_eq!(owned.data[0].chunk_count, 1);
+    assert_eq!(
+        owned.data[0].content_hash,
+        hex::encode(Sha256::digest(b"hello"))
+    );
+
+    let denied: Result<Vec<FileMetadata>, String> =
+        env.query(env.canisters.storage, alice, "get_all", ());
+    assert!(denied.is_err());
+
+    let all: Result<Vec<FileMetadata>, String> =
+        env.query(env.canisters.storage, env.controller, "get_all", ());
+    assert_eq!(all.unwrap().len(), 1);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn uploads_are_limited_by_quota_and_top_ups_raise_it() {
+    let env = TestEnv::new();
+    let alice = user("alice");
+
+    let limited: Result<(), String> = env.update(
+        env.canisters.storage,
+        env.controller,
+        "set_quota",
+        (
+            alice,
+            Some(Quota {
+                max_bytes: 10,
+                max_files: 5,
+            }),
+        ),
+    );
+    limited.unwrap();
+
+    let file_id = env.upload_file(alice, FileBuilder::new("a.txt").data(b"12345678").build());
+
+    env.advance();
+    let rejected: Vec<(String, FileUploadResolveType, String)> = env.update(
+        env.canisters.storage,
+        alice,
+        "upload_files",
+        (
+            vec![FileBuilder::new("b.txt").data(b"abc").build()],
+            None::<UploadContext>,
+        ),
+    );
+    assert_eq!(rejected[0].1, FileUploadResolveType::QuotaExceeded);
+
+    let begun: Result<UploadStatus, UploadError> = env.update(
+        env.canisters.storage,
+        alice,
+        "begin_upload",
+        (UploadMetadata::new("big.bin", 3),),
+    );
+    assert!(matches!(
+        begun,
+        Err(UploadError::QuotaExceeded(QuotaError::BytesExceeded {
+            bytes_used: 8,
+            requested: 3,
+            max_bytes: 10,
+        }))
+    ));
+
+    // Open sessions count toward the quota and are capped.
+    let begin = |sender, size: usize| -> Result<UploadStatus, UploadError> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "begin_upload",
+            (UploadMetadata::new("part.bin", size),),
+        )
+    };
+    assert!(begin(alice, 2).is_ok());
+    assert!(matches!(
+        begin(alice, 1),
+        Err(UploadError::QuotaExceeded(QuotaError::BytesExceeded {
+            requested: 3,
+            ..
+        }))
+    ));
+    env.advance();
+    let reserved: Vec<(String, FileUploadResolveType, String)> = env.update(
+        env.canisters.storage,
+        alice,
+        "upload_files",
+        (
+            vec![FileBuilder::new("c.txt").data(b"c").build()],
+            None::<UploadContext>,
+        ),
+    );
+    assert_eq!(reserved[0].1, FileUploadResolveType::QuotaExceeded);
+    let bob = user("bob");
+    for _ in 0..4 {
+        assert!(begin(bob, 1).is_ok());
+    }
+    assert!(matches!(begin(bob, 1), Err(UploadError::Rejected(_))));
+
+    let usage = || -> UsageReport {
+        let report: Result<UsageReport, String> = env.query(
+            env.canisters.storage,
+            alice,
+            "get_usage",
+            (None::<Principal>,),
+        );
+        report.unwrap()
+    };
+    assert_eq!(usage().usage.bytes_used, 8);
+    assert_eq!(usage().usage.file_count, 1);
+
+    let others: Result<UsageReport, String> = env.query(
+        env.canisters.storage,
+        user("bob"),
+        "get_usage",
+        (Some(alice),),
+    );
+    assert!(others.is_err());
+
+    env.mint(alice, 1_000_000_000);
+    env.approve(alice, env.canisters.storage, 100_010_000);
+    let top_up = |key: &str| -> Result<UsageReport, String> {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "top_up_quota",
+            (1u64, key.to_string()),
+        )
+    };
+    let topped_up = top_up("first").unwrap();
+    assert_eq!(topped_up.purchased_bytes, 1 << 30);
+    assert_eq!(topped_up.quota.max_bytes, 10 + (1 << 30));
+
+    let retried = top_up("first").unwrap();
+    assert_eq!(
+        retried.purchased_bytes,
+        1 << 30,
+        "a retry buys nothing more"
+    );
+    assert_eq!(
+        env.balance(alice),
+        candid::Nat::from(1_000_000_000u64 - 100_000_000 - 2 * 10_000)
+    );
+
+    env.upload_file(alice, FileBuilder::new("b.txt").data(b"abc").build());
+
+    env.update::<_, u64>(
+        env.canisters.storage,
+        alice,
+        "delete_files",
+        (vec![file_id.clone()],),
+    );
+    assert_eq!(usage().usage.bytes_used, 11, "trashed files still count");
+
+    env.update::<_, u64>(
+        env.canisters.storage,
+        alice,
+        "purge_files",
+        (vec![file_id],),
+    );
+    assert_eq!(usage().usage.bytes_used, 3);
+
+    // Bought bytes survive upgrades, and so does the paid top-up.
+    env.upgrade(env.canisters.storage, "storage");
+    assert_eq!(usage().purchased_bytes, 1 << 30);
+    assert_eq!(top_up("first").unwrap().purchased_bytes, 1 << 30);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn folders_resolve_paths_and_share_their_contents() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let courses = env.create_folder(alice, "courses", None);
+    let rust = env.create_folder(alice, "rust", Some(&courses));
+    let file_id = env.upload_file(
+        alice,
+        FileBuilder::new("intro.mp4")
+            .data(b"video")
+            .parent(&rust)
+            .build(),
+    );
+
+    let resolve = |sender, path: &str| -> Result<FolderEntry, String> {
+        env.query(
+            env.canisters.storage,
+            sender,
+            "resolve_path",
+            (path.to_string(), Some(alice)),
+        )
+    };
+    match resolve(alice, "/courses/rust/intro.mp4") {
+        Ok(FolderEntry::File(file)) => assert_eq!(file.id, file_id),
+        other => panic!("expected the file, got {other:?}"),
+    }
+    assert!(matches!(resolve(alice, "/courses/rust"), Ok(FolderEntry::Folder(f)) if f.id == rust));
+    assert!(resolve(alice, "/courses/go").is_err());
+    assert!(resolve(bob, "/courses/rust/intro.mp4").is_err());
+
+    env.advance();
+    let duplicate: Vec<(String, FileUploadResolveType, String)> = env.update(
+        env.canisters.storage,
+        alice,
+        "upload_files",
+        (
+            vec![
+                FileBuilder::new("intro.mp4")
+                    .data(b"other video")
+                    .parent(&rust)
+                    .build(),
+            ],
+            None::<UploadContext>,
+        ),
+    );
+    assert_eq!(duplicate[0].1, FileUploadResolveType::FailedToUpload);
+
+    let listed: Result<Page<FolderEntry>, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "list_folder",
+        (Some(courses.clone()), 1u64, 10u64),
+    );
+    let names: Vec<String> = listed
+        .unwrap()
+        .data
+        .iter()
+        .map(|entry| entry.name().to_string())
+        .collect();
+    assert_eq!(names, ["rust"]);
+
+    let unpaged: Result<Page<FolderEntry>, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "list_folder",
+        (Some(courses.clone()), 0u64, 0u64),
+    );
+    assert_eq!(unpaged.unwrap().data.len(), 1, "page and size start at one");
+
+    // Sharing the top folder shares everything below it.
+    let get_file = || -> Option<StoredFile> {
+        env.update(
+            env.canisters.storage,
+            bob,
+            "get_file",
+            (file_id.clone(), None::<bool>),
+        )
+    };
+    assert!(get_file().is_none());
+
+    let shared: Result<Folder, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "set_folder_access",
+        (courses.clone(), vec![(bob, Access::Read)], false),
+    );
+    assert!(shared.is_ok());
+    assert_eq!(get_file().unwrap().data, b"video");
+
+    let into_itself: Result<Folder, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "move_folder",
+        (courses.clone(), Some(rust.clone())),
+    );
+    assert!(into_itself.is_err());
+
+    let by_bob: Result<FolderDeletion, String> = env.update(
+        env.canisters.storage,
+        bob,
+        "delete_folder",
+        (courses.clone(),),
+    );
+    assert!(by_bob.is_err());
+
+    let delete = || -> Result<FolderDeletion, String> {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "delete_folder",
+            (courses.clone(),),
+        )
+    };
+    assert_eq!(
+        delete().unwrap(),
+        FolderDeletion {
+            deleted_folders: 2,
+            deleted_files: 1,
+        }
+    );
+    assert!(get_file().is_none());
+
+    let trash: Trash = env.query(env.canisters.storage, alice, "list_trash", ());
+    assert_eq!(trash.folders.len(), 1);
+    assert_eq!(trash.folders[0].folders[0].id, courses);
+    assert_eq!(trash.folders[0].file_ids, vec![file_id.clone()]);
+
+    let by_folder = |method, sender| -> u64 {
+        env.update(
+            env.canisters.storage,
+            sender,
+            method,
+            (vec![courses.clone()],),
+        )
+    };
+    assert_eq!(by_folder("restore_folders", bob), 0);
+    assert_eq!(by_folder("restore_folders", alice), 1);
+    assert_eq!(
+        get_file().unwrap().data,
+        b"video",
+        "the folder comes back with its files and access"
+    );
+
+    delete().unwrap();
+    assert_eq!(by_folder("purge_folders", alice), 1);
+    assert_eq!(by_folder("restore_folders", alice), 0);
+    let trash: Trash = env.query(env.canisters.storage, alice, "list_trash", ());
+    assert!(trash.folders.is_empty() && trash.files.is_empty());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn new_versions_keep_the_history_within_retention() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let file_id = env.upload_file(alice, FileBuilder::new("slides.pdf").data(b"one").build());
+
+    let upload_version = |sender, data: &[u8]| -> Result<FileVersion, String> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "upload_version",
+            (file_id.clone(), data.to_vec(), "Fixed typos".to_string()),
+        )
+    };
+    assert!(upload_version(bob, b"two").is_err());
+
+    let second = upload_version(alice, b"two").unwrap();
+    assert_eq!(second.version, 2);
+    assert_eq!(second.uploaded_by, alice);
+    assert!(upload_version(alice, b"two").is_err(), "same content");
+
+    let versions = || -> Vec<FileVersion> {
+        let versions: Result<Vec<FileVersion>, String> = env.query(
+            env.canisters.storage,
+            alice,
+            "list_versions",
+            (file_id.clone(),),
+        );
+        versions.unwrap()
+    };
+    let numbers =
+        |versions: Vec<FileVersion>| -> Vec<u32> { versions.iter().map(|v| v.version).collect() };
+    assert_eq!(numbers(versions()), [2, 1]);
+
+    let first: Result<StoredFile, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "get_version",
+        (file_id.clone(), 1u32),
+    );
+    assert_eq!(first.unwrap().data, b"one");
+
+    let restored: Result<FileVersion, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "restore_version",
+        (file_id.clone(), 1u32, None::<String>),
+    );
+    assert_eq!(restored.unwrap().version, 3);
+
+    let current: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_file",
+        (file_id.clone(), None::<bool>),
+    );
+    assert_eq!(current.unwrap().data, b"one");
+
+    let bytes_used = || -> u64 {
+        let report: Result<UsageReport, String> = env.query(
+            env.canisters.storage,
+            alice,
+            "get_usage",
+            (None::<Principal>,),
+        );
+        report.unwrap().usage.bytes_used
+    };
+    assert_eq!(bytes_used(), 9);
+
+    let retention: Result<(), String> = env.update(
+        env.canisters.storage,
+        alice,
+        "set_version_retention",
+        (file_id.clone(), 2u32),
+    );
+    assert!(retention.is_ok());
+    assert_eq!(numbers(versions()), [3, 2]);
+    assert_eq!(bytes_used(), 6);
+
+    env.purge_files(alice, &[file_id]);
+    assert_eq!(bytes_used(), 0);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn deleted_files_wait_in_the_trash_until_purged() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let kept = env.upload_file(alice, FileBuilder::new("kept.txt").data(b"kept").build());
+    let expired = env.upload_file(alice, FileBuilder::new("old.txt").data(b"old").build());
+
+    let delete: u64 = env.update(
+        env.canisters.storage,
+        alice,
+        "delete_files",
+        (vec![kept.clone(), expired.clone()],),
+    );
+    assert_eq!(delete, 2);
+
+    let trash = |sender| -> Trash { env.query(env.canisters.storage, sender, "list_trash", ()) };
+    assert_eq!(trash(alice).files.len(), 2);
+    assert!(trash(bob).files.is_empty());
+
+    let get_file = |file_id: &String| -> Option<StoredFile> {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "get_file",
+            (file_id.clone(), None::<bool>),
+        )
+    };
+    assert!(get_file(&kept).is_none());
+
+    let restore = |sender| -> u64 {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "restore_files",
+            (vec![kept.clone()],),
+        )
+    };
+    assert_eq!(restore(bob), 0);
+    assert_eq!(restore(alice), 1);
+    assert_eq!(get_file(&kept).unwrap().data, b"kept");
+
+    let retention: Result<(), String> = env.update(
+        env.canisters.storage,
+        env.controller,
+        "set_trash_retention",
+        (60u64,),
+    );
+    assert!(retention.is_ok());
+
+    env.pic.advance_time(Duration::from_secs(3601));
+    env.settle();
+
+    assert!(trash(alice).files.is_empty());
+    let restored: u64 = env.update(
+        env.canisters.storage,
+        alice,
+        "restore_files",
+        (vec![expired],),
+    );
+    assert_eq!(restored, 0, "the purge timer removed it");
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn groups_only_grant_access_to_files_shared_with_them() {
+    let env = TestEnv::new();
+    let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+
+    let create_group = |owner, name, members| -> GroupRecord {
+        env.advance();
+        let created: Result<GroupRecord, String> = env.update(
+            env.canisters.storage,
+            owner,
+            "create_group",
+            (GroupRecord::new(name, members),),
+        );
+        created.unwrap()
+    };
+    let team = create_group(alice, "team", vec![(bob, Access::Read)]);
+    assert_eq!(team.owner, alice);
+    create_group(carol, "book club", vec![]);
+
+    let file_id = env.upload_file(alice, FileBuilder::new("plan.txt").data(b"plan").build());
+
+    let get_file = |sender, mutable: bool| -> Option<StoredFile> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "get_file",
+            (file_id.clone(), Some(mutable)),
+        )
+    };
+    assert!(get_file(alice, true).is_some(), "owners hold every access");
+    assert!(get_file(bob, false).is_none());
+    assert!(get_file(carol, false).is_none(), "any group is not enough");
+
+    let add = |sender| -> Result<u64, String> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "add_group_files",
+            (team.id.clone(), vec![file_id.clone()]),
+        )
+    };
+    assert!(add(carol).is_err());
+    assert_eq!(add(alice), Ok(1));
+
+    assert_eq!(get_file(bob, false).unwrap().data, b"plan");
+    assert!(get_file(bob, true).is_none(), "read does not grant write");
+    assert!(get_file(carol, false).is_none());
+
+    let assign = |sender, users: Vec<(Principal, Access)>| -> Result<String, String> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "assign_group_members",
+            (users, team.id.clone()),
+        )
+    };
+    assert!(assign(alice, vec![(carol, Access::Write)]).is_ok());
+    assert!(
+        get_file(carol, true).is_some(),
+        "write grants read and write"
+    );
+
+    // Assigning follows the rules of `edit_group_members`: only admins assign,
+    // only the owner makes admins and existing members keep their access.
+    assert!(assign(carol, vec![(carol, Access::Admin)]).is_err());
+    assert!(assign(alice, vec![(bob, Access::Admin)]).is_ok());
+    assert_eq!(
+        assign(
+            bob,
+            vec![
+                (bob, Access::Admin),
+                (carol, Access::Admin),
+                (carol, Access::Read),
+                (user("dan"), Access::Removed),
+            ]
+        ),
+        Ok("No new users to assign.".to_string())
+    );
+    let members: Result<GroupRecord, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "get_group",
+        (team.id.clone(),),
+    );
+    assert_eq!(
+        members.unwrap().members,
+        [
+            (bob, Access::Read),
+            (carol, Access::Write),
+            (bob, Access::Admin)
+        ]
+    );
+
+    let remove = |sender| -> u64 {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "remove_group_files",
+            (team.id.clone(), vec![file_id.clone()]),
+        )
+    };
+    assert_eq!(remove(bob), 0);
+    assert_eq!(remove(alice), 1);
+    assert!(get_file(bob, false).is_none());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn explain_access_lists_every_grant_path() {
+    let env = TestEnv::new();
+    let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+
+    let shared = env.create_folder(alice, "shared", None);
+    let folder_access: Result<Folder, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "set_folder_access",
+        (shared.clone(), vec![(bob, Access::Read)], false),
+    );
+    assert!(folder_access.is_ok());
+
+    let file_id = env.upload_file(
+        alice,
+        FileBuilder::new("budget.xlsx")
+            .parent(&shared)
+            .allow(bob, Access::Write)
+            .build(),
+    );
+
+    let explain = |sender, principal: Principal| -> Result<AccessExplanation, String> {
+        env.query(
+            env.canisters.storage,
+            sender,
+            "explain_access",
+            (file_id.clone(), Some(principal)),
+        )
+    };
+
+    let bobs = explain(bob, bob).unwrap();
+    assert_eq!(bobs.effective, [Access::Write, Access::Read]);
+    let paths: Vec<(Access, GrantPath)> = bobs
+        .grants
+        .into_iter()
+        .map(|grant| (grant.access, grant.path))
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            (Access::Write, GrantPath::Direct),
+            (
+                Access::Read,
+                GrantPath::Folder {
+                    folder_id: shared,
+                    name: "shared".to_string(),
+                }
+            ),
+        ]
+    );
+
+    let owners = explain(alice, alice).unwrap();
+    assert_eq!(owners.grants[0].path, GrantPath::Owner);
+    assert!(owners.effective.contains(&Access::Delete));
+
+    assert!(explain(carol, carol).unwrap().effective.is_empty());
+    assert!(explain(carol, bob).is_err(), "only admins inspect others");
+    assert!(explain(alice, bob).is_ok());
+
+    let bulk = |sender| -> Result<Vec<AccessExplanation>, String> {
+        env.query(
+            env.canisters.storage,
+            sender,
+            "explain_access_bulk",
+            (vec![file_id.clone()], vec![alice, bob, carol]),
+        )
+    };
+    assert!(bulk(alice).is_err());
+    assert_eq!(bulk(env.controller).unwrap().len(), 3);
+}
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    image::RgbaImage::from_pixel(width, height, image::Rgba([200, 40, 40, 255]))
+        .write_to(
+            &mut std::io::Cursor::new(&mut data),
+            image::ImageFormat::Png,
+        )
+        .unwrap();
+    data
+}
+
+fn dimensions(data: &[u8]) -> (u32, u32) {
+    let image = image::load_from_memory(data).unwrap();
+    (image.width(), image.height())
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn images_have_resized_variants() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let original = png(400, 200);
+    let file_id = env.upload_file(
+        alice,
+        FileBuilder::new("banner.png")
+            .mime_type("image/png")
+            .data(&original)
+            .build(),
+    );
+
+    let variant = |sender, file_id: &str, size| -> Result<StoredFile, String> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "get_file_variant",
+            (file_id.to_string(), size),
+        )
+    };
+
+    let thumbnail = variant(alice, &file_id, VariantSize::Thumbnail).unwrap();
+    assert_eq!(thumbnail.mime_type, "image/png");
+    assert_eq!(thumbnail.size, thumbnail.data.len());
+    assert_eq!(dimensions(&thumbnail.data), (128, 64));
+
+    let medium = variant(alice, &file_id, VariantSize::Medium).unwrap();
+    assert_eq!(medium.data, original, "never upscaled");
+
+    assert!(variant(bob, &file_id, VariantSize::Small).is_err());
+
+    let text_id = env.upload_file(alice, FileBuilder::new("notes.txt").data(b"hi").build());
+    assert!(variant(alice, &text_id, VariantSize::Small).is_err());
+
+    let token: Result<String, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "create_download_token",
+        (file_id.clone(), 60u64),
+    );
+    let token = token.unwrap();
+
+    let small = env.http_get(
+        &format!("/files/{file_id}?variant=small&token={token}"),
+        &[],
+    );
+    assert_eq!(small.status_code, 200);
+    assert_eq!(small.header("Content-Type"), Some("image/png"));
+    assert_eq!(dimensions(&small.body), (320, 160));
+
+    let unknown = env.http_get(&format!("/files/{file_id}?variant=huge&token={token}"), &[]);
+    assert_eq!(unknown.status_code, 400);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn uploads_are_checked_against_their_content_and_context() {
+    let env = TestEnv::new();
+    let alice = user("alice");
+    let picture = png(16, 16);
+
+    let upload = |file: StoredFile, context: Option<UploadContext>| {
+        env.advance();
+        let uploaded: Vec<(String, FileUploadResolveType, String)> = env.update(
+            env.canisters.storage,
+            alice,
+            "upload_files",
+            (vec![file], context),
+        );
+        uploaded.into_iter().next().unwrap()
+    };
+
+    let mut claimed = FileBuilder::new("me.png")
+        .mime_type("image/png")
+        .data(&picture)
+        .build();
+    claimed.size = 1;
+    let (file_id, resolve, _) = upload(claimed, Some(UploadContext::ProfilePicture));
+    assert_eq!(resolve, FileUploadResolveType::SuccessfullyUploaded);
+
+    let stored: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_file",
+        (file_id.clone(), None::<bool>),
+    );
+    assert_eq!(
+        stored.unwrap().size,
+        picture.len(),
+        "size comes from the data"
+    );
+
+    let undeclared = upload(
+        FileBuilder::new("pixels")
+            .mime_type("")
+            .data(&png(8, 8))
+            .build(),
+        None,
+    );
+    let stored: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_file",
+        (undeclared.0, None::<bool>),
+    );
+    assert_eq!(stored.unwrap().mime_type, "image/png");
+
+    let rejected = |file: StoredFile, context| {
+        let (_, resolve, _) = upload(file, context);
+        resolve == FileUploadResolveType::ContentRejected
+    };
+    assert!(rejected(
+        FileBuilder::new("fake.png")
+            .mime_type("image/png")
+            .data(b"not a png")
+            .build(),
+        None
+    ));
+    assert!(rejected(
+        FileBuilder::new("logo.svg")
+            .mime_type("image/svg+xml")
+            .data(b"<svg></svg>")
+            .build(),
+        None
+    ));
+    assert!(rejected(
+        FileBuilder::new("photo.jpg")
+            .mime_type("image/jpeg")
+            .data(&png(4, 4))
+            .build(),
+        None
+    ));
+    assert!(rejected(
+        FileBuilder::new("cv.pdf")
+            .mime_type("application/pdf")
+            .data(b"%PDF-1.7 resume")
+            .build(),
+        Some(UploadContext::ProfilePicture)
+    ));
+
+    let mut metadata = UploadMetadata::new("huge.png", 6 * 1024 * 1024);
+    metadata.mime_type = "image/png".to_string();
+    metadata.context = Some(UploadContext::ProfilePicture);
+    let begun: Result<UploadStatus, UploadError> =
+        env.update(env.canisters.storage, alice, "begin_upload", (metadata,));
+    assert!(matches!(begun, Err(UploadError::Rejected(_))));
+
+    let set_policy = |sender| -> Result<(), String> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "set_upload_policy",
+            (
+                UploadContext::ProfilePicture,
+                UploadPolicy {
+                    allowed_types: vec!["image/*".to_string()],
+                    max_size: 64,
+                },
+            ),
+        )
+    };
+    assert!(set_policy(alice).is_err());
+    assert!(set_policy(env.controller).is_ok());
+    assert!(rejected(
+        FileBuilder::new("me2.png")
+            .mime_type("image/png")
+            .data(&png(32, 32))
+            .build(),
+        Some(UploadContext::ProfilePicture)
+    ));
+
+    let version: Result<FileVersion, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "upload_version",
+        (file_id, png(32, 32), "Bigger".to_string()),
+    );
+    assert!(version.is_err(), "versions keep the file's context");
+
+    let upload_for_alice =
+        |sender| -> Result<Vec<(String, FileUploadResolveType, String)>, String> {
+            env.update(
+                env.canisters.storage,
+                sender,
+                "upload_files_for",
+                (
+                    alice,
+                    vec![FileBuilder::new("bio.txt").data(b"hello").build()],
+                    None::<UploadContext>,
+                ),
+            )
+        };
+    assert!(
+        upload_for_alice(user("mallory")).is_err(),
+        "not a subscriber"
+    );
+
+    let (bio_id, _, _) = upload_for_alice(env.canisters.towntalk).unwrap().remove(0);
+    let get_bio = |sender| -> Option<StoredFile> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "get_file",
+            (bio_id.clone(), None::<bool>),
+        )
+    };
+    assert_eq!(get_bio(alice).expect("alice owns it").owner, alice);
+    assert!(
+        get_bio(env.canisters.towntalk).is_some(),
+        "towntalk can read it"
+    );
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn referenced_files_restrict_or_cascade_their_deletion() {
+    let env = TestEnv::new();
+    let (alice, bob, app) = (user("alice"), user("bob"), user("app"));
+
+    let delete = |file_id: &str| -> u64 {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "delete_files",
+            (vec![file_id.to_string()],),
+        )
+    };
+    let references = |file_id: &str| -> Vec<FileReference> {
+        let references: Result<Vec<FileReference>, String> = env.query(
+            env.canisters.storage,
+            alice,
+            "get_file_references",
+            (file_id.to_string(),),
+        );
+        references.unwrap()
+    };
+
+    // TownTalk is a cascading subscriber: purging a media takes it off the post.
+    let media_id = env.upload_file(alice, FileBuilder::new("cat.txt").data(b"meow").build());
+    let account = env.create_towntalk_account(alice, AccountBuilder::new("alice").build());
+    env.create_post(
+        alice,
+        PostBuilder::new("post-1", &account.id)
+            .medias(&[&media_id])
+            .build(),
+    );
+    env.settle();
+
+    assert_eq!(
+        references(&media_id),
+        [FileReference {
+            canister: env.canisters.towntalk,
+            entity: "post-1".to_string(),
+        }]
+    );
+
+    let medias = || -> Vec<String> {
+        let posts: Vec<Post> = env.query(
+            env.canisters.towntalk,
+            alice,
+            "get_posts",
+            (account.id.clone(),),
+        );
+        posts[0].medias.clone()
+    };
+
+    assert_eq!(delete(&media_id), 1);
+    env.settle();
+    assert_eq!(
+        medias(),
+        vec![media_id.clone()],
+        "the trash can still restore it"
+    );
+
+    let restored: u64 = env.update(
+        env.canisters.storage,
+        alice,
+        "restore_files",
+        (vec![media_id.clone()],),
+    );
+    assert_eq!(restored, 1);
+    assert_eq!(references(&media_id).len(), 1);
+
+    env.purge_files(alice, &[media_id]);
+    env.settle();
+    assert!(medias().is_empty());
+
+    // A restricting subscriber keeps its files around.
+    let folder = env.create_folder(alice, "docs", None);
+    let doc_id = env.upload_file(
+        alice,
+        FileBuilder::new("doc.txt")
+            .data(b"doc")
+            .parent(&folder)
+            .build(),
+    );
+    let add_references = |sender, user: Principal| -> Result<u64, String> {
+        env.update(
+            env.canisters.storage,
+            sender,
+            "add_file_references",
+            (vec![doc_id.clone()], "doc-1".to_string(), user),
+        )
+    };
+    assert!(add_references(app, alice).is_err(), "not a subscriber");
+
+    let registered: Result<(), String> = env.update(
+        env.canisters.storage,
+        env.controller,
+        "register_subscriber",
+        (app, DeletePolicy::Restrict),
+    );
+    registered.unwrap();
+    assert_eq!(add_references(app, bob), Ok(0), "bob can't read the doc");
+    assert_eq!(add_references(app, alice), Ok(1));
+
+    assert_eq!(delete(&doc_id), 0);
+    let folder_deleted: Result<FolderDeletion, String> =
+        env.update(env.canisters.storage, alice, "delete_folder", (folder,));
+    assert!(folder_deleted.is_err());
+
+    let unregistered: Result<(), String> = env.update(
+        env.canisters.storage,
+        env.controller,
+        "unregister_subscriber",
+        (app,),
+    );
+    unregistered.unwrap();
+    assert_eq!(delete(&doc_id), 1);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn the_index_rolls_uploads_over_to_new_buckets() {
+    let env = TestEnv::new();
+    let (alice, frontend) = (user("alice"), user("frontend"));
+    let index = env.canisters.storage_index;
+
+    let configured: Result<(), String> =
+        env.update(index, env.controller, "set_bucket_capacity", (100u64,));
+    configured.unwrap();
+    let configured: Result<(), String> =
+        env.update(index, env.controller, "set_max_buckets", (2u64,));
+    configured.unwrap();
+
+    let route = |size: u64| -> Result<Principal, String> {
+        env.update(index, frontend, "route_upload", (size,))
+    };
+    // Distinct contents, so the buckets don't share blobs.
+    let upload = |bucket: Principal, byte: &str, size: usize| -> String {
+        let file = FileBuilder::new(&format!("{byte}.txt"))
+            .data(byte.repeat(size).as_bytes())
+            .build();
+        let file_id = env.upload_file_to(bucket, alice, file);
+        env.settle();
+        file_id
+    };
+    let locate = |file_id: &str| -> Option<Principal> {
+        env.query(index, alice, "locate_file", (file_id.to_string(),))
+    };
+
+    let anonymous: Result<Principal, String> =
+        env.update(index, Principal::anonymous(), "route_upload", (60u64,));
+    assert!(anonymous.is_err());
+
+    // Only controllers and registered frontends have buckets created.
+    assert!(route(60).is_err());
+    let registered: Result<(), String> = env.update(index, alice, "register_frontend", (frontend,));
+    assert!(registered.is_err(), "only controllers register frontends");
+    let registered: Result<(), String> =
+        env.update(index, env.controller, "register_frontend", (frontend,));
+    registered.unwrap();
+
+    let first = route(60).unwrap();
+    let first_file = upload(first, "a", 60);
+    assert!(
+        first_file.starts_with(&first.to_text()),
+        "ids are per bucket"
+    );
+    assert_eq!(locate(&first_file), Some(first));
+
+    // 60 more bytes don't fit in the first bucket.
+    let second = route(60).unwrap();
+    assert_ne!(second, first);
+    let second_file = upload(second, "b", 60);
+    assert_eq!(locate(&second_file), Some(second));
+
+    // Older buckets are filled first, whoever routes.
+    let routed: Result<Principal, String> = env.update(index, alice, "route_upload", (30u64,));
+    assert_eq!(routed, Ok(first));
+    let routed: Result<Principal, String> = env.update(index, alice, "route_upload", (80u64,));
+    assert!(routed.is_err(), "alice can't have a bucket created");
+    assert!(route(101).is_err(), "larger than a bucket");
+    assert!(route(80).is_err(), "no more buckets allowed");
+
+    let buckets: Vec<Bucket> = env.query(index, alice, "get_buckets", ());
+    let usage: Vec<_> = buckets
+        .iter()
+        .map(|bucket| (bucket.canister_id, bucket.bytes_used, bucket.file_count))
+        .collect();
+    assert_eq!(usage, [(first, 60, 1), (second, 60, 1)]);
+
+    let located: Vec<(String, Option<Principal>)> = env.query(
+        index,
+        alice,
+        "locate_files",
+        (vec![first_file.clone(), "missing".to_string()],),
+    );
+    assert_eq!(
+        located,
+        [(first_file, Some(first)), ("missing".to_string(), None)]
+    );
+
+    let reported: Result<(), String> = env.update(
+        index,
+        alice,
+        "bucket_report",
+        (BucketReport {
+            stored: vec!["forged".to_string()],
+            removed: vec![],
+            bytes_used: 0,
+        },),
+    );
+    assert!(reported.is_err(), "only buckets report");
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn encrypted_files_keep_wrapped_keys_in_step_with_access() {
+    let env = TestEnv::new();
+    let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+
+    // Storage only ever sees ciphertext, declared as the type it encrypts.
+    let upload = |context: Option<UploadContext>| {
+        env.advance();
+        let uploaded: Vec<(String, FileUploadResolveType, String)> = env.update(
+            env.canisters.storage,
+            alice,
+            "upload_files",
+            (
+                vec![
+                    FileBuilder::new("diary.png")
+                        .mime_type("image/png")
+                        .data(b"ciphertext-1")
+                        .allow(bob, Access::Read)
+                        .build(),
+                ],
+                context,
+            ),
+        );
+        uploaded.into_iter().next().unwrap()
+    };
+    let (_, resolve, _) = upload(None);
+    assert_eq!(resolve, FileUploadResolveType::ContentRejected);
+    let (file_id, resolve, _) = upload(Some(UploadContext::Encrypted));
+    assert_eq!(resolve, FileUploadResolveType::SuccessfullyUploaded);
+
+    let plain_id = env.upload_file(alice, FileBuilder::new("notes.txt").data(b"notes").build());
+    let encrypted: Result<u32, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "encrypt_file",
+        (plain_id, vec![(alice, b"key".to_vec())]),
+    );
+    assert!(encrypted.is_err(), "not uploaded as ciphertext");
+
+    let wrapped = |user: Principal, key_version: u32| -> Vec<u8> {
+        format!("{user}-{key_version}").into_bytes()
+    };
+    let encrypt = |keys: Vec<(Principal, Vec<u8>)>| -> Result<u32, String> {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "encrypt_file",
+            (file_id.clone(), keys),
+        )
+    };
+    let key_of = |sender| -> Result<WrappedKey, String> {
+        env.query(
+            env.canisters.storage,
+            sender,
+            "get_file_key",
+            (file_id.clone(),),
+        )
+    };
+    let status = || -> FileKeyStatus {
+        let status: Result<FileKeyStatus, String> = env.query(
+            env.canisters.storage,
+            alice,
+            "get_file_key_status",
+            (file_id.clone(),),
+        );
+        status.unwrap()
+    };
+    let edit_access = |accesses: Vec<(Principal, Access)>| -> u64 {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "edit_allowed_users",
+            (file_id.clone(), accesses),
+        )
+    };
+
+    // Every principal with access gets a key, and no one else.
+    assert!(encrypt(vec![(alice, wrapped(alice, 1))]).is_err());
+    assert!(
+        encrypt(vec![
+            (alice, wrapped(alice, 1)),
+            (bob, wrapped(bob, 1)),
+            (carol, wrapped(carol, 1)),
+        ])
+        .is_err()
+    );
+    assert_eq!(
+        encrypt(vec![(alice, wrapped(alice, 1)), (bob, wrapped(bob, 1))]),
+        Ok(1)
+    );
+
+    assert_eq!(
+        key_of(bob),
+        Ok(WrappedKey {
+            key_version: 1,
+            wrapped_key: wrapped(bob, 1),
+        })
+    );
+    assert!(key_of(carol).is_err());
+
+    let listed: Vec<FileMetadata> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_files_by_id",
+        (vec![file_id.clone()],),
+    );
+    assert!(listed[0].encrypted);
+    assert_eq!(listed[0].mime_type, "image/png");
+
+    let variant: Result<StoredFile, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_file_variant",
+        (file_id.clone(), VariantSize::Thumbnail),
+    );
+    assert!(variant.is_err(), "ciphertext can't be resized");
+
+    let published: Result<String, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "edit_file_public_access",
+        (file_id.clone(), true),
+    );
+    assert!(published.is_err(), "encrypted files stay private");
+
+    // New holders get their key once they have access.
+    assert_eq!(edit_access(vec![(carol, Access::Admin)]), 1);
+    assert_eq!(status().missing, [carol]);
+
+    let share = |key_version: u32| -> Result<(), String> {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "share_file_key",
+            (file_id.clone(), carol, key_version, wrapped(carol, 1)),
+        )
+    };
+    assert!(share(2).is_err(), "not the current key version");
+    assert_eq!(share(1), Ok(()));
+    assert!(key_of(carol).is_ok());
+
+    // Revoking access drops the key and asks for a rotation.
+    assert_eq!(edit_access(vec![(carol, Access::Removed)]), 1);
+    assert!(key_of(carol).is_err());
+    assert!(status().rotation_pending);
+
+    let rotate = |keys: Vec<(Principal, Vec<u8>)>| -> Result<FileVersion, String> {
+        env.update(
+            env.canisters.storage,
+            alice,
+            "rotate_file_key",
+            (file_id.clone(), b"ciphertext-2".to_vec(), keys),
+        )
+    };
+    assert!(
+        rotate(vec![
+            (alice, wrapped(alice, 2)),
+            (bob, wrapped(bob, 2)),
+            (carol, wrapped(carol, 2)),
+        ])
+        .is_err()
+    );
+    let rotated = rotate(vec![(alice, wrapped(alice, 2)), (bob, wrapped(bob, 2))]).unwrap();
+    assert_eq!(rotated.version, 2);
+
+    // The first version is under a key nobody holds anymore, and plain uploads
+    // would be under none.
+    let restored: Result<FileVersion, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "restore_version",
+        (file_id.clone(), 1u32, None::<String>),
+    );
+    assert!(restored.is_err());
+    let uploaded: Result<FileVersion, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "upload_version",
+        (file_id.clone(), b"ciphertext-3".to_vec(), String::new()),
+    );
+    assert!(uploaded.is_err());
+
+    let after = status();
+    assert_eq!(after.key_version, 2);
+    assert!(!after.rotation_pending);
+    assert_eq!(key_of(bob).unwrap().wrapped_key, wrapped(bob, 2));
+
+    let file: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        bob,
+        "get_file",
+        (file_id.clone(), None::<bool>),
+    );
+    assert_eq!(file.unwrap().data, b"ciphertext-2");
+}
+
+fn filter(field: &str, operator: Operator, value: &str) -> Filter {
+    Filter {
+        field: field.to_string(),
+        operator,
+        value: value.to_string(),
+        between: None,
+        values: None,
+    }
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn search_finds_accessible_files_by_name_type_owner_and_date() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let upload = |owner, file| env.upload_file(owner, file);
+    let intro = upload(
+        alice,
+        FileBuilder::new("Rust intro.txt").data(b"intro").build(),
+    );
+    upload(
+        alice,
+        FileBuilder::new("rust-advanced.txt")
+            .data(b"advanced")
+            .build(),
+    );
+    let cat = upload(
+        alice,
+        FileBuilder::new("cat.png")
+            .mime_type("image/png")
+            .data(&png(2, 2))
+            .build(),
+    );
+    upload(
+        bob,
+        FileBuilder::new("rust secret.txt").data(b"secret").build(),
+    );
+    upload(
+        bob,
+        FileBuilder::new("rust public.txt")
+            .data(b"public")
+            .public()
+            .build(),
+    );
+    upload(
+        bob,
+        FileBuilder::new("rusty shared.txt")
+            .data(b"shared")
+            .allow(alice, Access::Read)
+            .build(),
+    );
+
+    let search = |text: Option<&str>, filters: Vec<Filter>, sort: Option<FileSort>| {
+        let found: Result<Page<FileMetadata>, String> = env.query(
+            env.canisters.storage,
+            alice,
+            "search_files",
+            (FileQuery {
+                text: text.map(str::to_string),
+                filters,
+                sort,
+                page: 1,
+                per_page: 10,
+            },),
+        );
+        found.unwrap().data
+    };
+    let by_name = Some(FileSort {
+        field: SortField::Name,
+        descending: false,
+    });
+    let names = |files: Vec<FileMetadata>| -> Vec<String> {
+        files.into_iter().map(|file| file.name).collect()
+    };
+
+    // Words match the start of name words; bob's private file stays hidden.
+    assert_eq!(
+        names(search(Some("RUS"), vec![], by_name.clone())),
+        [
+            "Rust intro.txt",
+            "rust public.txt",
+            "rust-advanced.txt",
+            "rusty shared.txt",
+        ]
+    );
+    assert_eq!(
+        names(search(Some("rust intro"), vec![], None)),
+        ["Rust intro.txt"]
+    );
+
+    let images = search(
+        None,
+        vec![filter("mime_type", Operator::EQUAL, "image/png")],
+        None,
+    );
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].id, cat);
+
+    assert_eq!(
+        names(search(
+            Some("rust"),
+            vec![filter("owner", Operator::EQUAL, &bob.to_text())],
+            by_name.clone(),
+        )),
+        ["rust public.txt", "rusty shared.txt"]
+    );
+
+    // Whole days compare as ranges.
+    assert_eq!(
+        search(
+            None,
+            vec![filter("uploaded_at", Operator::GREATER_THAN, "2000-01-01")],
+            None,
+        )
+        .len(),
+        5
+    );
+    assert!(
+        search(
+            None,
+            vec![filter("uploaded_at", Operator::LESS_THAN, "2000-01-01")],
+            None,
+        )
+        .is_empty()
+    );
+
+    let newest_first = search(None, vec![], None);
+    assert_eq!(newest_first.first().unwrap().name, "rusty shared.txt");
+
+    let second_page: Result<Page<FileMetadata>, String> = env.query(
+        env.canisters.storage,
+        alice,
+        "search_files",
+        (FileQuery {
+            text: None,
+            filters: vec![],
+            sort: by_name.clone(),
+            page: 2,
+            per_page: 2,
+        },),
+    );
+    assert_eq!(
+        names(second_page.unwrap().data),
+        ["rust public.txt", "rust-advanced.txt"]
+    );
+
+    // The indexes follow renames and deletions.
+    let renamed: Result<String, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "change_file_name",
+        (intro.clone(), "Kotlin intro.txt".to_string()),
+    );
+    renamed.unwrap();
+    assert!(search(Some("rust intro"), vec![], None).is_empty());
+    assert_eq!(
+        names(search(Some("kotlin"), vec![], None)),
+        ["Kotlin intro.txt"]
+    );
+
+    let deleted: u64 = env.update(env.canisters.storage, alice, "delete_files", (vec![intro],));
+    assert_eq!(deleted, 1);
+    assert!(search(Some("kotlin"), vec![], None).is_empty());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn tags_and_metadata_label_files_and_filter_searches() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let syllabus = env.upload_file(
+        alice,
+        FileBuilder::new("syllabus.txt")
+            .data(b"week 1")
+            .tag("Syllabus")
+            .tag("syllabus")
+            .metadata("level", "beginner")
+            .allow(bob, Access::Read)
+            .build(),
+    );
+    let dataset = env.upload_file(
+        alice,
+        FileBuilder::new("data.csv")
+            .data(b"a,b")
+            .tag("dataset")
+            .metadata("level", "advanced")
+            .build(),
+    );
+
+    let invalid: Vec<(String, FileUploadResolveType, String)> = env.update(
+        env.canisters.storage,
+        alice,
+        "upload_files",
+        (
+            vec![
+                FileBuilder::new("bad.txt")
+                    .data(b"bad")
+                    .tag("no spaces")
+                    .build(),
+            ],
+            None::<UploadContext>,
+        ),
+    );
+    assert_eq!(invalid[0].1, FileUploadResolveType::FailedToUpload);
+
+    let edit_tags = |sender, file_id: &str, add: &[&str], remove: &[&str]| {
+        let tags: Result<Vec<String>, String> = env.update(
+            env.canisters.storage,
+            sender,
+            "edit_file_tags",
+            (
+                file_id.to_string(),
+                add.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+                remove.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+            ),
+        );
+        tags
+    };
+
+    // Tags are normalized at upload and on every edit.
+    assert_eq!(
+        edit_tags(alice, &syllabus, &["Starter-Code"], &[]),
+        Ok(vec!["starter-code".to_string(), "syllabus".to_string()])
+    );
+    assert!(
+        edit_tags(bob, &syllabus, &["mine"], &[]).is_err(),
+        "readers can't edit"
+    );
+    assert_eq!(edit_tags(alice, &dataset, &[], &["dataset"]), Ok(vec![]));
+    assert_eq!(
+        edit_tags(alice, &dataset, &["dataset", "csv"], &[]),
+        Ok(vec!["csv".to_string(), "dataset".to_string()])
+    );
+
+    let metadata: Result<BTreeMap<String, String>, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "edit_file_metadata",
+        (
+            dataset.clone(),
+            BTreeMap::from([("rows".to_string(), "2".to_string())]),
+            vec!["missing".to_string()],
+        ),
+    );
+    assert_eq!(
+        metadata,
+        Ok(BTreeMap::from([
+            ("level".to_string(), "advanced".to_string()),
+            ("rows".to_string(), "2".to_string()),
+        ]))
+    );
+
+    let too_many: BTreeMap<String, String> = (0..40)
+        .map(|i| (format!("key-{i}"), "value".to_string()))
+        .collect();
+    let rejected: Result<BTreeMap<String, String>, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "edit_file_metadata",
+        (dataset.clone(), too_many, Vec::<String>::new()),
+    );
+    assert!(rejected.is_err(), "metadata is bounded");
+
+    let search = |sender, filters: Vec<Filter>| -> Vec<String> {
+        let found: Result<Page<FileMetadata>, String> = env.query(
+            env.canisters.storage,
+            sender,
+            "search_files",
+            (FileQuery {
+                text: None,
+                filters,
+                sort: None,
+                page: 1,
+                per_page: 10,
+            },),
+        );
+        found
+            .unwrap()
+            .data
+            .into_iter()
+            .map(|file| file.id)
+            .collect()
+    };
+
+    assert_eq!(
+        search(alice, vec![filter("tag:syllabus", Operator::EQUAL, "true")]),
+        [syllabus.as_str()]
+    );
+    assert_eq!(
+        search(alice, vec![filter("tags", Operator::ILIKE, "data")]),
+        [dataset.as_str()]
+    );
+    assert_eq!(
+        search(
+            alice,
+            vec![filter("metadata.level", Operator::EQUAL, "Advanced")]
+        ),
+        [dataset.as_str()]
+    );
+    assert_eq!(
+        search(
+            bob,
+            vec![filter("tag:starter-code", Operator::EQUAL, "true")]
+        ),
+        [syllabus.as_str()]
+    );
+}
+
+type ItemResult = (String, Result<(), String>);
+type BatchResults = Result<Vec<ItemResult>, String>;
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn batch_operations_report_per_item_results() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let folder = env.create_folder(alice, "docs", None);
+    let a = env.upload_file(alice, FileBuilder::new("a.txt").build());
+    let b = env.upload_file(alice, FileBuilder::new("b.txt").build());
+    let theirs = env.upload_file(bob, FileBuilder::new("theirs.txt").build());
+
+    let get_file = |file_id: &str| -> StoredFile {
+        let file: Option<StoredFile> = env.update(
+            env.canisters.storage,
+            alice,
+            "get_file",
+            (file_id.to_string(), None::<bool>),
+        );
+        file.expect("alice owns the file")
+    };
+    let failed = |results: &[ItemResult]| -> Vec<String> {
+        results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(id, _)| id.clone())
+            .collect()
+    };
+
+    // Without `atomic`, one failing item doesn't stop the others.
+    let tagged: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_tag_files",
+        (
+            vec![a.clone(), theirs.clone(), a.clone()],
+            vec!["Draft".to_string()],
+            Vec::<String>::new(),
+            false,
+        ),
+    );
+    let tagged = tagged.expect("the batch is valid");
+    assert_eq!(tagged.len(), 3);
+    assert!(tagged[0].1.is_ok());
+    assert!(tagged[1].1.is_err(), "alice can't edit bob's file");
+    assert_eq!(
+        tagged[2].1,
+        Err("This file is listed more than once.".to_string())
+    );
+    assert_eq!(get_file(&a).tags, ["draft"]);
+
+    // With `atomic`, nothing is applied unless every item passes.
+    let moved: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_move_files",
+        (vec![a.clone(), theirs.clone()], Some(folder.clone()), true),
+    );
+    let moved = moved.expect("the batch is valid");
+    assert_eq!(failed(&moved), [a.as_str(), theirs.as_str()]);
+    assert_eq!(
+        moved[0].1,
+        Err("Not applied, another item failed.".to_string())
+    );
+    assert_eq!(get_file(&a).parent_id, None);
+
+    let moved: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_move_files",
+        (vec![a.clone(), b.clone()], Some(folder.clone()), true),
+    );
+    assert!(failed(&moved.expect("the batch is valid")).is_empty());
+    assert_eq!(get_file(&b).parent_id, Some(folder.clone()));
+
+    // Atomic renames can't give two files of the folder the same name.
+    let renamed: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_rename_files",
+        (
+            vec![
+                (a.clone(), "same.txt".to_string()),
+                (b.clone(), "same.txt".to_string()),
+            ],
+            true,
+        ),
+    );
+    assert_eq!(
+        failed(&renamed.expect("the batch is valid")),
+        [a.as_str(), b.as_str()]
+    );
+    assert_eq!(get_file(&a).name, "a.txt");
+
+    let published: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_set_public",
+        (vec![a.clone(), b.clone()], true, false),
+    );
+    assert!(failed(&published.expect("the batch is valid")).is_empty());
+    assert!(get_file(&a).public && get_file(&b).public);
+
+    let shared: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_share_files",
+        (
+            vec![a.clone(), theirs.clone()],
+            vec![(bob, Access::Write)],
+            false,
+        ),
+    );
+    assert_eq!(
+        failed(&shared.expect("the batch is valid")),
+        [theirs.as_str()]
+    );
+    assert!(get_file(&a).allowed_users.contains(&(bob, Access::Write)));
+
+    let deleted: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_delete_files",
+        (vec![a.clone(), b.clone(), "missing".to_string()], false),
+    );
+    assert_eq!(failed(&deleted.expect("the batch is valid")), ["missing"]);
+    let gone: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_file",
+        (a.clone(), None::<bool>),
+    );
+    assert!(gone.is_none(), "deleted files are in the trash");
+
+    let too_many: BatchResults = env.update(
+        env.canisters.storage,
+        alice,
+        "batch_delete_files",
+        (vec![theirs.clone(); 501], false),
+    );
+    assert!(too_many.is_err());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn downloads_and_usage_show_up_in_storage_stats() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let notes = env.upload_file(
+        alice,
+        FileBuilder::new("notes.txt")
+            .data(b"notes")
+            .allow(bob, Access::Read)
+            .build(),
+    );
+    let logo_data = png(1, 1);
+    let logo_len = logo_data.len() as u64;
+    let logo = env.upload_file(
+        alice,
+        FileBuilder::new("logo.png")
+            .mime_type("image/png")
+            .data(&logo_data)
+            .build(),
+    );
+
+    let download = |sender, file_id: &str| {
+        let file: Option<StoredFile> = env.update(
+            env.canisters.storage,
+            sender,
+            "get_file",
+            (file_id.to_string(), None::<bool>),
+        );
+        assert!(file.is_some());
+    };
+    download(bob, &notes);
+    download(bob, &notes);
+    download(alice, &logo);
+
+    // HTTP downloads count too, later ranges of them don't.
+    let token: Result<String, String> = env.update(
+        env.canisters.storage,
+        alice,
+        "create_download_token",
+        (notes.clone(), 60u64),
+    );
+    let url = format!("/files/{notes}?token={}", token.unwrap());
+    assert_eq!(env.http_get(&url, &[]).status_code, 200);
+    assert_eq!(
+        env.http_get(&url, &[("Range", "bytes=2-")]).status_code,
+        206
+    );
+
+    let file_stats = |sender, file_id: &str| -> Result<FileStats, String> {
+        env.query(
+            env.canisters.storage,
+            sender,
+            "get_file_stats",
+            (file_id.to_string(),),
+        )
+    };
+    let stats = file_stats(alice, &notes).unwrap();
+    assert_eq!(stats.downloads, 3);
+    assert!(stats.last_accessed.is_some());
+    assert!(file_stats(bob, &notes).is_err(), "readers can't see stats");
+
+    let contents: Vec<StoredFile> = env.query(
+        env.canisters.storage,
+        bob,
+        "get_files_content",
+        (vec![notes.clone(), logo.clone()],),
+    );
+    assert_eq!(contents.len(), 1, "only readable files");
+    assert_eq!(contents[0].data, b"notes");
+    assert_eq!(
+        file_stats(alice, &notes).unwrap().downloads,
+        3,
+        "fetching contents is not a download"
+    );
+
+    // A day later storage grows again.
+    env.pic.advance_time(Duration::from_secs(25 * 60 * 60));
+    env.upload_file(bob, FileBuilder::new("bob.txt").data(b"hi").build());
+
+    let storage_stats = |sender| -> Result<StorageStats, String> {
+        env.query(
+            env.canisters.storage,
+            sender,
+            "get_storage_stats",
+            (GrowthPeriod::Day, 1u32),
+        )
+    };
+    assert!(storage_stats(alice).is_err());
+
+    let stats = storage_stats(env.controller).unwrap();
+    assert_eq!(
+        stats.usage,
+        Usage {
+            bytes_used: 7 + logo_len,
+            file_count: 3,
+        }
+    );
+
+    assert_eq!(stats.by_owner[0].owner, alice);
+    assert_eq!(stats.by_owner[0].usage.bytes_used, 5 + logo_len);
+    assert_eq!(stats.by_owner[0].downloads, 4);
+    assert_eq!(stats.by_owner[1].owner, bob);
+
+    let text = stats
+        .by_mime_type
+        .iter()
+        .find(|stats| stats.mime_type == "text/plain")
+        .unwrap();
+    assert_eq!(text.usage.bytes_used, 7);
+    assert_eq!(text.usage.file_count, 2);
+    assert_eq!(text.downloads, 3);
+
+    assert_eq!(stats.most_downloaded.len(), 1);
+    assert_eq!(stats.most_downloaded[0].file_id, notes);
+
+    assert_eq!(stats.growth.len(), 2);
+    assert_eq!(stats.growth[0].files_added, 2);
+    assert_eq!(stats.growth[1].bytes_added, 2);
+    assert_eq!(stats.growth[1].usage, stats.usage);
+}
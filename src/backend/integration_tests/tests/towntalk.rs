@@ -0,0 +1,232 @@
+use candid::Nat;
+use integration_tests::fixtures::{
+    AccountBuilder, Comment, FileBuilder, Post, PostBuilder, TowntalkAccount,
+};
+use integration_tests::{TestEnv, user};
+use utilities::StoredFile;
+use utilities::notifications::{NotificationEvent, NotificationType};
+use utilities::rewards::Payout;
+
+#[derive(candid::CandidType, serde::Deserialize)]
+struct NotificationPreferences {
+    muted: Vec<NotificationType>,
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn following_a_public_account_notifies_it() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let alice_account = env.create_towntalk_account(alice, AccountBuilder::new("alice").build());
+    let bob_account = env.create_towntalk_account(bob, AccountBuilder::new("bob").build());
+
+    env.update::<_, ()>(
+        env.canisters.towntalk,
+        bob,
+        "follow",
+        (bob_account.id.clone(), alice_account.id.clone()),
+    );
+    env.settle();
+
+    let inbox = env.notifications(alice, true);
+    assert_eq!(inbox.data.len(), 1);
+    assert_eq!(
+        inbox.data[0].event,
+        NotificationEvent::NewFollower {
+            account_id: alice_account.id,
+            follower_id: bob_account.id,
+        }
+    );
+
+    let marked: u64 = env.update(env.canisters.notifications, alice, "mark_all_as_read", ());
+    assert_eq!(marked, 1);
+    assert!(env.notifications(alice, true).data.is_empty());
+    assert_eq!(env.notifications(alice, false).data.len(), 1);
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn post_activity_notifies_the_poster_unless_muted() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let alice_account = env.create_towntalk_account(alice, AccountBuilder::new("alice").build());
+    let bob_account = env.create_towntalk_account(bob, AccountBuilder::new("bob").build());
+    env.create_post(
+        alice,
+        PostBuilder::new("post-1", &alice_account.id)
+            .title("Hello")
+            .build(),
+    );
+
+    env.update::<_, NotificationPreferences>(
+        env.canisters.notifications,
+        alice,
+        "set_preferences",
+        (NotificationPreferences {
+            muted: vec![NotificationType::PostCommented],
+        },),
+    );
+
+    env.update::<_, ()>(
+        env.canisters.towntalk,
+        bob,
+        "like_post",
+        (bob_account.id.clone(), "post-1".to_string()),
+    );
+    env.update::<_, ()>(
+        env.canisters.towntalk,
+        bob,
+        "comment_post",
+        (
+            bob_account.id.clone(),
+            "post-1".to_string(),
+            Comment {
+                id: "comment-1".to_string(),
+                comment: "Nice!".to_string(),
+                post_id: "post-1".to_string(),
+                poster_id: bob_account.id.clone(),
+                replied_to: None,
+                created_at: String::new(),
+                updated_at: String::new(),
+            },
+        ),
+    );
+    env.settle();
+
+    let kinds: Vec<NotificationType> = env
+        .notifications(alice, false)
+        .data
+        .into_iter()
+        .map(|n| n.kind)
+        .collect();
+    assert_eq!(kinds, vec![NotificationType::PostLiked]);
+    assert!(env.notifications(bob, false).data.is_empty());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn tipping_moves_tokens_to_the_creator() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let alice_account = env.create_towntalk_account(alice, AccountBuilder::new("alice").build());
+    let bob_account = env.create_towntalk_account(bob, AccountBuilder::new("bob").build());
+    env.create_post(alice, PostBuilder::new("post-1", &alice_account.id).build());
+
+    env.mint(bob, 1_000_000);
+    env.approve(bob, env.canisters.towntalk, 110_000);
+
+    let tip: Result<Payout, String> = env.update(
+        env.canisters.towntalk,
+        bob,
+        "tip_creator",
+        (
+            bob_account.id.clone(),
+            "post-1".to_string(),
+            100_000u64,
+            "tip-1".to_string(),
+        ),
+    );
+    assert!(tip.expect("tip is paid").is_paid());
+    assert_eq!(env.balance(alice), Nat::from(100_000u64));
+
+    // Retrying with the same key returns the paid tip without paying again.
+    let retried: Result<Payout, String> = env.update(
+        env.canisters.towntalk,
+        bob,
+        "tip_creator",
+        (
+            bob_account.id.clone(),
+            "post-1".to_string(),
+            100_000u64,
+            "tip-1".to_string(),
+        ),
+    );
+    assert!(retried.expect("the tip was paid").is_paid());
+    assert_eq!(env.balance(alice), Nat::from(100_000u64));
+
+    env.advance();
+    let unapproved: Result<Payout, String> = env.update(
+        env.canisters.towntalk,
+        bob,
+        "tip_creator",
+        (
+            bob_account.id,
+            "post-1".to_string(),
+            100_000u64,
+            "tip-2".to_string(),
+        ),
+    );
+    assert!(unapproved.is_err(), "the allowance is used up");
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn a_taken_post_id_is_not_overwritten() {
+    let env = TestEnv::new();
+    let (alice, bob) = (user("alice"), user("bob"));
+
+    let alice_account = env.create_towntalk_account(alice, AccountBuilder::new("alice").build());
+    let bob_account = env.create_towntalk_account(bob, AccountBuilder::new("bob").build());
+    env.create_post(
+        alice,
+        PostBuilder::new("post-1", &alice_account.id)
+            .title("Mine")
+            .build(),
+    );
+    env.create_post(
+        bob,
+        PostBuilder::new("post-1", &bob_account.id)
+            .title("Taken over")
+            .build(),
+    );
+
+    let posts = |owner, account_id: &str| -> Vec<Post> {
+        env.query(
+            env.canisters.towntalk,
+            owner,
+            "get_posts",
+            (account_id.to_string(),),
+        )
+    };
+    let titles: Vec<String> = posts(alice, &alice_account.id)
+        .into_iter()
+        .map(|post| post.title)
+        .collect();
+    assert_eq!(titles, vec!["Mine".to_string()]);
+    assert!(posts(bob, &bob_account.id).is_empty());
+}
+
+#[test]
+#[ignore = "needs PocketIC, see scripts/integration-tests.sh"]
+fn profile_pictures_go_to_the_configured_storage() {
+    let env = TestEnv::new();
+    let alice = user("alice");
+
+    let payload = AccountBuilder::new("alice")
+        .profile_picture(
+            FileBuilder::new("avatar.png")
+                .mime_type("image/png")
+                .data(b"\x89PNG\r\n\x1a\npixels")
+                .build(),
+        )
+        .build();
+    // The storage canister named by the client is ignored.
+    let account: TowntalkAccount = env.update(
+        env.canisters.towntalk,
+        alice,
+        "create_account",
+        (payload, env.canisters.ledger),
+    );
+
+    let picture_id = account.profile.profile_picture.expect("picture is stored");
+    let picture: Option<StoredFile> = env.update(
+        env.canisters.storage,
+        alice,
+        "get_file",
+        (picture_id, None::<bool>),
+    );
+    assert_eq!(picture.map(|file| file.owner), Some(alice));
+}
@@ -0,0 +1,67 @@
+use candid::{Nat, Principal};
+use ic_cdk::{api::msg_caller, export_candid};
+use std::cell::RefCell;
+use utilities::ledger::local::LocalLedger;
+use utilities::ledger::{
+    Account, Allowance, AllowanceArgs, ApproveArgs, ApproveError, TransferArg, TransferError,
+    TransferFromArgs, TransferFromError,
+};
+
+// Stand-in for `icrc1_ledger_canister` used by the integration tests. Same
+// ICRC-1/ICRC-2 interface as the deployed CRY ledger, backed by `LocalLedger`,
+// plus a controller-only `mint`.
+
+const TRANSFER_FEE: u64 = 10_000;
+
+thread_local! {
+    static LEDGER: RefCell<LocalLedger> =
+        RefCell::new(LocalLedger::new(Principal::anonymous(), TRANSFER_FEE));
+}
+
+fn with_ledger<R>(f: impl FnOnce(&LocalLedger) -> R) -> R {
+    LEDGER.with_borrow(|ledger| {
+        ledger.set_time(ic_cdk::api::time());
+        f(ledger)
+    })
+}
+
+#[ic_cdk::update]
+fn mint(to: Account, amount: u64) -> Result<Nat, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can mint.".to_string());
+    }
+
+    Ok(with_ledger(|ledger| ledger.mint(&to, amount)))
+}
+
+#[ic_cdk::query]
+fn icrc1_fee() -> Nat {
+    with_ledger(|ledger| ledger.fee.clone())
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    with_ledger(|ledger| ledger.icrc1_balance_of(&account))
+}
+
+#[ic_cdk::update]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    with_ledger(|ledger| ledger.icrc1_transfer(msg_caller(), arg))
+}
+
+#[ic_cdk::update]
+fn icrc2_approve(arg: ApproveArgs) -> Result<Nat, ApproveError> {
+    with_ledger(|ledger| ledger.icrc2_approve(msg_caller(), arg))
+}
+
+#[ic_cdk::update]
+fn icrc2_transfer_from(arg: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    with_ledger(|ledger| ledger.icrc2_transfer_from(msg_caller(), arg))
+}
+
+#[ic_cdk::query]
+fn icrc2_allowance(arg: AllowanceArgs) -> Allowance {
+    with_ledger(|ledger| ledger.icrc2_allowance(&arg))
+}
+
+export_candid!();
@@ -0,0 +1,251 @@
+use candid::{CandidType, Principal};
+use ic_cdk::{api::msg_caller, export_candid};
+use paginator::{HasFields, Paginator, PaginatorResponse};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use utilities::memory::{self, EMITTERS_MEMORY_ID};
+use utilities::notifications::{EmitEventInput, NotificationEvent, NotificationType};
+use utilities::now;
+
+// Oldest notifications are dropped once an inbox grows past this size.
+const MAX_INBOX_SIZE: usize = 500;
+
+#[derive(Clone, Serialize, Deserialize, CandidType)]
+pub struct Notification {
+    pub id: String,
+    pub kind: NotificationType,
+    pub event: NotificationEvent,
+    pub source: Principal, // Canister that emitted the event
+    pub read: bool,
+    pub created_at: String,
+}
+
+impl HasFields for Notification {
+    fn get_field(&self, field_name: &str) -> String {
+        match field_name {
+            "id" => self.id.clone(),
+            "kind" => format!("{:?}", self.kind),
+            "source" => self.source.to_text(),
+            "read" => self.read.to_string(),
+            "created_at" => self.created_at.clone(),
+            _ => "".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize, CandidType)]
+pub struct NotificationPreferences {
+    pub muted: Vec<NotificationType>,
+}
+
+thread_local! {
+    static EMITTERS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    static INBOXES: RefCell<HashMap<Principal, Vec<Notification>>> = RefCell::new(HashMap::new());
+    static PREFERENCES: RefCell<HashMap<Principal, NotificationPreferences>> = RefCell::new(HashMap::new());
+    static NEXT_NOTIFICATION_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+// EMITTERS
+
+#[ic_cdk::update]
+fn register_emitter(canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can register emitters.".to_string());
+    }
+
+    EMITTERS.with_borrow_mut(|emitters| emitters.insert(canister_id));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_emitter(canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can remove emitters.".to_string());
+    }
+
+    EMITTERS.with_borrow_mut(|emitters| emitters.remove(&canister_id));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_emitters() -> Vec<Principal> {
+    EMITTERS.with_borrow(|emitters| emitters.iter().cloned().collect())
+}
+
+// Fans the event out to every recipient that has not muted its type and
+// returns the number of inboxes it was delivered to.
+#[ic_cdk::update]
+fn emit(input: EmitEventInput) -> Result<usize, String> {
+    let source = msg_caller();
+    let allowed = EMITTERS.with_borrow(|emitters| emitters.contains(&source))
+        || ic_cdk::api::is_controller(&source);
+
+    if !allowed {
+        return Err("Caller is not a registered emitter.".to_string());
+    }
+
+    let kind = input.event.kind();
+    let created_at = now();
+    let mut delivered = 0;
+
+    let mut recipients = input.recipients;
+    recipients.sort();
+    recipients.dedup();
+
+    for recipient in recipients {
+        let muted = PREFERENCES.with_borrow(|state| {
+            state
+                .get(&recipient)
+                .is_some_and(|prefs| prefs.muted.contains(&kind))
+        });
+
+        if muted {
+            continue;
+        }
+
+        let id = NEXT_NOTIFICATION_ID.with(|next| {
+            let id = next.get();
+            next.set(id + 1);
+            id
+        });
+
+        let notification = Notification {
+            id: id.to_string(),
+            kind,
+            event: input.event.clone(),
+            source,
+            read: false,
+            created_at: created_at.clone(),
+        };
+
+        INBOXES.with_borrow_mut(|state| {
+            let inbox = state.entry(recipient).or_default();
+            inbox.push(notification);
+
+            if inbox.len() > MAX_INBOX_SIZE {
+                let overflow = inbox.len() - MAX_INBOX_SIZE;
+                inbox.drain(..overflow);
+            }
+        });
+
+        delivered += 1;
+    }
+
+    Ok(delivered)
+}
+
+// INBOX
+
+// Newest first.
+#[ic_cdk::query]
+fn get_notifications(
+    page: usize,
+    per_page: usize,
+    unread_only: bool,
+) -> PaginatorResponse<Notification> {
+    let notifications: Vec<Notification> = INBOXES.with_borrow(|state| {
+        state
+            .get(&msg_caller())
+            .map(|inbox| {
+                inbox
+                    .iter()
+                    .rev()
+                    .filter(|n| !unread_only || !n.read)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+
+    Paginator::new(notifications, vec![]).get(page.max(1), per_page.max(1))
+}
+
+#[ic_cdk::query]
+fn get_unread_count() -> usize {
+    INBOXES.with_borrow(|state| {
+        state
+            .get(&msg_caller())
+            .map(|inbox| inbox.iter().filter(|n| !n.read).count())
+            .unwrap_or(0)
+    })
+}
+
+#[ic_cdk::update]
+fn mark_as_read(notification_ids: Vec<String>) -> usize {
+    INBOXES.with_borrow_mut(|state| {
+        let Some(inbox) = state.get_mut(&msg_caller()) else {
+            return 0;
+        };
+
+        let mut marked = 0;
+        for notification in inbox
+            .iter_mut()
+            .filter(|n| !n.read && notification_ids.contains(&n.id))
+        {
+            notification.read = true;
+            marked += 1;
+        }
+        marked
+    })
+}
+
+#[ic_cdk::update]
+fn mark_all_as_read() -> usize {
+    INBOXES.with_borrow_mut(|state| {
+        let Some(inbox) = state.get_mut(&msg_caller()) else {
+            return 0;
+        };
+
+        let mut marked = 0;
+        for notification in inbox.iter_mut().filter(|n| !n.read) {
+            notification.read = true;
+            marked += 1;
+        }
+        marked
+    })
+}
+
+#[ic_cdk::update]
+fn delete_notifications(notification_ids: Vec<String>) {
+    INBOXES.with_borrow_mut(|state| {
+        if let Some(inbox) = state.get_mut(&msg_caller()) {
+            inbox.retain(|n| !notification_ids.contains(&n.id));
+        }
+    });
+}
+
+// PREFERENCES
+
+#[ic_cdk::query]
+fn get_preferences() -> NotificationPreferences {
+    PREFERENCES.with_borrow(|state| state.get(&msg_caller()).cloned().unwrap_or_default())
+}
+
+// Muted types are no longer delivered; notifications already in the inbox stay.
+#[ic_cdk::update]
+fn set_preferences(preferences: NotificationPreferences) -> NotificationPreferences {
+    let mut preferences = preferences;
+    preferences.muted.sort_by_key(|kind| *kind as u8);
+    preferences.muted.dedup();
+
+    PREFERENCES.with_borrow_mut(|state| state.insert(msg_caller(), preferences.clone()));
+    preferences
+}
+
+// UPGRADES
+
+// Emitters are configuration, so they are kept across upgrades.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    EMITTERS.with_borrow(|emitters| memory::save(EMITTERS_MEMORY_ID, emitters));
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    if let Some(emitters) = memory::restore(EMITTERS_MEMORY_ID) {
+        EMITTERS.set(emitters);
+    }
+}
+
+export_candid!();
@@ -1,10 +1,11 @@
 use candid::CandidType;
+use serde::Deserialize;
 
 pub trait HasFields {
     fn get_field(&self, field_name: &str) -> String;
 }
 
-#[derive(Clone, CandidType)]
+#[derive(Clone, CandidType, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum Operator {
     EQUAL,
@@ -21,7 +22,7 @@ pub enum Operator {
     NOT_IN,
 }
 
-#[derive(Clone, CandidType)]
+#[derive(Clone, CandidType, Deserialize)]
 pub struct Filter {
     pub field: String,
     pub operator: Operator,
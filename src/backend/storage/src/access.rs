@@ -0,0 +1,164 @@
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::{Access, FILES, StoredFile, folders, groups, has_file_permission};
+
+// Permission resolution for files. `file_grants` lists every access a
+// principal holds on a file together with the path it comes from, which is
+// what `has_file_permission` checks and what `explain_access` reports when
+// someone can't open a file.
+
+// Batches of `explain_access_bulk` are capped so they fit in one message.
+const MAX_BULK_EXPLANATIONS: usize = 1_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum GrantPath {
+    Owner,
+    Direct,
+    Public,
+    Folder { folder_id: String, name: String },
+    Group { group_id: String, name: String },
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Grant {
+    pub access: Access,
+    pub path: GrantPath,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct AccessExplanation {
+    pub file_id: String,
+    pub principal: Principal,
+    // Every access the grants satisfy, strongest first.
+    pub effective: Vec<Access>,
+    pub grants: Vec<Grant>,
+}
+
+pub fn file_grants(file: &StoredFile, principal: Principal) -> Vec<Grant> {
+    let mut grants: Vec<Grant> = Vec::new();
+
+    if file.owner == principal {
+        grants.push(Grant {
+            access: Access::Owner,
+            path: GrantPath::Owner,
+        });
+    }
+
+    grants.extend(
+        file.allowed_users
+            .iter()
+            .filter(|(user, _)| *user == principal)
+            .map(|(_, access)| Grant {
+                access: access.clone(),
+                path: GrantPath::Direct,
+            }),
+    );
+
+    grants.extend(
+        folders::held_along(file.parent_id.as_deref(), principal)
+            .into_iter()
+            .map(|(folder, access)| Grant {
+                access,
+                path: GrantPath::Folder {
+                    folder_id: folder.id,
+                    name: folder.name,
+                },
+            }),
+    );
+
+    grants.extend(
+        groups::held_on_file(&file.id, principal)
+            .into_iter()
+            .map(|(group, access)| Grant {
+                access,
+                path: GrantPath::Group {
+                    group_id: group.id,
+                    name: group.name,
+                },
+            }),
+    );
+
+    if file.public {
+        grants.push(Grant {
+            access: Access::Public,
+            path: GrantPath::Public,
+        });
+    }
+
+    grants
+}
+
+fn explain(file: &StoredFile, principal: Principal) -> AccessExplanation {
+    let grants = file_grants(file, principal);
+
+    let effective = [
+        Access::Owner,
+        Access::Admin,
+        Access::Write,
+        Access::Read,
+        Access::Delete,
+    ]
+    .into_iter()
+    .filter(|access| grants.iter().any(|grant| grant.access.satisfies(access)))
+    .collect();
+
+    AccessExplanation {
+        file_id: file.id.clone(),
+        principal,
+        effective,
+        grants,
+    }
+}
+
+// Anyone can explain their own access; explaining someone else's takes admin
+// access to the file.
+#[ic_cdk::query]
+fn explain_access(
+    file_id: String,
+    principal: Option<Principal>,
+) -> Result<AccessExplanation, String> {
+    let caller = msg_caller();
+    let principal = principal.unwrap_or(caller);
+
+    FILES.with_borrow(|files| {
+        let file = files.get(&file_id).ok_or("StoredFile not found.")?;
+
+        if principal != caller
+            && !has_file_permission(file, &[Access::Admin], None)
+            && !ic_cdk::api::is_controller(&caller)
+        {
+            return Err("You are not authorized to inspect access to this file.".to_string());
+        }
+
+        Ok(explain(file, principal))
+    })
+}
+
+// Every pair of `file_ids` and `principals`, for admin tooling. Unknown files
+// are skipped.
+#[ic_cdk::query]
+fn explain_access_bulk(
+    file_ids: Vec<String>,
+    principals: Vec<Principal>,
+) -> Result<Vec<AccessExplanation>, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can inspect access in bulk.".to_string());
+    }
+
+    if file_ids.len().saturating_mul(principals.len()) > MAX_BULK_EXPLANATIONS {
+        return Err(format!(
+            "At most {MAX_BULK_EXPLANATIONS} explanations per call."
+        ));
+    }
+
+    Ok(FILES.with_borrow(|files| {
+        file_ids
+            .iter()
+            .filter_map(|id| files.get(id))
+            .flat_map(|file| principals.iter().map(|principal| explain(file, *principal)))
+            .collect()
+    }))
+}
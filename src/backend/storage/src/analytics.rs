@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use utilities::now;
+
+use crate::quotas::{self, Usage};
+use crate::{Access, FILES, content_len, has_file_permission};
+
+// Storage analytics. Files count their downloads and when they were last read.
+// Only reads made in update calls are counted: `get_file`,
+// `get_file_with_token` and HTTP downloads, which `http_request` upgrades to
+// `http_request_update` when it serves the start of a file. State changes made
+// by queries are dropped, so `get_chunk` reads go uncounted, as do canisters
+// fetching the files they show with `get_files_content`.
+// Usage changes, as tracked for quotas, are summed per day to show how storage
+// grows.
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct FileStats {
+    pub downloads: u64,
+    pub last_accessed: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Serialize, Deserialize)]
+pub enum GrowthPeriod {
+    Day,
+    Month,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct OwnerStats {
+    pub owner: Principal,
+    pub usage: Usage,
+    pub downloads: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct MimeTypeStats {
+    pub mime_type: String,
+    pub usage: Usage,
+    pub downloads: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct GrowthBucket {
+    // `2026-10-19` for days, `2026-10` for months.
+    pub period: String,
+    pub bytes_added: u64,
+    pub bytes_removed: u64,
+    pub files_added: u64,
+    pub files_removed: u64,
+    // Usage at the end of the period.
+    pub usage: Usage,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct PopularFile {
+    pub file_id: String,
+    pub name: String,
+    pub owner: Principal,
+    pub stats: FileStats,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub usage: Usage,
+    // Largest first.
+    pub by_owner: Vec<OwnerStats>,
+    pub by_mime_type: Vec<MimeTypeStats>,
+    // Oldest first.
+    pub growth: Vec<GrowthBucket>,
+    pub most_downloaded: Vec<PopularFile>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Growth {
+    bytes_added: u64,
+    bytes_removed: u64,
+    files_added: u64,
+    files_removed: u64,
+}
+
+thread_local! {
+    static FILE_STATS: RefCell<HashMap<String, FileStats>> = RefCell::new(HashMap::new());
+    // Keyed by day.
+    static GROWTH: RefCell<BTreeMap<String, Growth>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn today() -> String {
+    now().split(' ').next().unwrap_or_default().to_string()
+}
+
+pub fn downloaded(file_id: &str) {
+    FILE_STATS.with_borrow_mut(|stats| {
+        let stats = stats.entry(file_id.to_string()).or_default();
+        stats.downloads += 1;
+        stats.last_accessed = Some(now());
+    });
+}
+
+pub fn forget(file_id: &str) {
+    FILE_STATS.with_borrow_mut(|stats| stats.remove(file_id));
+}
+
+// Called by `quotas` on every usage change.
+pub fn usage_changed(bytes: u64, files: u64, added: bool) {
+    GROWTH.with_borrow_mut(|growth| {
+        let growth = growth.entry(today()).or_default();
+
+        if added {
+            growth.bytes_added += bytes;
+            growth.files_added += files;
+        } else {
+            growth.bytes_removed += bytes;
+            growth.files_removed += files;
+        }
+    });
+}
+
+fn stats_of(file_id: &str) -> FileStats {
+    FILE_STATS.with_borrow(|stats| stats.get(file_id).cloned().unwrap_or_default())
+}
+
+fn growth(period: GrowthPeriod) -> Vec<GrowthBucket> {
+    let mut buckets: Vec<GrowthBucket> = Vec::new();
+    let mut usage = Usage::default();
+
+    GROWTH.with_borrow(|growth| {
+        for (day, day_growth) in growth {
+            let period = match period {
+                GrowthPeriod::Day => day.clone(),
+                GrowthPeriod::Month => day.chars().take(7).collect(),
+            };
+
+            if buckets.last().map(|bucket| &bucket.period) != Some(&period) {
+                buckets.push(GrowthBucket {
+                    period,
+                    ..Default::default()
+                });
+            }
+            let bucket = buckets.last_mut().expect("A bucket was pushed above.");
+
+            bucket.bytes_added += day_growth.bytes_added;
+            bucket.bytes_removed += day_growth.bytes_removed;
+            bucket.files_added += day_growth.files_added;
+            bucket.files_removed += day_growth.files_removed;
+
+            usage.bytes_used = (usage.bytes_used + day_growth.bytes_added)
+                .saturating_sub(day_growth.bytes_removed);
+            usage.file_count = (usage.file_count + day_growth.files_added)
+                .saturating_sub(day_growth.files_removed);
+            bucket.usage = usage;
+        }
+    });
+
+    buckets
+}
+
+// Download counters of a file, for those who can edit it.
+#[ic_cdk::query]
+fn get_file_stats(file_id: String) -> Result<FileStats, String> {
+    FILES.with_borrow(|files| {
+        let file = files.get(&file_id).ok_or("StoredFile not found.")?;
+
+        let mut access = Access::can_edit();
+        access.push(Access::Owner);
+
+        if !has_file_permission(file, &access, None) {
+            return Err("You are not authorized to view the stats of this file.".to_string());
+        }
+
+        Ok(stats_of(&file_id))
+    })
+}
+
+// Usage per owner and per mime type, growth over `period`s and the `top` most
+// downloaded files. Owner usage includes older versions, as quotas count it;
+// mime type usage covers current content only.
+#[ic_cdk::query]
+fn get_storage_stats(period: GrowthPeriod, top: u32) -> Result<StorageStats, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can read storage stats.".to_string());
+    }
+
+    let mut downloads_by_owner: HashMap<Principal, u64> = HashMap::new();
+    let mut by_mime_type: BTreeMap<String, MimeTypeStats> = BTreeMap::new();
+    let mut most_downloaded: Vec<PopularFile> = Vec::new();
+
+    FILES.with_borrow(|files| {
+        for file in files.values() {
+            let stats = stats_of(&file.id);
+
+            *downloads_by_owner.entry(file.owner).or_default() += stats.downloads;
+
+            let mime_type = by_mime_type
+                .entry(file.mime_type.clone())
+                .or_insert_with(|| MimeTypeStats {
+                    mime_type: file.mime_type.clone(),
+                    usage: Usage::default(),
+                    downloads: 0,
+                });
+            mime_type.usage.bytes_used += content_len(file);
+            mime_type.usage.file_count += 1;
+            mime_type.downloads += stats.downloads;
+
+            if stats.downloads > 0 {
+                most_downloaded.push(PopularFile {
+                    file_id: file.id.clone(),
+                    name: file.name.clone(),
+                    owner: file.owner,
+                    stats,
+                });
+            }
+        }
+    });
+
+    let mut by_owner: Vec<OwnerStats> = quotas::all_usage()
+        .into_iter()
+        .filter(|(_, usage)| usage.file_count > 0 || usage.bytes_used > 0)
+        .map(|(owner, usage)| OwnerStats {
+            owner,
+            usage,
+            downloads: downloads_by_owner.get(&owner).copied().unwrap_or(0),
+        })
+        .collect();
+    by_owner.sort_by(|a, b| {
+        b.usage
+            .bytes_used
+            .cmp(&a.usage.bytes_used)
+            .then(a.owner.cmp(&b.owner))
+    });
+
+    let mut by_mime_type: Vec<MimeTypeStats> = by_mime_type.into_values().collect();
+    by_mime_type.sort_by(|a, b| {
+        b.usage
+            .bytes_used
+            .cmp(&a.usage.bytes_used)
+            .then(a.mime_type.cmp(&b.mime_type))
+    });
+
+    most_downloaded.sort_by(|a, b| {
+        b.stats
+            .downloads
+            .cmp(&a.stats.downloads)
+            .then(a.file_id.cmp(&b.file_id))
+    });
+    most_downloaded.truncate(top as usize);
+
+    Ok(StorageStats {
+        usage: by_owner
+            .iter()
+            .fold(Usage::default(), |total, owner| Usage {
+                bytes_used: total.bytes_used + owner.usage.bytes_used,
+                file_count: total.file_count + owner.usage.file_count,
+            }),
+        by_owner,
+        by_mime_type,
+        growth: growth(period),
+        most_downloaded,
+    })
+}
@@ -0,0 +1,235 @@
+use std::collections::{HashMap, HashSet};
+
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+
+use crate::{
+    Access, FILES, StoredFile, check_delete, check_publish, check_rename, check_share, folders,
+    record_deletion, rename_file, set_public, share_file, tags, trash_file,
+};
+
+// Batch variants of the file endpoints. Each takes a list of file ids and
+// returns one result per id, in order. Without `atomic`, items are applied one
+// after the other and a failing item doesn't stop the rest. With `atomic`,
+// every item is checked first and nothing is applied unless all of them pass.
+// Atomic renames and moves also refuse to give two files of the batch the same
+// name in a folder.
+
+const MAX_BATCH: usize = 500;
+
+const NOT_APPLIED: &str = "Not applied, another item failed.";
+
+pub type ItemResult = (String, Result<(), String>);
+
+fn run<T>(
+    items: Vec<(String, T)>,
+    atomic: bool,
+    mut check: impl FnMut(&HashMap<String, StoredFile>, &str, &T) -> Result<(), String>,
+    mut apply: impl FnMut(&mut HashMap<String, StoredFile>, &str, T) -> Result<(), String>,
+) -> Result<Vec<ItemResult>, String> {
+    if items.len() > MAX_BATCH {
+        return Err(format!("Batches are limited to {MAX_BATCH} files."));
+    }
+
+    FILES.with_borrow_mut(|files| {
+        let mut seen = HashSet::new();
+        let items: Vec<(String, Result<T, String>)> = items
+            .into_iter()
+            .map(|(file_id, item)| {
+                if seen.insert(file_id.clone()) {
+                    (file_id, Ok(item))
+                } else {
+                    (
+                        file_id,
+                        Err("This file is listed more than once.".to_string()),
+                    )
+                }
+            })
+            .collect();
+
+        if !atomic {
+            return Ok(items
+                .into_iter()
+                .map(|(file_id, item)| {
+                    let result = item.and_then(|item| apply(files, &file_id, item));
+                    (file_id, result)
+                })
+                .collect());
+        }
+
+        let checked: Vec<(String, Result<T, String>)> = items
+            .into_iter()
+            .map(|(file_id, item)| {
+                let item = item.and_then(|item| check(files, &file_id, &item).map(|_| item));
+                (file_id, item)
+            })
+            .collect();
+
+        if checked.iter().any(|(_, item)| item.is_err()) {
+            return Ok(checked
+                .into_iter()
+                .map(|(file_id, item)| {
+                    let result = match item {
+                        Ok(_) => Err(NOT_APPLIED.to_string()),
+                        Err(err) => Err(err),
+                    };
+                    (file_id, result)
+                })
+                .collect());
+        }
+
+        Ok(checked
+            .into_iter()
+            .map(|(file_id, item)| {
+                let result = apply(
+                    files,
+                    &file_id,
+                    item.expect("Every item was checked above."),
+                );
+                (file_id, result)
+            })
+            .collect())
+    })
+}
+
+fn with_unit(file_ids: Vec<String>) -> Vec<(String, ())> {
+    file_ids.into_iter().map(|file_id| (file_id, ())).collect()
+}
+
+// Claims `name` in `parent_id` for a file of an atomic batch. Only names in
+// folders are unique, see `folders::is_name_taken`.
+fn claim(
+    claimed: &mut HashSet<(String, String)>,
+    parent_id: Option<&str>,
+    name: &str,
+) -> Result<(), String> {
+    let Some(parent_id) = parent_id else {
+        return Ok(());
+    };
+
+    if !claimed.insert((parent_id.to_string(), name.to_string())) {
+        return Err(format!(
+            "\"{name}\" is given to another file of this batch."
+        ));
+    }
+
+    Ok(())
+}
+
+// Takes `(file_id, new_file_name)` pairs.
+#[ic_cdk::update]
+fn batch_rename_files(
+    renames: Vec<(String, String)>,
+    atomic: bool,
+) -> Result<Vec<ItemResult>, String> {
+    let mut claimed = HashSet::new();
+
+    run(
+        renames,
+        atomic,
+        |files, file_id, new_file_name| {
+            check_rename(files, file_id, new_file_name)?;
+
+            let file = files.get(file_id).expect("The file was checked above.");
+            claim(&mut claimed, file.parent_id.as_deref(), new_file_name)
+        },
+        |files, file_id, new_file_name| {
+            rename_file(files, file_id, new_file_name).map_err(str::to_string)
+        },
+    )
+}
+
+#[ic_cdk::update]
+fn batch_move_files(
+    file_ids: Vec<String>,
+    new_parent_id: Option<String>,
+    atomic: bool,
+) -> Result<Vec<ItemResult>, String> {
+    let principal: Principal = msg_caller();
+    let mut claimed = HashSet::new();
+
+    run(
+        with_unit(file_ids),
+        atomic,
+        |files, file_id, _| {
+            folders::check_move(files, file_id, new_parent_id.as_deref(), principal)?;
+
+            let file = files.get(file_id).expect("The file was checked above.");
+            claim(&mut claimed, new_parent_id.as_deref(), &file.name)
+        },
+        |files, file_id, _| folders::move_to(files, file_id, new_parent_id.clone()).map(|_| ()),
+    )
+}
+
+#[ic_cdk::update]
+fn batch_set_public(
+    file_ids: Vec<String>,
+    public: bool,
+    atomic: bool,
+) -> Result<Vec<ItemResult>, String> {
+    run(
+        with_unit(file_ids),
+        atomic,
+        |files, file_id, _| {
+            let file = files.get(file_id).ok_or("StoredFile not found.")?;
+            Ok(check_publish(file, public)?)
+        },
+        |files, file_id, _| set_public(files, file_id, public).map_err(str::to_string),
+    )
+}
+
+// Applies the same `accesses` to every file, as `edit_allowed_users` does.
+#[ic_cdk::update]
+fn batch_share_files(
+    file_ids: Vec<String>,
+    accesses: Vec<(Principal, Access)>,
+    atomic: bool,
+) -> Result<Vec<ItemResult>, String> {
+    run(
+        with_unit(file_ids),
+        atomic,
+        |files, file_id, _| {
+            let file = files.get(file_id).ok_or("StoredFile not found.")?;
+            check_share(file)
+        },
+        |files, file_id, _| share_file(files, file_id, &accesses).map(|_| ()),
+    )
+}
+
+// Moves files to the trash, like `delete_files`.
+#[ic_cdk::update]
+fn batch_delete_files(file_ids: Vec<String>, atomic: bool) -> Result<Vec<ItemResult>, String> {
+    let mut deleted_files = Vec::new();
+
+    let results = run(
+        with_unit(file_ids),
+        atomic,
+        |files, file_id, _| check_delete(files, file_id),
+        |files, file_id, _| {
+            deleted_files.push(trash_file(files, file_id)?);
+            Ok(())
+        },
+    )?;
+
+    record_deletion("batch_delete_files", &deleted_files);
+
+    Ok(results)
+}
+
+#[ic_cdk::update]
+fn batch_tag_files(
+    file_ids: Vec<String>,
+    add: Vec<String>,
+    remove: Vec<String>,
+    atomic: bool,
+) -> Result<Vec<ItemResult>, String> {
+    let add = tags::normalize_tags(&add)?;
+    let remove = tags::tags_to_remove(&remove);
+
+    run(
+        with_unit(file_ids),
+        atomic,
+        |files, file_id, _| tags::check_retag(files, file_id, &add, &remove),
+        |files, file_id, _| tags::retag(files, file_id, &add, &remove).map(|_| ()),
+    )
+}
@@ -0,0 +1,131 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use utilities::audit;
+
+// Content-addressed blob store. File contents are kept once per SHA-256 and
+// shared by every `StoredFile` whose `content_hash` points at them. Blobs whose
+// reference count dropped to zero are removed by the garbage collector.
+
+const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+struct Blob {
+    data: Vec<u8>,
+    ref_count: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct GarbageCollection {
+    pub removed_blobs: usize,
+    pub freed_bytes: usize,
+}
+
+thread_local! {
+    static BLOBS: RefCell<HashMap<String, Blob>> = RefCell::new(HashMap::new());
+    // The bytes of every blob, referenced or not yet collected.
+    static STORED_BYTES: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// Adds a reference to `data`, storing it only if no blob has that hash yet.
+pub fn store(content_hash: &str, data: Vec<u8>) {
+    BLOBS.with_borrow_mut(|blobs| {
+        blobs
+            .entry(content_hash.to_string())
+            .or_insert_with(|| {
+                STORED_BYTES.set(STORED_BYTES.get() + data.len() as u64);
+                Blob { data, ref_count: 0 }
+            })
+            .ref_count += 1;
+    });
+}
+
+// Adds a reference to a blob that is already stored, `false` if there is none.
+pub fn retain(content_hash: &str) -> bool {
+    BLOBS.with_borrow_mut(|blobs| {
+        blobs
+            .get_mut(content_hash)
+            .map(|blob| blob.ref_count += 1)
+            .is_some()
+    })
+}
+
+pub fn release(content_hash: &str) {
+    BLOBS.with_borrow_mut(|blobs| {
+        if let Some(blob) = blobs.get_mut(content_hash) {
+            blob.ref_count = blob.ref_count.saturating_sub(1);
+        }
+    });
+}
+
+pub fn read<R>(content_hash: &str, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+    BLOBS.with_borrow(|blobs| blobs.get(content_hash).map(|blob| f(&blob.data)))
+}
+
+pub fn content(content_hash: &str) -> Vec<u8> {
+    read(content_hash, |data| data.to_vec()).unwrap_or_default()
+}
+
+pub fn stored_bytes() -> u64 {
+    STORED_BYTES.get()
+}
+
+pub fn start_gc_timer() {
+    ic_cdk_timers::set_timer_interval(GC_INTERVAL, || {
+        remove_unreferenced();
+    });
+}
+
+fn remove_unreferenced() -> GarbageCollection {
+    BLOBS.with_borrow_mut(|blobs| {
+        let mut collected = GarbageCollection {
+            removed_blobs: 0,
+            freed_bytes: 0,
+        };
+
+        blobs.retain(|_, blob| {
+            if blob.ref_count > 0 {
+                return true;
+            }
+
+            collected.removed_blobs += 1;
+            collected.freed_bytes += blob.data.len();
+            false
+        });
+
+        STORED_BYTES.set(STORED_BYTES.get() - collected.freed_bytes as u64);
+        collected
+    })
+}
+
+#[ic_cdk::update]
+fn collect_garbage() -> Result<GarbageCollection, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can collect garbage.".to_string());
+    }
+
+    let collected = remove_unreferenced();
+
+    if collected.removed_blobs > 0 {
+        audit::record(
+            "collect_garbage",
+            vec![],
+            Some(format!(
+                "{} unreferenced blobs, {} bytes",
+                collected.removed_blobs, collected.freed_bytes
+            )),
+            None,
+        );
+    }
+
+    Ok(collected)
+}
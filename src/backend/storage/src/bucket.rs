@@ -0,0 +1,60 @@
+use std::cell::RefCell;
+
+use ic_cdk::call::Call;
+use ic_principal::Principal;
+
+use utilities::{BucketReport, bucket_file_id, generate_uuid};
+
+use crate::blobs;
+
+// Storage as a bucket of a `storage_index` canister, which installs buckets
+// with its own id as the init argument. A bucket reports the files it stores
+// and removes to the index, fire and forget, so the index can route uploads by
+// capacity and tell clients where a file lives. Storage deployed on its own has
+// no index and reports nothing.
+
+thread_local! {
+    static INDEX_CANISTER_ID: RefCell<Option<Principal>> = const { RefCell::new(None) };
+}
+
+pub fn configure(index_canister_id: Option<Principal>) {
+    INDEX_CANISTER_ID.with_borrow_mut(|id| *id = index_canister_id);
+}
+
+fn report(stored: Vec<String>, removed: Vec<String>) {
+    let Some(index_canister_id) = INDEX_CANISTER_ID.with_borrow(|id| *id) else {
+        return;
+    };
+
+    let report = BucketReport {
+        stored,
+        removed,
+        bytes_used: blobs::stored_bytes(),
+    };
+
+    if let Err(err) = Call::unbounded_wait(index_canister_id, "bucket_report")
+        .with_arg(&report)
+        .oneway()
+    {
+        ic_cdk::println!("Failed to report to the storage index: {err}");
+    }
+}
+
+// Id for a new file, namespaced by the bucket when there's an index.
+pub fn new_file_id() -> String {
+    let id = generate_uuid();
+
+    if INDEX_CANISTER_ID.with_borrow(|index| index.is_none()) {
+        return id;
+    }
+
+    bucket_file_id(ic_cdk::api::canister_self(), &id)
+}
+
+pub fn file_stored(file_id: &str) {
+    report(vec![file_id.to_string()], Vec::new());
+}
+
+pub fn file_removed(file_id: &str) {
+    report(Vec::new(), vec![file_id.to_string()]);
+}
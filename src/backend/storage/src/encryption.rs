@@ -0,0 +1,314 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use utilities::audit;
+
+use crate::policy::{self, UploadContext};
+use crate::versions::{self, FileVersion};
+use crate::{Access, FILES, StoredFile, has_file_permission};
+
+// End-to-end encrypted files. Clients encrypt content with a file key of their
+// own and upload the ciphertext, then hand storage the file key wrapped for
+// each key holder: the owner and every principal in `allowed_users`. Storage
+// never sees the file key, it only keeps the wrapped keys in step with the
+// ACL. Revoking a holder drops their wrapped key and marks the file for
+// rotation, which replaces the content with ciphertext under a new key.
+// Older versions stay encrypted with the key they were made with.
+//
+// Grants from groups, folders and share links reach the ciphertext only, and
+// encrypted files can't be published or have variants.
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub key_version: u32,
+    pub wrapped_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct FileKeyStatus {
+    pub key_version: u32,
+    pub holders: Vec<Principal>,
+    // Principals with access but no wrapped key yet.
+    pub missing: Vec<Principal>,
+    pub rotation_pending: bool,
+}
+
+struct FileKeys {
+    key_version: u32,
+    wrapped_keys: BTreeMap<Principal, Vec<u8>>,
+    rotation_pending: bool,
+}
+
+thread_local! {
+    static FILE_KEYS: RefCell<HashMap<String, FileKeys>> = RefCell::new(HashMap::new());
+}
+
+pub fn is_encrypted(file_id: &str) -> bool {
+    FILE_KEYS.with_borrow(|keys| keys.contains_key(file_id))
+}
+
+// Who should hold a wrapped key for `file`.
+fn key_holders(file: &StoredFile) -> BTreeSet<Principal> {
+    file.allowed_users
+        .iter()
+        .filter(|(_, access)| *access != Access::Removed)
+        .map(|(user, _)| *user)
+        .chain(std::iter::once(file.owner))
+        .collect()
+}
+
+// A full set of wrapped keys: one for every holder and for no one else.
+fn holders_keys(
+    file: &StoredFile,
+    wrapped_keys: Vec<(Principal, Vec<u8>)>,
+) -> Result<BTreeMap<Principal, Vec<u8>>, String> {
+    let holders = key_holders(file);
+    let wrapped_keys: BTreeMap<Principal, Vec<u8>> = wrapped_keys.into_iter().collect();
+
+    if wrapped_keys.values().any(|key| key.is_empty()) {
+        return Err("Wrapped keys can't be empty.".to_string());
+    }
+    if let Some(stranger) = wrapped_keys.keys().find(|user| !holders.contains(user)) {
+        return Err(format!("{stranger} has no access to this file."));
+    }
+    if let Some(missing) = holders.iter().find(|user| !wrapped_keys.contains_key(user)) {
+        return Err(format!("A wrapped key for {missing} is missing."));
+    }
+
+    Ok(wrapped_keys)
+}
+
+fn require_admin(file: &StoredFile) -> Result<(), String> {
+    if !has_file_permission(file, &[Access::Admin], None) {
+        return Err("You are not authorized to manage the keys of this file.".to_string());
+    }
+
+    Ok(())
+}
+
+// Drops the wrapped keys of principals who lost access to `file`.
+pub fn access_changed(file: &StoredFile) {
+    let holders = key_holders(file);
+
+    let revoked = FILE_KEYS.with_borrow_mut(|keys| {
+        let Some(file_keys) = keys.get_mut(&file.id) else {
+            return Vec::new();
+        };
+
+        let revoked: Vec<Principal> = file_keys
+            .wrapped_keys
+            .keys()
+            .filter(|user| !holders.contains(user))
+            .copied()
+            .collect();
+        for user in &revoked {
+            file_keys.wrapped_keys.remove(user);
+        }
+        if !revoked.is_empty() {
+            file_keys.rotation_pending = true;
+        }
+
+        revoked
+    });
+
+    if !revoked.is_empty() {
+        audit::record(
+            "revoke_file_keys",
+            vec![file.id.clone()],
+            Some(
+                revoked
+                    .iter()
+                    .map(|user| user.to_text())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            None,
+        );
+    }
+}
+
+pub fn forget(file_id: &str) {
+    FILE_KEYS.with_borrow_mut(|keys| keys.remove(file_id));
+}
+
+// Marks the file's ciphertext content as encrypted under key version 1. Only
+// files uploaded with the `Encrypted` context hold ciphertext.
+#[ic_cdk::update]
+fn encrypt_file(file_id: String, wrapped_keys: Vec<(Principal, Vec<u8>)>) -> Result<u32, String> {
+    let file = FILES
+        .with_borrow(|files| files.get(&file_id).cloned())
+        .ok_or("StoredFile not found.")?;
+
+    if file.owner != msg_caller() {
+        return Err("Only the owner can encrypt this file.".to_string());
+    }
+    if file.public {
+        return Err("Public files can't be encrypted.".to_string());
+    }
+    if is_encrypted(&file_id) {
+        return Err("This file is already encrypted.".to_string());
+    }
+    if policy::context_of(&file_id) != Some(UploadContext::Encrypted) {
+        return Err("Only files uploaded as Encrypted can be encrypted.".to_string());
+    }
+
+    let wrapped_keys = holders_keys(&file, wrapped_keys)?;
+    let holders = wrapped_keys.len();
+
+    FILE_KEYS.with_borrow_mut(|keys| {
+        keys.insert(
+            file_id.clone(),
+            FileKeys {
+                key_version: 1,
+                wrapped_keys,
+                rotation_pending: false,
+            },
+        )
+    });
+    audit::record(
+        "encrypt_file",
+        vec![file_id],
+        None,
+        Some(format!("key version 1 for {holders} holders")),
+    );
+
+    Ok(1)
+}
+
+// The caller's wrapped key for the current key version.
+#[ic_cdk::query]
+fn get_file_key(file_id: String) -> Result<WrappedKey, String> {
+    let caller = msg_caller();
+
+    FILE_KEYS.with_borrow(|keys| {
+        let file_keys = keys.get(&file_id).ok_or("This file is not encrypted.")?;
+        let wrapped_key = file_keys
+            .wrapped_keys
+            .get(&caller)
+            .ok_or("You have no key for this file.")?;
+
+        Ok(WrappedKey {
+            key_version: file_keys.key_version,
+            wrapped_key: wrapped_key.clone(),
+        })
+    })
+}
+
+#[ic_cdk::query]
+fn get_file_key_status(file_id: String) -> Result<FileKeyStatus, String> {
+    let file = FILES
+        .with_borrow(|files| files.get(&file_id).cloned())
+        .ok_or("StoredFile not found.")?;
+    require_admin(&file)?;
+
+    FILE_KEYS.with_borrow(|keys| {
+        let file_keys = keys.get(&file_id).ok_or("This file is not encrypted.")?;
+
+        Ok(FileKeyStatus {
+            key_version: file_keys.key_version,
+            holders: file_keys.wrapped_keys.keys().copied().collect(),
+            missing: key_holders(&file)
+                .into_iter()
+                .filter(|user| !file_keys.wrapped_keys.contains_key(user))
+                .collect(),
+            rotation_pending: file_keys.rotation_pending,
+        })
+    })
+}
+
+// Gives a principal who was granted access their wrapped key.
+#[ic_cdk::update]
+fn share_file_key(
+    file_id: String,
+    user: Principal,
+    key_version: u32,
+    wrapped_key: Vec<u8>,
+) -> Result<(), String> {
+    let file = FILES
+        .with_borrow(|files| files.get(&file_id).cloned())
+        .ok_or("StoredFile not found.")?;
+    require_admin(&file)?;
+
+    if !key_holders(&file).contains(&user) {
+        return Err(format!("{user} has no access to this file."));
+    }
+    if wrapped_key.is_empty() {
+        return Err("Wrapped keys can't be empty.".to_string());
+    }
+
+    FILE_KEYS.with_borrow_mut(|keys| {
+        let file_keys = keys
+            .get_mut(&file_id)
+            .ok_or("This file is not encrypted.")?;
+
+        if key_version != file_keys.key_version {
+            return Err(format!(
+                "The current key version is {}.",
+                file_keys.key_version
+            ));
+        }
+
+        file_keys.wrapped_keys.insert(user, wrapped_key);
+        Ok(())
+    })?;
+
+    audit::record(
+        "share_file_key",
+        vec![file_id],
+        None,
+        Some(format!("{user} at key version {key_version}")),
+    );
+
+    Ok(())
+}
+
+// Replaces the content with `data`, encrypted under a new file key, and the
+// wrapped keys with those of the new key.
+#[ic_cdk::update]
+fn rotate_file_key(
+    file_id: String,
+    data: Vec<u8>,
+    wrapped_keys: Vec<(Principal, Vec<u8>)>,
+) -> Result<FileVersion, String> {
+    let file = FILES
+        .with_borrow(|files| files.get(&file_id).cloned())
+        .ok_or("StoredFile not found.")?;
+    require_admin(&file)?;
+
+    if !is_encrypted(&file_id) {
+        return Err("This file is not encrypted.".to_string());
+    }
+
+    let wrapped_keys = holders_keys(&file, wrapped_keys)?;
+
+    let version = FILES.with_borrow_mut(|files| {
+        let file = files.get_mut(&file_id).ok_or("StoredFile not found.")?;
+        versions::add_version(file, data, "Key rotation".to_string())
+    })?;
+
+    let (before, after) = FILE_KEYS.with_borrow_mut(|keys| {
+        let file_keys = keys
+            .get_mut(&file_id)
+            .expect("The keys were checked above.");
+        let before = file_keys.key_version;
+
+        file_keys.key_version += 1;
+        file_keys.wrapped_keys = wrapped_keys;
+        file_keys.rotation_pending = false;
+
+        (before, file_keys.key_version)
+    });
+    audit::record(
+        "rotate_file_key",
+        vec![file_id],
+        Some(format!("key version {before}")),
+        Some(format!("key version {after}")),
+    );
+
+    Ok(version)
+}
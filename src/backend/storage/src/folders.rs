@@ -0,0 +1,648 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use paginator::{HasFields, Paginator, PaginatorResponse};
+use serde::{Deserialize, Serialize};
+
+use utilities::{audit, generate_uuid, now};
+
+use crate::{
+    Access, FILES, FileMetadata, StoredFile, discard_file, has_file_permission, held_access,
+    references, satisfies_any, search, trash,
+};
+
+// Folders. Files and folders point at their folder through `parent_id`, those
+// without one sit in their owner's root. A folder's owner, `allowed_users` and
+// `public` flag apply to everything below it, so sharing a folder shares its
+// whole subtree. Names are unique among the entries of a folder, which lets
+// `resolve_path` turn `/courses/rust/intro.mp4` into a folder or file.
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Folder {
+    pub id: String,
+    pub name: String,
+    pub parent_id: Option<String>,
+    pub owner: Principal,
+    pub allowed_users: Vec<(Principal, Access)>,
+    pub public: bool,
+    pub created_at: String,
+}
+
+impl HasFields for Folder {
+    fn get_field(&self, field_name: &str) -> String {
+        match field_name {
+            "id" => self.id.clone(),
+            "name" => self.name.clone(),
+            "parent_id" => self.parent_id.clone().unwrap_or_default(),
+            "owner" => self.owner.to_text(),
+            "public" => self.public.to_string(),
+            "created_at" => self.created_at.clone(),
+            _ => "".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct CreateFolderInput {
+    pub name: String,
+    pub parent_id: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub enum FolderEntry {
+    Folder(Folder),
+    File(FileMetadata),
+}
+
+impl HasFields for FolderEntry {
+    fn get_field(&self, field_name: &str) -> String {
+        match (self, field_name) {
+            (FolderEntry::Folder(_), "kind") => "folder".to_string(),
+            (FolderEntry::File(_), "kind") => "file".to_string(),
+            (FolderEntry::Folder(folder), _) => folder.get_field(field_name),
+            (FolderEntry::File(file), _) => file.get_field(field_name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct FolderDeletion {
+    pub deleted_folders: usize,
+    pub deleted_files: usize,
+}
+
+thread_local! {
+    static FOLDERS: RefCell<HashMap<String, Folder>> = RefCell::new(HashMap::new());
+}
+
+fn grants(folder: &Folder, operations: &[Access], principal: Principal) -> bool {
+    satisfies_any(
+        &held_access(
+            folder.owner,
+            folder.public,
+            &folder.allowed_users,
+            principal,
+        ),
+        operations,
+    )
+}
+
+// Whether `folder_id` or one of its ancestors grants `principal` any of
+// `operations`. Owning a folder grants everything below it.
+pub fn has_permission(
+    folder_id: Option<&str>,
+    operations: &[Access],
+    principal: Principal,
+) -> bool {
+    FOLDERS.with_borrow(|folders| {
+        let mut current = folder_id;
+
+        while let Some(folder) = current.and_then(|id| folders.get(id)) {
+            if grants(folder, operations, principal) {
+                return true;
+            }
+            current = folder.parent_id.as_deref();
+        }
+
+        false
+    })
+}
+
+// What `folder_id` and its ancestors give `principal`, each with the folder
+// it comes from.
+pub fn held_along(folder_id: Option<&str>, principal: Principal) -> Vec<(Folder, Access)> {
+    FOLDERS.with_borrow(|folders| {
+        let mut held = Vec::new();
+        let mut current = folder_id;
+
+        while let Some(folder) = current.and_then(|id| folders.get(id)) {
+            held.extend(
+                held_access(
+                    folder.owner,
+                    folder.public,
+                    &folder.allowed_users,
+                    principal,
+                )
+                .into_iter()
+                .map(|access| (folder.clone(), access)),
+            );
+            current = folder.parent_id.as_deref();
+        }
+
+        held
+    })
+}
+
+// Folders `principal` holds something on, directly or through an ancestor.
+pub fn reachable(principal: Principal) -> HashSet<String> {
+    FOLDERS.with_borrow(|folders| {
+        folders
+            .values()
+            .filter(|folder| {
+                !held_access(
+                    folder.owner,
+                    folder.public,
+                    &folder.allowed_users,
+                    principal,
+                )
+                .is_empty()
+            })
+            .flat_map(|folder| subtree(folders, &folder.id))
+            .collect()
+    })
+}
+
+pub fn exists(folder_id: &str) -> bool {
+    FOLDERS.with_borrow(|folders| folders.contains_key(folder_id))
+}
+
+// Puts back a folder taken out by `delete_folder`.
+pub fn insert(folder: Folder) {
+    FOLDERS.with_borrow_mut(|folders| folders.insert(folder.id.clone(), folder));
+}
+
+// Whether `name` is used by a folder or file in `parent_id`. Root entries are
+// only compared with the ones of the same owner. Files uploaded to the root
+// skip this check and keep allowing duplicate names among themselves.
+pub fn is_name_taken(
+    files: &HashMap<String, StoredFile>,
+    parent_id: Option<&str>,
+    owner: Principal,
+    name: &str,
+    except_id: Option<&str>,
+) -> bool {
+    let is_sibling = |entry_parent: Option<&str>, entry_owner: Principal, entry_id: &str| {
+        entry_parent == parent_id
+            && (parent_id.is_some() || entry_owner == owner)
+            && except_id != Some(entry_id)
+    };
+
+    FOLDERS.with_borrow(|folders| {
+        folders.values().any(|folder| {
+            is_sibling(folder.parent_id.as_deref(), folder.owner, &folder.id) && folder.name == name
+        })
+    }) || files.values().any(|file| {
+        is_sibling(file.parent_id.as_deref(), file.owner, &file.id) && file.name == name
+    })
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err("Names must be non-empty and cannot contain '/'.".to_string());
+    }
+
+    Ok(())
+}
+
+// Ids of `folder_id` and every folder below it.
+fn subtree(folders: &HashMap<String, Folder>, folder_id: &str) -> HashSet<String> {
+    let mut ids = HashSet::from([folder_id.to_string()]);
+    let mut pending = vec![folder_id.to_string()];
+
+    while let Some(id) = pending.pop() {
+        for folder in folders.values() {
+            if folder.parent_id.as_deref() == Some(id.as_str()) && ids.insert(folder.id.clone()) {
+                pending.push(folder.id.clone());
+            }
+        }
+    }
+
+    ids
+}
+
+// The ids of the files directly in `folder_ids`.
+fn files_in(folder_ids: &HashSet<String>) -> Vec<String> {
+    FILES.with_borrow(|files| {
+        files
+            .values()
+            .filter(|file| {
+                file.parent_id
+                    .as_ref()
+                    .is_some_and(|id| folder_ids.contains(id))
+            })
+            .map(|file| file.id.clone())
+            .collect()
+    })
+}
+
+fn get_folder(folder_id: &str) -> Result<Folder, String> {
+    FOLDERS
+        .with_borrow(|folders| folders.get(folder_id).cloned())
+        .ok_or("Folder not found.".to_string())
+}
+
+fn readable() -> Vec<Access> {
+    let mut access = Access::all();
+    access.push(Access::Owner);
+    access
+}
+
+// Entries are only created in the caller's root or in folders they can edit.
+fn check_destination(parent_id: Option<&str>, principal: Principal) -> Result<(), String> {
+    match parent_id {
+        None => Ok(()),
+        Some(parent_id) if !exists(parent_id) => Err("Folder not found.".to_string()),
+        Some(parent_id) if !has_permission(Some(parent_id), &Access::can_edit(), principal) => {
+            Err("You are not authorized to edit this folder.".to_string())
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+#[ic_cdk::update]
+fn create_folder(input: CreateFolderInput) -> Result<Folder, String> {
+    let principal = msg_caller();
+
+    validate_name(&input.name)?;
+    check_destination(input.parent_id.as_deref(), principal)?;
+
+    if FILES.with_borrow(|files| {
+        is_name_taken(
+            files,
+            input.parent_id.as_deref(),
+            principal,
+            &input.name,
+            None,
+        )
+    }) {
+        return Err(format!("\"{}\" already exists in this folder.", input.name));
+    }
+
+    let id = generate_uuid();
+
+    let folder = Folder {
+        id: id.clone(),
+        name: input.name,
+        parent_id: input.parent_id,
+        owner: principal,
+        allowed_users: Vec::new(),
+        public: false,
+        created_at: now(),
+    };
+
+    FOLDERS.with_borrow_mut(|folders| folders.insert(id, folder.clone()));
+
+    Ok(folder)
+}
+
+#[ic_cdk::update]
+fn rename_folder(folder_id: String, new_name: String) -> Result<Folder, String> {
+    let principal = msg_caller();
+    let folder = get_folder(&folder_id)?;
+
+    if !has_permission(Some(&folder_id), &Access::can_edit(), principal) {
+        return Err("You are not authorized to rename this folder.".to_string());
+    }
+
+    validate_name(&new_name)?;
+
+    if FILES.with_borrow(|files| {
+        is_name_taken(
+            files,
+            folder.parent_id.as_deref(),
+            folder.owner,
+            &new_name,
+            Some(&folder_id),
+        )
+    }) {
+        return Err(format!("\"{new_name}\" already exists in this folder."));
+    }
+
+    FOLDERS.with_borrow_mut(|folders| {
+        let folder = folders
+            .get_mut(&folder_id)
+            .expect("The folder was read above.");
+        folder.name = new_name;
+        Ok(folder.clone())
+    })
+}
+
+// `None` moves the folder to its owner's root.
+#[ic_cdk::update]
+fn move_folder(folder_id: String, new_parent_id: Option<String>) -> Result<Folder, String> {
+    let principal = msg_caller();
+    let folder = get_folder(&folder_id)?;
+
+    if !has_permission(Some(&folder_id), &[Access::Admin], principal) {
+        return Err("You are not authorized to move this folder.".to_string());
+    }
+
+    check_destination(new_parent_id.as_deref(), principal)?;
+
+    if let Some(parent_id) = &new_parent_id
+        && FOLDERS.with_borrow(|folders| subtree(folders, &folder_id).contains(parent_id))
+    {
+        return Err("A folder cannot be moved into itself.".to_string());
+    }
+
+    if FILES.with_borrow(|files| {
+        is_name_taken(
+            files,
+            new_parent_id.as_deref(),
+            folder.owner,
+            &folder.name,
+            Some(&folder_id),
+        )
+    }) {
+        return Err(format!(
+            "\"{}\" already exists in the destination folder.",
+            folder.name
+        ));
+    }
+
+    FOLDERS.with_borrow_mut(|folders| {
+        let folder = folders
+            .get_mut(&folder_id)
+            .expect("The folder was read above.");
+        folder.parent_id = new_parent_id;
+        Ok(folder.clone())
+    })
+}
+
+pub fn check_move(
+    files: &HashMap<String, StoredFile>,
+    file_id: &str,
+    new_parent_id: Option<&str>,
+    principal: Principal,
+) -> Result<(), String> {
+    check_destination(new_parent_id, principal)?;
+
+    let file = files.get(file_id).ok_or("StoredFile not found.")?;
+
+    let mut access = Access::can_edit();
+    access.push(Access::Owner);
+
+    if !has_file_permission(file, &access, None) {
+        return Err("You are not authorized to move this file.".to_string());
+    }
+
+    if new_parent_id.is_some()
+        && is_name_taken(files, new_parent_id, file.owner, &file.name, Some(file_id))
+    {
+        return Err(format!(
+            "\"{}\" already exists in the destination folder.",
+            file.name
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn move_to(
+    files: &mut HashMap<String, StoredFile>,
+    file_id: &str,
+    new_parent_id: Option<String>,
+) -> Result<FileMetadata, String> {
+    check_move(files, file_id, new_parent_id.as_deref(), msg_caller())?;
+
+    let file = files.get_mut(file_id).expect("The file was checked above.");
+    file.parent_id = new_parent_id;
+    search::index(file);
+
+    Ok(FileMetadata::from(&*file))
+}
+
+#[ic_cdk::update]
+fn move_file(file_id: String, new_parent_id: Option<String>) -> Result<FileMetadata, String> {
+    FILES.with_borrow_mut(|files| move_to(files, &file_id, new_parent_id))
+}
+
+// Moves the folder to the trash with every folder and file below it, whoever
+// uploaded them. Only the owner of the folder or of one of its ancestors can
+// do this, and they can restore it from the trash.
+#[ic_cdk::update]
+fn delete_folder(folder_id: String) -> Result<FolderDeletion, String> {
+    let principal = msg_caller();
+    let folder = get_folder(&folder_id)?;
+
+    if !has_permission(Some(&folder_id), &[Access::Owner], principal) {
+        return Err("You are not authorized to delete this folder.".to_string());
+    }
+
+    let folder_ids = FOLDERS.with_borrow(|folders| subtree(folders, &folder_id));
+    let file_ids = files_in(&folder_ids);
+
+    if let Some(file_id) = file_ids
+        .iter()
+        .find(|id| references::restricted_by(id).is_some())
+    {
+        return Err(format!(
+            "StoredFile {file_id} in this folder is still referenced by another canister."
+        ));
+    }
+
+    // The deleted folder goes first, see `trash::TrashedFolder`.
+    let removed_folders: Vec<Folder> = FOLDERS.with_borrow_mut(|folders| {
+        std::iter::once(&folder_id)
+            .chain(folder_ids.iter().filter(|id| **id != folder_id))
+            .filter_map(|id| folders.remove(id))
+            .collect()
+    });
+
+    let removed_files: Vec<StoredFile> = FILES.with_borrow_mut(|files| {
+        file_ids
+            .iter()
+            .filter_map(|id| discard_file(files, id))
+            .collect()
+    });
+
+    trash::trash_folder(
+        removed_folders.clone(),
+        removed_files.iter().map(|f| f.id.clone()).collect(),
+    );
+
+    let mut target_ids: Vec<String> = removed_folders.iter().map(|f| f.id.clone()).collect();
+    target_ids.extend(removed_files.iter().map(|f| f.id.clone()));
+
+    audit::record(
+        "delete_folder",
+        target_ids,
+        Some(format!(
+            "{} ({} folders, {} files)",
+            folder.name,
+            removed_folders.len(),
+            removed_files.len()
+        )),
+        None,
+    );
+
+    Ok(FolderDeletion {
+        deleted_folders: removed_folders.len(),
+        deleted_files: removed_files.len(),
+    })
+}
+
+// Only the owner shares a folder; the new access applies to its whole subtree.
+#[ic_cdk::update]
+fn set_folder_access(
+    folder_id: String,
+    allowed_users: Vec<(Principal, Access)>,
+    public: bool,
+) -> Result<Folder, String> {
+    let folder = get_folder(&folder_id)?;
+
+    if folder.owner != msg_caller() {
+        return Err("You are not authorized to share this folder.".to_string());
+    }
+
+    if allowed_users
+        .iter()
+        .any(|(_, access)| matches!(access, Access::Owner | Access::Removed))
+    {
+        return Err("Folders can only grant Admin, Write, Read or Delete access.".to_string());
+    }
+
+    let updated = FOLDERS.with_borrow_mut(|folders| {
+        let folder = folders
+            .get_mut(&folder_id)
+            .expect("The folder was read above.");
+        folder.allowed_users = allowed_users;
+        folder.public = public;
+        folder.clone()
+    });
+
+    let folder_ids = FOLDERS.with_borrow(|folders| subtree(folders, &folder_id));
+    references::permissions_changed(&files_in(&folder_ids));
+
+    audit::record(
+        "set_folder_access",
+        vec![folder_id],
+        Some(format!(
+            "{:?}, public: {}",
+            folder.allowed_users, folder.public
+        )),
+        Some(format!(
+            "{:?}, public: {}",
+            updated.allowed_users, updated.public
+        )),
+    );
+
+    Ok(updated)
+}
+
+#[ic_cdk::query]
+fn get_folder_by_id(folder_id: String) -> Result<Folder, String> {
+    let folder = get_folder(&folder_id)?;
+
+    if !has_permission(Some(&folder_id), &readable(), msg_caller()) {
+        return Err("You are not authorized to view this folder.".to_string());
+    }
+
+    Ok(folder)
+}
+
+// Lists a folder, or the caller's root for `None`, folders first and then by
+// name.
+#[ic_cdk::query]
+fn list_folder(
+    folder_id: Option<String>,
+    page: usize,
+    per_page: usize,
+) -> Result<PaginatorResponse<FolderEntry>, String> {
+    let principal = msg_caller();
+
+    if let Some(folder_id) = &folder_id {
+        get_folder(folder_id)?;
+
+        if !has_permission(Some(folder_id), &readable(), principal) {
+            return Err("You are not authorized to view this folder.".to_string());
+        }
+    }
+
+    let is_entry = |parent_id: Option<&String>, owner: Principal| {
+        parent_id == folder_id.as_ref() && (folder_id.is_some() || owner == principal)
+    };
+
+    let mut folders: Vec<Folder> = FOLDERS.with_borrow(|folders| {
+        folders
+            .values()
+            .filter(|folder| is_entry(folder.parent_id.as_ref(), folder.owner))
+            .cloned()
+            .collect()
+    });
+    folders.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut files: Vec<FileMetadata> = FILES.with_borrow(|files| {
+        files
+            .values()
+            .filter(|file| is_entry(file.parent_id.as_ref(), file.owner))
+            .map(FileMetadata::from)
+            .collect()
+    });
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let entries: Vec<FolderEntry> = folders
+        .into_iter()
+        .map(FolderEntry::Folder)
+        .chain(files.into_iter().map(FolderEntry::File))
+        .collect();
+
+    Ok(Paginator::new(entries, vec![]).get(page.max(1), per_page.max(1)))
+}
+
+// Resolves `/a/b/c` from the root of `owner`, the caller by default. The last
+// segment can name a folder or a file.
+#[ic_cdk::query]
+fn resolve_path(path: String, owner: Option<Principal>) -> Result<FolderEntry, String> {
+    let principal = msg_caller();
+    let owner = owner.unwrap_or(principal);
+    let not_found = || format!("{path} not found.");
+
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let Some((last, parents)) = segments.split_last() else {
+        return Err("The path is empty.".to_string());
+    };
+
+    let find_folder = |folders: &HashMap<String, Folder>, parent_id: Option<&str>, name: &str| {
+        folders
+            .values()
+            .find(|folder| {
+                folder.parent_id.as_deref() == parent_id
+                    && (parent_id.is_some() || folder.owner == owner)
+                    && folder.name == name
+            })
+            .map(|folder| folder.id.clone())
+    };
+
+    let (parent_id, folder_id) = FOLDERS.with_borrow(|folders| {
+        let mut parent_id: Option<String> = None;
+
+        for name in parents {
+            parent_id =
+                Some(find_folder(folders, parent_id.as_deref(), name).ok_or_else(not_found)?);
+        }
+
+        let folder_id = find_folder(folders, parent_id.as_deref(), last);
+        Ok::<_, String>((parent_id, folder_id))
+    })?;
+
+    if let Some(folder_id) = folder_id {
+        if !has_permission(Some(&folder_id), &readable(), principal) {
+            return Err("You are not authorized to view this folder.".to_string());
+        }
+
+        return get_folder(&folder_id).map(FolderEntry::Folder);
+    }
+
+    FILES.with_borrow(|files| {
+        let mut matches = files.values().filter(|file| {
+            file.parent_id == parent_id
+                && (parent_id.is_some() || file.owner == owner)
+                && file.name == *last
+        });
+
+        let file = matches.next().ok_or_else(not_found)?;
+        if matches.next().is_some() {
+            return Err(format!("{path} matches several files."));
+        }
+
+        if !has_file_permission(file, &readable(), None) {
+            return Err("You are not authorized to read this file.".to_string());
+        }
+
+        Ok(FolderEntry::File(FileMetadata::from(file)))
+    })
+}
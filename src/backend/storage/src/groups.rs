@@ -0,0 +1,426 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use paginator::{Paginator, PaginatorResponse};
+
+use utilities::{audit, generate_uuid};
+
+use crate::{
+    Access, FILES, FileMetadata, Group, StoredFile, has_file_permission, references, satisfies_any,
+    trash,
+};
+
+// Groups and the files shared with them. Files are linked to groups by id, so
+// renaming a group or changing its members applies to every file in it. On a
+// file linked to a group, the group owner holds `Admin` and every member holds
+// the access they have in the group; groups a file is not linked to grant
+// nothing. Accesses follow `Access::satisfies`.
+
+thread_local! {
+    pub static GROUPS: RefCell<HashMap<String, Group>> = RefCell::new(HashMap::new());
+    // File id to the ids of the groups it is shared with.
+    static FILE_GROUPS: RefCell<HashMap<String, BTreeSet<String>>> = RefCell::new(HashMap::new());
+}
+
+// What `principal` holds in `group`.
+fn held_in(group: &Group, principal: Principal) -> Vec<Access> {
+    if group.owner == principal {
+        return vec![Access::Owner];
+    }
+
+    group
+        .members
+        .iter()
+        .filter(|(user, _)| *user == principal)
+        .map(|(_, access)| access.clone())
+        .collect()
+}
+
+pub fn is_member(group_id: &str, principal: Principal) -> bool {
+    GROUPS.with_borrow(|groups| {
+        groups
+            .get(group_id)
+            .is_some_and(|grp| !held_in(grp, principal).is_empty())
+    })
+}
+
+// The groups `file_id` is shared with that still exist.
+pub fn of_file(file_id: &str) -> Vec<Group> {
+    let group_ids =
+        FILE_GROUPS.with_borrow(|links| links.get(file_id).cloned().unwrap_or_default());
+
+    GROUPS.with_borrow(|groups| {
+        group_ids
+            .iter()
+            .filter_map(|id| groups.get(id).cloned())
+            .collect()
+    })
+}
+
+pub fn files_of(group_id: &str) -> Vec<String> {
+    FILE_GROUPS.with_borrow(|links| {
+        links
+            .iter()
+            .filter(|(_, group_ids)| group_ids.contains(group_id))
+            .map(|(file_id, _)| file_id.clone())
+            .collect()
+    })
+}
+
+// The files of every group `principal` owns or belongs to.
+pub fn files_for(principal: Principal) -> Vec<String> {
+    let group_ids: BTreeSet<String> = GROUPS.with_borrow(|groups| {
+        groups
+            .values()
+            .filter(|grp| !held_in(grp, principal).is_empty())
+            .map(|grp| grp.id.clone())
+            .collect()
+    });
+
+    FILE_GROUPS.with_borrow(|links| {
+        links
+            .iter()
+            .filter(|(_, linked)| !linked.is_disjoint(&group_ids))
+            .map(|(file_id, _)| file_id.clone())
+            .collect()
+    })
+}
+
+// What the groups of `file_id` give `principal` on the file, each with the
+// group it comes from.
+pub fn held_on_file(file_id: &str, principal: Principal) -> Vec<(Group, Access)> {
+    of_file(file_id)
+        .into_iter()
+        .flat_map(|grp| {
+            held_in(&grp, principal)
+                .into_iter()
+                .map(move |access| match access {
+                    Access::Owner => (grp.clone(), Access::Admin),
+                    access => (grp.clone(), access),
+                })
+        })
+        .collect()
+}
+
+pub fn link(file_id: &str, group_ids: impl IntoIterator<Item = String>) -> usize {
+    FILE_GROUPS.with_borrow_mut(|links| {
+        let linked = links.entry(file_id.to_string()).or_default();
+        group_ids
+            .into_iter()
+            .filter(|id| linked.insert(id.clone()))
+            .count()
+    })
+}
+
+pub fn unlink_file(file_id: &str) {
+    FILE_GROUPS.with_borrow_mut(|links| links.remove(file_id));
+}
+
+pub fn unlink_group(group_id: &str) {
+    FILE_GROUPS.with_borrow_mut(|links| {
+        links.values_mut().for_each(|group_ids| {
+            group_ids.remove(group_id);
+        });
+        links.retain(|_, group_ids| !group_ids.is_empty());
+    });
+}
+
+#[ic_cdk::query]
+pub fn check_group_permission(
+    group_id: String,
+    operations: Vec<Access>,
+    user: Option<Principal>,
+) -> bool {
+    let principal: Principal = user.unwrap_or(msg_caller());
+
+    GROUPS.with_borrow(|groups| {
+        groups
+            .get(&group_id)
+            .is_some_and(|grp| satisfies_any(&held_in(grp, principal), &operations))
+    })
+}
+
+#[ic_cdk::update]
+fn create_group(group: Group) -> Result<Group, String> {
+    let principal: Principal = msg_caller();
+
+    let id = generate_uuid();
+
+    let mut members = group.members;
+    members.retain(|(user, access)| *user != principal && *access != Access::Owner);
+
+    let inserted_group = Group {
+        id: id.clone(),
+        owner: principal,
+        members,
+        ..group
+    };
+
+    GROUPS.with_borrow_mut(|groups| groups.insert(id, inserted_group.clone()));
+
+    Ok(inserted_group)
+}
+
+// Moves the caller's groups to the trash.
+#[ic_cdk::update]
+fn delete_groups(group_ids: Vec<String>) -> usize {
+    let mut deleted_groups: Vec<Group> = Vec::new();
+
+    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
+        let principal: Principal = msg_caller();
+
+        for group_id in group_ids.iter() {
+            if groups
+                .get(group_id)
+                .is_some_and(|grp| grp.owner == principal)
+                && let Some(grp) = groups.remove(group_id)
+            {
+                deleted_groups.push(grp.clone());
+                trash::trash_group(grp);
+            }
+        }
+    });
+
+    if !deleted_groups.is_empty() {
+        audit::record(
+            "delete_groups",
+            deleted_groups.iter().map(|grp| grp.id.clone()).collect(),
+            Some(
+                deleted_groups
+                    .iter()
+                    .map(|grp| format!("{} ({} members)", grp.name, grp.members.len()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            None,
+        );
+    }
+
+    deleted_groups.len()
+}
+
+// Sharing a file with a group takes write access in the group and the right to
+// share the file, which its owner and admins have.
+#[ic_cdk::update]
+fn add_group_files(group_id: String, file_ids: Vec<String>) -> Result<usize, String> {
+    if !check_group_permission(group_id.clone(), Access::can_edit(), None) {
+        return Err("You are not authorized to add files to this group.".to_string());
+    }
+
+    let shareable: Vec<String> = FILES.with_borrow(|files| {
+        file_ids
+            .iter()
+            .filter(|id| {
+                files
+                    .get(*id)
+                    .is_some_and(|file| has_file_permission(file, &[Access::Admin], None))
+            })
+            .cloned()
+            .collect()
+    });
+
+    let added: Vec<String> = shareable
+        .iter()
+        .filter(|file_id| link(file_id, [group_id.clone()]) > 0)
+        .cloned()
+        .collect();
+    references::permissions_changed(&added);
+
+    if !added.is_empty() {
+        audit::record(
+            "add_group_files",
+            vec![group_id],
+            None,
+            Some(added.join(", ")),
+        );
+    }
+
+    Ok(added.len())
+}
+
+// Group admins can take any file out of the group, file owners their own.
+#[ic_cdk::update]
+fn remove_group_files(group_id: String, file_ids: Vec<String>) -> usize {
+    let principal = msg_caller();
+    let is_group_admin = check_group_permission(group_id.clone(), Access::admin_access(), None);
+
+    let removable: Vec<String> = FILES.with_borrow(|files| {
+        file_ids
+            .iter()
+            .filter(|id| is_group_admin || files.get(*id).is_some_and(|f| f.owner == principal))
+            .cloned()
+            .collect()
+    });
+
+    let removed: Vec<String> = FILE_GROUPS.with_borrow_mut(|links| {
+        removable
+            .into_iter()
+            .filter(|file_id| {
+                links
+                    .get_mut(file_id)
+                    .is_some_and(|group_ids| group_ids.remove(&group_id))
+            })
+            .collect()
+    });
+
+    references::permissions_changed(&removed);
+
+    if !removed.is_empty() {
+        audit::record(
+            "remove_group_files",
+            vec![group_id],
+            Some(removed.join(", ")),
+            None,
+        );
+    }
+
+    removed.len()
+}
+
+// Grants `user` a new membership. Only the owner makes admins; other members
+// keep their current access, `edit_group_members` removes it first.
+fn add_member(group: &mut Group, is_owner: bool, user: Principal, access: &Access) -> bool {
+    match access {
+        Access::Owner | Access::Removed => false,
+
+        Access::Admin => {
+            if is_owner && !group.members.contains(&(user, Access::Admin)) {
+                group.members.push((user, Access::Admin));
+                true
+            } else {
+                false
+            }
+        }
+
+        _ => {
+            // For Read/Write
+            let already_has = Access::all()
+                .iter()
+                .chain(std::iter::once(&Access::Removed))
+                .any(|perm| group.members.contains(&(user, perm.clone())));
+
+            if !already_has {
+                group.members.push((user, access.clone()));
+            }
+            !already_has
+        }
+    }
+}
+
+// Whether the caller can edit `group`'s members, and whether as its owner.
+fn member_editor(group: &Group) -> Option<bool> {
+    let principal: Principal = msg_caller();
+
+    let is_owner = group.owner == principal;
+    let is_admin = !is_owner && group.members.contains(&(principal, Access::Admin));
+
+    (is_owner || is_admin).then_some(is_owner)
+}
+
+// Adds members under the rules of `edit_group_members`.
+#[ic_cdk::update]
+fn assign_group_members(
+    users: Vec<(Principal, Access)>,
+    group_id: String,
+) -> Result<String, &'static str> {
+    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
+        let grp = groups.get_mut(&group_id).ok_or("Group not found.")?;
+        let is_owner = member_editor(grp).ok_or("You are not authorized to assign members.")?;
+
+        let assigned = users
+            .iter()
+            .filter(|(user, access)| *user != grp.owner && add_member(grp, is_owner, *user, access))
+            .count();
+
+        if assigned == 0 {
+            return Ok("No new users to assign.".to_string());
+        }
+
+        Ok(format!("{assigned} users assigned."))
+    })
+}
+
+#[ic_cdk::update]
+fn edit_group_members(group_id: String, new_accesses: Vec<(Principal, Access)>) -> usize {
+    let mut updated_users: usize = 0;
+
+    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
+        if let Some(group) = groups.get_mut(&group_id) {
+            let Some(is_owner) = member_editor(group) else {
+                return;
+            };
+
+            for (user, access) in new_accesses.iter() {
+                match access {
+                    Access::Removed => {
+                        if group.members.contains(&(*user, Access::Admin)) && is_owner {
+                            group.members.retain(|(u, _)| u != user);
+                            updated_users += 1;
+                        } else {
+                            group.members.retain(|(u, a)| !(u == user && a == access));
+                        }
+                    }
+
+                    _ => {
+                        if add_member(group, is_owner, *user, access) {
+                            updated_users += 1;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    updated_users
+}
+
+#[ic_cdk::query]
+fn get_group(group_id: String) -> Result<Group, &'static str> {
+    GROUPS.with_borrow(
+        |groups: &HashMap<String, Group>| match groups.get(&group_id) {
+            Some(grp) => {
+                if !held_in(grp, msg_caller()).is_empty() {
+                    Ok(grp.clone())
+                } else {
+                    Err("You do not have permission to view this group.")
+                }
+            }
+            None => Err("Group not found."),
+        },
+    )
+}
+
+#[ic_cdk::query]
+fn get_groups(page: usize, per_page: usize) -> PaginatorResponse<Group> {
+    let groups = GROUPS.with_borrow(|groups: &HashMap<String, Group>| {
+        let principal: Principal = msg_caller();
+        groups
+            .values()
+            .filter(|grp: &&Group| !held_in(grp, principal).is_empty())
+            .cloned()
+            .collect()
+    });
+
+    Paginator::new(groups, vec![]).get(page, per_page)
+}
+
+// The files shared with a group that the caller can read.
+#[ic_cdk::query]
+fn get_group_files(group_id: String) -> Result<Vec<FileMetadata>, String> {
+    if !is_member(&group_id, msg_caller()) {
+        return Err("You do not have permission to view this group.".to_string());
+    }
+
+    let file_ids = files_of(&group_id);
+
+    Ok(FILES.with_borrow(|files| {
+        file_ids
+            .iter()
+            .filter_map(|id| files.get(id))
+            .filter(|file: &&StoredFile| has_file_permission(file, &[Access::Read], None))
+            .map(FileMetadata::from)
+            .collect()
+    }))
+}
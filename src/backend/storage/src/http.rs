@@ -0,0 +1,428 @@
+use candid::{CandidType, define_function};
+use serde::Deserialize;
+
+use crate::uploads::{CHUNK_SIZE, chunk_count};
+use crate::variants::{self, VariantSize};
+use crate::{FILES, StoredFile, analytics, blobs, share_links, tokens};
+
+// HTTP gateway interface. Files are served at `/files/<file_id>`; public files
+// need nothing else, other files need a `?token=` from `create_download_token`
+// or a share link. Responses are not certified, so they must be fetched through
+// the `raw` gateway domain. Bodies larger than one chunk are streamed chunk by
+// chunk. Images also have smaller renditions at `?variant=thumbnail`, `small`
+// or `medium`; the first request for one is upgraded to an update call that
+// renders it. Downloads are upgraded too, so they're counted, see `analytics`.
+
+pub type HeaderField = (String, String);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+    pub upgrade: Option<bool>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingToken {
+    pub file_id: String,
+    pub index: u32,
+    pub token: Option<String>,
+    pub variant: Option<VariantSize>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingToken>,
+}
+
+define_function!(pub StreamingCallback : (StreamingToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingToken,
+    },
+}
+
+impl HttpResponse {
+    fn status(status_code: u16, message: &str) -> Self {
+        Self {
+            status_code,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: message.as_bytes().to_vec(),
+            streaming_strategy: None,
+            upgrade: None,
+        }
+    }
+
+    // Asks the gateway to repeat the request as `http_request_update`.
+    fn upgrade() -> Self {
+        Self {
+            status_code: 200,
+            headers: Vec::new(),
+            body: Vec::new(),
+            streaming_strategy: None,
+            upgrade: Some(true),
+        }
+    }
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // `/files/<file_id>` and the optional `token` query parameter.
+    fn file_route(&self) -> Option<(&str, Option<&str>)> {
+        let (path, _) = self.url.split_once('?').unwrap_or((&self.url, ""));
+        let file_id = path.strip_prefix("/files/")?.trim_end_matches('/');
+
+        if file_id.is_empty() || file_id.contains('/') {
+            return None;
+        }
+
+        Some((file_id, self.query_param("token")))
+    }
+
+    fn query_param(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.url.split_once('?')?;
+
+        query.split('&').find_map(|param| {
+            param
+                .split_once('=')
+                .filter(|(key, _)| *key == name)
+                .map(|(_, value)| value)
+        })
+    }
+}
+
+enum Authorization {
+    Allowed,
+    Upgrade,
+    Denied,
+}
+
+// Share links with a use limit are only redeemed in update calls, where the
+// use can be counted.
+// Types a browser can show without running anything from them. The rest,
+// `text/html` and `image/svg+xml` among them, are served as attachments so
+// they can't script the storage origin.
+fn is_inline_safe(mime_type: &str) -> bool {
+    let essence = mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    matches!(
+        essence.as_str(),
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp" | "application/pdf" | "text/plain"
+    ) || essence.starts_with("video/")
+        || essence.starts_with("audio/")
+}
+
+fn authorize(file: &StoredFile, token: Option<&str>, in_update: bool) -> Authorization {
+    let allowed = match token {
+        _ if file.public => true,
+        None => false,
+        Some(token) if !share_links::is_share_token(token) => tokens::verify(file, token),
+        Some(token) if !in_update && share_links::is_metered(token) => {
+            return Authorization::Upgrade;
+        }
+        Some(token) => share_links::redeem(token, Some(&file.id), false).is_ok(),
+    };
+
+    if allowed {
+        Authorization::Allowed
+    } else {
+        Authorization::Denied
+    }
+}
+
+// Streamed continuations never count a use.
+fn can_stream(file: &StoredFile, token: Option<&str>) -> bool {
+    match token {
+        _ if file.public => true,
+        None => false,
+        Some(token) if share_links::is_share_token(token) => {
+            share_links::allows_read(&file.id, token)
+        }
+        Some(token) => tokens::verify(file, token),
+    }
+}
+
+// Parses a single `bytes=` range against a body of `size` bytes into an
+// inclusive `(start, end)`. `None` for headers we don't handle, which are
+// answered with the whole body, and `Err` when the range can't be satisfied.
+fn parse_range(range: &str, size: usize) -> Option<Result<(usize, usize), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<usize>().ok()?;
+            if suffix == 0 {
+                return Some(Err(()));
+            }
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        (start, "") => (start.parse().ok()?, size.saturating_sub(1)),
+        (start, end) => (start.parse().ok()?, end.parse::<usize>().ok()?),
+    };
+
+    if start >= size || end < start {
+        return Some(Err(()));
+    }
+
+    Some(Ok((start, end.min(size - 1))))
+}
+
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    serve(request, false)
+}
+
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    serve(request, true)
+}
+
+fn serve(request: HttpRequest, in_update: bool) -> HttpResponse {
+    if request.method != "GET" && request.method != "HEAD" {
+        return HttpResponse::status(405, "Method not allowed.");
+    }
+
+    let Some((file_id, token)) = request.file_route() else {
+        return HttpResponse::status(404, "Not found.");
+    };
+
+    let variant = match request.query_param("variant").map(VariantSize::parse) {
+        None => None,
+        Some(Some(size)) => Some(size),
+        Some(None) => return HttpResponse::status(400, "Unknown variant."),
+    };
+
+    let Some(file) = FILES.with_borrow(|files| files.get(file_id).cloned()) else {
+        return HttpResponse::status(404, "StoredFile not found.");
+    };
+
+    match authorize(&file, token, in_update) {
+        Authorization::Allowed => {}
+        Authorization::Upgrade => return HttpResponse::upgrade(),
+        Authorization::Denied => {
+            return HttpResponse::status(403, "A valid token is required for this file.");
+        }
+    }
+
+    // From here on `file` is what gets served, the rendition if one was asked
+    // for.
+    let file = match variant {
+        None => file,
+        Some(size) => {
+            if let Err(err) = variants::check_source(&file) {
+                return HttpResponse::status(415, err);
+            }
+
+            let rendition = match variants::cached(&file, size) {
+                Some(rendition) => Ok(rendition),
+                None if !in_update => return HttpResponse::upgrade(),
+                None => variants::ensure(&file, size),
+            };
+
+            match rendition {
+                Ok(rendition) => StoredFile {
+                    content_hash: rendition.content_hash,
+                    mime_type: rendition.mime_type,
+                    ..file
+                },
+                Err(err) => return HttpResponse::status(415, &err),
+            }
+        }
+    };
+
+    let etag = format!("\"{}\"", file.content_hash);
+    let mime_type = if file.mime_type.is_empty() {
+        "application/octet-stream".to_string()
+    } else {
+        file.mime_type.clone()
+    };
+    let cache_control = if file.public {
+        "public, max-age=3600"
+    } else {
+        "private"
+    };
+
+    let inline = is_inline_safe(&mime_type);
+    let mut headers: Vec<HeaderField> = vec![
+        ("Content-Type".to_string(), mime_type),
+        ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+        ("ETag".to_string(), etag.clone()),
+        ("Accept-Ranges".to_string(), "bytes".to_string()),
+        ("Cache-Control".to_string(), cache_control.to_string()),
+    ];
+    if !inline {
+        headers.push(("Content-Disposition".to_string(), "attachment".to_string()));
+    }
+
+    if request.header("If-None-Match") == Some(etag.as_str()) {
+        return HttpResponse {
+            status_code: 304,
+            headers,
+            body: Vec::new(),
+            streaming_strategy: None,
+            upgrade: None,
+        };
+    }
+
+    // A download serves the start of the file; later ranges of the same one
+    // aren't counted again.
+    let head = request.method == "HEAD";
+    let download = !head
+        && request
+            .header("Range")
+            .is_none_or(|range| range.trim().starts_with("bytes=0-"));
+    if download {
+        if !in_update {
+            return HttpResponse::upgrade();
+        }
+        analytics::downloaded(&file.id);
+    }
+
+    blobs::read(&file.content_hash, |data| {
+        let size = data.len();
+
+        // Ranges are capped at one chunk; clients ask again from where the
+        // returned `Content-Range` ends.
+        match request
+            .header("Range")
+            .and_then(|range| parse_range(range, size))
+        {
+            Some(Err(())) => {
+                headers.push(("Content-Range".to_string(), format!("bytes */{size}")));
+                return HttpResponse {
+                    status_code: 416,
+                    headers,
+                    body: Vec::new(),
+                    streaming_strategy: None,
+                    upgrade: None,
+                };
+            }
+            Some(Ok((start, end))) => {
+                let end = end.min(start + CHUNK_SIZE - 1);
+
+                headers.push((
+                    "Content-Range".to_string(),
+                    format!("bytes {start}-{end}/{size}"),
+                ));
+                headers.push(("Content-Length".to_string(), (end - start + 1).to_string()));
+
+                return HttpResponse {
+                    status_code: 206,
+                    headers,
+                    body: if head {
+                        Vec::new()
+                    } else {
+                        data[start..=end].to_vec()
+                    },
+                    streaming_strategy: None,
+                    upgrade: None,
+                };
+            }
+            None => {}
+        }
+
+        headers.push(("Content-Length".to_string(), size.to_string()));
+
+        let streaming_strategy =
+            (!head && size > CHUNK_SIZE).then(|| StreamingStrategy::Callback {
+                callback: StreamingCallback::new(
+                    ic_cdk::api::canister_self(),
+                    "http_request_streaming_callback".to_string(),
+                ),
+                token: StreamingToken {
+                    file_id: file.id.clone(),
+                    index: 1,
+                    token: token.map(str::to_string),
+                    variant,
+                },
+            });
+
+        HttpResponse {
+            status_code: 200,
+            headers,
+            body: if head {
+                Vec::new()
+            } else {
+                data[..size.min(CHUNK_SIZE)].to_vec()
+            },
+            streaming_strategy,
+            upgrade: None,
+        }
+    })
+    .unwrap_or_else(|| HttpResponse::status(500, "StoredFile content is missing."))
+}
+
+#[ic_cdk::query]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    let finished = StreamingCallbackHttpResponse {
+        body: Vec::new(),
+        token: None,
+    };
+
+    let Some(file) = FILES.with_borrow(|files| files.get(&token.file_id).cloned()) else {
+        return finished;
+    };
+
+    if !can_stream(&file, token.token.as_deref()) {
+        return finished;
+    }
+
+    let file = match token.variant {
+        None => file,
+        Some(size) => match variants::cached(&file, size) {
+            Some(rendition) => StoredFile {
+                content_hash: rendition.content_hash,
+                ..file
+            },
+            None => return finished,
+        },
+    };
+
+    blobs::read(&file.content_hash, |data| {
+        let start = token.index as usize * CHUNK_SIZE;
+        if start >= data.len() {
+            return finished.clone();
+        }
+
+        let end = (start + CHUNK_SIZE).min(data.len());
+        let next_index = token.index + 1;
+
+        StreamingCallbackHttpResponse {
+            body: data[start..end].to_vec(),
+            token: (next_index < chunk_count(data.len())).then(|| StreamingToken {
+                index: next_index,
+                ..token.clone()
+            }),
+        }
+    })
+    .unwrap_or(finished)
+}
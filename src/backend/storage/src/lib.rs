@@ -1,4 +1,5 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 
 use candid::CandidType;
 use ic_cdk::api::msg_caller;
@@ -7,7 +8,46 @@ use ic_principal::Principal;
 use paginator::{HasFields, Paginator, PaginatorResponse};
 use serde::{Deserialize, Serialize};
 
-use utilities::{generate_uuid, now};
+use utilities::audit::{self, AuditEntry};
+use utilities::now;
+
+mod access;
+mod analytics;
+mod batch;
+mod blobs;
+mod bucket;
+mod encryption;
+mod folders;
+mod groups;
+mod http;
+mod policy;
+mod quotas;
+mod references;
+mod search;
+mod share_links;
+mod tags;
+mod tokens;
+mod trash;
+mod uploads;
+mod variants;
+mod versions;
+
+use access::AccessExplanation;
+use analytics::{FileStats, GrowthPeriod, StorageStats};
+use batch::ItemResult;
+use blobs::GarbageCollection;
+use encryption::{FileKeyStatus, WrappedKey};
+use folders::{CreateFolderInput, Folder, FolderDeletion, FolderEntry};
+use http::{HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingToken};
+use policy::{UploadContext, UploadPolicy};
+use quotas::{Quota, Usage, UsageReport};
+use references::{DeletePolicy, FileReference, Subscriber};
+use search::FileQuery;
+use share_links::{CreateShareLinkInput, ShareLink};
+use trash::Trash;
+use uploads::{FileChunk, UploadError, UploadMetadata, UploadStatus};
+use variants::VariantSize;
+use versions::FileVersion;
 
 // Utils
 
@@ -28,6 +68,8 @@ pub enum FileUploadResolveType {
     SuccessfullyUploaded,
     FailedToUpload,
     AlreadyUploaded,
+    QuotaExceeded,
+    ContentRejected,
 }
 
 impl Access {
@@ -49,6 +91,29 @@ impl Access {
     pub fn admin_access() -> Vec<Access> {
         [Access::Admin, Access::Delete].to_vec()
     }
+
+    // Whether holding `self` is enough where `required` is asked for. Accesses
+    // rank Owner > Admin > Write > Read and each one grants those below it.
+    // Owners can do anything, `Public` grants `Read` and `Delete` only itself.
+    pub fn satisfies(&self, required: &Access) -> bool {
+        fn rank(access: &Access) -> Option<u8> {
+            match access {
+                Access::Read => Some(0),
+                Access::Write => Some(1),
+                Access::Admin => Some(2),
+                Access::Owner => Some(3),
+                _ => None,
+            }
+        }
+
+        match (self, required) {
+            (Access::Owner, _) | (Access::Public, Access::Read) => true,
+            _ => match (rank(self), rank(required)) {
+                (Some(held), Some(required)) => held >= required,
+                _ => self == required,
+            },
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, CandidType, Debug)]
@@ -79,10 +144,14 @@ pub struct StoredFile {
     mime_type: String,
     size: usize,
     data: Vec<u8>,
+    content_hash: String,
+    parent_id: Option<String>,
     owner: Principal,
     groups: Vec<Group>,
     allowed_users: Vec<(Principal, Access)>,
     public: bool,
+    tags: Vec<String>,
+    metadata: BTreeMap<String, String>,
     uploaded_at: String,
 }
 
@@ -93,227 +162,123 @@ impl HasFields for StoredFile {
             "name" => self.name.clone(),
             "mime_type" => self.mime_type.clone(),
             "size" => self.size.to_string(),
+            "content_hash" => self.content_hash.clone(),
+            "parent_id" => self.parent_id.clone().unwrap_or_default(),
             "owner" => self.owner.to_text(),
             "public" => self.public.to_string(),
-            _ => "".to_string(),
+            _ => tags::field(&self.tags, &self.metadata, field_name),
         }
     }
 }
 
-thread_local! {
-    static FILES: RefCell<HashMap<String, StoredFile>> = RefCell::new(HashMap::new());
-    static GROUPS: RefCell<HashMap<String, Group>> = RefCell::new(HashMap::new());
-}
-
-#[ic_cdk::query]
-fn get_all() -> Vec<StoredFile> {
-    FILES.with_borrow(|file_map| file_map.values().cloned().collect())
+// What listings return: a `StoredFile` without its `data`. Content is read by
+// id with `get_file`, `get_chunk` or over HTTP.
+#[derive(Clone, Serialize, Deserialize, CandidType, Debug)]
+pub struct FileMetadata {
+    id: String,
+    name: String,
+    mime_type: String,
+    size: usize,
+    content_hash: String,
+    chunk_count: u32,
+    version: u32,
+    parent_id: Option<String>,
+    owner: Principal,
+    groups: Vec<Group>,
+    allowed_users: Vec<(Principal, Access)>,
+    public: bool,
+    // The content is ciphertext, see `encryption`.
+    encrypted: bool,
+    tags: Vec<String>,
+    metadata: BTreeMap<String, String>,
+    uploaded_at: String,
 }
 
-// Groups
-#[ic_cdk::query]
-fn check_group_permission(
-    group_id: String,
-    operations: Vec<Access>,
-    user: Option<Principal>,
-) -> bool {
-    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
-        let principal: Principal = user.unwrap_or(msg_caller());
-
-        match groups.get_mut(&group_id) {
-            Some(grp) => operations
-                .iter()
-                .any(|op| grp.owner == principal || grp.members.contains(&(principal, op.clone()))),
-            None => false,
+impl From<&StoredFile> for FileMetadata {
+    fn from(file: &StoredFile) -> Self {
+        let content_len = content_len(file) as usize;
+
+        Self {
+            id: file.id.clone(),
+            name: file.name.clone(),
+            mime_type: file.mime_type.clone(),
+            size: file.size,
+            content_hash: file.content_hash.clone(),
+            chunk_count: uploads::chunk_count(content_len),
+            version: versions::current_version(&file.id),
+            parent_id: file.parent_id.clone(),
+            owner: file.owner,
+            groups: groups::of_file(&file.id),
+            allowed_users: file.allowed_users.clone(),
+            public: file.public,
+            encrypted: encryption::is_encrypted(&file.id),
+            tags: file.tags.clone(),
+            metadata: file.metadata.clone(),
+            uploaded_at: file.uploaded_at.clone(),
         }
-    })
-}
-
-#[ic_cdk::query]
-fn create_group(group: Group) {
-    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
-        let principal: Principal = msg_caller();
-
-        let mut inserted_group = group.clone();
-
-        let id = generate_uuid();
-        inserted_group.id = id.clone();
-        inserted_group.owner = principal;
-
-        groups.insert(id, group);
-    });
+    }
 }
 
-#[ic_cdk::query]
-fn delete_groups(group_ids: Vec<String>) -> usize {
-    let mut deleted_group_count: usize = 0;
-
-    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
-        let principal: Principal = msg_caller();
-
-        for group_id in group_ids.iter() {
-            if let Some(grp) = groups.get_mut(group_id) {
-                if grp.owner == principal {
-                    let was_deleted = GROUPS.with(|groups: &RefCell<HashMap<String, Group>>| {
-                        groups.borrow_mut().remove(group_id).is_some()
-                    });
-
-                    if was_deleted {
-                        deleted_group_count += 1;
-                    }
-                }
-            }
+impl HasFields for FileMetadata {
+    fn get_field(&self, field_name: &str) -> String {
+        match field_name {
+            "id" => self.id.clone(),
+            "name" => self.name.clone(),
+            "mime_type" => self.mime_type.clone(),
+            "size" => self.size.to_string(),
+            "content_hash" => self.content_hash.clone(),
+            "version" => self.version.to_string(),
+            "parent_id" => self.parent_id.clone().unwrap_or_default(),
+            "owner" => self.owner.to_text(),
+            "public" => self.public.to_string(),
+            "uploaded_at" => self.uploaded_at.clone(),
+            _ => tags::field(&self.tags, &self.metadata, field_name),
         }
-    });
-
-    deleted_group_count
-}
-
-#[ic_cdk::query]
-fn remove_group_files(group_id: String, file_ids: Vec<String>) -> usize {
-    let mut removed_file_count: usize = 0;
-
-    if check_group_permission(group_id, Access::admin_access(), None) {
-        GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
-            file_ids.iter().for_each(|file_id: &String| {
-                if groups.remove(file_id).is_some() {
-                    removed_file_count += 1;
-                }
-            });
-        });
     }
-
-    removed_file_count
 }
 
-#[ic_cdk::update]
-fn assign_group_members(
-    users: Vec<(Principal, Access)>,
-    group_id: String,
-) -> Result<String, &'static str> {
-    GROUPS.with_borrow_mut(
-        |groups: &mut HashMap<String, Group>| match groups.get_mut(&group_id) {
-            Some(grp) => {
-                let non_inserted_users: Vec<(Principal, Access)> = users
-                    .iter()
-                    .filter(|user: &&(Principal, Access)| {
-                        !grp.members.contains(user) && user.1 != Access::Owner
-                    })
-                    .cloned()
-                    .collect();
-
-                if non_inserted_users.is_empty() {
-                    return Ok("No new users to assign.".to_string());
-                }
-
-                if check_group_permission(group_id, Access::admin_access(), None) {
-                    return Err("You are not authorized to assign members.");
-                }
-
-                for user in non_inserted_users.clone() {
-                    grp.members.push(user);
-                }
-
-                Ok(format!("{} users assigned.", non_inserted_users.len()))
-            }
-            None => Err("Group not found."),
-        },
-    )
+thread_local! {
+    // Files are kept without `data` and `groups`. Their content lives in the
+    // blob store under `content_hash`, their groups are linked by id in
+    // `groups`, and both are filled back in by `hydrate`.
+    static FILES: RefCell<HashMap<String, StoredFile>> = RefCell::new(HashMap::new());
 }
 
-#[ic_cdk::update]
-fn edit_group_members(group_id: String, new_accesses: Vec<(Principal, Access)>) -> usize {
-    let mut updated_users: usize = 0;
-
-    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
-        if let Some(group) = groups.get_mut(&group_id) {
-            let principal: Principal = msg_caller();
-
-            let is_owner = group.owner == principal;
-            let is_admin = !is_owner && group.members.contains(&(principal, Access::Admin));
-
-            if !is_owner && !is_admin {
-                return;
-            }
-
-            let all_permissions = Access::all()
-                .iter()
-                .cloned()
-                .chain(std::iter::once(Access::Removed))
-                .collect::<Vec<_>>();
-
-            for (user, access) in new_accesses.iter() {
-                match access {
-                    Access::Owner => {}
-
-                    Access::Removed => {
-                        if group.members.contains(&(*user, Access::Admin)) && is_owner {
-                            group.members.retain(|(u, _)| u != user);
-                            updated_users += 1;
-                        } else {
-                            group.members.retain(|(u, a)| !(u == user && a == access));
-                        }
-                    }
-
-                    Access::Admin => {
-                        if is_owner && !group.members.contains(&(*user, Access::Admin)) {
-                            group.members.push((*user, Access::Admin));
-                            updated_users += 1;
-                        }
-                    }
-
-                    _ => {
-                        // For Read/Write
-                        let already_has = all_permissions
-                            .iter()
-                            .any(|perm| group.members.contains(&(*user, perm.clone())));
-
-                        if !already_has {
-                            group.members.push((*user, access.clone()));
-                            updated_users += 1;
-                        }
-                    }
-                }
-            }
-        }
-    });
-
-    updated_users
+fn content_len(file: &StoredFile) -> u64 {
+    blobs::read(&file.content_hash, |data| data.len() as u64).unwrap_or(0)
 }
 
-#[ic_cdk::query]
-fn get_group(group_id: String) -> Result<Group, &'static str> {
-    GROUPS.with_borrow(
-        |groups: &HashMap<String, Group>| match groups.get(&group_id) {
-            Some(grp) => {
-                let principal: Principal = msg_caller();
+fn hydrate(mut file: StoredFile) -> StoredFile {
+    file.data = blobs::content(&file.content_hash);
+    file.groups = groups::of_file(&file.id);
+    file
+}
 
-                if grp.owner == principal || grp.members.iter().any(|(user, _)| user == &principal)
-                {
-                    Ok(grp.clone())
-                } else {
-                    Err("You do not have permission to view this group.")
-                }
-            }
-            None => Err("Group not found."),
-        },
-    )
+// Drops everything that refers to a removed file: its blob reference, older
+// versions, renditions, quota usage, share links, wrapped keys, upload
+// context, download stats and references held by other canisters, which are
+// told about it.
+fn release_file(file: &StoredFile) {
+    quotas::remove(file, content_len(file));
+    blobs::release(&file.content_hash);
+    share_links::remove_file_links(&file.id);
+    versions::forget(file);
+    variants::forget(&file.id);
+    encryption::forget(&file.id);
+    policy::forget(&file.id);
+    analytics::forget(&file.id);
+    groups::unlink_file(&file.id);
+    references::file_deleted(&file.id);
+    bucket::file_removed(&file.id);
 }
 
 #[ic_cdk::query]
-fn get_groups(page: usize, per_page: usize) -> PaginatorResponse<Group> {
-    let groups = GROUPS.with_borrow(|groups: &HashMap<String, Group>| {
-        let principal: Principal = msg_caller();
-        groups
-            .values()
-            .filter(|grp: &&Group| {
-                grp.owner == principal || grp.members.iter().any(|(user, _)| user == &principal)
-            })
-            .cloned()
-            .collect()
-    });
+fn get_all() -> Result<Vec<FileMetadata>, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can list every file.".to_string());
+    }
 
-    Paginator::new(groups, vec![]).get(page, per_page)
+    Ok(FILES.with_borrow(|file_map| file_map.values().map(FileMetadata::from).collect()))
 }
 
 // Files
@@ -323,24 +288,46 @@ fn check_file_permission(
     operations: Vec<Access>,
     user: Option<Principal>,
 ) -> bool {
+    has_file_permission(&file, &operations, user)
+}
+
+// Borrowing counterpart of `check_file_permission`, so large files are not
+// cloned just to be checked. Any grant from `access::file_grants` that
+// satisfies one of `operations` is enough.
+fn has_file_permission(file: &StoredFile, operations: &[Access], user: Option<Principal>) -> bool {
     let principal = user.unwrap_or(msg_caller());
 
-    if operations.contains(&Access::Owner) && file.owner == principal
-        || operations.contains(&Access::Public) && file.public
-        || operations
-            .iter()
-            .any(|op: &Access| file.allowed_users.contains(&(principal, op.clone())))
-    {
-        return true;
+    access::file_grants(file, principal)
+        .iter()
+        .any(|grant| operations.iter().any(|op| grant.access.satisfies(op)))
+}
+
+// What `principal` holds on an item from its owner, public flag and grants.
+fn held_access(
+    owner: Principal,
+    public: bool,
+    allowed_users: &[(Principal, Access)],
+    principal: Principal,
+) -> Vec<Access> {
+    let mut held: Vec<Access> = allowed_users
+        .iter()
+        .filter(|(user, _)| *user == principal)
+        .map(|(_, access)| access.clone())
+        .collect();
+
+    if owner == principal {
+        held.push(Access::Owner);
+    }
+    if public {
+        held.push(Access::Public);
     }
 
-    GROUPS.with_borrow_mut(|groups: &mut HashMap<String, Group>| {
-        groups.iter().any(|(_id, group): (&String, &Group)| {
-            operations
-                .iter()
-                .any(|op: &Access| group.members.contains(&(principal, op.clone())))
-        })
-    })
+    held
+}
+
+fn satisfies_any(held: &[Access], operations: &[Access]) -> bool {
+    held.iter()
+        .any(|held| operations.iter().any(|op| held.satisfies(op)))
 }
 
 #[ic_cdk::update]
@@ -354,8 +341,9 @@ fn get_file(file_id: String, mutable: Option<bool>) -> Option<StoredFile> {
                     Access::all()
                 };
 
-                if check_file_permission(file.clone(), access_requirements, None) {
-                    Some(file.clone())
+                if has_file_permission(file, &access_requirements, None) {
+                    analytics::downloaded(&file_id);
+                    Some(hydrate(file.clone()))
                 } else {
                     None
                 }
@@ -366,16 +354,29 @@ fn get_file(file_id: String, mutable: Option<bool>) -> Option<StoredFile> {
 }
 
 #[ic_cdk::update]
-fn get_files_by_id(file_ids: Vec<String>) -> Vec<StoredFile> {
+fn get_files_by_id(file_ids: Vec<String>) -> Vec<FileMetadata> {
     FILES.with_borrow(|file_map: &HashMap<String, StoredFile>| {
         file_map
             .values()
             .filter(|file: &&StoredFile| {
-                file_ids.contains(&file.id)
-                    && check_file_permission((*file).clone(), vec![Access::Read], None)
+                file_ids.contains(&file.id) && has_file_permission(file, &[Access::Read], None)
             })
-            .cloned()
-            .collect::<Vec<StoredFile>>()
+            .map(FileMetadata::from)
+            .collect::<Vec<FileMetadata>>()
+    })
+}
+
+// Contents of the readable files among `file_ids`, in one call, for canisters
+// that show files they reference. These reads are not counted as downloads.
+#[ic_cdk::query]
+fn get_files_content(file_ids: Vec<String>) -> Vec<StoredFile> {
+    FILES.with_borrow(|files| {
+        file_ids
+            .iter()
+            .filter_map(|file_id| files.get(file_id))
+            .filter(|file| has_file_permission(file, &Access::all(), None))
+            .map(|file| hydrate(file.clone()))
+            .collect()
     })
 }
 
@@ -385,29 +386,29 @@ fn get_files(
     page: usize,
     public: bool,
     owned: bool,
-) -> PaginatorResponse<StoredFile> {
-    let my_files: Vec<StoredFile> = FILES.with(|files: &RefCell<HashMap<String, StoredFile>>| {
+) -> PaginatorResponse<FileMetadata> {
+    let my_files: Vec<FileMetadata> = FILES.with(|files: &RefCell<HashMap<String, StoredFile>>| {
         if owned {
             let principal: Principal = msg_caller();
             return files
                 .borrow()
                 .values()
                 .filter(|f| f.owner == principal)
-                .cloned()
+                .map(FileMetadata::from)
                 .collect();
         } else if public {
             return files
                 .borrow()
                 .values()
                 .filter(|f| f.public)
-                .cloned()
+                .map(FileMetadata::from)
                 .collect();
         } else {
             return files
                 .borrow()
                 .values()
                 .filter(|f| check_file_permission((*f).clone(), Access::all(), None))
-                .cloned()
+                .map(FileMetadata::from)
                 .collect();
         }
     });
@@ -416,17 +417,51 @@ fn get_files(
     paginator.get(page, per_page)
 }
 
+// `size` and `mime_type` are taken from the content, see `policy`.
 #[ic_cdk::update]
-fn upload_files(files: Vec<StoredFile>) -> Vec<(String, FileUploadResolveType, String)> {
-    let mut uploaded_files: Vec<(String, FileUploadResolveType, String)> = vec![];
+fn upload_files(
+    files: Vec<StoredFile>,
+    context: Option<UploadContext>,
+) -> Vec<(String, FileUploadResolveType, String)> {
+    store_uploads(files, context, msg_caller(), None)
+}
 
-    let principal = msg_caller();
+// Uploads `files` for `owner`, for subscriber canisters that take files from
+// their users, like a profile picture. The files belong to `owner` and count
+// towards their quota; the calling canister can read them.
+#[ic_cdk::update]
+fn upload_files_for(
+    owner: Principal,
+    files: Vec<StoredFile>,
+    context: Option<UploadContext>,
+) -> Result<Vec<(String, FileUploadResolveType, String)>, String> {
+    let canister = msg_caller();
+
+    if !references::is_subscriber(canister) {
+        return Err("Only subscriber canisters can upload for others.".to_string());
+    }
+
+    Ok(store_uploads(files, context, owner, Some(canister)))
+}
+
+// Stores `files` for `principal`, giving `reader` read access to them.
+fn store_uploads(
+    files: Vec<StoredFile>,
+    context: Option<UploadContext>,
+    principal: Principal,
+    reader: Option<Principal>,
+) -> Vec<(String, FileUploadResolveType, String)> {
+    let mut uploaded_files: Vec<(String, FileUploadResolveType, String)> = vec![];
 
     FILES.with_borrow_mut(|files_map: &mut HashMap<String, StoredFile>| {
         for file in files.iter() {
             if !file.groups.is_empty()
                 && file.groups.iter().any(|grp: &Group| {
-                    !check_group_permission(grp.id.clone(), Access::can_edit(), None)
+                    !groups::check_group_permission(
+                        grp.id.clone(),
+                        Access::can_edit(),
+                        Some(principal),
+                    )
                 })
             {
                 uploaded_files.push((
@@ -437,74 +472,259 @@ fn upload_files(files: Vec<StoredFile>) -> Vec<(String, FileUploadResolveType, S
                 continue;
             }
 
-            let key: String = generate_uuid();
+            if let Some(parent_id) = file.parent_id.as_deref() {
+                if !folders::exists(parent_id)
+                    || !folders::has_permission(Some(parent_id), &Access::can_edit(), principal)
+                {
+                    uploaded_files.push((
+                        file.name.clone(),
+                        FileUploadResolveType::NotAuthorized,
+                        "You are not authorized in this folder.".to_string(),
+                    ));
+                    continue;
+                }
+
+                if folders::is_name_taken(files_map, Some(parent_id), principal, &file.name, None) {
+                    uploaded_files.push((
+                        file.name.clone(),
+                        FileUploadResolveType::FailedToUpload,
+                        "A file with this name already exists in this folder.".to_string(),
+                    ));
+                    continue;
+                }
+            }
+
+            let mime_type = match policy::admit(context, &file.mime_type, &file.data) {
+                Ok(mime_type) => mime_type,
+                Err(err) => {
+                    uploaded_files.push((
+                        file.name.clone(),
+                        FileUploadResolveType::ContentRejected,
+                        err,
+                    ));
+                    continue;
+                }
+            };
+
+            let file_tags = match tags::normalize_tags(&file.tags)
+                .and_then(|file_tags| tags::check_metadata(&file.metadata).map(|_| file_tags))
+            {
+                Ok(file_tags) => file_tags,
+                Err(err) => {
+                    uploaded_files.push((
+                        file.name.clone(),
+                        FileUploadResolveType::FailedToUpload,
+                        err,
+                    ));
+                    continue;
+                }
+            };
+
+            let content_hash = blobs::hash(&file.data);
+
+            // Open upload sessions hold their share of the quota too.
+            let (pending_bytes, open_uploads) = uploads::pending(principal);
+            if let Err(err) = quotas::check_files(
+                principal,
+                open_uploads + 1,
+                pending_bytes + file.data.len() as u64,
+            ) {
+                uploaded_files.push((
+                    file.name.clone(),
+                    FileUploadResolveType::QuotaExceeded,
+                    err.to_string(),
+                ));
+                continue;
+            }
+
+            let key: String = bucket::new_file_id();
 
             let mut inserted_file = file.clone();
 
             inserted_file.id = key.clone();
             inserted_file.owner = principal;
             inserted_file.uploaded_at = now();
-
-            if files_map
-                .insert(key.clone(), inserted_file.clone())
-                .is_none()
-            {
-                uploaded_files.push((
-                    key,
-                    FileUploadResolveType::SuccessfullyUploaded,
-                    "Successfully uploaded.".to_string(),
-                ));
-            } else {
-                uploaded_files.push((
-                    key,
-                    FileUploadResolveType::AlreadyUploaded,
-                    "StoredFile is already uploaded.".to_string(),
-                ));
+            inserted_file.content_hash = content_hash;
+            inserted_file.mime_type = mime_type;
+            inserted_file.size = inserted_file.data.len();
+            inserted_file.tags = file_tags;
+            if let Some(reader) = reader {
+                inserted_file.allowed_users.push((reader, Access::Read));
             }
+            groups::link(
+                &key,
+                std::mem::take(&mut inserted_file.groups)
+                    .into_iter()
+                    .map(|grp| grp.id),
+            );
+
+            quotas::add(&inserted_file, inserted_file.data.len() as u64);
+            blobs::store(
+                &inserted_file.content_hash,
+                std::mem::take(&mut inserted_file.data),
+            );
+            search::index(&inserted_file);
+            files_map.insert(key.clone(), inserted_file);
+            policy::remember(&key, context);
+
+            bucket::file_stored(&key);
+            uploaded_files.push((
+                key,
+                FileUploadResolveType::SuccessfullyUploaded,
+                "Successfully uploaded.".to_string(),
+            ));
         }
     });
 
     uploaded_files
 }
 
+// Whether the caller can move `file_id` to the trash. Files a `Restrict`
+// subscriber still references are kept, see `references`.
+fn check_delete(files: &HashMap<String, StoredFile>, file_id: &str) -> Result<(), String> {
+    let file = files.get(file_id).ok_or("StoredFile not found.")?;
+
+    if file.owner != msg_caller() {
+        return Err("Only the owner can delete this file.".to_string());
+    }
+    if let Some(canister) = references::restricted_by(file_id) {
+        return Err(format!("StoredFile is still used by {canister}."));
+    }
+
+    Ok(())
+}
+
+// Moves a file to the trash, returning it for the audit log.
+fn trash_file(
+    files: &mut HashMap<String, StoredFile>,
+    file_id: &str,
+) -> Result<StoredFile, String> {
+    check_delete(files, file_id)?;
+
+    // Subscribers hear about it once it's purged, as it can still be restored.
+    Ok(discard_file(files, file_id).expect("The file was checked above."))
+}
+
+// Moves a file to the trash without any check, see `trash_file`.
+fn discard_file(files: &mut HashMap<String, StoredFile>, file_id: &str) -> Option<StoredFile> {
+    let file = files.remove(file_id)?;
+    search::unindex(file_id);
+    trash::trash_file(file.clone());
+
+    Some(file)
+}
+
+fn record_deletion(endpoint: &str, deleted_files: &[StoredFile]) {
+    if deleted_files.is_empty() {
+        return;
+    }
+
+    audit::record(
+        endpoint,
+        deleted_files.iter().map(|f| f.id.clone()).collect(),
+        Some(
+            deleted_files
+                .iter()
+                .map(|f| format!("{} ({}, {} bytes)", f.name, f.mime_type, f.size))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        None,
+    );
+}
+
+// Moves the caller's files to the trash, skipping those `check_delete` keeps.
 #[ic_cdk::update]
 fn delete_files(file_ids: Vec<String>) -> usize {
-    let mut deleted_file_count: usize = 0;
-
-    for file_id in file_ids.iter() {
-        FILES.with_borrow_mut(|files_map: &mut HashMap<String, StoredFile>| {
-            if let Some(f) = files_map.get_mut(file_id) {
-                let principal: Principal = msg_caller();
-                if f.owner == principal {
-                    let was_deleted =
-                        FILES.with(|files| files.borrow_mut().remove(file_id).is_some());
-
-                    if was_deleted {
-                        deleted_file_count += 1;
-                    }
-                }
-            }
-        });
+    let deleted_files: Vec<StoredFile> = FILES.with_borrow_mut(|files_map| {
+        file_ids
+            .iter()
+            .filter_map(|file_id| trash_file(files_map, file_id).ok())
+            .collect()
+    });
+
+    record_deletion("delete_files", &deleted_files);
+
+    deleted_files.len()
+}
+
+// Names are unique inside folders, see `folders::is_name_taken`.
+fn check_rename(
+    files: &HashMap<String, StoredFile>,
+    file_id: &str,
+    new_file_name: &str,
+) -> Result<(), &'static str> {
+    let file = files.get(file_id).ok_or("StoredFile not found.")?;
+
+    if !has_file_permission(file, &Access::can_edit(), None) {
+        return Err("You are not authorized to change this file name.");
+    }
+
+    if file.parent_id.is_some()
+        && folders::is_name_taken(
+            files,
+            file.parent_id.as_deref(),
+            file.owner,
+            new_file_name,
+            Some(file_id),
+        )
+    {
+        return Err("A file with this name already exists in this folder.");
     }
 
-    deleted_file_count
+    Ok(())
+}
+
+fn rename_file(
+    files: &mut HashMap<String, StoredFile>,
+    file_id: &str,
+    new_file_name: String,
+) -> Result<(), &'static str> {
+    check_rename(files, file_id, &new_file_name)?;
+
+    let file = files.get_mut(file_id).expect("The file was checked above.");
+    file.name = new_file_name;
+    search::index(file);
+
+    Ok(())
 }
 
 #[ic_cdk::update]
 fn change_file_name(file_id: String, new_file_name: String) -> Result<&'static str, &'static str> {
-    FILES.with_borrow_mut(
-        |files: &mut HashMap<String, StoredFile>| match files.get_mut(&file_id) {
-            Some(file) => {
-                if !check_file_permission(file.clone(), Access::can_edit(), None) {
-                    return Err("You are not authorized to change this file name.");
-                }
+    FILES.with_borrow_mut(|files| rename_file(files, &file_id, new_file_name))?;
 
-                file.name = new_file_name;
-                Ok("StoredFile name changed successfully.")
-            }
-            None => Err("StoredFile not found."),
-        },
-    )
+    Ok("StoredFile name changed successfully.")
+}
+
+fn check_publish(file: &StoredFile, new_access: bool) -> Result<(), &'static str> {
+    if !has_file_permission(file, &Access::can_edit(), None) {
+        if new_access {
+            return Err("You are not authorized to publish this file.");
+        } else {
+            return Err("You are not authroized to unpublish this file.");
+        }
+    }
+
+    if new_access && encryption::is_encrypted(&file.id) {
+        return Err("Encrypted files can't be published.");
+    }
+
+    Ok(())
+}
+
+fn set_public(
+    files: &mut HashMap<String, StoredFile>,
+    file_id: &str,
+    new_access: bool,
+) -> Result<(), &'static str> {
+    let file = files.get_mut(file_id).ok_or("StoredFile not found.")?;
+    check_publish(file, new_access)?;
+
+    file.public = new_access;
+    search::index(file);
+    references::permissions_changed(&[file_id.to_string()]);
+
+    Ok(())
 }
 
 #[ic_cdk::update]
@@ -512,88 +732,161 @@ fn edit_file_public_access(
     file_id: String,
     new_access: bool,
 ) -> Result<&'static str, &'static str> {
-    FILES.with_borrow_mut(
-        |files: &mut HashMap<String, StoredFile>| match files.get_mut(&file_id) {
-            Some(file) => {
-                if !check_file_permission(file.clone(), Access::can_edit(), None) {
-                    if new_access {
-                        return Err("You are not authorized to publish this file.");
-                    } else {
-                        return Err("You are not authroized to unpublish this file.");
-                    }
-                }
+    FILES.with_borrow_mut(|files| set_public(files, &file_id, new_access))?;
 
-                file.public = new_access;
-                if new_access {
-                    Ok("The file has been published successfully.")
-                } else {
-                    Ok("The file has been unpublished successfully.")
-                }
-            }
-            None => Err("StoredFile not found."),
-        },
-    )
+    if new_access {
+        Ok("The file has been published successfully.")
+    } else {
+        Ok("The file has been unpublished successfully.")
+    }
 }
 
-#[ic_cdk::update]
-fn edit_allowed_users(file_id: String, new_accesses: Vec<(Principal, Access)>) -> usize {
+// Owners and admins share a file.
+fn check_share(file: &StoredFile) -> Result<(), String> {
+    let principal: Principal = msg_caller();
+
+    if file.owner != principal && !file.allowed_users.contains(&(principal, Access::Admin)) {
+        return Err("You are not authorized to share this file.".to_string());
+    }
+
+    Ok(())
+}
+
+// Returns how many users were added or removed.
+fn share_file(
+    files: &mut HashMap<String, StoredFile>,
+    file_id: &str,
+    new_accesses: &[(Principal, Access)],
+) -> Result<usize, String> {
     let mut updated_users: usize = 0;
 
-    FILES.with_borrow_mut(|files: &mut HashMap<String, StoredFile>| {
-        if let Some(file) = files.get_mut(&file_id) {
-            let principal: Principal = msg_caller();
+    let file = files.get_mut(file_id).ok_or("StoredFile not found.")?;
+    check_share(file)?;
+
+    let is_owner = file.owner == msg_caller();
+    let before = format!("{:?}", file.allowed_users);
+
+    let all_permissions = Access::all()
+        .iter()
+        .cloned()
+        .chain(std::iter::once(Access::Removed))
+        .collect::<Vec<_>>();
+
+    for (user, access) in new_accesses.iter() {
+        match access {
+            Access::Owner => {}
 
-            let is_owner = file.owner == principal;
-            let is_admin = !is_owner && file.allowed_users.contains(&(principal, Access::Admin));
+            Access::Removed => {
+                if file.allowed_users.contains(&(*user, Access::Admin)) && is_owner {
+                    file.allowed_users.retain(|(u, _)| u != user);
+                    updated_users += 1;
+                } else {
+                    file.allowed_users
+                        .retain(|(u, a)| !(u == user && a == access));
+                }
+            }
 
-            if !is_owner && !is_admin {
-                return;
+            Access::Admin => {
+                if is_owner && !file.allowed_users.contains(&(*user, Access::Admin)) {
+                    file.allowed_users.push((*user, Access::Admin));
+                    updated_users += 1;
+                }
             }
 
-            let all_permissions = Access::all()
-                .iter()
-                .cloned()
-                .chain(std::iter::once(Access::Removed))
-                .collect::<Vec<_>>();
-
-            for (user, access) in new_accesses.iter() {
-                match access {
-                    Access::Owner => {}
-
-                    Access::Removed => {
-                        if file.allowed_users.contains(&(*user, Access::Admin)) && is_owner {
-                            file.allowed_users.retain(|(u, _)| u != user);
-                            updated_users += 1;
-                        } else {
-                            file.allowed_users
-                                .retain(|(u, a)| !(u == user && a == access));
-                        }
-                    }
-
-                    Access::Admin => {
-                        if is_owner && !file.allowed_users.contains(&(*user, Access::Admin)) {
-                            file.allowed_users.push((*user, Access::Admin));
-                            updated_users += 1;
-                        }
-                    }
-
-                    _ => {
-                        // For Read/Write
-                        let already_has = all_permissions
-                            .iter()
-                            .any(|perm| file.allowed_users.contains(&(*user, perm.clone())));
-
-                        if !already_has {
-                            file.allowed_users.push((*user, access.clone()));
-                            updated_users += 1;
-                        }
-                    }
+            _ => {
+                // For Read/Write
+                let already_has = all_permissions
+                    .iter()
+                    .any(|perm| file.allowed_users.contains(&(*user, perm.clone())));
+
+                if !already_has {
+                    file.allowed_users.push((*user, access.clone()));
+                    updated_users += 1;
                 }
             }
         }
-    });
+    }
+
+    let after = format!("{:?}", file.allowed_users);
+    encryption::access_changed(file);
+    search::index(file);
+
+    if before != after {
+        references::permissions_changed(&[file_id.to_string()]);
+        audit::record(
+            "edit_allowed_users",
+            vec![file_id.to_string()],
+            Some(before),
+            Some(after),
+        );
+    }
+
+    Ok(updated_users)
+}
 
-    updated_users
+#[ic_cdk::update]
+fn edit_allowed_users(file_id: String, new_accesses: Vec<(Principal, Access)>) -> usize {
+    FILES
+        .with_borrow_mut(|files| share_file(files, &file_id, &new_accesses))
+        .unwrap_or(0)
+}
+
+// Buckets created by a `storage_index` get its id, see `bucket`.
+#[ic_cdk::init]
+fn init(index_canister_id: Option<Principal>) {
+    bucket::configure(index_canister_id);
+    uploads::start_cleanup_timer();
+    blobs::start_gc_timer();
+    share_links::start_expiry_timer();
+    trash::start_purge_timer();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    quotas::save();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(index_canister_id: Option<Principal>) {
+    quotas::restore();
+    bucket::configure(index_canister_id);
+    uploads::start_cleanup_timer();
+    blobs::start_gc_timer();
+    share_links::start_expiry_timer();
+    trash::start_purge_timer();
+}
+
+// Audit
+#[ic_cdk::query]
+fn get_audit_log(page: usize, per_page: usize) -> Result<PaginatorResponse<AuditEntry>, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can read the audit log.".to_string());
+    }
+
+    Ok(audit::page(page, per_page))
 }
 
 export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accesses_grant_those_ranked_below_them() {
+        assert!(Access::Owner.satisfies(&Access::Delete));
+        assert!(Access::Admin.satisfies(&Access::Write));
+        assert!(Access::Write.satisfies(&Access::Read));
+        assert!(!Access::Read.satisfies(&Access::Write));
+        assert!(!Access::Write.satisfies(&Access::Admin));
+    }
+
+    #[test]
+    fn public_and_delete_only_grant_themselves() {
+        assert!(Access::Public.satisfies(&Access::Read));
+        assert!(!Access::Public.satisfies(&Access::Write));
+        assert!(Access::Delete.satisfies(&Access::Delete));
+        assert!(!Access::Admin.satisfies(&Access::Delete));
+        assert!(!Access::Delete.satisfies(&Access::Read));
+    }
+}
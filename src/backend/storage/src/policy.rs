@@ -0,0 +1,402 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use serde::{Deserialize, Serialize};
+
+use utilities::audit;
+
+// Upload content policy. The type of uploaded content is detected from its
+// magic bytes and must agree with the declared `mime_type`; other types without
+// a signature, like plain text, are taken as declared, except images, video
+// and audio, which must have a known one. Uploads must also have one of their
+// context's allowed types and stay within its size limit, which controllers
+// can change. Uploads without a context follow the `General` policy.
+// `Encrypted` uploads are ciphertext, so their content can't be recognized and
+// is taken as declared. Files remember their context, so new versions are held
+// to it too.
+
+const MIB: u64 = 1024 * 1024;
+
+struct Signature {
+    // Bytes expected at each offset.
+    parts: &'static [(usize, &'static [u8])],
+    // The first one is what undeclared content is stored as.
+    types: &'static [&'static str],
+}
+
+impl Signature {
+    fn matches(&self, data: &[u8]) -> bool {
+        self.parts
+            .iter()
+            .all(|(offset, magic)| data.get(*offset..offset + magic.len()) == Some(*magic))
+    }
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        parts: &[(0, b"\x89PNG\r\n\x1a\n")],
+        types: &["image/png"],
+    },
+    Signature {
+        parts: &[(0, b"\xff\xd8\xff")],
+        types: &["image/jpeg", "image/jpg"],
+    },
+    Signature {
+        parts: &[(0, b"GIF8")],
+        types: &["image/gif"],
+    },
+    Signature {
+        parts: &[(0, b"RIFF"), (8, b"WEBP")],
+        types: &["image/webp"],
+    },
+    Signature {
+        parts: &[(0, b"RIFF"), (8, b"WAVE")],
+        types: &["audio/wav", "audio/x-wav", "audio/wave"],
+    },
+    Signature {
+        parts: &[(0, b"%PDF-")],
+        types: &["application/pdf"],
+    },
+    Signature {
+        parts: &[(0, b"PK\x03\x04")],
+        types: &[
+            "application/zip",
+            "application/x-zip-compressed",
+            "application/epub+zip",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+            "application/vnd.oasis.opendocument.text",
+        ],
+    },
+    Signature {
+        parts: &[(0, b"\x1f\x8b")],
+        types: &["application/gzip", "application/x-gzip"],
+    },
+    Signature {
+        parts: &[(4, b"ftyp")],
+        types: &["video/mp4", "video/quicktime", "audio/mp4", "audio/x-m4a"],
+    },
+    Signature {
+        parts: &[(0, b"\x1a\x45\xdf\xa3")],
+        types: &["video/webm", "audio/webm", "video/x-matroska"],
+    },
+    Signature {
+        parts: &[(0, b"OggS")],
+        types: &["audio/ogg", "video/ogg", "application/ogg"],
+    },
+    Signature {
+        parts: &[(0, b"ID3")],
+        types: &["audio/mpeg", "audio/mp3"],
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Serialize, Deserialize)]
+pub enum UploadContext {
+    General,
+    ProfilePicture,
+    PostMedia,
+    CourseResource,
+    CompetitionSubmission,
+    Encrypted,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct UploadPolicy {
+    // Exact types, `type/*` for a whole family or `*/*` for anything.
+    pub allowed_types: Vec<String>,
+    pub max_size: u64,
+}
+
+fn policy(allowed_types: &[&str], max_size: u64) -> UploadPolicy {
+    UploadPolicy {
+        allowed_types: allowed_types.iter().map(|t| t.to_string()).collect(),
+        max_size,
+    }
+}
+
+fn default_policies() -> HashMap<UploadContext, UploadPolicy> {
+    HashMap::from([
+        (UploadContext::General, policy(&["*/*"], 500 * MIB)),
+        (UploadContext::Encrypted, policy(&["*/*"], 500 * MIB)),
+        (
+            UploadContext::ProfilePicture,
+            policy(
+                &["image/png", "image/jpeg", "image/gif", "image/webp"],
+                5 * MIB,
+            ),
+        ),
+        (
+            UploadContext::PostMedia,
+            policy(
+                &[
+                    "image/png",
+                    "image/jpeg",
+                    "image/gif",
+                    "image/webp",
+                    "video/mp4",
+                    "video/webm",
+                    "video/quicktime",
+                    "audio/mpeg",
+                    "audio/ogg",
+                    "audio/wav",
+                ],
+                100 * MIB,
+            ),
+        ),
+        (
+            UploadContext::CourseResource,
+            policy(
+                &[
+                    "application/pdf",
+                    "application/zip",
+                    "application/epub+zip",
+                    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+                    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+                    "text/plain",
+                    "text/markdown",
+                    "image/*",
+                    "video/*",
+                    "audio/*",
+                ],
+                500 * MIB,
+            ),
+        ),
+        (
+            UploadContext::CompetitionSubmission,
+            policy(
+                &[
+                    "application/zip",
+                    "application/gzip",
+                    "application/pdf",
+                    "text/plain",
+                    "image/png",
+                    "image/jpeg",
+                ],
+                50 * MIB,
+            ),
+        ),
+    ])
+}
+
+thread_local! {
+    static POLICIES: RefCell<HashMap<UploadContext, UploadPolicy>> = RefCell::new(default_policies());
+    // Contexts of the files uploaded for anything but `General`.
+    static FILE_CONTEXTS: RefCell<HashMap<String, UploadContext>> = RefCell::new(HashMap::new());
+}
+
+// `image/png; charset=...` is `image/png`.
+fn base_type(mime_type: &str) -> String {
+    mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// The type `data` is stored as. Content with a known signature must be
+// declared as one of its types, and types with a signature must have it.
+pub fn detect(declared: &str, data: &[u8]) -> Result<String, String> {
+    let declared_base = base_type(declared);
+    let declared_base = declared_base.as_str();
+
+    match SIGNATURES.iter().find(|signature| signature.matches(data)) {
+        Some(signature) if declared_base.is_empty() => Ok(signature.types[0].to_string()),
+        Some(signature) if signature.types.contains(&declared_base) => Ok(declared.to_string()),
+        Some(signature) => Err(format!(
+            "The content is {}, not {declared_base}.",
+            signature.types[0]
+        )),
+        None if SIGNATURES
+            .iter()
+            .any(|signature| signature.types.contains(&declared_base)) =>
+        {
+            Err(format!("The content is not {declared_base}."))
+        }
+        None if ["image/", "video/", "audio/"]
+            .iter()
+            .any(|family| declared_base.starts_with(family)) =>
+        {
+            Err(format!("{declared_base} content can't be recognized."))
+        }
+        None if declared_base.is_empty() => Ok("application/octet-stream".to_string()),
+        None => Ok(declared.to_string()),
+    }
+}
+
+// Whether `context` takes `size` bytes of `mime_type`.
+pub fn check(context: Option<UploadContext>, mime_type: &str, size: u64) -> Result<(), String> {
+    let context = context.unwrap_or(UploadContext::General);
+
+    let policy = POLICIES.with_borrow(|policies| policies.get(&context).cloned());
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+
+    if size > policy.max_size {
+        return Err(format!(
+            "{context:?} uploads are limited to {} bytes.",
+            policy.max_size
+        ));
+    }
+
+    let mime_type = base_type(mime_type);
+    let allowed = policy
+        .allowed_types
+        .iter()
+        .any(|allowed| match allowed.strip_suffix("/*") {
+            Some("*") => true,
+            Some(family) => mime_type.split('/').next() == Some(family),
+            None => *allowed == mime_type,
+        });
+
+    if !allowed {
+        return Err(format!(
+            "{mime_type} is not allowed for {context:?} uploads."
+        ));
+    }
+
+    Ok(())
+}
+
+// The type content starting with `head` is stored as in `context`.
+pub fn content_type(
+    context: Option<UploadContext>,
+    declared: &str,
+    head: &[u8],
+) -> Result<String, String> {
+    match context {
+        Some(UploadContext::Encrypted) if base_type(declared).is_empty() => {
+            Ok("application/octet-stream".to_string())
+        }
+        Some(UploadContext::Encrypted) => Ok(declared.to_string()),
+        _ => detect(declared, head),
+    }
+}
+
+// Finds the type of `data` and checks it against the context's policy.
+pub fn admit(
+    context: Option<UploadContext>,
+    declared: &str,
+    data: &[u8],
+) -> Result<String, String> {
+    let mime_type = content_type(context, declared, data)?;
+    check(context, &mime_type, data.len() as u64)?;
+
+    Ok(mime_type)
+}
+
+pub fn remember(file_id: &str, context: Option<UploadContext>) {
+    match context {
+        None | Some(UploadContext::General) => {}
+        Some(context) => {
+            FILE_CONTEXTS.with_borrow_mut(|contexts| contexts.insert(file_id.to_string(), context));
+        }
+    }
+}
+
+pub fn context_of(file_id: &str) -> Option<UploadContext> {
+    FILE_CONTEXTS.with_borrow(|contexts| contexts.get(file_id).copied())
+}
+
+pub fn forget(file_id: &str) {
+    FILE_CONTEXTS.with_borrow_mut(|contexts| contexts.remove(file_id));
+}
+
+#[ic_cdk::query]
+fn get_upload_policies() -> Vec<(UploadContext, UploadPolicy)> {
+    POLICIES.with_borrow(|policies| {
+        policies
+            .iter()
+            .map(|(context, policy)| (*context, policy.clone()))
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn set_upload_policy(context: UploadContext, policy: UploadPolicy) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure upload policies.".to_string());
+    }
+
+    let describe = |policy: &UploadPolicy| {
+        format!(
+            "{} up to {} bytes",
+            policy.allowed_types.join(", "),
+            policy.max_size
+        )
+    };
+
+    let before = POLICIES.with_borrow_mut(|policies| policies.insert(context, policy.clone()));
+    audit::record(
+        "set_upload_policy",
+        vec![format!("{context:?}")],
+        before.as_ref().map(describe),
+        Some(describe(&policy)),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n rest of the image";
+
+    #[test]
+    fn detect_takes_signed_content_declared_as_one_of_its_types() {
+        assert_eq!(detect("image/png", PNG), Ok("image/png".to_string()));
+        assert_eq!(
+            detect("image/jpg", b"\xff\xd8\xff\xe0"),
+            Ok("image/jpg".to_string())
+        );
+        assert_eq!(detect("", PNG), Ok("image/png".to_string()));
+    }
+
+    #[test]
+    fn detect_refuses_content_that_disagrees_with_its_type() {
+        assert!(detect("application/pdf", PNG).is_err());
+        assert!(detect("image/png", b"plain text").is_err());
+        assert!(detect("text/plain", PNG).is_err());
+    }
+
+    #[test]
+    fn detect_refuses_media_without_a_known_signature() {
+        assert!(detect("image/svg+xml", b"<svg></svg>").is_err());
+        assert!(detect("image/x-foo", b"pixels").is_err());
+        assert!(detect("video/x-foo", b"frames").is_err());
+        assert!(detect("audio/x-foo", b"samples").is_err());
+    }
+
+    #[test]
+    fn encrypted_content_is_taken_as_declared() {
+        let encrypted = Some(UploadContext::Encrypted);
+
+        assert_eq!(
+            content_type(encrypted, "image/png", b"ciphertext"),
+            Ok("image/png".to_string())
+        );
+        assert_eq!(
+            content_type(encrypted, "", PNG),
+            Ok("application/octet-stream".to_string())
+        );
+        assert!(content_type(None, "image/png", b"ciphertext").is_err());
+    }
+
+    #[test]
+    fn detect_takes_unsigned_types_as_declared() {
+        assert_eq!(
+            detect("text/plain; charset=utf-8", b"hello"),
+            Ok("text/plain; charset=utf-8".to_string())
+        );
+        assert_eq!(
+            detect("", b"hello"),
+            Ok("application/octet-stream".to_string())
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use utilities::audit;
+use utilities::ledger::{Account, IcrcLedger};
+use utilities::memory::{self, TOP_UPS_MEMORY_ID};
+use utilities::rewards::{self, Payout, PayoutBook, pay};
+
+use crate::{FILES, StoredFile, analytics, content_len, groups};
+
+// Storage quotas. Every owner has a byte and file-count limit, the default one
+// unless a controller set their own, plus whatever they bought in CRY. Groups
+// have no limit of their own, their usage is summed from their files.
+
+pub const GIB: u64 = 1024 * 1024 * 1024;
+
+// Price of one extra GiB, in CRY base units.
+pub const TOP_UP_PRICE_PER_GIB: u64 = 100_000_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct Usage {
+    pub bytes_used: u64,
+    pub file_count: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_bytes: u64,
+    pub max_files: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub usage: Usage,
+    pub quota: Quota,
+    pub purchased_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum QuotaError {
+    BytesExceeded {
+        bytes_used: u64,
+        requested: u64,
+        max_bytes: u64,
+    },
+    FilesExceeded {
+        max_files: u64,
+    },
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaError::BytesExceeded {
+                bytes_used,
+                requested,
+                max_bytes,
+            } => write!(
+                f,
+                "Storage quota exceeded: {bytes_used} of {max_bytes} bytes used, {requested} more requested."
+            ),
+            QuotaError::FilesExceeded { max_files } => {
+                write!(f, "File quota exceeded: at most {max_files} files allowed.")
+            }
+        }
+    }
+}
+
+thread_local! {
+    static USAGE: RefCell<HashMap<Principal, Usage>> = RefCell::new(HashMap::new());
+    static DEFAULT_QUOTA: Cell<Quota> = const {
+        Cell::new(Quota {
+            max_bytes: GIB,
+            max_files: 10_000,
+        })
+    };
+    static QUOTAS: RefCell<HashMap<Principal, Quota>> = RefCell::new(HashMap::new());
+    static PURCHASED_BYTES: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    static PAYOUTS: RefCell<PayoutBook> = RefCell::new(PayoutBook::default());
+    // Top-ups whose bytes were added to `PURCHASED_BYTES`.
+    static CREDITED_TOP_UPS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+// Bought bytes were paid for, so they and the payouts behind them are saved
+// in `pre_upgrade` and loaded back in `post_upgrade`.
+pub fn save() {
+    rewards::save_book(&PAYOUTS);
+    PURCHASED_BYTES.with_borrow(|purchased| {
+        CREDITED_TOP_UPS
+            .with_borrow(|credited| memory::save(TOP_UPS_MEMORY_ID, &(purchased, credited)))
+    });
+}
+
+pub fn restore() {
+    rewards::restore_book(&PAYOUTS);
+    if let Some((purchased, credited)) = memory::restore(TOP_UPS_MEMORY_ID) {
+        PURCHASED_BYTES.set(purchased);
+        CREDITED_TOP_UPS.set(credited);
+    }
+}
+
+fn purchased_bytes(owner: Principal) -> u64 {
+    PURCHASED_BYTES.with_borrow(|purchased| purchased.get(&owner).copied().unwrap_or(0))
+}
+
+pub fn quota_of(owner: Principal) -> Quota {
+    let quota = QUOTAS
+        .with_borrow(|quotas| quotas.get(&owner).copied())
+        .unwrap_or_else(|| DEFAULT_QUOTA.get());
+
+    Quota {
+        max_bytes: quota.max_bytes.saturating_add(purchased_bytes(owner)),
+        ..quota
+    }
+}
+
+pub fn all_usage() -> Vec<(Principal, Usage)> {
+    USAGE.with_borrow(|usage| {
+        usage
+            .iter()
+            .map(|(owner, usage)| (*owner, *usage))
+            .collect()
+    })
+}
+
+pub fn usage_of(owner: Principal) -> Usage {
+    USAGE.with_borrow(|usage| usage.get(&owner).copied().unwrap_or_default())
+}
+
+// Whether `owner` can store one more file of `bytes`.
+pub fn check(owner: Principal, bytes: u64) -> Result<(), QuotaError> {
+    check_files(owner, 1, bytes)
+}
+
+// Whether `owner` can store `files` more files of `bytes` in total.
+pub fn check_files(owner: Principal, files: u64, bytes: u64) -> Result<(), QuotaError> {
+    let quota = quota_of(owner);
+
+    if usage_of(owner).file_count.saturating_add(files) > quota.max_files {
+        return Err(QuotaError::FilesExceeded {
+            max_files: quota.max_files,
+        });
+    }
+
+    check_bytes(owner, bytes)
+}
+
+// Whether `owner` can store `bytes` more without adding a file.
+pub fn check_bytes(owner: Principal, bytes: u64) -> Result<(), QuotaError> {
+    let quota = quota_of(owner);
+    let usage = usage_of(owner);
+
+    if usage.bytes_used.saturating_add(bytes) > quota.max_bytes {
+        return Err(QuotaError::BytesExceeded {
+            bytes_used: usage.bytes_used,
+            requested: bytes,
+            max_bytes: quota.max_bytes,
+        });
+    }
+
+    Ok(())
+}
+
+fn apply(usage: &mut Usage, bytes: u64, files: u64, added: bool) {
+    if added {
+        usage.bytes_used += bytes;
+        usage.file_count += files;
+    } else {
+        usage.bytes_used = usage.bytes_used.saturating_sub(bytes);
+        usage.file_count = usage.file_count.saturating_sub(files);
+    }
+}
+
+fn track(file: &StoredFile, bytes: u64, files: u64, added: bool) {
+    USAGE.with_borrow_mut(|usage| apply(usage.entry(file.owner).or_default(), bytes, files, added));
+    analytics::usage_changed(bytes, files, added);
+}
+
+pub fn add(file: &StoredFile, bytes: u64) {
+    track(file, bytes, 1, true);
+}
+
+pub fn remove(file: &StoredFile, bytes: u64) {
+    track(file, bytes, 1, false);
+}
+
+// Bytes an existing file holds besides its content, such as older versions.
+pub fn add_bytes(file: &StoredFile, bytes: u64) {
+    track(file, bytes, 0, true);
+}
+
+pub fn remove_bytes(file: &StoredFile, bytes: u64) {
+    track(file, bytes, 0, false);
+}
+
+fn is_controller() -> bool {
+    ic_cdk::api::is_controller(&msg_caller())
+}
+
+fn report(owner: Principal) -> UsageReport {
+    UsageReport {
+        usage: usage_of(owner),
+        quota: quota_of(owner),
+        purchased_bytes: purchased_bytes(owner),
+    }
+}
+
+// The caller's usage, or any principal's for controllers.
+#[ic_cdk::query]
+fn get_usage(principal: Option<Principal>) -> Result<UsageReport, String> {
+    let owner = principal.unwrap_or(msg_caller());
+
+    if owner != msg_caller() && !is_controller() {
+        return Err("Only controllers can read other principals' usage.".to_string());
+    }
+
+    Ok(report(owner))
+}
+
+#[ic_cdk::query]
+fn get_group_usage(group_id: String) -> Result<Usage, String> {
+    if !groups::is_member(&group_id, msg_caller()) && !is_controller() {
+        return Err("You do not have permission to view this group.".to_string());
+    }
+
+    let file_ids = groups::files_of(&group_id);
+
+    Ok(FILES.with_borrow(|files| {
+        let mut usage = Usage::default();
+        for file in file_ids.iter().filter_map(|id| files.get(id)) {
+            apply(&mut usage, content_len(file), 1, true);
+        }
+        usage
+    }))
+}
+
+#[ic_cdk::update]
+fn set_default_quota(quota: Quota) -> Result<(), String> {
+    if !is_controller() {
+        return Err("Only controllers can configure quotas.".to_string());
+    }
+
+    let before = DEFAULT_QUOTA.replace(quota);
+    audit::record(
+        "set_default_quota",
+        vec![],
+        Some(format!("{before:?}")),
+        Some(format!("{quota:?}")),
+    );
+
+    Ok(())
+}
+
+// `None` puts `principal` back on the default quota.
+#[ic_cdk::update]
+fn set_quota(principal: Principal, quota: Option<Quota>) -> Result<(), String> {
+    if !is_controller() {
+        return Err("Only controllers can configure quotas.".to_string());
+    }
+
+    let before = QUOTAS.with_borrow_mut(|quotas| match quota {
+        Some(quota) => quotas.insert(principal, quota),
+        None => quotas.remove(&principal),
+    });
+    audit::record(
+        "set_quota",
+        vec![principal.to_text()],
+        Some(format!("{before:?}")),
+        Some(format!("{quota:?}")),
+    );
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_ledger_canister(ledger_canister_id: Principal) -> Result<(), String> {
+    if !is_controller() {
+        return Err("Only controllers can configure the ledger.".to_string());
+    }
+
+    utilities::ledger::configure(ledger_canister_id);
+    Ok(())
+}
+
+// Buys `gib` extra GiB for the caller at `TOP_UP_PRICE_PER_GIB`. The caller
+// must first `icrc2_approve` this canister for the price plus the ledger fee.
+// `top_up_key` is chosen by the caller: calling again with the same key
+// retries the same purchase instead of buying again.
+#[ic_cdk::update]
+async fn top_up_quota(gib: u64, top_up_key: String) -> Result<UsageReport, String> {
+    if gib == 0 {
+        return Err("Top-up must be at least one GiB.".to_string());
+    }
+
+    if top_up_key.is_empty() {
+        return Err("A top-up key is required.".to_string());
+    }
+
+    let principal = msg_caller();
+    let top_up_id = format!("{principal}:{top_up_key}");
+    let payout_id = format!("quota-top-up:{top_up_id}");
+    let price = gib.saturating_mul(TOP_UP_PRICE_PER_GIB);
+
+    let reused = PAYOUTS.with_borrow(|book| {
+        book.get(&payout_id)
+            .is_some_and(|payout| payout.amount != price)
+    });
+    if reused {
+        return Err("This top-up key was already used for another amount.".to_string());
+    }
+
+    let ledger = IcrcLedger::configured().map_err(|_| "Ledger is not configured.")?;
+
+    let payout = pay(
+        &PAYOUTS,
+        &ledger,
+        Payout::quota_top_up(
+            top_up_id,
+            gib,
+            Account::of(principal),
+            Account::of(ic_cdk::api::canister_self()),
+            price,
+            ic_cdk::api::time(),
+        ),
+        ic_cdk::api::time(),
+    )
+    .await;
+
+    if !payout.is_paid() {
+        return Err(format!("Top-up could not be paid: {:?}", payout.status));
+    }
+
+    if CREDITED_TOP_UPS.with_borrow_mut(|credited| credited.insert(payout_id)) {
+        PURCHASED_BYTES.with_borrow_mut(|purchased| {
+            let bytes = purchased.entry(principal).or_default();
+            *bytes = bytes.saturating_add(gib.saturating_mul(GIB));
+        });
+    }
+
+    Ok(report(principal))
+}
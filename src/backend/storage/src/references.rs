@@ -0,0 +1,273 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_cdk::call::Call;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use utilities::audit;
+
+use crate::{Access, FILES, has_file_permission};
+
+// File references held by other canisters. Subscriber canisters, registered by
+// a controller, record which of their entities use a file, such as the post a
+// media belongs to. Deleting a file referenced by a `Restrict` subscriber is
+// refused; with `Cascade` the file goes and, once it's purged from the trash,
+// the subscriber is told which of its entities lost it. Subscribers also hear
+// about permission changes on files they reference. Events are delivered to
+// their `storage_event` method, fire and forget.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum DeletePolicy {
+    Restrict,
+    Cascade,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Subscriber {
+    pub canister: Principal,
+    pub on_delete: DeletePolicy,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Serialize, Deserialize)]
+pub struct FileReference {
+    pub canister: Principal,
+    pub entity: String,
+}
+
+// Entities are those of the receiving canister.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub enum StorageEvent {
+    FileDeleted {
+        file_id: String,
+        entities: Vec<String>,
+    },
+    PermissionsChanged {
+        file_id: String,
+        entities: Vec<String>,
+    },
+}
+
+thread_local! {
+    static SUBSCRIBERS: RefCell<HashMap<Principal, DeletePolicy>> = RefCell::new(HashMap::new());
+    static REFERENCES: RefCell<HashMap<String, BTreeSet<FileReference>>> = RefCell::new(HashMap::new());
+}
+
+fn deliver(canister: Principal, event: StorageEvent) {
+    if let Err(err) = Call::unbounded_wait(canister, "storage_event")
+        .with_arg(&event)
+        .oneway()
+    {
+        ic_cdk::println!("Failed to deliver a storage event to {canister}: {err}");
+    }
+}
+
+// The entities of each subscriber referencing `file_id`.
+fn entities_by_canister(file_id: &str) -> BTreeMap<Principal, Vec<String>> {
+    let references = REFERENCES.with_borrow(|refs| refs.get(file_id).cloned().unwrap_or_default());
+
+    let mut entities: BTreeMap<Principal, Vec<String>> = BTreeMap::new();
+    for reference in references {
+        entities
+            .entry(reference.canister)
+            .or_default()
+            .push(reference.entity);
+    }
+
+    entities
+}
+
+// The subscriber whose references keep `file_id` from being deleted.
+pub fn restricted_by(file_id: &str) -> Option<Principal> {
+    let canisters = entities_by_canister(file_id).into_keys();
+
+    SUBSCRIBERS.with_borrow(|subscribers| {
+        canisters
+            .into_iter()
+            .find(|canister| subscribers.get(canister) == Some(&DeletePolicy::Restrict))
+    })
+}
+
+// Drops the references to a deleted file and tells their subscribers.
+pub fn file_deleted(file_id: &str) {
+    let entities = entities_by_canister(file_id);
+    REFERENCES.with_borrow_mut(|refs| refs.remove(file_id));
+
+    for (canister, entities) in entities {
+        deliver(
+            canister,
+            StorageEvent::FileDeleted {
+                file_id: file_id.to_string(),
+                entities,
+            },
+        );
+    }
+}
+
+pub fn permissions_changed(file_ids: &[String]) {
+    for file_id in file_ids {
+        for (canister, entities) in entities_by_canister(file_id) {
+            deliver(
+                canister,
+                StorageEvent::PermissionsChanged {
+                    file_id: file_id.clone(),
+                    entities,
+                },
+            );
+        }
+    }
+}
+
+fn require_controller() -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can manage subscribers.".to_string());
+    }
+
+    Ok(())
+}
+
+pub fn is_subscriber(canister: Principal) -> bool {
+    SUBSCRIBERS.with_borrow(|subscribers| subscribers.contains_key(&canister))
+}
+
+fn require_subscriber() -> Result<Principal, String> {
+    let caller = msg_caller();
+
+    if !is_subscriber(caller) {
+        return Err("Only subscriber canisters can reference files.".to_string());
+    }
+
+    Ok(caller)
+}
+
+#[ic_cdk::update]
+fn register_subscriber(canister: Principal, on_delete: DeletePolicy) -> Result<(), String> {
+    require_controller()?;
+
+    let before = SUBSCRIBERS.with_borrow_mut(|subscribers| subscribers.insert(canister, on_delete));
+    audit::record(
+        "register_subscriber",
+        vec![canister.to_text()],
+        before.map(|policy| format!("{policy:?}")),
+        Some(format!("{on_delete:?}")),
+    );
+
+    Ok(())
+}
+
+// Also drops the references the canister held.
+#[ic_cdk::update]
+fn unregister_subscriber(canister: Principal) -> Result<(), String> {
+    require_controller()?;
+
+    let Some(before) = SUBSCRIBERS.with_borrow_mut(|subscribers| subscribers.remove(&canister))
+    else {
+        return Err("Subscriber not found.".to_string());
+    };
+
+    REFERENCES.with_borrow_mut(|refs| {
+        refs.values_mut()
+            .for_each(|references| references.retain(|r| r.canister != canister));
+        refs.retain(|_, references| !references.is_empty());
+    });
+
+    audit::record(
+        "unregister_subscriber",
+        vec![canister.to_text()],
+        Some(format!("{before:?}")),
+        None,
+    );
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_subscribers() -> Result<Vec<Subscriber>, String> {
+    require_controller()?;
+
+    Ok(SUBSCRIBERS.with_borrow(|subscribers| {
+        subscribers
+            .iter()
+            .map(|(canister, on_delete)| Subscriber {
+                canister: *canister,
+                on_delete: *on_delete,
+            })
+            .collect()
+    }))
+}
+
+// Records that the caller's `entity`, acting for `user`, uses `file_ids`. Files
+// `user` can't read are skipped like unknown ones, so an entity can't pin the
+// files of others.
+#[ic_cdk::update]
+fn add_file_references(
+    file_ids: Vec<String>,
+    entity: String,
+    user: Principal,
+) -> Result<usize, String> {
+    let canister = require_subscriber()?;
+    let reference = FileReference { canister, entity };
+
+    let existing: Vec<String> = FILES.with_borrow(|files| {
+        file_ids
+            .into_iter()
+            .filter(|id| {
+                files
+                    .get(id)
+                    .is_some_and(|file| has_file_permission(file, &[Access::Read], Some(user)))
+            })
+            .collect()
+    });
+
+    Ok(REFERENCES.with_borrow_mut(|refs| {
+        existing
+            .into_iter()
+            .filter(|file_id| {
+                refs.entry(file_id.clone())
+                    .or_default()
+                    .insert(reference.clone())
+            })
+            .count()
+    }))
+}
+
+#[ic_cdk::update]
+fn remove_file_references(file_ids: Vec<String>, entity: String) -> Result<usize, String> {
+    let canister = require_subscriber()?;
+    let reference = FileReference { canister, entity };
+
+    Ok(REFERENCES.with_borrow_mut(|refs| {
+        let removed = file_ids
+            .iter()
+            .filter(|file_id| {
+                refs.get_mut(*file_id)
+                    .is_some_and(|references| references.remove(&reference))
+            })
+            .count();
+        refs.retain(|_, references| !references.is_empty());
+
+        removed
+    }))
+}
+
+// Who uses a file, for its admins and controllers.
+#[ic_cdk::query]
+fn get_file_references(file_id: String) -> Result<Vec<FileReference>, String> {
+    FILES.with_borrow(|files| {
+        let file = files.get(&file_id).ok_or("StoredFile not found.")?;
+
+        if !has_file_permission(file, &[Access::Admin], None)
+            && !ic_cdk::api::is_controller(&msg_caller())
+        {
+            return Err("You are not authorized to see who uses this file.".to_string());
+        }
+
+        Ok(REFERENCES.with_borrow(|refs| {
+            refs.get(&file_id)
+                .map(|references| references.iter().cloned().collect())
+                .unwrap_or_default()
+        }))
+    })
+}
@@ -0,0 +1,394 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use paginator::{Filter, Operator, Paginator, PaginatorResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::{Access, FILES, FileMetadata, StoredFile, folders, groups, has_file_permission};
+
+// File search. Every write to a file's name, owner, type, public flag,
+// `allowed_users`, folder or tags updates the indexes below, so `search_files`
+// finds its candidates through them: the files matching the name words, owner,
+// type, tags and upload date asked for, or every file the caller can reach
+// when the query asks for none of these. Only candidates are read from
+// `FILES`, checked for access and run through the remaining filters.
+
+#[derive(Clone, Copy, Debug, CandidType, Serialize, Deserialize)]
+pub enum SortField {
+    Name,
+    Size,
+    MimeType,
+    UploadedAt,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct FileSort {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct FileQuery {
+    // Words the name must contain, each as the start of a word in it.
+    pub text: Option<String>,
+    // On any `FileMetadata` field, or on tags and metadata as `tags` describes.
+    // `uploaded_at` takes dates like `2025-01-31` or `2025-01-31 14:00` with
+    // the comparison operators.
+    pub filters: Vec<Filter>,
+    // Newest first when missing.
+    pub sort: Option<FileSort>,
+    pub page: usize,
+    pub per_page: usize,
+}
+
+// What a file was indexed under, to take it out again.
+struct Indexed {
+    words: BTreeSet<String>,
+    owner: Principal,
+    mime_type: String,
+    date: String,
+    public: bool,
+    grantees: BTreeSet<Principal>,
+    parent_id: Option<String>,
+    tags: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct Indexes {
+    files: HashMap<String, Indexed>,
+    by_word: BTreeMap<String, BTreeSet<String>>,
+    by_owner: BTreeMap<Principal, BTreeSet<String>>,
+    by_type: BTreeMap<String, BTreeSet<String>>,
+    by_date: BTreeSet<(String, String)>,
+    public: BTreeSet<String>,
+    by_grantee: BTreeMap<Principal, BTreeSet<String>>,
+    by_parent: BTreeMap<String, BTreeSet<String>>,
+    by_tag: BTreeMap<String, BTreeSet<String>>,
+}
+
+thread_local! {
+    static INDEXES: RefCell<Indexes> = RefCell::new(Indexes::default());
+}
+
+fn words(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+fn base_type(mime_type: &str) -> String {
+    mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// `now()` writes hours without padding, `9:05:00.0`, which doesn't sort as
+// text. Pads them so dates compare in order.
+fn date_key(date: &str) -> String {
+    let date = date.trim();
+    let Some((day, time)) = date.split_once(' ') else {
+        return date.to_string();
+    };
+    let time = time.split(' ').next().unwrap_or_default();
+
+    match time.split_once(':') {
+        Some((hour, rest)) => format!("{day} {hour:0>2}:{rest}"),
+        None => format!("{day} {time:0>2}"),
+    }
+}
+
+fn insert<K: Ord>(index: &mut BTreeMap<K, BTreeSet<String>>, key: K, file_id: &str) {
+    index.entry(key).or_default().insert(file_id.to_string());
+}
+
+fn remove<K: Ord>(index: &mut BTreeMap<K, BTreeSet<String>>, key: &K, file_id: &str) {
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(file_id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+// Takes a file out of the indexes, when it leaves `FILES`.
+pub fn unindex(file_id: &str) {
+    INDEXES.with_borrow_mut(|indexes| {
+        let Some(indexed) = indexes.files.remove(file_id) else {
+            return;
+        };
+
+        for word in &indexed.words {
+            remove(&mut indexes.by_word, word, file_id);
+        }
+        remove(&mut indexes.by_owner, &indexed.owner, file_id);
+        remove(&mut indexes.by_type, &indexed.mime_type, file_id);
+        indexes.by_date.remove(&(indexed.date, file_id.to_string()));
+        if indexed.public {
+            indexes.public.remove(file_id);
+        }
+        for grantee in &indexed.grantees {
+            remove(&mut indexes.by_grantee, grantee, file_id);
+        }
+        if let Some(parent_id) = &indexed.parent_id {
+            remove(&mut indexes.by_parent, parent_id, file_id);
+        }
+        for tag in &indexed.tags {
+            remove(&mut indexes.by_tag, tag, file_id);
+        }
+    });
+}
+
+// (Re)indexes a file, after it entered `FILES` or one of its indexed fields
+// changed.
+pub fn index(file: &StoredFile) {
+    unindex(&file.id);
+
+    let indexed = Indexed {
+        words: words(&file.name),
+        owner: file.owner,
+        mime_type: base_type(&file.mime_type),
+        date: date_key(&file.uploaded_at),
+        public: file.public,
+        grantees: file
+            .allowed_users
+            .iter()
+            .filter(|(_, access)| *access != Access::Removed)
+            .map(|(user, _)| *user)
+            .collect(),
+        parent_id: file.parent_id.clone(),
+        tags: file.tags.iter().cloned().collect(),
+    };
+
+    INDEXES.with_borrow_mut(|indexes| {
+        let file_id = file.id.as_str();
+
+        for word in &indexed.words {
+            insert(&mut indexes.by_word, word.clone(), file_id);
+        }
+        insert(&mut indexes.by_owner, indexed.owner, file_id);
+        insert(&mut indexes.by_type, indexed.mime_type.clone(), file_id);
+        indexes
+            .by_date
+            .insert((indexed.date.clone(), file_id.to_string()));
+        if indexed.public {
+            indexes.public.insert(file_id.to_string());
+        }
+        for grantee in &indexed.grantees {
+            insert(&mut indexes.by_grantee, *grantee, file_id);
+        }
+        if let Some(parent_id) = &indexed.parent_id {
+            insert(&mut indexes.by_parent, parent_id.clone(), file_id);
+        }
+        for tag in &indexed.tags {
+            insert(&mut indexes.by_tag, tag.clone(), file_id);
+        }
+
+        indexes.files.insert(file.id.clone(), indexed);
+    });
+}
+
+// Files `principal` may reach: owned, shared directly, public, or through a
+// group or folder. Access is checked on each of them afterwards.
+fn reachable(indexes: &Indexes, principal: Principal) -> BTreeSet<String> {
+    let mut ids: BTreeSet<String> = indexes.public.clone();
+
+    for index in [&indexes.by_owner, &indexes.by_grantee] {
+        if let Some(owned) = index.get(&principal) {
+            ids.extend(owned.iter().cloned());
+        }
+    }
+
+    ids.extend(groups::files_for(principal));
+
+    for folder_id in folders::reachable(principal) {
+        if let Some(files) = indexes.by_parent.get(&folder_id) {
+            ids.extend(files.iter().cloned());
+        }
+    }
+
+    ids
+}
+
+// Files with a word starting with `prefix`.
+fn with_word(indexes: &Indexes, prefix: &str) -> BTreeSet<String> {
+    indexes
+        .by_word
+        .range(prefix.to_string()..)
+        .take_while(|(word, _)| word.starts_with(prefix))
+        .flat_map(|(_, ids)| ids.iter().cloned())
+        .collect()
+}
+
+fn with_date(indexes: &Indexes, filter: &Filter) -> Result<BTreeSet<String>, String> {
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let value = date_key(&filter.value);
+    // A date covers every time it starts: `2025-01-31` is the whole day.
+    // Keys from `start` sort before all of them, keys from `end` after.
+    let start = |date: &str| (date.to_string(), String::new());
+    let end = |date: &str| (format!("{date}{}", char::MAX), String::new());
+
+    let (from, to) = match filter.operator {
+        Operator::GREATER_THAN => (Excluded(end(&value)), Unbounded),
+        Operator::GREATER_THAN_OR_EQUAL => (Included(start(&value)), Unbounded),
+        Operator::LESS_THAN => (Unbounded, Excluded(start(&value))),
+        Operator::LESS_THAN_OR_EQUAL => (Unbounded, Excluded(end(&value))),
+        Operator::BETWEEN => {
+            let (min, max) = filter
+                .between
+                .as_ref()
+                .ok_or("BETWEEN needs `between` dates.")?;
+            let (min, max) = (start(&date_key(min)), end(&date_key(max)));
+            if min >= max {
+                return Ok(BTreeSet::new());
+            }
+            (Included(min), Excluded(max))
+        }
+        _ => return Err("uploaded_at only takes comparisons and BETWEEN.".to_string()),
+    };
+
+    Ok(indexes
+        .by_date
+        .range((from, to))
+        .map(|(_, id)| id.clone())
+        .collect())
+}
+
+// The files of an indexed filter. Filters without an index are left to
+// `Paginator::filter_data`.
+fn matching(indexes: &Indexes, filter: &Filter) -> Result<Option<BTreeSet<String>>, String> {
+    let matching: BTreeSet<String> = match (filter.field.as_str(), &filter.operator) {
+        ("owner", Operator::EQUAL) => {
+            let owner = Principal::from_text(filter.value.trim())
+                .map_err(|_| format!("{} is not a principal.", filter.value))?;
+            indexes.by_owner.get(&owner).cloned().unwrap_or_default()
+        }
+        ("mime_type", Operator::EQUAL) => indexes
+            .by_type
+            .get(&base_type(&filter.value))
+            .cloned()
+            .unwrap_or_default(),
+        ("mime_type", Operator::IN) => filter
+            .values
+            .iter()
+            .flatten()
+            .filter_map(|value| indexes.by_type.get(&base_type(value)))
+            .flatten()
+            .cloned()
+            .collect(),
+        ("uploaded_at", _) => with_date(indexes, filter)?,
+        (field, Operator::EQUAL)
+            if field.starts_with("tag:") && filter.value.trim().eq_ignore_ascii_case("true") =>
+        {
+            let tag = field["tag:".len()..].to_lowercase();
+            indexes.by_tag.get(&tag).cloned().unwrap_or_default()
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(matching))
+}
+
+// The files matching every indexed part of `query`, smallest sets first.
+// `None` when the query has no indexed part.
+fn selected(indexes: &Indexes, query: &FileQuery) -> Result<Option<BTreeSet<String>>, String> {
+    let mut sets: Vec<BTreeSet<String>> = words(query.text.as_deref().unwrap_or_default())
+        .iter()
+        .map(|word| with_word(indexes, word))
+        .collect();
+    for filter in &query.filters {
+        sets.extend(matching(indexes, filter)?);
+    }
+
+    sets.sort_by_key(BTreeSet::len);
+    let mut sets = sets.into_iter();
+    let Some(mut ids) = sets.next() else {
+        return Ok(None);
+    };
+    for set in sets {
+        ids.retain(|id| set.contains(id));
+    }
+
+    Ok(Some(ids))
+}
+
+fn compare(a: &FileMetadata, b: &FileMetadata, field: SortField) -> std::cmp::Ordering {
+    match field {
+        SortField::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+        SortField::Size => a.size.cmp(&b.size),
+        SortField::MimeType => a.mime_type.cmp(&b.mime_type),
+        SortField::UploadedAt => date_key(&a.uploaded_at).cmp(&date_key(&b.uploaded_at)),
+    }
+}
+
+#[ic_cdk::query]
+fn search_files(query: FileQuery) -> Result<PaginatorResponse<FileMetadata>, String> {
+    if query.page == 0 || query.per_page == 0 {
+        return Err("Pages start at 1 and hold at least one file.".to_string());
+    }
+
+    let principal = msg_caller();
+
+    // Whether the caller can reach a selected file is left to the access check
+    // below.
+    let ids = INDEXES.with_borrow(|indexes| -> Result<BTreeSet<String>, String> {
+        Ok(selected(indexes, &query)?.unwrap_or_else(|| reachable(indexes, principal)))
+    })?;
+
+    let mut access = Access::all();
+    access.push(Access::Owner);
+
+    let candidates: Vec<FileMetadata> = FILES.with_borrow(|files| {
+        ids.iter()
+            .filter_map(|id| files.get(id))
+            .filter(|file| has_file_permission(file, &access, Some(principal)))
+            .map(FileMetadata::from)
+            .collect()
+    });
+
+    // Dates were matched through the index, their text doesn't compare.
+    let filters: Vec<Filter> = query
+        .filters
+        .into_iter()
+        .filter(|filter| filter.field != "uploaded_at")
+        .collect();
+    let mut found = Paginator::new(candidates, filters).filter_data(None, None);
+
+    let sort = query.sort.unwrap_or(FileSort {
+        field: SortField::UploadedAt,
+        descending: true,
+    });
+    found.sort_by(|a, b| {
+        let order = compare(a, b, sort.field).then_with(|| a.id.cmp(&b.id));
+        if sort.descending {
+            order.reverse()
+        } else {
+            order
+        }
+    });
+
+    Ok(Paginator::new(found, vec![]).get(query.page, query.per_page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_keys_sort_in_time_order() {
+        assert_eq!(
+            date_key("2026-10-19 9:05:00.0 +00:00:00"),
+            "2026-10-19 09:05:00.0"
+        );
+        assert_eq!(date_key("2026-10-19 10:05:00.0"), "2026-10-19 10:05:00.0");
+        assert_eq!(date_key("2026-10-19"), "2026-10-19");
+        assert!(date_key("2026-10-19 9:05:00.0") < date_key("2026-10-19 10:00:00.0"));
+    }
+}
@@ -0,0 +1,271 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use utilities::{audit, now};
+
+use crate::tokens::{self, now_seconds};
+use crate::{Access, FILES, StoredFile, analytics, hydrate};
+
+// Share links: capability tokens for one file, minted by its owner or admins.
+// A link grants `Read` or `Write` until it expires, optionally for a limited
+// number of uses, and can be revoked at any time. Tokens look like
+// `s<link_id>.<hmac>` and are redeemed with `get_file_with_token` or as the
+// `?token=` of an HTTP request; each redemption counts as one use, streamed
+// continuations of a response do not.
+
+const MAX_LINK_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub id: u64,
+    pub file_id: String,
+    pub access: Access,
+    pub created_by: Principal,
+    pub created_at: String,
+    pub expires_at: u64,
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub token: String,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct CreateShareLinkInput {
+    pub file_id: String,
+    pub access: Access,
+    pub expires_in_seconds: u64,
+    pub max_uses: Option<u32>,
+}
+
+impl ShareLink {
+    // Everything the token vouches for; `uses` is tracked separately.
+    fn signed_message(&self) -> String {
+        format!(
+            "share.{}.{}.{:?}.{}.{:?}",
+            self.id, self.file_id, self.access, self.expires_at, self.max_uses
+        )
+    }
+
+    fn is_used_up(&self) -> bool {
+        self.max_uses.is_some_and(|max_uses| self.uses >= max_uses)
+    }
+}
+
+thread_local! {
+    static SHARE_LINKS: RefCell<HashMap<u64, ShareLink>> = RefCell::new(HashMap::new());
+    static NEXT_LINK_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn is_share_token(token: &str) -> bool {
+    token.starts_with('s')
+}
+
+// Looks up the link behind `token` and checks its signature, expiry and file.
+// The use limit is left to the caller.
+fn check<R>(
+    links: &mut HashMap<u64, ShareLink>,
+    token: &str,
+    file_id: Option<&str>,
+    f: impl FnOnce(&mut ShareLink) -> Result<R, String>,
+) -> Result<R, String> {
+    let invalid = || "This share link is invalid or was revoked.".to_string();
+
+    let (link_id, signature) = token
+        .strip_prefix('s')
+        .and_then(|token| token.split_once('.'))
+        .ok_or_else(invalid)?;
+    let link_id = link_id.parse::<u64>().map_err(|_| invalid())?;
+
+    let link = links.get_mut(&link_id).ok_or_else(invalid)?;
+
+    if !tokens::verify_signature(&link.signed_message(), signature)
+        || file_id.is_some_and(|file_id| file_id != link.file_id)
+    {
+        return Err(invalid());
+    }
+
+    if link.expires_at < now_seconds() {
+        return Err("This share link has expired.".to_string());
+    }
+
+    f(link)
+}
+
+// Whether a response started with `token` may keep streaming.
+pub fn allows_read(file_id: &str, token: &str) -> bool {
+    SHARE_LINKS.with_borrow_mut(|links| check(links, token, Some(file_id), |_| Ok(())).is_ok())
+}
+
+// Metered links have to be redeemed in an update call to count the use.
+pub fn is_metered(token: &str) -> bool {
+    SHARE_LINKS.with_borrow_mut(|links| {
+        check(links, token, None, |link| Ok(link.max_uses.is_some())).unwrap_or(false)
+    })
+}
+
+pub fn redeem(token: &str, file_id: Option<&str>, mutable: bool) -> Result<ShareLink, String> {
+    SHARE_LINKS.with_borrow_mut(|links| {
+        check(links, token, file_id, |link| {
+            if mutable && !Access::can_edit().contains(&link.access) {
+                return Err("This share link does not allow editing.".to_string());
+            }
+
+            if link.is_used_up() {
+                return Err("This share link has been used up.".to_string());
+            }
+
+            link.uses += 1;
+            Ok(link.clone())
+        })
+    })
+}
+
+pub fn remove_file_links(file_id: &str) {
+    SHARE_LINKS.with_borrow_mut(|links| links.retain(|_, link| link.file_id != file_id));
+}
+
+pub fn start_expiry_timer() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, || {
+        let now = now_seconds();
+
+        SHARE_LINKS.with_borrow_mut(|links| {
+            links.retain(|_, link| link.expires_at >= now && !link.is_used_up());
+        });
+    });
+}
+
+fn can_manage_links(file: &StoredFile, principal: Principal) -> bool {
+    file.owner == principal || file.allowed_users.contains(&(principal, Access::Admin))
+}
+
+#[ic_cdk::update]
+async fn create_share_link(input: CreateShareLinkInput) -> Result<ShareLink, String> {
+    let principal = msg_caller();
+
+    if !matches!(input.access, Access::Read | Access::Write) {
+        return Err("Share links grant Read or Write access.".to_string());
+    }
+
+    match FILES.with_borrow(|files| {
+        files
+            .get(&input.file_id)
+            .map(|file| can_manage_links(file, principal))
+    }) {
+        None => return Err("StoredFile not found.".to_string()),
+        Some(false) => return Err("You are not authorized to share this file.".to_string()),
+        Some(true) => {}
+    }
+
+    tokens::ensure_secret().await?;
+
+    let id = NEXT_LINK_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+
+    let mut link = ShareLink {
+        id,
+        file_id: input.file_id,
+        access: input.access,
+        created_by: principal,
+        created_at: now(),
+        expires_at: now_seconds() + input.expires_in_seconds.min(MAX_LINK_TTL_SECONDS),
+        max_uses: input.max_uses,
+        uses: 0,
+        token: String::new(),
+    };
+    let signature = tokens::sign(&link.signed_message()).expect("The secret was just set.");
+    link.token = format!("s{id}.{signature}");
+
+    SHARE_LINKS.with_borrow_mut(|links| links.insert(id, link.clone()));
+
+    audit::record(
+        "create_share_link",
+        vec![link.file_id.clone()],
+        None,
+        Some(format!(
+            "link {} ({:?}, expires at {}, max uses {:?})",
+            link.id, link.access, link.expires_at, link.max_uses
+        )),
+    );
+
+    Ok(link)
+}
+
+#[ic_cdk::query]
+fn list_share_links(file_id: String) -> Result<Vec<ShareLink>, String> {
+    let principal = msg_caller();
+
+    match FILES.with_borrow(|files| {
+        files
+            .get(&file_id)
+            .map(|file| can_manage_links(file, principal))
+    }) {
+        None => return Err("StoredFile not found.".to_string()),
+        Some(false) => return Err("You are not authorized to view these links.".to_string()),
+        Some(true) => {}
+    }
+
+    let mut file_links: Vec<ShareLink> = SHARE_LINKS.with_borrow(|links| {
+        links
+            .values()
+            .filter(|link| link.file_id == file_id)
+            .cloned()
+            .collect()
+    });
+    file_links.sort_by_key(|link| link.id);
+
+    Ok(file_links)
+}
+
+// The file owner can revoke any link to it, other admins only their own.
+#[ic_cdk::update]
+fn revoke_share_link(link_id: u64) -> Result<(), String> {
+    let principal = msg_caller();
+
+    let link = SHARE_LINKS
+        .with_borrow(|links| links.get(&link_id).cloned())
+        .ok_or("Share link not found.")?;
+
+    let is_file_owner = FILES.with_borrow(|files| {
+        files
+            .get(&link.file_id)
+            .is_some_and(|f| f.owner == principal)
+    });
+
+    if !is_file_owner && link.created_by != principal {
+        return Err("You are not authorized to revoke this link.".to_string());
+    }
+
+    SHARE_LINKS.with_borrow_mut(|links| links.remove(&link_id));
+
+    audit::record(
+        "revoke_share_link",
+        vec![link.file_id.clone()],
+        Some(format!(
+            "link {} ({:?}, {} uses)",
+            link.id, link.access, link.uses
+        )),
+        None,
+    );
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn get_file_with_token(token: String, mutable: Option<bool>) -> Result<StoredFile, String> {
+    let link = redeem(&token, None, mutable.unwrap_or(false))?;
+    analytics::downloaded(&link.file_id);
+
+    FILES
+        .with_borrow(|files| files.get(&link.file_id).cloned())
+        .map(hydrate)
+        .ok_or("StoredFile not found.".to_string())
+}
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+
+use utilities::audit;
+
+use crate::{Access, FILES, StoredFile, has_file_permission, search};
+
+// File tags and metadata. Tags are short labels such as `syllabus` or
+// `starter-code`, kept lowercase, sorted and without duplicates. Metadata is a
+// small map of custom attributes. Both are set at upload and edited by anyone
+// who can edit the file. Filters reach them through `HasFields`: `tags` is
+// the comma-separated list, `tag:<name>` is `true` on tagged files and
+// `metadata.<key>` is the value of an attribute.
+
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 32;
+const MAX_METADATA_ENTRIES: usize = 32;
+const MAX_METADATA_KEY_LEN: usize = 64;
+const MAX_METADATA_VALUE_LEN: usize = 1024;
+
+fn normalize_tag(tag: &str) -> Result<String, String> {
+    let tag = tag.trim().to_lowercase();
+
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+        return Err(format!("Tags are 1 to {MAX_TAG_LEN} characters long."));
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "\"{tag}\" can only have letters, digits, '-' and '_'."
+        ));
+    }
+
+    Ok(tag)
+}
+
+// `tags` normalized, checked against the limits.
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized = tags
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .collect::<Result<Vec<_>, _>>()?;
+    normalized.sort();
+    normalized.dedup();
+
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("Files have at most {MAX_TAGS} tags."));
+    }
+
+    Ok(normalized)
+}
+
+pub fn check_metadata(metadata: &BTreeMap<String, String>) -> Result<(), String> {
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err(format!(
+            "Files have at most {MAX_METADATA_ENTRIES} metadata entries."
+        ));
+    }
+
+    for (key, value) in metadata {
+        if key.trim().is_empty() || key.chars().count() > MAX_METADATA_KEY_LEN {
+            return Err(format!(
+                "Metadata keys are 1 to {MAX_METADATA_KEY_LEN} characters long."
+            ));
+        }
+        if value.chars().count() > MAX_METADATA_VALUE_LEN {
+            return Err(format!(
+                "Metadata values are at most {MAX_METADATA_VALUE_LEN} characters long."
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn field(file_tags: &[String], metadata: &BTreeMap<String, String>, name: &str) -> String {
+    if name == "tags" {
+        return file_tags.join(",");
+    }
+    if let Some(tag) = name.strip_prefix("tag:") {
+        return file_tags.contains(&tag.to_lowercase()).to_string();
+    }
+    if let Some(key) = name.strip_prefix("metadata.") {
+        return metadata.get(key).cloned().unwrap_or_default();
+    }
+
+    String::new()
+}
+
+fn check_edit(file: &StoredFile) -> Result<(), String> {
+    let mut access = Access::can_edit();
+    access.push(Access::Owner);
+
+    if !has_file_permission(file, &access, None) {
+        return Err("You are not authorized to edit this file.".to_string());
+    }
+
+    Ok(())
+}
+
+// Runs `edit` on the file for a caller who can edit it.
+fn edit_file<T>(
+    files: &mut HashMap<String, StoredFile>,
+    file_id: &str,
+    edit: impl FnOnce(&mut StoredFile) -> Result<T, String>,
+) -> Result<T, String> {
+    let file = files.get_mut(file_id).ok_or("StoredFile not found.")?;
+    check_edit(file)?;
+
+    let edited = edit(file)?;
+    search::index(file);
+
+    Ok(edited)
+}
+
+// `tags` without `remove` and with `add`, which are normalized already.
+fn retagged(tags: &[String], add: &[String], remove: &[String]) -> Result<Vec<String>, String> {
+    let tags: Vec<String> = tags
+        .iter()
+        .filter(|tag| !remove.contains(tag))
+        .chain(add)
+        .cloned()
+        .collect();
+
+    normalize_tags(&tags)
+}
+
+pub fn check_retag(
+    files: &HashMap<String, StoredFile>,
+    file_id: &str,
+    add: &[String],
+    remove: &[String],
+) -> Result<(), String> {
+    let file = files.get(file_id).ok_or("StoredFile not found.")?;
+    check_edit(file)?;
+
+    retagged(&file.tags, add, remove).map(|_| ())
+}
+
+// Edits the tags of a file, recording the change. `add` is normalized and
+// `remove` lowercased by the caller. Returns the tags after the edit.
+pub fn retag(
+    files: &mut HashMap<String, StoredFile>,
+    file_id: &str,
+    add: &[String],
+    remove: &[String],
+) -> Result<Vec<String>, String> {
+    let (before, after) = edit_file(files, file_id, |file| {
+        let tags = retagged(&file.tags, add, remove)?;
+        let before = std::mem::replace(&mut file.tags, tags);
+
+        Ok((before, file.tags.clone()))
+    })?;
+
+    if before != after {
+        audit::record(
+            "edit_file_tags",
+            vec![file_id.to_string()],
+            Some(before.join(", ")),
+            Some(after.join(", ")),
+        );
+    }
+
+    Ok(after)
+}
+
+pub fn tags_to_remove(remove: &[String]) -> Vec<String> {
+    remove.iter().map(|tag| tag.trim().to_lowercase()).collect()
+}
+
+// Returns the file's tags after the edit.
+#[ic_cdk::update]
+fn edit_file_tags(
+    file_id: String,
+    add: Vec<String>,
+    remove: Vec<String>,
+) -> Result<Vec<String>, String> {
+    let add = normalize_tags(&add)?;
+    let remove = tags_to_remove(&remove);
+
+    FILES.with_borrow_mut(|files| retag(files, &file_id, &add, &remove))
+}
+
+// Sets the entries of `set` and drops the keys in `remove`. Returns the
+// file's metadata after the edit.
+#[ic_cdk::update]
+fn edit_file_metadata(
+    file_id: String,
+    set: BTreeMap<String, String>,
+    remove: Vec<String>,
+) -> Result<BTreeMap<String, String>, String> {
+    let (before, after) = FILES.with_borrow_mut(|files| {
+        edit_file(files, &file_id, |file| {
+            let before = file.metadata.clone();
+
+            let mut metadata = file.metadata.clone();
+            for key in &remove {
+                metadata.remove(key);
+            }
+            metadata.extend(set);
+            check_metadata(&metadata)?;
+            file.metadata = metadata;
+
+            Ok((before, file.metadata.clone()))
+        })
+    })?;
+
+    if before != after {
+        audit::record(
+            "edit_file_metadata",
+            vec![file_id],
+            Some(format!("{before:?}")),
+            Some(format!("{after:?}")),
+        );
+    }
+
+    Ok(after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn tags_are_lowercase_sorted_and_unique() {
+        assert_eq!(
+            normalize_tags(&tags(&[" Starter-Code", "syllabus", "starter-code"])),
+            Ok(tags(&["starter-code", "syllabus"]))
+        );
+    }
+
+    #[test]
+    fn tags_are_checked_against_the_limits() {
+        assert!(normalize_tags(&tags(&[""])).is_err());
+        assert!(normalize_tags(&tags(&["two words"])).is_err());
+        assert!(normalize_tags(&["a".repeat(MAX_TAG_LEN + 1)]).is_err());
+
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| format!("tag{i}")).collect();
+        assert!(normalize_tags(&many).is_err());
+        assert!(normalize_tags(&many[..MAX_TAGS]).is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+use std::cell::RefCell;
+
+use hmac::{Hmac, Mac};
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use sha2::Sha256;
+
+use crate::{Access, FILES, StoredFile, has_file_permission};
+
+// Signed download tokens. A token lets whoever holds it read one non-public
+// file over HTTP until it expires, without the request carrying the holder's
+// principal. Tokens are `<expires_at>.<principal>.<hmac>` where the HMAC
+// covers the file id, the expiry and the principal that minted the token, and
+// is keyed with a secret drawn from `raw_rand`. A token only works while that
+// principal can still read the file. Share links sign their tokens with the
+// same secret.
+//
+// The secret lives on the heap, so an upgrade invalidates every token.
+
+const MAX_TOKEN_TTL_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+thread_local! {
+    static SECRET: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+fn mac(secret: &[u8], message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length.");
+    mac.update(message.as_bytes());
+    mac
+}
+
+pub async fn ensure_secret() -> Result<(), String> {
+    if SECRET.with_borrow(|secret| secret.is_some()) {
+        return Ok(());
+    }
+
+    let bytes = ic_cdk::management_canister::raw_rand()
+        .await
+        .map_err(|err| format!("Failed to generate the signing secret: {err}"))?;
+
+    // Another call may have set it while this one was waiting.
+    SECRET.with_borrow_mut(|secret| {
+        secret.get_or_insert(bytes);
+    });
+
+    Ok(())
+}
+
+// Hex HMAC of `message`, `None` until `ensure_secret` has run.
+pub fn sign(message: &str) -> Option<String> {
+    SECRET.with_borrow(|secret| {
+        secret
+            .as_ref()
+            .map(|secret| hex::encode(mac(secret, message).finalize().into_bytes()))
+    })
+}
+
+pub fn verify_signature(message: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    SECRET.with_borrow(|secret| {
+        secret
+            .as_ref()
+            .is_some_and(|secret| mac(secret, message).verify_slice(&signature).is_ok())
+    })
+}
+
+pub fn now_seconds() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000
+}
+
+fn can_read(file: &StoredFile, principal: Principal) -> bool {
+    let mut access = Access::all();
+    access.push(Access::Owner);
+
+    has_file_permission(file, &access, Some(principal))
+}
+
+pub fn verify(file: &StoredFile, token: &str) -> bool {
+    let mut parts = token.splitn(3, '.');
+    let (Some(expires_at), Some(principal), Some(signature)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let (Ok(expires_at), Ok(minted_by)) =
+        (expires_at.parse::<u64>(), Principal::from_text(principal))
+    else {
+        return false;
+    };
+
+    expires_at >= now_seconds()
+        && verify_signature(&format!("{}.{expires_at}.{principal}", file.id), signature)
+        && can_read(file, minted_by)
+}
+
+// Callers with read access mint a token valid for at most seven days, to be
+// passed as `?token=` on `/files/<file_id>`. Revoking the caller's access
+// revokes the token too.
+#[ic_cdk::update]
+async fn create_download_token(file_id: String, ttl_seconds: u64) -> Result<String, String> {
+    let principal = msg_caller();
+    let readable =
+        FILES.with_borrow(|files| files.get(&file_id).map(|file| can_read(file, principal)));
+
+    match readable {
+        None => return Err("StoredFile not found.".to_string()),
+        Some(false) => return Err("You are not authorized to read this file.".to_string()),
+        Some(true) => {}
+    }
+
+    ensure_secret().await?;
+
+    let expires_at = now_seconds() + ttl_seconds.min(MAX_TOKEN_TTL_SECONDS);
+    let signature =
+        sign(&format!("{file_id}.{expires_at}.{principal}")).expect("The secret was just set.");
+
+    Ok(format!("{expires_at}.{principal}.{signature}"))
+}
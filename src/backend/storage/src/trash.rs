@@ -0,0 +1,466 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use utilities::audit;
+
+use crate::folders::{self, Folder};
+use crate::groups::{self, GROUPS};
+use crate::{FILES, FileMetadata, Group, StoredFile, release_file, search};
+
+// Trash bin. `delete_files`, `delete_groups` and `delete_folder` move items
+// here instead of dropping them; their owner can list, restore or purge them
+// until the purge timer removes whatever has been in the trash for longer than
+// the retention. Trashed files keep their content and still count towards the
+// owner's quota. A deleted folder is restored or purged by whoever deleted it,
+// together with the folders and files that went with it and are still here.
+
+const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct TrashedFile {
+    pub file: FileMetadata,
+    pub deleted_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct TrashedGroup {
+    pub group: Group,
+    pub deleted_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct TrashedFolder {
+    // The deleted folder first, then the folders below it.
+    pub folders: Vec<Folder>,
+    pub file_ids: Vec<String>,
+    pub deleted_by: Principal,
+    pub deleted_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Trash {
+    pub files: Vec<TrashedFile>,
+    pub groups: Vec<TrashedGroup>,
+    pub folders: Vec<TrashedFolder>,
+}
+
+thread_local! {
+    // Keyed by id, with the time the item was deleted.
+    static TRASHED_FILES: RefCell<HashMap<String, (StoredFile, u64)>> = RefCell::new(HashMap::new());
+    static TRASHED_GROUPS: RefCell<HashMap<String, (Group, u64)>> = RefCell::new(HashMap::new());
+    // Keyed by the id of the deleted folder.
+    static TRASHED_FOLDERS: RefCell<HashMap<String, TrashedFolder>> = RefCell::new(HashMap::new());
+    static RETENTION_SECONDS: Cell<u64> = const { Cell::new(DEFAULT_RETENTION.as_secs()) };
+}
+
+pub fn trash_file(file: StoredFile) {
+    TRASHED_FILES.with_borrow_mut(|trash| {
+        trash.insert(file.id.clone(), (file, ic_cdk::api::time()));
+    });
+}
+
+pub fn trash_group(group: Group) {
+    TRASHED_GROUPS.with_borrow_mut(|trash| {
+        trash.insert(group.id.clone(), (group, ic_cdk::api::time()));
+    });
+}
+
+pub fn trash_folder(folders: Vec<Folder>, file_ids: Vec<String>) {
+    let Some(folder_id) = folders.first().map(|folder| folder.id.clone()) else {
+        return;
+    };
+
+    TRASHED_FOLDERS.with_borrow_mut(|trash| {
+        trash.insert(
+            folder_id,
+            TrashedFolder {
+                folders,
+                file_ids,
+                deleted_by: msg_caller(),
+                deleted_at: ic_cdk::api::time(),
+            },
+        );
+    });
+}
+
+// Takes the caller's items among `ids` out of `trash`.
+fn take_owned<T>(
+    trash: &mut HashMap<String, (T, u64)>,
+    ids: &[String],
+    owner: impl Fn(&T) -> Principal,
+) -> Vec<T> {
+    let principal = msg_caller();
+
+    let mut taken = Vec::new();
+
+    for id in ids {
+        if trash
+            .get(id)
+            .is_some_and(|(item, _)| owner(item) == principal)
+            && let Some((item, _)) = trash.remove(id)
+        {
+            taken.push(item);
+        }
+    }
+
+    taken
+}
+
+pub fn start_purge_timer() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, purge_expired);
+}
+
+fn purge_expired() {
+    let retention = Duration::from_secs(RETENTION_SECONDS.get()).as_nanos() as u64;
+    let cutoff = ic_cdk::api::time().saturating_sub(retention);
+
+    let expired: Vec<StoredFile> = TRASHED_FILES.with_borrow_mut(|trash| {
+        let ids: Vec<String> = trash
+            .iter()
+            .filter(|(_, (_, deleted_at))| *deleted_at < cutoff)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        ids.iter()
+            .filter_map(|id| trash.remove(id).map(|(file, _)| file))
+            .collect()
+    });
+    expired.iter().for_each(release_file);
+
+    let expired_groups: Vec<String> = TRASHED_GROUPS.with_borrow_mut(|trash| {
+        let ids: Vec<String> = trash
+            .iter()
+            .filter(|(_, (_, deleted_at))| *deleted_at < cutoff)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        ids.into_iter()
+            .filter(|id| trash.remove(id).is_some())
+            .collect()
+    });
+    expired_groups
+        .iter()
+        .for_each(|group_id| groups::unlink_group(group_id));
+
+    // Their files expire on their own.
+    TRASHED_FOLDERS.with_borrow_mut(|trash| {
+        trash.retain(|_, trashed| trashed.deleted_at >= cutoff);
+    });
+}
+
+// Takes the folders the caller deleted among `folder_ids` out of the trash.
+fn take_folders(folder_ids: &[String]) -> Vec<TrashedFolder> {
+    let principal = msg_caller();
+
+    TRASHED_FOLDERS.with_borrow_mut(|trash| {
+        let mut taken = Vec::new();
+
+        for id in folder_ids {
+            if trash
+                .get(id)
+                .is_some_and(|trashed| trashed.deleted_by == principal)
+                && let Some(trashed) = trash.remove(id)
+            {
+                taken.push(trashed);
+            }
+        }
+
+        taken
+    })
+}
+
+// Takes the files among `file_ids` out of the trash, whoever owns them.
+fn take_files(file_ids: &[String]) -> Vec<StoredFile> {
+    TRASHED_FILES.with_borrow_mut(|trash| {
+        file_ids
+            .iter()
+            .filter_map(|id| trash.remove(id).map(|(file, _)| file))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_trash() -> Trash {
+    let principal = msg_caller();
+
+    let mut files: Vec<TrashedFile> = TRASHED_FILES.with_borrow(|trash| {
+        trash
+            .values()
+            .filter(|(file, _)| file.owner == principal)
+            .map(|(file, deleted_at)| TrashedFile {
+                file: FileMetadata::from(file),
+                deleted_at: *deleted_at,
+            })
+            .collect()
+    });
+    files.sort_by_key(|trashed| std::cmp::Reverse(trashed.deleted_at));
+
+    let mut groups: Vec<TrashedGroup> = TRASHED_GROUPS.with_borrow(|trash| {
+        trash
+            .values()
+            .filter(|(group, _)| group.owner == principal)
+            .map(|(group, deleted_at)| TrashedGroup {
+                group: group.clone(),
+                deleted_at: *deleted_at,
+            })
+            .collect()
+    });
+    groups.sort_by_key(|trashed| std::cmp::Reverse(trashed.deleted_at));
+
+    let mut folders: Vec<TrashedFolder> = TRASHED_FOLDERS.with_borrow(|trash| {
+        trash
+            .values()
+            .filter(|trashed| trashed.deleted_by == principal)
+            .cloned()
+            .collect()
+    });
+    folders.sort_by_key(|trashed| std::cmp::Reverse(trashed.deleted_at));
+
+    Trash {
+        files,
+        groups,
+        folders,
+    }
+}
+
+// Files go back to their folder, or to the owner's root when the folder is
+// gone or got another entry with the same name in the meantime.
+#[ic_cdk::update]
+fn restore_files(file_ids: Vec<String>) -> usize {
+    let restored = TRASHED_FILES.with_borrow_mut(|trash| take_owned(trash, &file_ids, |f| f.owner));
+
+    FILES.with_borrow_mut(|files| {
+        for mut file in restored.iter().cloned() {
+            let parent_id = file.parent_id.as_deref();
+
+            if parent_id.is_some_and(|parent_id| {
+                !folders::exists(parent_id)
+                    || folders::is_name_taken(files, Some(parent_id), file.owner, &file.name, None)
+            }) {
+                file.parent_id = None;
+            }
+
+            search::index(&file);
+            files.insert(file.id.clone(), file);
+        }
+    });
+
+    if !restored.is_empty() {
+        audit::record(
+            "restore_files",
+            restored.iter().map(|f| f.id.clone()).collect(),
+            None,
+            Some(
+                restored
+                    .iter()
+                    .map(|f| f.name.clone())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        );
+    }
+
+    restored.len()
+}
+
+// Deletes trashed files for good.
+#[ic_cdk::update]
+fn purge_files(file_ids: Vec<String>) -> usize {
+    let purged = TRASHED_FILES.with_borrow_mut(|trash| take_owned(trash, &file_ids, |f| f.owner));
+    purged.iter().for_each(release_file);
+
+    if !purged.is_empty() {
+        audit::record(
+            "purge_files",
+            purged.iter().map(|f| f.id.clone()).collect(),
+            Some(
+                purged
+                    .iter()
+                    .map(|f| format!("{} ({}, {} bytes)", f.name, f.mime_type, f.size))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            None,
+        );
+    }
+
+    purged.len()
+}
+
+// Restores deleted folders with the folders and files that went with them. A
+// folder goes back to its parent, or to its owner's root when the parent is
+// gone or got another entry with the same name in the meantime.
+#[ic_cdk::update]
+fn restore_folders(folder_ids: Vec<String>) -> usize {
+    let restored = take_folders(&folder_ids);
+
+    FILES.with_borrow_mut(|files| {
+        for trashed in restored.iter() {
+            for (index, mut folder) in trashed.folders.iter().cloned().enumerate() {
+                let parent_id = folder.parent_id.as_deref();
+
+                if index == 0
+                    && parent_id.is_some_and(|parent_id| {
+                        !folders::exists(parent_id)
+                            || folders::is_name_taken(
+                                files,
+                                Some(parent_id),
+                                folder.owner,
+                                &folder.name,
+                                None,
+                            )
+                    })
+                {
+                    folder.parent_id = None;
+                }
+
+                folders::insert(folder);
+            }
+
+            for file in take_files(&trashed.file_ids) {
+                search::index(&file);
+                files.insert(file.id.clone(), file);
+            }
+        }
+    });
+
+    if !restored.is_empty() {
+        audit::record(
+            "restore_folders",
+            restored
+                .iter()
+                .flat_map(|trashed| &trashed.folders)
+                .map(|folder| folder.id.clone())
+                .collect(),
+            None,
+            Some(
+                restored
+                    .iter()
+                    .map(|trashed| trashed.folders[0].name.clone())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        );
+    }
+
+    restored.len()
+}
+
+// Deletes trashed folders for good, with the files that went with them.
+#[ic_cdk::update]
+fn purge_folders(folder_ids: Vec<String>) -> usize {
+    let purged = take_folders(&folder_ids);
+
+    let purged_files: Vec<StoredFile> = purged
+        .iter()
+        .flat_map(|trashed| take_files(&trashed.file_ids))
+        .collect();
+    purged_files.iter().for_each(release_file);
+
+    if !purged.is_empty() {
+        audit::record(
+            "purge_folders",
+            purged
+                .iter()
+                .flat_map(|trashed| &trashed.folders)
+                .map(|folder| folder.id.clone())
+                .chain(purged_files.iter().map(|f| f.id.clone()))
+                .collect(),
+            Some(
+                purged
+                    .iter()
+                    .map(|trashed| {
+                        format!(
+                            "{} ({} folders, {} files)",
+                            trashed.folders[0].name,
+                            trashed.folders.len(),
+                            trashed.file_ids.len()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            None,
+        );
+    }
+
+    purged.len()
+}
+
+#[ic_cdk::update]
+fn restore_groups(group_ids: Vec<String>) -> usize {
+    let restored =
+        TRASHED_GROUPS.with_borrow_mut(|trash| take_owned(trash, &group_ids, |g| g.owner));
+
+    GROUPS.with_borrow_mut(|groups| {
+        for group in restored.iter().cloned() {
+            groups.insert(group.id.clone(), group);
+        }
+    });
+
+    if !restored.is_empty() {
+        audit::record(
+            "restore_groups",
+            restored.iter().map(|g| g.id.clone()).collect(),
+            None,
+            Some(
+                restored
+                    .iter()
+                    .map(|g| g.name.clone())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        );
+    }
+
+    restored.len()
+}
+
+#[ic_cdk::update]
+fn purge_groups(group_ids: Vec<String>) -> usize {
+    let purged = TRASHED_GROUPS.with_borrow_mut(|trash| take_owned(trash, &group_ids, |g| g.owner));
+    purged
+        .iter()
+        .for_each(|group| groups::unlink_group(&group.id));
+
+    if !purged.is_empty() {
+        audit::record(
+            "purge_groups",
+            purged.iter().map(|g| g.id.clone()).collect(),
+            Some(
+                purged
+                    .iter()
+                    .map(|g| format!("{} ({} members)", g.name, g.members.len()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            None,
+        );
+    }
+
+    purged.len()
+}
+
+#[ic_cdk::update]
+fn set_trash_retention(retention_seconds: u64) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure the trash.".to_string());
+    }
+
+    let before = RETENTION_SECONDS.replace(retention_seconds);
+    audit::record(
+        "set_trash_retention",
+        vec![],
+        Some(format!("{before} seconds")),
+        Some(format!("{retention_seconds} seconds")),
+    );
+
+    Ok(())
+}
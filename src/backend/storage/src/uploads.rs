@@ -0,0 +1,404 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use utilities::now;
+
+use crate::bucket;
+use crate::policy::{self, UploadContext};
+use crate::quotas::{self, QuotaError};
+use crate::{
+    Access, FILES, Group, StoredFile, blobs, folders, groups, has_file_permission, search,
+};
+
+// Chunked uploads and downloads. Files too large for a single message are
+// sent as `CHUNK_SIZE` pieces into an upload session, verified against their
+// SHA-256 on commit and read back one chunk at a time.
+
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Sessions without a new chunk for this long are dropped by the cleanup timer.
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Chunks are kept on the heap until commit, so open sessions are capped and
+// count toward the quota as if their files were stored.
+const MAX_OPEN_UPLOADS: u64 = 4;
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct UploadMetadata {
+    pub name: String,
+    pub mime_type: String,
+    pub size: usize,
+    pub groups: Vec<Group>,
+    pub allowed_users: Vec<(Principal, Access)>,
+    pub public: bool,
+    pub parent_id: Option<String>,
+    pub context: Option<UploadContext>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct UploadStatus {
+    pub upload_id: u64,
+    pub chunk_size: usize,
+    pub chunk_count: u32,
+    pub missing_chunks: Vec<u32>,
+}
+
+// Starting and committing an upload can run into the caller's quota.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub enum UploadError {
+    QuotaExceeded(QuotaError),
+    Rejected(String),
+}
+
+impl From<String> for UploadError {
+    fn from(message: String) -> Self {
+        UploadError::Rejected(message)
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct FileChunk {
+    pub index: u32,
+    pub chunk_count: u32,
+    pub data: Vec<u8>,
+}
+
+struct UploadSession {
+    owner: Principal,
+    metadata: UploadMetadata,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    last_activity: u64,
+}
+
+impl UploadSession {
+    fn chunk_count(&self) -> u32 {
+        chunk_count(self.metadata.size)
+    }
+
+    fn status(&self, upload_id: u64) -> UploadStatus {
+        UploadStatus {
+            upload_id,
+            chunk_size: CHUNK_SIZE,
+            chunk_count: self.chunk_count(),
+            missing_chunks: (0..self.chunk_count())
+                .filter(|index| !self.chunks.contains_key(index))
+                .collect(),
+        }
+    }
+
+    // Every chunk is `CHUNK_SIZE` long except the last, which holds the rest.
+    fn expected_len(&self, index: u32) -> usize {
+        if index + 1 < self.chunk_count() {
+            CHUNK_SIZE
+        } else {
+            self.metadata.size - CHUNK_SIZE * index as usize
+        }
+    }
+}
+
+thread_local! {
+    static UPLOADS: RefCell<HashMap<u64, UploadSession>> = RefCell::new(HashMap::new());
+    static NEXT_UPLOAD_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn chunk_count(size: usize) -> u32 {
+    size.div_ceil(CHUNK_SIZE).max(1) as u32
+}
+
+// The folder may have gained a file with the same name while uploading.
+fn name_taken(metadata: &UploadMetadata) -> bool {
+    metadata.parent_id.is_some()
+        && FILES.with_borrow(|files| {
+            folders::is_name_taken(
+                files,
+                metadata.parent_id.as_deref(),
+                msg_caller(),
+                &metadata.name,
+                None,
+            )
+        })
+}
+
+fn duplicate_name() -> String {
+    "A file with this name already exists in this folder.".to_string()
+}
+
+// Declared bytes and number of the sessions `owner` has open.
+pub fn pending(owner: Principal) -> (u64, u64) {
+    UPLOADS.with_borrow(|uploads| {
+        uploads
+            .values()
+            .filter(|session| session.owner == owner)
+            .fold((0, 0), |(bytes, count), session| {
+                (bytes + session.metadata.size as u64, count + 1)
+            })
+    })
+}
+
+fn with_owned_session<R>(
+    upload_id: u64,
+    f: impl FnOnce(&mut UploadSession) -> Result<R, String>,
+) -> Result<R, String> {
+    UPLOADS.with_borrow_mut(|uploads| match uploads.get_mut(&upload_id) {
+        Some(session) if session.owner == msg_caller() => f(session),
+        _ => Err("Upload session not found.".to_string()),
+    })
+}
+
+// The caller needs edit access to the upload's groups and folder, both when
+// the upload begins and again when it is committed.
+fn check_destination(metadata: &UploadMetadata) -> Result<(), String> {
+    if metadata.groups.iter().any(|grp: &Group| {
+        !groups::check_group_permission(grp.id.clone(), Access::can_edit(), None)
+    }) {
+        return Err("You are not authorized in this group.".to_string());
+    }
+
+    if let Some(parent_id) = metadata.parent_id.as_deref()
+        && (!folders::exists(parent_id)
+            || !folders::has_permission(Some(parent_id), &Access::can_edit(), msg_caller()))
+    {
+        return Err("You are not authorized in this folder.".to_string());
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn begin_upload(metadata: UploadMetadata) -> Result<UploadStatus, UploadError> {
+    check_destination(&metadata)?;
+
+    if metadata.parent_id.is_some() && name_taken(&metadata) {
+        return Err(duplicate_name().into());
+    }
+
+    policy::check(metadata.context, &metadata.mime_type, metadata.size as u64)?;
+    let (pending_bytes, open_uploads) = pending(msg_caller());
+    if open_uploads >= MAX_OPEN_UPLOADS {
+        return Err(format!(
+            "At most {MAX_OPEN_UPLOADS} uploads can be open at once, commit or abort one first."
+        )
+        .into());
+    }
+    quotas::check_files(
+        msg_caller(),
+        open_uploads + 1,
+        pending_bytes + metadata.size as u64,
+    )
+    .map_err(UploadError::QuotaExceeded)?;
+
+    let upload_id = NEXT_UPLOAD_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+
+    let session = UploadSession {
+        owner: msg_caller(),
+        metadata,
+        chunks: BTreeMap::new(),
+        last_activity: ic_cdk::api::time(),
+    };
+    let status = session.status(upload_id);
+
+    UPLOADS.with_borrow_mut(|uploads| uploads.insert(upload_id, session));
+
+    Ok(status)
+}
+
+// Re-sending a chunk overwrites it, so a client resumes by calling
+// `get_upload_status` and uploading whatever is still missing.
+#[ic_cdk::update]
+fn put_chunk(upload_id: u64, index: u32, data: Vec<u8>) -> Result<UploadStatus, String> {
+    with_owned_session(upload_id, |session| {
+        if index >= session.chunk_count() {
+            return Err(format!(
+                "Chunk {index} is out of range, the upload has {} chunks.",
+                session.chunk_count()
+            ));
+        }
+
+        let expected_len = session.expected_len(index);
+        if data.len() != expected_len {
+            return Err(format!(
+                "Chunk {index} must be {expected_len} bytes, got {}.",
+                data.len()
+            ));
+        }
+
+        session.chunks.insert(index, data);
+        session.last_activity = ic_cdk::api::time();
+
+        Ok(session.status(upload_id))
+    })
+}
+
+#[ic_cdk::query]
+fn get_upload_status(upload_id: u64) -> Result<UploadStatus, String> {
+    UPLOADS.with_borrow(|uploads| match uploads.get(&upload_id) {
+        Some(session) if session.owner == msg_caller() => Ok(session.status(upload_id)),
+        _ => Err("Upload session not found.".to_string()),
+    })
+}
+
+// Assembles the chunks into a `StoredFile` owned by the caller. `sha256` is
+// the hex digest of the whole file. On a mismatch or a full quota the session
+// is kept, so the client can re-send corrupted chunks or commit again once it
+// freed some space. Content that is already stored is shared, not copied.
+#[ic_cdk::update]
+fn commit_upload(upload_id: u64, sha256: String) -> Result<String, UploadError> {
+    let content_hash = sha256.to_lowercase();
+
+    let (owner, size, mime_type) = UPLOADS.with_borrow(|uploads| {
+        let session = match uploads.get(&upload_id) {
+            Some(session) if session.owner == msg_caller() => session,
+            _ => return Err("Upload session not found.".to_string()),
+        };
+
+        let missing_chunks = session.status(upload_id).missing_chunks;
+        if !missing_chunks.is_empty() {
+            return Err(format!("Missing chunks: {missing_chunks:?}."));
+        }
+
+        let mut hasher = Sha256::new();
+        session
+            .chunks
+            .values()
+            .for_each(|chunk| hasher.update(chunk));
+
+        if hex::encode(hasher.finalize()) != content_hash {
+            return Err("Checksum mismatch, re-send the corrupted chunks.".to_string());
+        }
+
+        check_destination(&session.metadata)?;
+
+        if name_taken(&session.metadata) {
+            return Err(duplicate_name());
+        }
+
+        // Signatures sit at the start of the content.
+        let first_chunk = session.chunks.get(&0).map_or(&[][..], Vec::as_slice);
+        let mime_type = policy::content_type(
+            session.metadata.context,
+            &session.metadata.mime_type,
+            first_chunk,
+        )?;
+        policy::check(
+            session.metadata.context,
+            &mime_type,
+            session.metadata.size as u64,
+        )?;
+
+        Ok((session.owner, session.metadata.size, mime_type))
+    })?;
+
+    quotas::check(owner, size as u64).map_err(UploadError::QuotaExceeded)?;
+
+    let file_id = bucket::new_file_id();
+
+    let session = UPLOADS
+        .with_borrow_mut(|uploads| uploads.remove(&upload_id))
+        .expect("The session was checked above.");
+    let UploadMetadata {
+        name,
+        groups,
+        allowed_users,
+        public,
+        parent_id,
+        context,
+        ..
+    } = session.metadata;
+
+    let file = StoredFile {
+        id: file_id.clone(),
+        name,
+        mime_type,
+        size,
+        data: Vec::new(),
+        content_hash: content_hash.clone(),
+        parent_id,
+        owner,
+        groups: Vec::new(),
+        allowed_users,
+        public,
+        tags: Vec::new(),
+        metadata: BTreeMap::new(),
+        uploaded_at: now(),
+    };
+    groups::link(&file_id, groups.into_iter().map(|grp| grp.id));
+
+    quotas::add(&file, size as u64);
+    blobs::store(
+        &content_hash,
+        session.chunks.into_values().flatten().collect(),
+    );
+    search::index(&file);
+    FILES.with_borrow_mut(|files| files.insert(file_id.clone(), file));
+    policy::remember(&file_id, context);
+    bucket::file_stored(&file_id);
+
+    Ok(file_id)
+}
+
+#[ic_cdk::update]
+fn abort_upload(upload_id: u64) -> Result<(), String> {
+    with_owned_session(upload_id, |_| Ok(()))?;
+    UPLOADS.with_borrow_mut(|uploads| uploads.remove(&upload_id));
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_chunk(file_id: String, index: u32) -> Result<FileChunk, String> {
+    FILES.with_borrow(|files| {
+        let Some(file) = files.get(&file_id) else {
+            return Err("StoredFile not found.".to_string());
+        };
+
+        let mut access = Access::all();
+        access.push(Access::Owner);
+
+        if !has_file_permission(file, &access, None) {
+            return Err("You are not authorized to read this file.".to_string());
+        }
+
+        blobs::read(&file.content_hash, |data| {
+            let chunk_count = chunk_count(data.len());
+            if index >= chunk_count {
+                return Err(format!(
+                    "Chunk {index} is out of range, the file has {chunk_count} chunks."
+                ));
+            }
+
+            let start = index as usize * CHUNK_SIZE;
+            let end = (start + CHUNK_SIZE).min(data.len());
+
+            Ok(FileChunk {
+                index,
+                chunk_count,
+                data: data[start..end].to_vec(),
+            })
+        })
+        .unwrap_or_else(|| Err("StoredFile content is missing.".to_string()))
+    })
+}
+
+pub fn start_cleanup_timer() {
+    ic_cdk_timers::set_timer_interval(CLEANUP_INTERVAL, remove_abandoned_uploads);
+}
+
+fn remove_abandoned_uploads() {
+    let cutoff = ic_cdk::api::time().saturating_sub(SESSION_TTL.as_nanos() as u64);
+
+    UPLOADS.with_borrow_mut(|uploads| {
+        uploads.retain(|_, session| session.last_activity >= cutoff);
+    });
+}
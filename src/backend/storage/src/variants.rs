@@ -0,0 +1,196 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use candid::CandidType;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+
+use crate::{Access, FILES, StoredFile, blobs, encryption, has_file_permission};
+
+// Image renditions. Smaller copies of an image are rendered the first time
+// they are asked for, with `get_file_variant` or `?variant=` over HTTP, and
+// kept as blobs linked to the file. A rendition belongs to the content it was
+// made from, so a new version of the file gets new renditions.
+
+// Larger sources would not decode within one message's instruction limit.
+const MAX_SOURCE_DIMENSION: u32 = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Serialize, Deserialize)]
+pub enum VariantSize {
+    Thumbnail,
+    Small,
+    Medium,
+}
+
+impl VariantSize {
+    // Longest side, in pixels.
+    fn max_dimension(self) -> u32 {
+        match self {
+            VariantSize::Thumbnail => 128,
+            VariantSize::Small => 320,
+            VariantSize::Medium => 800,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "thumbnail" => Some(VariantSize::Thumbnail),
+            "small" => Some(VariantSize::Small),
+            "medium" => Some(VariantSize::Medium),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rendition {
+    pub content_hash: String,
+    pub mime_type: String,
+    source_hash: String,
+}
+
+thread_local! {
+    static RENDITIONS: RefCell<HashMap<(String, VariantSize), Rendition>> = RefCell::new(HashMap::new());
+}
+
+// Variants are made of images storage can read.
+pub fn check_source(file: &StoredFile) -> Result<(), &'static str> {
+    if encryption::is_encrypted(&file.id) {
+        return Err("Encrypted files have no variants.");
+    }
+    if !file.mime_type.starts_with("image/") {
+        return Err("Only images have variants.");
+    }
+
+    Ok(())
+}
+
+// The rendition of the file's current content, if it was already made.
+pub fn cached(file: &StoredFile, size: VariantSize) -> Option<Rendition> {
+    RENDITIONS.with_borrow(|renditions| {
+        renditions
+            .get(&(file.id.clone(), size))
+            .filter(|rendition| rendition.source_hash == file.content_hash)
+            .cloned()
+    })
+}
+
+pub fn ensure(file: &StoredFile, size: VariantSize) -> Result<Rendition, String> {
+    if let Some(rendition) = cached(file, size) {
+        return Ok(rendition);
+    }
+
+    let rendered = blobs::read(&file.content_hash, |data| render(data, size))
+        .ok_or("StoredFile content is missing.")??;
+
+    let rendition = match rendered {
+        // Already small enough, the source is its own rendition.
+        None => {
+            blobs::retain(&file.content_hash);
+            Rendition {
+                content_hash: file.content_hash.clone(),
+                mime_type: file.mime_type.clone(),
+                source_hash: file.content_hash.clone(),
+            }
+        }
+        Some((data, format)) => {
+            let content_hash = blobs::hash(&data);
+            blobs::store(&content_hash, data);
+            Rendition {
+                content_hash,
+                mime_type: format.to_mime_type().to_string(),
+                source_hash: file.content_hash.clone(),
+            }
+        }
+    };
+
+    let stale = RENDITIONS.with_borrow_mut(|renditions| {
+        renditions.insert((file.id.clone(), size), rendition.clone())
+    });
+    if let Some(stale) = stale {
+        blobs::release(&stale.content_hash);
+    }
+
+    Ok(rendition)
+}
+
+// `None` when the image already fits in `size`.
+fn render(data: &[u8], size: VariantSize) -> Result<Option<(Vec<u8>, ImageFormat)>, String> {
+    let mut reader = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|err| err.to_string())?;
+    let format = reader
+        .format()
+        .filter(|format| matches!(format, ImageFormat::Png | ImageFormat::Jpeg))
+        .ok_or("Only PNG and JPEG images have variants.")?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    reader.limits(limits);
+
+    let image = reader
+        .decode()
+        .map_err(|err| format!("The image could not be decoded: {err}"))?;
+
+    let max_dimension = size.max_dimension();
+    if image.width() <= max_dimension && image.height() <= max_dimension {
+        return Ok(None);
+    }
+
+    let resized = image.resize(max_dimension, max_dimension, FilterType::Triangle);
+    // JPEG has no alpha channel.
+    let resized = match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(resized.to_rgb8()),
+        _ => resized,
+    };
+
+    let mut encoded = Vec::new();
+    resized
+        .write_to(&mut Cursor::new(&mut encoded), format)
+        .map_err(|err| format!("The image could not be encoded: {err}"))?;
+
+    Ok(Some((encoded, format)))
+}
+
+// Releases the renditions of a removed file.
+pub fn forget(file_id: &str) {
+    RENDITIONS.with_borrow_mut(|renditions| {
+        renditions.retain(|(id, _), rendition| {
+            if id == file_id {
+                blobs::release(&rendition.content_hash);
+            }
+            id != file_id
+        });
+    });
+}
+
+// An update call, since a missing rendition is made and stored on the way.
+#[ic_cdk::update]
+fn get_file_variant(file_id: String, size: VariantSize) -> Result<StoredFile, String> {
+    let file = FILES
+        .with_borrow(|files| files.get(&file_id).cloned())
+        .ok_or("StoredFile not found.")?;
+
+    let mut access = Access::all();
+    access.push(Access::Owner);
+
+    if !has_file_permission(&file, &access, None) {
+        return Err("You are not authorized to read this file.".to_string());
+    }
+
+    check_source(&file)?;
+
+    let rendition = ensure(&file, size)?;
+    let data = blobs::content(&rendition.content_hash);
+
+    Ok(StoredFile {
+        size: data.len(),
+        data,
+        content_hash: rendition.content_hash,
+        mime_type: rendition.mime_type,
+        ..file
+    })
+}
@@ -0,0 +1,321 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::CandidType;
+use ic_cdk::api::msg_caller;
+use ic_principal::Principal;
+use serde::{Deserialize, Serialize};
+
+use utilities::{audit, now};
+
+use crate::{
+    Access, FILES, StoredFile, blobs, bucket, content_len, encryption, has_file_permission, policy,
+    quotas,
+};
+
+// File versions. Uploading new content to a file keeps the previous content as
+// an older version, up to the file's retention limit. Every version holds its
+// own blob reference and counts towards the owner's quota; the newest one is
+// the file's current content. Restoring a version adds it again as the newest,
+// so the history only ever grows at the end. Encrypted files only get new
+// versions from key rotations, as versions don't record the key of their
+// ciphertext.
+
+const DEFAULT_RETENTION: u32 = 10;
+const MAX_RETENTION: u32 = 100;
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub version: u32,
+    pub content_hash: String,
+    pub size: usize,
+    pub uploaded_by: Principal,
+    pub uploaded_at: String,
+    pub comment: String,
+}
+
+struct History {
+    // Oldest first, the last one is current.
+    versions: Vec<FileVersion>,
+    retention: u32,
+}
+
+thread_local! {
+    // Files get a history with their second version.
+    static HISTORIES: RefCell<HashMap<String, History>> = RefCell::new(HashMap::new());
+}
+
+fn first_version(file: &StoredFile) -> FileVersion {
+    FileVersion {
+        version: 1,
+        content_hash: file.content_hash.clone(),
+        size: content_len(file) as usize,
+        uploaded_by: file.owner,
+        uploaded_at: file.uploaded_at.clone(),
+        comment: String::new(),
+    }
+}
+
+fn new_history(file: &StoredFile) -> History {
+    History {
+        versions: vec![first_version(file)],
+        retention: DEFAULT_RETENTION,
+    }
+}
+
+// Drops the oldest versions beyond the retention limit.
+fn prune(file: &StoredFile, history: &mut History) {
+    let excess = history
+        .versions
+        .len()
+        .saturating_sub(history.retention as usize);
+
+    for version in history.versions.drain(..excess) {
+        blobs::release(&version.content_hash);
+        quotas::remove_bytes(file, version.size as u64);
+    }
+}
+
+// Makes `content_hash` the current content of `file`. The caller has already
+// added a blob reference for it.
+fn push(file: &mut StoredFile, content_hash: String, size: usize, comment: String) -> FileVersion {
+    quotas::add_bytes(file, size as u64);
+
+    let version = HISTORIES.with_borrow_mut(|histories| {
+        let history = histories
+            .entry(file.id.clone())
+            .or_insert_with(|| new_history(file));
+
+        let version = FileVersion {
+            version: history.versions.last().map_or(1, |v| v.version) + 1,
+            content_hash: content_hash.clone(),
+            size,
+            uploaded_by: msg_caller(),
+            uploaded_at: now(),
+            comment,
+        };
+
+        history.versions.push(version.clone());
+        prune(file, history);
+
+        version
+    });
+
+    file.content_hash = content_hash;
+    file.size = size;
+
+    version
+}
+
+pub fn current_version(file_id: &str) -> u32 {
+    HISTORIES.with_borrow(|histories| {
+        histories
+            .get(file_id)
+            .and_then(|history| history.versions.last())
+            .map_or(1, |version| version.version)
+    })
+}
+
+// Releases the older versions of a deleted file. Its current content is
+// released with the file.
+pub fn forget(file: &StoredFile) {
+    let Some(mut history) = HISTORIES.with_borrow_mut(|histories| histories.remove(&file.id))
+    else {
+        return;
+    };
+
+    history.versions.pop();
+    for version in history.versions {
+        blobs::release(&version.content_hash);
+        quotas::remove_bytes(file, version.size as u64);
+    }
+}
+
+fn find_version(file: &StoredFile, version: u32) -> Result<FileVersion, String> {
+    HISTORIES
+        .with_borrow(|histories| {
+            histories.get(&file.id).map_or_else(
+                || vec![first_version(file)],
+                |history| history.versions.clone(),
+            )
+        })
+        .into_iter()
+        .find(|v| v.version == version)
+        .ok_or(format!("Version {version} not found."))
+}
+
+fn editable() -> Vec<Access> {
+    let mut access = Access::can_edit();
+    access.push(Access::Owner);
+    access
+}
+
+fn readable() -> Vec<Access> {
+    let mut access = Access::all();
+    access.push(Access::Owner);
+    access
+}
+
+#[ic_cdk::update]
+fn upload_version(file_id: String, data: Vec<u8>, comment: String) -> Result<FileVersion, String> {
+    FILES.with_borrow_mut(|files| {
+        let file = files.get_mut(&file_id).ok_or("StoredFile not found.")?;
+
+        if !has_file_permission(file, &editable(), None) {
+            return Err("You are not authorized to edit this file.".to_string());
+        }
+        if encryption::is_encrypted(&file.id) {
+            return Err("Encrypted files get new content by rotating their key.".to_string());
+        }
+
+        add_version(file, data, comment)
+    })
+}
+
+// Makes `data` the current content of `file`, for callers that have checked
+// the caller may edit it.
+pub fn add_version(
+    file: &mut StoredFile,
+    data: Vec<u8>,
+    comment: String,
+) -> Result<FileVersion, String> {
+    // A new version keeps the type and the context of the file.
+    policy::admit(policy::context_of(&file.id), &file.mime_type, &data)?;
+
+    let content_hash = blobs::hash(&data);
+    if content_hash == file.content_hash {
+        return Err("This content is already the current version.".to_string());
+    }
+
+    quotas::check_bytes(file.owner, data.len() as u64).map_err(|err| err.to_string())?;
+
+    let size = data.len();
+    blobs::store(&content_hash, data);
+
+    let version = push(file, content_hash, size, comment);
+    bucket::file_stored(&file.id);
+
+    Ok(version)
+}
+
+// Newest first.
+#[ic_cdk::query]
+fn list_versions(file_id: String) -> Result<Vec<FileVersion>, String> {
+    FILES.with_borrow(|files| {
+        let file = files.get(&file_id).ok_or("StoredFile not found.")?;
+
+        if !has_file_permission(file, &readable(), None) {
+            return Err("You are not authorized to read this file.".to_string());
+        }
+
+        let mut versions = HISTORIES.with_borrow(|histories| {
+            histories.get(&file_id).map_or_else(
+                || vec![first_version(file)],
+                |history| history.versions.clone(),
+            )
+        });
+        versions.reverse();
+
+        Ok(versions)
+    })
+}
+
+// The file as it was at `version`.
+#[ic_cdk::query]
+fn get_version(file_id: String, version: u32) -> Result<StoredFile, String> {
+    FILES.with_borrow(|files| {
+        let file = files.get(&file_id).ok_or("StoredFile not found.")?;
+
+        if !has_file_permission(file, &readable(), None) {
+            return Err("You are not authorized to read this file.".to_string());
+        }
+
+        let version = find_version(file, version)?;
+
+        Ok(StoredFile {
+            data: blobs::content(&version.content_hash),
+            content_hash: version.content_hash,
+            size: version.size,
+            ..file.clone()
+        })
+    })
+}
+
+#[ic_cdk::update]
+fn restore_version(
+    file_id: String,
+    version: u32,
+    comment: Option<String>,
+) -> Result<FileVersion, String> {
+    let restored = FILES.with_borrow_mut(|files| {
+        let file = files.get_mut(&file_id).ok_or("StoredFile not found.")?;
+
+        if !has_file_permission(file, &editable(), None) {
+            return Err("You are not authorized to edit this file.".to_string());
+        }
+
+        // Older ciphertext is under a key the holders may no longer have.
+        if encryption::is_encrypted(&file.id) {
+            return Err("Versions of encrypted files can't be restored.".to_string());
+        }
+
+        let target = find_version(file, version)?;
+        if target.content_hash == file.content_hash {
+            return Err("This content is already the current version.".to_string());
+        }
+
+        quotas::check_bytes(file.owner, target.size as u64).map_err(|err| err.to_string())?;
+
+        if !blobs::retain(&target.content_hash) {
+            return Err("The content of this version is missing.".to_string());
+        }
+
+        Ok(push(
+            file,
+            target.content_hash,
+            target.size,
+            comment.unwrap_or_else(|| format!("Restored version {version}.")),
+        ))
+    })?;
+
+    audit::record(
+        "restore_version",
+        vec![file_id],
+        Some(format!("version {}", restored.version - 1)),
+        Some(format!(
+            "version {} (restored from {version})",
+            restored.version
+        )),
+    );
+
+    Ok(restored)
+}
+
+// How many versions the file keeps, the current one included.
+#[ic_cdk::update]
+fn set_version_retention(file_id: String, max_versions: u32) -> Result<(), String> {
+    if !(1..=MAX_RETENTION).contains(&max_versions) {
+        return Err(format!(
+            "Files keep between 1 and {MAX_RETENTION} versions."
+        ));
+    }
+
+    FILES.with_borrow(|files| {
+        let file = files.get(&file_id).ok_or("StoredFile not found.")?;
+
+        if file.owner != msg_caller() {
+            return Err("Only the owner can change the retention of this file.".to_string());
+        }
+
+        HISTORIES.with_borrow_mut(|histories| {
+            let history = histories
+                .entry(file_id.clone())
+                .or_insert_with(|| new_history(file));
+
+            history.retention = max_versions;
+            prune(file, history);
+        });
+
+        Ok(())
+    })
+}
@@ -0,0 +1,35 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+// Embeds the storage canister wasm as the code of new buckets, gzipped so it
+// fits in one `install_code` call. Build `storage` for wasm32 first, or point
+// `BUCKET_WASM` at its wasm. Without it the index still builds, but can't
+// create buckets.
+fn main() {
+    let wasm_path = env::var("BUCKET_WASM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
+                .join("../../../target/wasm32-unknown-unknown/release/storage.wasm")
+        });
+
+    println!("cargo:rerun-if-env-changed=BUCKET_WASM");
+    println!("cargo:rerun-if-changed={}", wasm_path.display());
+
+    let gzipped = match fs::read(&wasm_path) {
+        Ok(wasm) => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&wasm).unwrap();
+            encoder.finish().unwrap()
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("bucket.wasm.gz"), gzipped).unwrap();
+}
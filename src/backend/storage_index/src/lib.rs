@@ -0,0 +1,402 @@
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::management_canister::{
+    CanisterInstallMode, CanisterSettings, CreateCanisterArgs, InstallCodeArgs,
+    create_canister_with_extra_cycles, install_code,
+};
+use ic_cdk::{api::msg_caller, export_candid};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use paginator::PaginatorResponse;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use utilities::audit::{self, AuditEntry};
+use utilities::memory::{
+    self, BUCKETS_MEMORY_ID, FILE_BUCKETS_MEMORY_ID, FRONTENDS_MEMORY_ID, INDEX_SETTINGS_MEMORY_ID,
+    Memory, PENDING_BUCKET_MEMORY_ID,
+};
+use utilities::{BucketReport, bucket_file_id, now};
+
+// Storage index. Files live in buckets, storage canisters the index creates
+// from the wasm embedded at build time (see `build.rs`). Clients ask
+// `route_upload` which bucket to upload to, upload there directly and find
+// files again with `locate_files`. Buckets report what they store, which keeps
+// their usage and the file id to bucket map here up to date. Once no bucket has
+// room for an upload, a new one is created, up to `max_buckets`, when a
+// controller or a frontend registered by one routes it. Buckets
+// prefix their file ids with their canister id, so ids don't collide across
+// buckets. Everything the index knows lives in stable memory.
+
+const BUCKET_WASM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/bucket.wasm.gz"));
+
+// Buckets keep their files on the heap, which is limited to 4 GiB.
+const DEFAULT_BUCKET_CAPACITY: u64 = 3 * 1024 * 1024 * 1024;
+
+// Every bucket costs `BUCKET_CYCLES`, so only this many are created unless a
+// controller allows more.
+const DEFAULT_MAX_BUCKETS: u64 = 10;
+
+// Cycles every new bucket starts with.
+const BUCKET_CYCLES: u128 = 2_000_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Bucket {
+    pub canister_id: Principal,
+    pub bytes_used: u64,
+    pub file_count: u64,
+    pub created_at: String,
+}
+
+impl Storable for Bucket {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode bucket."))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        Encode!(&self).expect("Failed to encode bucket.")
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode bucket.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Serialize, Deserialize)]
+struct Settings {
+    bucket_capacity: u64,
+    max_buckets: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            bucket_capacity: DEFAULT_BUCKET_CAPACITY,
+            max_buckets: DEFAULT_MAX_BUCKETS,
+        }
+    }
+}
+
+impl Storable for Settings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode index settings."))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        Encode!(&self).expect("Failed to encode index settings.")
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode index settings.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Keyed by creation order, uploads fill older buckets first.
+    static BUCKETS: RefCell<StableBTreeMap<u64, Bucket, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(BUCKETS_MEMORY_ID)));
+    static FILE_BUCKETS: RefCell<StableBTreeMap<String, Principal, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(FILE_BUCKETS_MEMORY_ID)));
+    static SETTINGS: RefCell<StableCell<Settings, Memory>> = RefCell::new(StableCell::init(
+        memory::get(INDEX_SETTINGS_MEMORY_ID),
+        Settings::default(),
+    ));
+    // A bucket created without its code installed yet. The next bucket
+    // creation installs it instead of creating another one.
+    static PENDING_BUCKET: RefCell<StableCell<Option<Principal>, Memory>> =
+        RefCell::new(StableCell::init(memory::get(PENDING_BUCKET_MEMORY_ID), None));
+    // Canisters allowed to have buckets created, besides controllers. The
+    // size routed is only a claim, so anyone else could spend a bucket's
+    // cycles with a single oversized one.
+    static FRONTENDS: RefCell<StableBTreeMap<Principal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(FRONTENDS_MEMORY_ID)));
+    // Set while a bucket is being created, so concurrent uploads don't create
+    // one each.
+    static CREATING_BUCKET: Cell<bool> = const { Cell::new(false) };
+}
+
+fn settings() -> Settings {
+    SETTINGS.with_borrow(|settings| *settings.get())
+}
+
+fn update_settings(update: impl FnOnce(&mut Settings)) -> Settings {
+    SETTINGS.with_borrow_mut(|cell| {
+        let mut settings = *cell.get();
+        update(&mut settings);
+        cell.set(settings)
+    })
+}
+
+async fn create_bucket() -> Result<Principal, String> {
+    if BUCKET_WASM.is_empty() {
+        return Err("No bucket wasm was embedded, build storage first.".to_string());
+    }
+
+    let index_canister_id = ic_cdk::api::canister_self();
+
+    let canister_id = match PENDING_BUCKET.with_borrow(|pending| *pending.get()) {
+        Some(canister_id) => canister_id,
+        None => {
+            let bucket_count = BUCKETS.with_borrow(|buckets| buckets.len());
+            if bucket_count >= settings().max_buckets {
+                return Err("No more buckets can be created, ask a controller.".to_string());
+            }
+
+            let created = create_canister_with_extra_cycles(
+                &CreateCanisterArgs {
+                    settings: Some(CanisterSettings {
+                        controllers: Some(vec![index_canister_id]),
+                        ..Default::default()
+                    }),
+                },
+                BUCKET_CYCLES,
+            )
+            .await
+            .map_err(|err| format!("Failed to create a bucket: {err}"))?;
+
+            // Recorded before installing, so a failed install is retried
+            // instead of leaving the canister behind.
+            PENDING_BUCKET.with_borrow_mut(|pending| pending.set(Some(created.canister_id)));
+            created.canister_id
+        }
+    };
+
+    install_code(&InstallCodeArgs {
+        mode: CanisterInstallMode::Install,
+        canister_id,
+        wasm_module: BUCKET_WASM.to_vec(),
+        arg: candid::encode_one(Some(index_canister_id)).unwrap(),
+    })
+    .await
+    .map_err(|err| format!("Failed to install bucket {canister_id}: {err}"))?;
+
+    PENDING_BUCKET.with_borrow_mut(|pending| pending.set(None));
+    BUCKETS.with_borrow_mut(|buckets| {
+        buckets.insert(
+            buckets.len(),
+            Bucket {
+                canister_id,
+                bytes_used: 0,
+                file_count: 0,
+                created_at: now(),
+            },
+        )
+    });
+    audit::record("create_bucket", vec![canister_id.to_text()], None, None);
+
+    Ok(canister_id)
+}
+
+// The position of a bucket in `BUCKETS`.
+fn bucket_key(canister_id: Principal) -> Option<u64> {
+    BUCKETS.with_borrow(|buckets| {
+        buckets
+            .iter()
+            .find(|entry| entry.value().canister_id == canister_id)
+            .map(|entry| *entry.key())
+    })
+}
+
+// The bucket to upload `size` bytes to. The capacity is a soft limit: uploads
+// routed at the same time can together go past it.
+#[ic_cdk::update]
+async fn route_upload(size: u64) -> Result<Principal, String> {
+    if msg_caller() == Principal::anonymous() {
+        return Err("Sign in to upload files.".to_string());
+    }
+
+    let capacity = settings().bucket_capacity;
+    if size > capacity {
+        return Err(format!("Files are limited to {capacity} bytes."));
+    }
+
+    let with_room = BUCKETS.with_borrow(|buckets| {
+        buckets
+            .values()
+            .find(|bucket| bucket.bytes_used + size <= capacity)
+            .map(|bucket| bucket.canister_id)
+    });
+    if let Some(canister_id) = with_room {
+        return Ok(canister_id);
+    }
+
+    let caller = msg_caller();
+    if !ic_cdk::api::is_controller(&caller)
+        && !FRONTENDS.with_borrow(|frontends| frontends.contains_key(&caller))
+    {
+        return Err("No bucket has room for this file, try again later.".to_string());
+    }
+
+    if CREATING_BUCKET.replace(true) {
+        return Err("A new bucket is being created, try again shortly.".to_string());
+    }
+    let created = create_bucket().await;
+    CREATING_BUCKET.set(false);
+
+    created
+}
+
+// Buckets only report ids from their own namespace, see `bucket_file_id`.
+#[ic_cdk::update]
+fn bucket_report(report: BucketReport) -> Result<(), String> {
+    let bucket_id = msg_caller();
+
+    let Some(key) = bucket_key(bucket_id) else {
+        return Err("Only buckets can report.".to_string());
+    };
+
+    let namespace = bucket_file_id(bucket_id, "");
+    if let Some(file_id) = report
+        .stored
+        .iter()
+        .chain(&report.removed)
+        .find(|file_id| !file_id.starts_with(&namespace))
+    {
+        return Err(format!("{file_id} is not a file of this bucket."));
+    }
+
+    let (stored, removed) = FILE_BUCKETS.with_borrow_mut(|file_buckets| {
+        let stored = report
+            .stored
+            .into_iter()
+            .filter(|file_id| file_buckets.insert(file_id.clone(), bucket_id).is_none())
+            .count() as u64;
+        let removed = report
+            .removed
+            .iter()
+            .filter(|file_id| file_buckets.remove(*file_id).is_some())
+            .count() as u64;
+
+        (stored, removed)
+    });
+
+    BUCKETS.with_borrow_mut(|buckets| {
+        let mut bucket = buckets.get(&key).expect("The bucket was found above.");
+        bucket.bytes_used = report.bytes_used;
+        bucket.file_count = (bucket.file_count + stored).saturating_sub(removed);
+        buckets.insert(key, bucket);
+    });
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn locate_file(file_id: String) -> Option<Principal> {
+    FILE_BUCKETS.with_borrow(|file_buckets| file_buckets.get(&file_id))
+}
+
+#[ic_cdk::query]
+fn locate_files(file_ids: Vec<String>) -> Vec<(String, Option<Principal>)> {
+    FILE_BUCKETS.with_borrow(|file_buckets| {
+        file_ids
+            .into_iter()
+            .map(|id| {
+                let bucket = file_buckets.get(&id);
+                (id, bucket)
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_buckets() -> Vec<Bucket> {
+    BUCKETS.with_borrow(|buckets| buckets.values().collect())
+}
+
+#[ic_cdk::query]
+fn get_bucket_capacity() -> u64 {
+    settings().bucket_capacity
+}
+
+fn require_controller() -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure buckets.".to_string());
+    }
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_bucket_capacity(bytes: u64) -> Result<(), String> {
+    require_controller()?;
+
+    let before = update_settings(|settings| settings.bucket_capacity = bytes);
+    audit::record(
+        "set_bucket_capacity",
+        vec![],
+        Some(format!("{} bytes", before.bucket_capacity)),
+        Some(format!("{bytes} bytes")),
+    );
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_max_buckets() -> u64 {
+    settings().max_buckets
+}
+
+#[ic_cdk::update]
+fn set_max_buckets(max_buckets: u64) -> Result<(), String> {
+    require_controller()?;
+
+    let before = update_settings(|settings| settings.max_buckets = max_buckets);
+    audit::record(
+        "set_max_buckets",
+        vec![],
+        Some(before.max_buckets.to_string()),
+        Some(max_buckets.to_string()),
+    );
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_frontends() -> Vec<Principal> {
+    FRONTENDS.with_borrow(|frontends| frontends.keys().collect())
+}
+
+#[ic_cdk::update]
+fn register_frontend(canister_id: Principal) -> Result<(), String> {
+    require_controller()?;
+
+    FRONTENDS.with_borrow_mut(|frontends| frontends.insert(canister_id, ()));
+    audit::record("register_frontend", vec![canister_id.to_text()], None, None);
+
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unregister_frontend(canister_id: Principal) -> Result<(), String> {
+    require_controller()?;
+
+    if FRONTENDS
+        .with_borrow_mut(|frontends| frontends.remove(&canister_id))
+        .is_none()
+    {
+        return Err("Not a registered frontend.".to_string());
+    }
+    audit::record(
+        "unregister_frontend",
+        vec![canister_id.to_text()],
+        None,
+        None,
+    );
+
+    Ok(())
+}
+
+// Audit
+#[ic_cdk::query]
+fn get_audit_log(page: usize, per_page: usize) -> Result<PaginatorResponse<AuditEntry>, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can read the audit log.".to_string());
+    }
+
+    Ok(audit::page(page, per_page))
+}
+
+export_candid!();
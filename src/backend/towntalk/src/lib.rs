@@ -5,7 +5,14 @@ use paginator::{HasFields, Paginator, PaginatorResponse};
 use serde::{Deserialize, Serialize};
 use std::{cell::RefCell, collections::HashMap};
 
-use utilities::{StoredFile, generate_uuid, get_files, now, upload_files};
+use utilities::audit::{self, AuditEntry};
+use utilities::ledger::{Account as LedgerAccount, IcrcLedger};
+use utilities::notifications::{NotificationEvent, notify};
+use utilities::rewards::{self, Payout, PayoutBook, PayoutSource, pay};
+use utilities::{
+    FileUploadResolveType, StorageEvent, StoredFile, UploadContext, generate_uuid, get_files, now,
+    reference_files, unreference_files, upload_files_for,
+};
 
 #[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
 struct Comment {
@@ -32,20 +39,6 @@ struct Post {
     updated_at: String,
 }
 
-#[derive(CandidType, Clone, Serialize, Deserialize)]
-struct PostCreationPayload {
-    id: String,
-    poster_id: String,
-    title: String,
-    caption: String,
-    medias: Vec<StoredFile>,
-    likes: Vec<String>,
-    shares: Vec<String>,
-    comments: Vec<Comment>,
-    created_at: String,
-    updated_at: String,
-}
-
 #[derive(CandidType, Clone, Serialize, Deserialize)]
 struct FeedPost {
     id: String,
@@ -168,20 +161,6 @@ struct Echo {
     created_at: String,
 }
 
-#[derive(CandidType, Clone, Serialize, Deserialize)]
-pub enum LikableType {
-    POST,
-    STORY,
-}
-
-#[derive(CandidType, Clone, Serialize, Deserialize)]
-struct Like {
-    account_id: String,
-    likable_id: String,
-    likable_type: LikableType,
-    created_at: String,
-}
-
 #[derive(CandidType, Clone, Serialize, Deserialize)]
 pub enum ReportType {
     SPAM,
@@ -218,12 +197,6 @@ struct FollowRequest {
     requested_at: String,
 }
 
-#[derive(CandidType, Clone, Serialize, Deserialize)]
-struct FollowRequestReturnPayload {
-    requester: AccountVisibleInformation,
-    requested_at: String,
-}
-
 #[derive(CandidType, Clone, Serialize, Deserialize)]
 struct ValidityCheckingPayload {
     username: String,
@@ -234,10 +207,10 @@ thread_local! {
     static ACCOUNTS: RefCell<HashMap<String, Account>> = RefCell::new(HashMap::new());
     static FOLLOW_REQUESTS: RefCell<HashMap<String, FollowRequest>> = RefCell::new(HashMap::new());
     static POSTS: RefCell<HashMap<String, Post>> = RefCell::new(HashMap::new());
-    static LIKES: RefCell<HashMap<String, Like>> = RefCell::new(HashMap::new());
     static COMMENTS: RefCell<HashMap<String, Comment>> = RefCell::new(HashMap::new());
     static ECHOS: RefCell<HashMap<String, Echo>> = RefCell::new(HashMap::new());
     static REPORTS: RefCell<HashMap<String, Report>> = RefCell::new(HashMap::new());
+    static PAYOUTS: RefCell<PayoutBook> = RefCell::new(PayoutBook::default());
 }
 
 // Accounts
@@ -376,7 +349,7 @@ fn check_validity(payload: ValidityCheckingPayload) -> bool {
 #[ic_cdk::update]
 async fn create_account(
     payload: AccountCreationPayload,
-    storage_canister_id: Principal,
+    _storage_canister_id: Principal,
 ) -> Account {
     let principal: Principal = msg_caller();
 
@@ -384,16 +357,39 @@ async fn create_account(
 
     let mut profile_picture_id: Option<String> = None;
 
-    if let Some(pfp) = payload.profile.profile_picture {
-        let upload_response = upload_files(storage_canister_id, vec![pfp]).await;
-
-        if !upload_response.is_empty() {
-            profile_picture_id = Some(
-                upload_response
-                    .iter()
-                    .map(|(id, _, _)| id.clone())
-                    .collect::<Vec<String>>()[0]
-                    .clone(),
+    // Pictures go to the configured storage canister, never to one the client
+    // names, and are dropped until one is configured.
+    if let Some(pfp) = payload.profile.profile_picture
+        && let Some(storage_canister_id) = utilities::storage_canister_id()
+    {
+        // The picture belongs to the user, towntalk can only read it.
+        let upload_response = upload_files_for(
+            storage_canister_id,
+            principal,
+            vec![pfp],
+            Some(UploadContext::ProfilePicture),
+        )
+        .await;
+
+        // A rejected picture leaves the account without one.
+        profile_picture_id = upload_response
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(_, resolve, _)| {
+                matches!(
+                    resolve,
+                    FileUploadResolveType::SuccessfullyUploaded
+                        | FileUploadResolveType::AlreadyUploaded
+                )
+            })
+            .map(|(id, _, _)| id);
+
+        if let Some(pfp_id) = &profile_picture_id {
+            reference_files(
+                storage_canister_id,
+                vec![pfp_id.clone()],
+                account_id.clone(),
+                principal,
             );
         }
     }
@@ -499,20 +495,61 @@ async fn get_user_accounts(storage_canister_id: Principal) -> Vec<AccountVisible
     result
 }
 
+// Drops the storage references of a deleted account's picture and post medias,
+// so their owners can delete them.
+fn release_files(account_id: &str, profile_picture: Option<String>, post_ids: &[String]) {
+    let Some(storage_canister_id) = utilities::storage_canister_id() else {
+        return;
+    };
+
+    unreference_files(
+        storage_canister_id,
+        profile_picture.into_iter().collect(),
+        account_id.to_string(),
+    );
+
+    POSTS.with_borrow(|post_map: &HashMap<String, Post>| {
+        for post_id in post_ids {
+            if let Some(post) = post_map.get(post_id) {
+                unreference_files(storage_canister_id, post.medias.clone(), post_id.clone());
+            }
+        }
+    });
+}
+
 #[ic_cdk::update]
 fn delete_account(payload: AccountDeletionPayload) {
     let account_id = payload.account_id.clone();
+    let owned = is_owned(account_id.clone());
 
-    ACCOUNTS.with_borrow_mut(|accounts: &mut HashMap<String, Account>| {
+    let deleted = ACCOUNTS.with_borrow_mut(|accounts: &mut HashMap<String, Account>| {
         let account = accounts.get_mut(&account_id);
 
-        if let Some(acc) = account {
-            if is_owned(account_id.clone()) {
-                acc.deleted_at = Some(now());
-            }
+        if let Some(acc) = account
+            && owned
+        {
+            let before = format!("deleted_at: {:?}", acc.deleted_at);
+            acc.deleted_at = Some(now());
+            return Some((
+                before,
+                format!("deleted_at: {:?}", acc.deleted_at),
+                acc.profile.profile_picture.clone(),
+                acc.posts.clone(),
+            ));
         }
+        None
     });
 
+    if let Some((before, after, profile_picture, post_ids)) = deleted {
+        audit::record(
+            "delete_account",
+            vec![account_id.clone()],
+            Some(before),
+            Some(after),
+        );
+        release_files(&account_id, profile_picture, &post_ids);
+    }
+
     let principal: Principal = msg_caller();
 
     USER_ACCOUNTS.with_borrow_mut(
@@ -539,22 +576,36 @@ fn report_account(payload: Report) {
 
 #[ic_cdk::update]
 fn block_account(account_id: String, target_id: String) {
-    ACCOUNTS.with_borrow_mut(|account_map: &mut HashMap<String, Account>| {
-        if let Some(acc) = account_map.get_mut(&account_id) {
-            if is_owned(account_id) {
-                acc.blocked.push((target_id, now()));
-            }
+    let owned = is_owned(account_id.clone());
+
+    let blocked = ACCOUNTS.with_borrow_mut(|account_map: &mut HashMap<String, Account>| {
+        if let Some(acc) = account_map.get_mut(&account_id)
+            && owned
+        {
+            let before = acc.blocked.len();
+            acc.blocked.push((target_id.clone(), now()));
+            return Some((before, acc.blocked.len()));
         }
-    })
+        None
+    });
+
+    if let Some((before, after)) = blocked {
+        audit::record(
+            "block_account",
+            vec![account_id, target_id],
+            Some(format!("blocked accounts: {before}")),
+            Some(format!("blocked accounts: {after}")),
+        );
+    }
 }
 
 #[ic_cdk::update]
 fn unblock_account(account_id: String, target_id: String) {
     ACCOUNTS.with_borrow_mut(|account_map: &mut HashMap<String, Account>| {
-        if let Some(acc) = account_map.get_mut(&account_id) {
-            if is_owned(account_id) {
-                acc.blocked.retain(|(blocked, _)| blocked != &target_id);
-            }
+        if let Some(acc) = account_map.get_mut(&account_id)
+            && is_owned(account_id)
+        {
+            acc.blocked.retain(|(blocked, _)| blocked != &target_id);
         }
     })
 }
@@ -609,25 +660,39 @@ fn get_profile(account_id: String) -> Option<AccountProfile> {
 
 #[ic_cdk::update]
 fn follow(account_id: String, target_id: String) {
-    if is_owned(account_id.clone()) {
+    if is_owned(account_id.clone()) && can_view(account_id.clone(), target_id.clone()) {
         let account_id_cloned = account_id.clone();
         ACCOUNTS.with_borrow_mut(|account_map| {
             if let Some(acc) = account_map.get_mut(&target_id) {
-                if can_view(account_id_cloned.clone(), acc.id.clone()) {
-                    if acc.private {
-                        // create follow request
-                        FOLLOW_REQUESTS.with_borrow_mut(|request_map| {
-                            request_map.insert(
-                                target_id,
-                                FollowRequest {
-                                    requester_id: account_id.clone(),
-                                    requested_at: now(),
-                                },
-                            );
-                        });
-                    } else {
-                        acc.followers.push((account_id_cloned.clone(), now()));
-                    }
+                if acc.private {
+                    // create follow request
+                    FOLLOW_REQUESTS.with_borrow_mut(|request_map| {
+                        request_map.insert(
+                            target_id.clone(),
+                            FollowRequest {
+                                requester_id: account_id.clone(),
+                                requested_at: now(),
+                            },
+                        );
+                    });
+
+                    notify(
+                        vec![acc.user_id],
+                        NotificationEvent::FollowRequest {
+                            account_id: target_id,
+                            requester_id: account_id.clone(),
+                        },
+                    );
+                } else {
+                    acc.followers.push((account_id_cloned.clone(), now()));
+
+                    notify(
+                        vec![acc.user_id],
+                        NotificationEvent::NewFollower {
+                            account_id: target_id,
+                            follower_id: account_id_cloned,
+                        },
+                    );
                 }
             }
         });
@@ -669,22 +734,22 @@ fn accept_follow_request(account_id: String, target_id: String) {
     if is_owned(account_id.clone()) {
         let account_id_cloned = account_id.clone();
         ACCOUNTS.with_borrow_mut(|account_map| {
-            if let Some(acc) = account_map.get_mut(&target_id) {
-                if can_view(account_id_cloned.clone(), acc.id.clone()) {
-                    if acc.private {
-                        // create follow request
-                        FOLLOW_REQUESTS.with_borrow_mut(|request_map| {
-                            request_map.insert(
-                                target_id,
-                                FollowRequest {
-                                    requester_id: account_id.clone(),
-                                    requested_at: now(),
-                                },
-                            );
-                        });
-                    } else {
-                        acc.followers.push((account_id_cloned.clone(), now()));
-                    }
+            if let Some(acc) = account_map.get_mut(&target_id)
+                && can_view(account_id_cloned.clone(), acc.id.clone())
+            {
+                if acc.private {
+                    // create follow request
+                    FOLLOW_REQUESTS.with_borrow_mut(|request_map| {
+                        request_map.insert(
+                            target_id,
+                            FollowRequest {
+                                requester_id: account_id.clone(),
+                                requested_at: now(),
+                            },
+                        );
+                    });
+                } else {
+                    acc.followers.push((account_id_cloned.clone(), now()));
                 }
             }
         });
@@ -780,14 +845,26 @@ async fn get_following(
 // Posts
 #[ic_cdk::update]
 fn create_post(account_id: String, post: Post) {
-    if is_owned(account_id.clone()) {
+    // Post ids come from the client, so a taken id is refused, never overwritten.
+    let is_taken = POSTS.with_borrow(|post_map| post_map.contains_key(&post.id));
+
+    if is_owned(account_id.clone()) && !is_taken {
         let account_id_cloned = account_id.clone();
         let post_cloned = post.clone();
 
         POSTS.with_borrow_mut(|post_map: &mut HashMap<String, Post>| {
-            post_map.insert(account_id_cloned.clone(), post_cloned.clone());
+            post_map.insert(post_cloned.id.clone(), post_cloned.clone());
         });
 
+        if let Some(storage_canister_id) = utilities::storage_canister_id() {
+            reference_files(
+                storage_canister_id,
+                post.medias.clone(),
+                post.id.clone(),
+                msg_caller(),
+            );
+        }
+
         ACCOUNTS.with_borrow_mut(|account_map: &mut HashMap<String, Account>| {
             if let Some(acc) = account_map.get_mut(&account_id_cloned) {
                 acc.posts.push(post_cloned.id);
@@ -882,39 +959,78 @@ async fn get_feeds(
 
 #[ic_cdk::update]
 fn like_post(account_id: String, post_id: String) {
-    POSTS.with_borrow_mut(|post_map: &mut HashMap<String, Post>| {
-        if let Some(post) = post_map.get_mut(&post_id) {
-            if can_view(account_id.clone(), post.poster_id.clone()) {
-                if post.likes.contains(&account_id) {
-                    post.likes.retain(|p| p != &account_id);
-                } else {
-                    post.likes.push(account_id.clone());
-                }
+    let liked_poster = POSTS.with_borrow_mut(|post_map: &mut HashMap<String, Post>| {
+        if let Some(post) = post_map.get_mut(&post_id)
+            && can_view(account_id.clone(), post.poster_id.clone())
+        {
+            if post.likes.contains(&account_id) {
+                post.likes.retain(|p| p != &account_id);
+            } else {
+                post.likes.push(account_id.clone());
+                return Some(post.poster_id.clone());
             }
         }
+        None
     });
+
+    if let Some(poster_id) = liked_poster {
+        notify_poster(
+            &poster_id,
+            &account_id,
+            NotificationEvent::PostLiked {
+                post_id,
+                account_id: account_id.clone(),
+            },
+        );
+    }
 }
 
 #[ic_cdk::update]
 fn comment_post(account_id: String, post_id: String, comment: Comment) {
-    POSTS.with_borrow_mut(|post_map: &mut HashMap<String, Post>| {
-        if let Some(post) = post_map.get_mut(&post_id) {
-            if can_view(account_id.clone(), post.poster_id.clone()) {
-                post.comments.push(comment.clone());
-            }
+    let commented_poster = POSTS.with_borrow_mut(|post_map: &mut HashMap<String, Post>| {
+        if let Some(post) = post_map.get_mut(&post_id)
+            && can_view(account_id.clone(), post.poster_id.clone())
+        {
+            post.comments.push(comment.clone());
+            return Some(post.poster_id.clone());
         }
+        None
     });
+
+    if let Some(poster_id) = commented_poster {
+        notify_poster(
+            &poster_id,
+            &account_id,
+            NotificationEvent::PostCommented {
+                post_id,
+                comment_id: comment.id,
+                account_id: account_id.clone(),
+            },
+        );
+    }
+}
+
+// Nobody is notified about their own activity.
+fn notify_poster(poster_id: &str, account_id: &str, event: NotificationEvent) {
+    if poster_id == account_id {
+        return;
+    }
+
+    if let Some(user_id) =
+        ACCOUNTS.with_borrow(|account_map| account_map.get(poster_id).map(|acc| acc.user_id))
+    {
+        notify(vec![user_id], event);
+    }
 }
 
 #[ic_cdk::update]
 fn remove_comment(account_id: String, post_id: String, comment_id: String) {
     POSTS.with_borrow_mut(|post_map: &mut HashMap<String, Post>| {
-        if let Some(post) = post_map.get_mut(&post_id) {
-            if is_comment_owner(account_id.clone(), comment_id.clone())
-                || is_post_owner(post.id.clone())
-            {
-                post.comments.retain(|c| c.id != comment_id);
-            }
+        if let Some(post) = post_map.get_mut(&post_id)
+            && (is_comment_owner(account_id.clone(), comment_id.clone())
+                || is_post_owner(post.id.clone()))
+        {
+            post.comments.retain(|c| c.id != comment_id);
         }
     });
 
@@ -978,7 +1094,6 @@ async fn get_echos(
                             })
                         });
 
-                        let storage_canister_id = storage_canister_id;
                         let a_id = a.id.clone();
 
                         async move {
@@ -1018,6 +1133,156 @@ async fn get_echos(
     }
 }
 
+// Notifications
+#[ic_cdk::update]
+fn set_notifications_canister(notifications_canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure notifications.".to_string());
+    }
+
+    utilities::notifications::configure(notifications_canister_id);
+    Ok(())
+}
+
+// Storage
+// Posts reference their medias in the storage canister set here, which then
+// refuses or reports their deletion through `storage_event`.
+#[ic_cdk::update]
+fn set_storage_canister(storage_canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure storage.".to_string());
+    }
+
+    utilities::configure_storage(storage_canister_id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn storage_event(event: StorageEvent) {
+    if utilities::storage_canister_id() != Some(msg_caller()) {
+        return;
+    }
+
+    match event {
+        StorageEvent::FileDeleted { file_id, entities } => {
+            POSTS.with_borrow_mut(|post_map: &mut HashMap<String, Post>| {
+                for post_id in entities.iter() {
+                    if let Some(post) = post_map.get_mut(post_id) {
+                        post.medias.retain(|media| *media != file_id);
+                    }
+                }
+            });
+
+            ACCOUNTS.with_borrow_mut(|account_map: &mut HashMap<String, Account>| {
+                for account_id in entities.iter() {
+                    if let Some(acc) = account_map.get_mut(account_id)
+                        && acc.profile.profile_picture.as_ref() == Some(&file_id)
+                    {
+                        acc.profile.profile_picture = None;
+                    }
+                }
+            });
+        }
+        // Medias are read through storage, which checks permissions itself.
+        StorageEvent::PermissionsChanged { .. } => {}
+    }
+}
+
+// Tips
+#[ic_cdk::update]
+fn set_ledger_canister(ledger_canister_id: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can configure the ledger.".to_string());
+    }
+
+    utilities::ledger::configure(ledger_canister_id);
+    Ok(())
+}
+
+// Moves `amount` CRY from the caller to the post creator. The caller must
+// first `icrc2_approve` this canister for the amount plus the ledger fee.
+// `tip_key` is chosen by the client and reused when retrying the same tip, so
+// a retry never pays twice.
+#[ic_cdk::update]
+async fn tip_creator(
+    account_id: String,
+    post_id: String,
+    amount: u64,
+    tip_key: String,
+) -> Result<Payout, String> {
+    if !is_owned(account_id.clone()) {
+        return Err("You do not own this account.".to_string());
+    }
+
+    if amount == 0 {
+        return Err("Tip amount must be greater than zero.".to_string());
+    }
+
+    if tip_key.is_empty() {
+        return Err("A tip key is required.".to_string());
+    }
+
+    let tip_id = format!("{}:{post_id}:{tip_key}", msg_caller());
+    let reused = PAYOUTS.with_borrow(|book| {
+        book.get(&format!("tip:{tip_id}"))
+            .is_some_and(|payout| payout.amount != amount)
+    });
+    if reused {
+        return Err("This tip key was already used for another amount.".to_string());
+    }
+
+    let poster_id = POSTS
+        .with_borrow(|post_map| post_map.get(&post_id).map(|p| p.poster_id.clone()))
+        .ok_or("Post not found.")?;
+
+    if !can_view(account_id.clone(), poster_id.clone()) {
+        return Err("You cannot view this post.".to_string());
+    }
+
+    let creator = ACCOUNTS
+        .with_borrow(|account_map| account_map.get(&poster_id).map(|acc| acc.user_id))
+        .ok_or("Creator not found.")?;
+
+    let ledger = IcrcLedger::configured().map_err(|_| "Ledger is not configured.")?;
+
+    let payout = pay(
+        &PAYOUTS,
+        &ledger,
+        Payout::creator_tip(
+            tip_id,
+            post_id,
+            account_id,
+            LedgerAccount::of(msg_caller()),
+            LedgerAccount::of(creator),
+            amount,
+            ic_cdk::api::time(),
+        ),
+        ic_cdk::api::time(),
+    )
+    .await;
+
+    if payout.is_paid() {
+        Ok(payout)
+    } else {
+        Err(format!("Tip could not be paid: {:?}", payout.status))
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_payouts() -> Vec<Payout> {
+    let principal = msg_caller();
+
+    PAYOUTS.with_borrow(|book| {
+        book.list()
+            .into_iter()
+            .filter(|payout| {
+                payout.to.owner == principal
+                    || payout.source == PayoutSource::Allowance(LedgerAccount::of(principal))
+            })
+            .collect()
+    })
+}
+
 #[ic_cdk::query]
 fn get_echo() {}
 
@@ -1094,4 +1359,25 @@ fn get_echo() {}
 //     "Seeded demo accounts with personalized posts and varied media.".to_string()
 // }
 
+// Audit
+#[ic_cdk::query]
+fn get_audit_log(page: usize, per_page: usize) -> Result<PaginatorResponse<AuditEntry>, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can read the audit log.".to_string());
+    }
+
+    Ok(audit::page(page, per_page))
+}
+
+// Upgrades
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    rewards::save_book(&PAYOUTS);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    rewards::restore_book(&PAYOUTS);
+}
+
 export_candid!();
@@ -2,9 +2,11 @@ use candid::CandidType;
 use ic_cdk::api::msg_caller;
 use ic_cdk::export_candid;
 use ic_principal::Principal;
+use paginator::PaginatorResponse;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use utilities::audit::{self, AuditEntry};
 
 #[derive(Clone, Serialize, Deserialize, CandidType)]
 struct User {
@@ -51,12 +53,16 @@ fn seeder() {
             username: "Frank".to_string(),
         },
     ];
+    let ids = demo_users.iter().map(|user| user.id.to_text()).collect();
+
     USERS.with(|users| {
         let mut users = users.borrow_mut();
         for user in demo_users {
             users.users.insert(user.id, user);
         }
     });
+
+    audit::record("seeder", ids, None, Some("seeded demo users".to_string()));
 }
 
 #[ic_cdk::update]
@@ -85,4 +91,13 @@ fn get_all_users() -> Vec<(Principal, User)> {
     })
 }
 
+#[ic_cdk::query]
+fn get_audit_log(page: usize, per_page: usize) -> Result<PaginatorResponse<AuditEntry>, String> {
+    if !ic_cdk::api::is_controller(&msg_caller()) {
+        return Err("Only controllers can read the audit log.".to_string());
+    }
+
+    Ok(audit::page(page, per_page))
+}
+
 export_candid!();
@@ -0,0 +1,142 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::StableBTreeMap;
+use ic_stable_structures::storable::{Bound, Storable};
+use paginator::PaginatorResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::memory::{self, AUDIT_LOG_MEMORY_ID, Memory};
+
+// Oldest entries are dropped once the log grows past this size.
+pub const MAX_AUDIT_ENTRIES: u64 = 10_000;
+
+// One privileged or destructive call. `before` and `after` are short
+// human-readable summaries of the affected state, not full snapshots.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub actor: Principal,
+    pub endpoint: String,
+    pub target_ids: Vec<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub created_at: String,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode audit entry."))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        Encode!(&self).expect("Failed to encode audit entry.")
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode audit entry.")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    // Lives in stable memory, so the log survives canister upgrades.
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(AUDIT_LOG_MEMORY_ID)));
+}
+
+// Appends an entry on behalf of the current caller and returns its id.
+pub fn record(
+    endpoint: &str,
+    target_ids: Vec<String>,
+    before: Option<String>,
+    after: Option<String>,
+) -> u64 {
+    append(
+        AuditEntry {
+            id: 0,
+            actor: ic_cdk::api::msg_caller(),
+            endpoint: endpoint.to_string(),
+            target_ids,
+            before,
+            after,
+            created_at: crate::now(),
+        },
+        MAX_AUDIT_ENTRIES,
+    )
+}
+
+fn append(entry: AuditEntry, retention: u64) -> u64 {
+    AUDIT_LOG.with_borrow_mut(|log| {
+        let id = log.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        log.insert(id, AuditEntry { id, ..entry });
+
+        while log.len() > retention {
+            log.pop_first();
+        }
+
+        id
+    })
+}
+
+pub fn len() -> u64 {
+    AUDIT_LOG.with_borrow(|log| log.len())
+}
+
+// Newest first. Pages start at 1, like `Paginator::get`.
+pub fn page(page: usize, per_page: usize) -> PaginatorResponse<AuditEntry> {
+    let page = page.max(1);
+    let per_page = per_page.max(1);
+
+    let data: Vec<AuditEntry> = AUDIT_LOG.with_borrow(|log| {
+        log.values()
+            .rev()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .collect()
+    });
+
+    PaginatorResponse {
+        total_data: data.len(),
+        curr_page: page,
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(endpoint: &str) -> AuditEntry {
+        AuditEntry {
+            id: 0,
+            actor: Principal::anonymous(),
+            endpoint: endpoint.to_string(),
+            target_ids: vec!["target".to_string()],
+            before: Some("before".to_string()),
+            after: None,
+            created_at: "now".to_string(),
+        }
+    }
+
+    #[test]
+    fn retention_drops_oldest_entries_and_keeps_ids_increasing() {
+        for i in 0..5 {
+            assert_eq!(append(entry(&format!("call_{i}")), 3), i);
+        }
+
+        assert_eq!(len(), 3);
+
+        let newest = page(1, 10);
+        let endpoints: Vec<&str> = newest.data.iter().map(|e| e.endpoint.as_str()).collect();
+        assert_eq!(endpoints, vec!["call_4", "call_3", "call_2"]);
+        assert_eq!(newest.data[0].id, 4);
+
+        let second = page(2, 2);
+        assert_eq!(second.data.len(), 1);
+        assert_eq!(second.data[0].endpoint, "call_2");
+        assert!(page(3, 2).data.is_empty());
+    }
+}
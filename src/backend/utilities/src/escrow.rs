@@ -0,0 +1,688 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::thread::LocalKey;
+
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::ledger::{Account, Ledger, LedgerError, Subaccount};
+use crate::memory::{self, ESCROWS_MEMORY_ID};
+use crate::rewards::{Payout, PayoutBook, PayoutSource, PayoutStatus, RewardKind, pay};
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum EscrowPurpose {
+    PrizePool { competition_id: String },
+    CourseAccess { course_id: u64, buyer: Principal },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum EscrowState {
+    Open,      // created, waiting for the depositor's funds
+    Funded,    // funds are locked in the escrow subaccount
+    Releasing, // release plan fixed, transfers in flight or to be retried
+    Released,
+    Refunding,
+    Refunded,
+    Cancelled, // closed before any funds were locked
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct EscrowEvent {
+    pub state: EscrowState,
+    pub block_indexes: Vec<Nat>,
+    pub note: String,
+    pub at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Escrow {
+    pub id: String,
+    pub purpose: EscrowPurpose,
+    pub depositor: Account,
+    pub holder: Account,
+    pub amount: Nat,
+    pub state: EscrowState,
+    pub releases: Vec<(Account, Nat)>,
+    pub history: Vec<EscrowEvent>,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum EscrowError {
+    NotFound,
+    InvalidState(EscrowState),
+    InvalidAmount(String),
+    Ledger(LedgerError),
+}
+
+impl fmt::Display for EscrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EscrowError::NotFound => write!(f, "Escrow not found."),
+            EscrowError::InvalidState(state) => write!(f, "Escrow is {state:?}."),
+            EscrowError::InvalidAmount(reason) => write!(f, "{reason}"),
+            EscrowError::Ledger(err) => write!(f, "Ledger error: {err:?}"),
+        }
+    }
+}
+
+pub fn escrow_subaccount(escrow_id: &str) -> Subaccount {
+    Sha256::digest(format!("escrow:{escrow_id}").as_bytes()).to_vec()
+}
+
+#[derive(Default, Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct EscrowBook {
+    escrows: HashMap<String, Escrow>,
+}
+
+impl EscrowBook {
+    pub fn get(&self, id: &str) -> Option<Escrow> {
+        self.escrows.get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Escrow> {
+        self.escrows.values().cloned().collect()
+    }
+
+    fn transition(
+        &mut self,
+        id: &str,
+        state: EscrowState,
+        block_indexes: Vec<Nat>,
+        note: String,
+        at: u64,
+    ) -> Option<Escrow> {
+        let escrow = self.escrows.get_mut(id)?;
+
+        escrow.state = state.clone();
+        escrow.history.push(EscrowEvent {
+            state,
+            block_indexes,
+            note,
+            at,
+        });
+
+        Some(escrow.clone())
+    }
+}
+
+// Escrow books live on the heap like payout books, and are saved and restored
+// around upgrades the same way.
+pub fn save_book(book: &'static LocalKey<RefCell<EscrowBook>>) {
+    book.with_borrow(|book| memory::save(ESCROWS_MEMORY_ID, book));
+}
+
+pub fn restore_book(book: &'static LocalKey<RefCell<EscrowBook>>) {
+    if let Some(restored) = memory::restore(ESCROWS_MEMORY_ID) {
+        book.with_borrow_mut(|book| *book = restored);
+    }
+}
+
+// Moves funds in and out of escrow subaccounts of `canister`. Every ledger
+// movement goes through the payout book, so retrying any step is idempotent.
+pub struct EscrowEngine<L: Ledger> {
+    pub escrows: &'static LocalKey<RefCell<EscrowBook>>,
+    pub payouts: &'static LocalKey<RefCell<PayoutBook>>,
+    pub ledger: L,
+    pub canister: Principal,
+}
+
+impl<L: Ledger> EscrowEngine<L> {
+    fn escrow(&self, id: &str) -> Result<Escrow, EscrowError> {
+        self.escrows
+            .with_borrow(|book| book.get(id))
+            .ok_or(EscrowError::NotFound)
+    }
+
+    fn transition(
+        &self,
+        id: &str,
+        state: EscrowState,
+        block_indexes: Vec<Nat>,
+        note: String,
+        now_nanos: u64,
+    ) -> Escrow {
+        self.escrows
+            .with_borrow_mut(|book| book.transition(id, state, block_indexes, note, now_nanos))
+            .expect("Escrow exists.")
+    }
+
+    async fn fee(&self) -> Result<Nat, EscrowError> {
+        self.ledger.fee().await.map_err(EscrowError::Ledger)
+    }
+
+    // Pulls `amount` from `depositor` (who must have approved this canister for
+    // `amount` plus the ledger fee) into the escrow subaccount.
+    pub async fn lock(
+        &self,
+        id: String,
+        purpose: EscrowPurpose,
+        depositor: Account,
+        amount: u64,
+        now_nanos: u64,
+    ) -> Result<Escrow, EscrowError> {
+        if amount == 0 {
+            return Err(EscrowError::InvalidAmount(
+                "Escrow amount must be greater than zero.".to_string(),
+            ));
+        }
+
+        let escrow = self.escrows.with_borrow_mut(|book| {
+            book.escrows
+                .entry(id.clone())
+                .or_insert_with(|| Escrow {
+                    id: id.clone(),
+                    purpose,
+                    depositor,
+                    holder: Account {
+                        owner: self.canister,
+                        subaccount: Some(escrow_subaccount(&id)),
+                    },
+                    amount: Nat::from(amount),
+                    state: EscrowState::Open,
+                    releases: Vec::new(),
+                    history: vec![EscrowEvent {
+                        state: EscrowState::Open,
+                        block_indexes: Vec::new(),
+                        note: "Escrow opened.".to_string(),
+                        at: now_nanos,
+                    }],
+                    created_at: now_nanos,
+                })
+                .clone()
+        });
+
+        if escrow.state != EscrowState::Open {
+            return Ok(escrow);
+        }
+
+        let payout = pay(
+            self.payouts,
+            &self.ledger,
+            Payout::new(
+                format!("escrow:{id}:lock"),
+                RewardKind::EscrowLock {
+                    escrow_id: id.clone(),
+                },
+                PayoutSource::Allowance(escrow.depositor.clone()),
+                escrow.holder.clone(),
+                escrow.amount.clone(),
+                now_nanos,
+            ),
+            now_nanos,
+        )
+        .await;
+
+        match payout.status {
+            PayoutStatus::Paid { block_index } => Ok(self.transition(
+                &id,
+                EscrowState::Funded,
+                vec![block_index],
+                "Funds locked.".to_string(),
+                now_nanos,
+            )),
+            PayoutStatus::Failed { error } => {
+                self.transition(
+                    &id,
+                    EscrowState::Open,
+                    Vec::new(),
+                    format!("Lock failed: {error:?}"),
+                    now_nanos,
+                );
+                Err(EscrowError::Ledger(error))
+            }
+            PayoutStatus::Pending => Err(EscrowError::InvalidState(EscrowState::Open)),
+        }
+    }
+
+    // Splits the escrow between beneficiaries. Each share pays for its own
+    // transfer fee. Whatever the plan leaves over goes back to the depositor,
+    // minus the fee, so an empty plan returns the whole escrow. Once started
+    // the plan is fixed and later calls retry it.
+    pub async fn release(
+        &self,
+        id: &str,
+        plan: Vec<(Account, u64)>,
+        now_nanos: u64,
+    ) -> Result<Escrow, EscrowError> {
+        // Read the escrow after the fee call, so no other message can change
+        // its state between the checks below and fixing the release plan.
+        let fee = self.fee().await?;
+        let escrow = self.escrow(id)?;
+
+        let escrow = match escrow.state {
+            EscrowState::Funded => {
+                if plan.iter().any(|(_, share)| *share <= fee) {
+                    return Err(EscrowError::InvalidAmount(
+                        "Every share must exceed the ledger fee.".to_string(),
+                    ));
+                }
+
+                let total: u64 = plan.iter().map(|(_, share)| share).sum();
+                if total > escrow.amount {
+                    return Err(EscrowError::InvalidAmount(
+                        "Release plan exceeds the escrowed amount.".to_string(),
+                    ));
+                }
+
+                self.escrows.with_borrow_mut(|book| {
+                    if let Some(escrow) = book.escrows.get_mut(id) {
+                        escrow.releases = plan
+                            .into_iter()
+                            .map(|(to, share)| (to, Nat::from(share)))
+                            .collect();
+                    }
+                });
+
+                self.transition(
+                    id,
+                    EscrowState::Releasing,
+                    Vec::new(),
+                    "Release started.".to_string(),
+                    now_nanos,
+                )
+            }
+            EscrowState::Releasing => escrow,
+            state => return Err(EscrowError::InvalidState(state)),
+        };
+
+        let mut block_indexes = Vec::new();
+        let mut failure = None;
+
+        for (index, (to, share)) in escrow.releases.iter().enumerate() {
+            let payout = pay(
+                self.payouts,
+                &self.ledger,
+                Payout::new(
+                    format!("escrow:{id}:release:{index}"),
+                    RewardKind::EscrowRelease {
+                        escrow_id: id.to_string(),
+                        index: index as u64,
+                    },
+                    PayoutSource::Subaccount(escrow_subaccount(id)),
+                    to.clone(),
+                    share.clone() - fee.clone(),
+                    now_nanos,
+                ),
+                now_nanos,
+            )
+            .await;
+
+            match payout.status {
+                PayoutStatus::Paid { block_index } => block_indexes.push(block_index),
+                PayoutStatus::Failed { error } => failure = Some(error),
+                PayoutStatus::Pending => {}
+            }
+        }
+
+        // Less than the fee can't be moved and stays in the subaccount.
+        let released: Nat = escrow
+            .releases
+            .iter()
+            .fold(Nat::from(0u64), |total, (_, share)| total + share.clone());
+        let remainder = escrow.amount.clone() - released;
+
+        if remainder > fee {
+            let payout = pay(
+                self.payouts,
+                &self.ledger,
+                Payout::new(
+                    format!("escrow:{id}:refund"),
+                    RewardKind::EscrowRefund {
+                        escrow_id: id.to_string(),
+                    },
+                    PayoutSource::Subaccount(escrow_subaccount(id)),
+                    escrow.depositor.clone(),
+                    remainder - fee.clone(),
+                    now_nanos,
+                ),
+                now_nanos,
+            )
+            .await;
+
+            match payout.status {
+                PayoutStatus::Paid { block_index } => block_indexes.push(block_index),
+                PayoutStatus::Failed { error } => failure = Some(error),
+                PayoutStatus::Pending => {}
+            }
+        }
+
+        match failure {
+            None => Ok(self.transition(
+                id,
+                EscrowState::Released,
+                block_indexes,
+                "Funds released.".to_string(),
+                now_nanos,
+            )),
+            Some(error) => {
+                self.transition(
+                    id,
+                    EscrowState::Releasing,
+                    block_indexes,
+                    format!("Release incomplete: {error:?}"),
+                    now_nanos,
+                );
+                Err(EscrowError::Ledger(error))
+            }
+        }
+    }
+
+    // Returns the escrowed funds, minus the transfer fee, to the depositor.
+    pub async fn refund(&self, id: &str, now_nanos: u64) -> Result<Escrow, EscrowError> {
+        let escrow = self.escrow(id)?;
+
+        match escrow.state {
+            EscrowState::Open => {
+                let lock = self
+                    .payouts
+                    .with_borrow(|book| book.get(&format!("escrow:{id}:lock")));
+
+                // A lock with an unknown outcome may still have moved funds.
+                let may_be_funded = lock.is_some_and(|payout| match &payout.status {
+                    PayoutStatus::Failed { error } => error.is_outcome_unknown(),
+                    _ => true,
+                });
+
+                if may_be_funded {
+                    return Err(EscrowError::InvalidState(EscrowState::Open));
+                }
+
+                return Ok(self.transition(
+                    id,
+                    EscrowState::Cancelled,
+                    Vec::new(),
+                    "Cancelled before funding.".to_string(),
+                    now_nanos,
+                ));
+            }
+            EscrowState::Funded => {
+                self.transition(
+                    id,
+                    EscrowState::Refunding,
+                    Vec::new(),
+                    "Refund started.".to_string(),
+                    now_nanos,
+                );
+            }
+            EscrowState::Refunding => {}
+            state => return Err(EscrowError::InvalidState(state)),
+        }
+
+        let fee = self.fee().await?;
+        if escrow.amount <= fee {
+            return Err(EscrowError::InvalidAmount(
+                "Escrowed amount does not cover the refund fee.".to_string(),
+            ));
+        }
+
+        let payout = pay(
+            self.payouts,
+            &self.ledger,
+            Payout::new(
+                format!("escrow:{id}:refund"),
+                RewardKind::EscrowRefund {
+                    escrow_id: id.to_string(),
+                },
+                PayoutSource::Subaccount(escrow_subaccount(id)),
+                escrow.depositor.clone(),
+                escrow.amount.clone() - fee,
+                now_nanos,
+            ),
+            now_nanos,
+        )
+        .await;
+
+        match payout.status {
+            PayoutStatus::Paid { block_index } => Ok(self.transition(
+                id,
+                EscrowState::Refunded,
+                vec![block_index],
+                "Funds refunded.".to_string(),
+                now_nanos,
+            )),
+            PayoutStatus::Failed { error } => {
+                self.transition(
+                    id,
+                    EscrowState::Refunding,
+                    Vec::new(),
+                    format!("Refund failed: {error:?}"),
+                    now_nanos,
+                );
+                Err(EscrowError::Ledger(error))
+            }
+            PayoutStatus::Pending => Err(EscrowError::InvalidState(EscrowState::Refunding)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::ledger::ApproveArgs;
+    use crate::ledger::local::LocalLedger;
+
+    const FEE: u64 = 10;
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    thread_local! {
+        static ESCROWS: RefCell<EscrowBook> = RefCell::new(EscrowBook::default());
+        static PAYOUTS: RefCell<PayoutBook> = RefCell::new(PayoutBook::default());
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn engine() -> EscrowEngine<LocalLedger> {
+        ESCROWS.with_borrow_mut(|book| *book = EscrowBook::default());
+        PAYOUTS.with_borrow_mut(|book| *book = PayoutBook::default());
+
+        let ledger = LocalLedger::new(principal(1), FEE);
+        ledger.set_time(NOW);
+
+        EscrowEngine {
+            escrows: &ESCROWS,
+            payouts: &PAYOUTS,
+            ledger,
+            canister: principal(1),
+        }
+    }
+
+    fn fund(engine: &EscrowEngine<LocalLedger>, depositor: &Account, amount: u64) {
+        engine.ledger.mint(depositor, amount + 2 * FEE);
+        engine
+            .ledger
+            .icrc2_approve(
+                depositor.owner,
+                ApproveArgs {
+                    from_subaccount: None,
+                    spender: Account::of(engine.canister),
+                    amount: Nat::from(amount + FEE),
+                    expected_allowance: None,
+                    expires_at: None,
+                    fee: None,
+                    memo: None,
+                    created_at_time: None,
+                },
+            )
+            .unwrap();
+    }
+
+    fn prize_pool() -> EscrowPurpose {
+        EscrowPurpose::PrizePool {
+            competition_id: "comp1".to_string(),
+        }
+    }
+
+    #[test]
+    fn locks_and_releases_prize_pool() {
+        let engine = engine();
+        let sponsor = Account::of(principal(2));
+        let (first, second) = (Account::of(principal(3)), Account::of(principal(4)));
+        fund(&engine, &sponsor, 1_000);
+
+        let escrow = block_on(engine.lock(
+            "pool".to_string(),
+            prize_pool(),
+            sponsor.clone(),
+            1_000,
+            NOW,
+        ))
+        .unwrap();
+        assert_eq!(escrow.state, EscrowState::Funded);
+        assert_eq!(
+            engine.ledger.icrc1_balance_of(&escrow.holder),
+            Nat::from(1_000u64)
+        );
+
+        let escrow = block_on(engine.release(
+            "pool",
+            vec![(first.clone(), 700), (second.clone(), 300)],
+            NOW,
+        ))
+        .unwrap();
+
+        assert_eq!(escrow.state, EscrowState::Released);
+        assert_eq!(engine.ledger.icrc1_balance_of(&first), Nat::from(690u64));
+        assert_eq!(engine.ledger.icrc1_balance_of(&second), Nat::from(290u64));
+        assert_eq!(
+            engine.ledger.icrc1_balance_of(&escrow.holder),
+            Nat::from(0u64)
+        );
+        assert_eq!(
+            escrow
+                .history
+                .iter()
+                .map(|event| event.state.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                EscrowState::Open,
+                EscrowState::Funded,
+                EscrowState::Releasing,
+                EscrowState::Released
+            ]
+        );
+    }
+
+    #[test]
+    fn release_returns_the_remainder_to_the_depositor() {
+        let engine = engine();
+        let sponsor = Account::of(principal(2));
+        let winner = Account::of(principal(3));
+        fund(&engine, &sponsor, 1_000);
+
+        block_on(engine.lock(
+            "pool".to_string(),
+            prize_pool(),
+            sponsor.clone(),
+            1_000,
+            NOW,
+        ))
+        .unwrap();
+        let before = engine.ledger.icrc1_balance_of(&sponsor);
+
+        let escrow = block_on(engine.release("pool", vec![(winner.clone(), 600)], NOW)).unwrap();
+
+        assert_eq!(escrow.state, EscrowState::Released);
+        assert_eq!(engine.ledger.icrc1_balance_of(&winner), Nat::from(590u64));
+        assert_eq!(
+            engine.ledger.icrc1_balance_of(&sponsor),
+            before + Nat::from(390u64)
+        );
+        assert_eq!(
+            engine.ledger.icrc1_balance_of(&escrow.holder),
+            Nat::from(0u64)
+        );
+    }
+
+    #[test]
+    fn empty_release_plan_returns_the_escrow() {
+        let engine = engine();
+        let sponsor = Account::of(principal(2));
+        fund(&engine, &sponsor, 1_000);
+
+        block_on(engine.lock(
+            "pool".to_string(),
+            prize_pool(),
+            sponsor.clone(),
+            1_000,
+            NOW,
+        ))
+        .unwrap();
+        let before = engine.ledger.icrc1_balance_of(&sponsor);
+
+        let escrow = block_on(engine.release("pool", Vec::new(), NOW)).unwrap();
+
+        assert_eq!(escrow.state, EscrowState::Released);
+        assert_eq!(
+            engine.ledger.icrc1_balance_of(&sponsor),
+            before + Nat::from(990u64)
+        );
+    }
+
+    #[test]
+    fn refunds_on_cancellation() {
+        let engine = engine();
+        let buyer = Account::of(principal(2));
+        fund(&engine, &buyer, 500);
+
+        block_on(engine.lock(
+            "course".to_string(),
+            EscrowPurpose::CourseAccess {
+                course_id: 1,
+                buyer: buyer.owner,
+            },
+            buyer.clone(),
+            500,
+            NOW,
+        ))
+        .unwrap();
+
+        let escrow = block_on(engine.refund("course", NOW)).unwrap();
+
+        assert_eq!(escrow.state, EscrowState::Refunded);
+        assert_eq!(engine.ledger.icrc1_balance_of(&buyer), Nat::from(490u64));
+        assert_eq!(
+            block_on(engine.release("course", vec![(buyer, 100)], NOW)).unwrap_err(),
+            EscrowError::InvalidState(EscrowState::Refunded)
+        );
+    }
+
+    #[test]
+    fn lock_without_approval_stays_open_and_can_be_cancelled() {
+        let engine = engine();
+        let sponsor = Account::of(principal(2));
+
+        let err = block_on(engine.lock("pool".to_string(), prize_pool(), sponsor, 1_000, NOW))
+            .unwrap_err();
+
+        assert!(matches!(err, EscrowError::Ledger(_)));
+        assert_eq!(
+            block_on(engine.refund("pool", NOW)).unwrap().state,
+            EscrowState::Cancelled
+        );
+    }
+
+    #[test]
+    fn release_plan_cannot_exceed_escrow() {
+        let engine = engine();
+        let sponsor = Account::of(principal(2));
+        fund(&engine, &sponsor, 1_000);
+
+        block_on(engine.lock("pool".to_string(), prize_pool(), sponsor, 1_000, NOW)).unwrap();
+
+        let err = block_on(engine.release("pool", vec![(Account::of(principal(3)), 1_001)], NOW))
+            .unwrap_err();
+
+        assert!(matches!(err, EscrowError::InvalidAmount(_)));
+        assert_eq!(
+            ESCROWS.with_borrow(|book| book.get("pool")).unwrap().state,
+            EscrowState::Funded
+        );
+    }
+}
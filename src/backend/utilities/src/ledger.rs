@@ -0,0 +1,260 @@
+use std::cell::RefCell;
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::call::Call;
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::memory::{self, LEDGER_MEMORY_ID, Memory};
+
+pub mod local;
+
+pub type Subaccount = Vec<u8>;
+
+// ICRC-1 / ICRC-2 wire types, mirroring the `icrc1_ledger_canister` interface.
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, CandidType, Serialize, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn of(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+
+    // A missing subaccount and the all-zero subaccount are the same ledger account.
+    pub fn effective_subaccount(&self) -> [u8; 32] {
+        let mut subaccount = [0u8; 32];
+        if let Some(bytes) = &self.subaccount {
+            let len = bytes.len().min(32);
+            subaccount[..len].copy_from_slice(&bytes[..len]);
+        }
+        subaccount
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum LedgerError {
+    NotConfigured,
+    CallFailed(String),
+    Transfer(TransferError),
+    Approve(ApproveError),
+    TransferFrom(TransferFromError),
+}
+
+impl LedgerError {
+    // A failed call may still have been executed by the ledger, so the same
+    // memo and `created_at_time` must be reused when retrying it.
+    pub fn is_outcome_unknown(&self) -> bool {
+        matches!(self, LedgerError::CallFailed(_))
+    }
+
+    pub fn is_too_old(&self) -> bool {
+        matches!(
+            self,
+            LedgerError::Transfer(TransferError::TooOld)
+                | LedgerError::Approve(ApproveError::TooOld)
+                | LedgerError::TransferFrom(TransferFromError::TooOld)
+        )
+    }
+}
+
+#[allow(async_fn_in_trait)] // canisters are single threaded, futures never need to be `Send`.
+pub trait Ledger {
+    async fn balance_of(&self, account: Account) -> Result<Nat, LedgerError>;
+    async fn transfer(&self, arg: TransferArg) -> Result<Nat, LedgerError>;
+    async fn approve(&self, arg: ApproveArgs) -> Result<Nat, LedgerError>;
+    async fn transfer_from(&self, arg: TransferFromArgs) -> Result<Nat, LedgerError>;
+    async fn allowance(&self, arg: AllowanceArgs) -> Result<Allowance, LedgerError>;
+    async fn fee(&self) -> Result<Nat, LedgerError>;
+}
+
+// Client for the deployed `icrc1_ledger_canister`.
+#[derive(Clone, Copy, Debug)]
+pub struct IcrcLedger {
+    pub canister_id: Principal,
+}
+
+impl IcrcLedger {
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    // The ledger configured for the calling canister, see `configure`.
+    pub fn configured() -> Result<Self, LedgerError> {
+        ledger_canister_id()
+            .map(Self::new)
+            .ok_or(LedgerError::NotConfigured)
+    }
+
+    async fn call<A: CandidType, R: CandidType + for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        arg: A,
+    ) -> Result<R, LedgerError> {
+        Call::unbounded_wait(self.canister_id, method)
+            .with_arg(arg)
+            .await
+            .map_err(|err| LedgerError::CallFailed(err.to_string()))?
+            .candid::<R>()
+            .map_err(|err| LedgerError::CallFailed(err.to_string()))
+    }
+}
+
+impl Ledger for IcrcLedger {
+    async fn balance_of(&self, account: Account) -> Result<Nat, LedgerError> {
+        self.call("icrc1_balance_of", account).await
+    }
+
+    async fn transfer(&self, arg: TransferArg) -> Result<Nat, LedgerError> {
+        transfer_result(self.call("icrc1_transfer", arg).await?)
+    }
+
+    async fn approve(&self, arg: ApproveArgs) -> Result<Nat, LedgerError> {
+        approve_result(self.call("icrc2_approve", arg).await?)
+    }
+
+    async fn transfer_from(&self, arg: TransferFromArgs) -> Result<Nat, LedgerError> {
+        transfer_from_result(self.call("icrc2_transfer_from", arg).await?)
+    }
+
+    async fn allowance(&self, arg: AllowanceArgs) -> Result<Allowance, LedgerError> {
+        self.call("icrc2_allowance", arg).await
+    }
+
+    async fn fee(&self) -> Result<Nat, LedgerError> {
+        self.call("icrc1_fee", ()).await
+    }
+}
+
+// A `Duplicate` answer means the transaction was already applied by an earlier
+// attempt, which is exactly what an idempotent retry wants to hear.
+pub fn transfer_result(result: Result<Nat, TransferError>) -> Result<Nat, LedgerError> {
+    result.or_else(|err| match err {
+        TransferError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        err => Err(LedgerError::Transfer(err)),
+    })
+}
+
+pub fn approve_result(result: Result<Nat, ApproveError>) -> Result<Nat, LedgerError> {
+    result.or_else(|err| match err {
+        ApproveError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        err => Err(LedgerError::Approve(err)),
+    })
+}
+
+pub fn transfer_from_result(result: Result<Nat, TransferFromError>) -> Result<Nat, LedgerError> {
+    result.or_else(|err| match err {
+        TransferFromError::Duplicate { duplicate_of } => Ok(duplicate_of),
+        err => Err(LedgerError::TransferFrom(err)),
+    })
+}
+
+thread_local! {
+    // Lives in stable memory, so the configured ledger survives upgrades.
+    static LEDGER_CANISTER_ID: RefCell<StableCell<Option<Principal>, Memory>> =
+        RefCell::new(StableCell::init(memory::get(LEDGER_MEMORY_ID), None));
+}
+
+pub fn configure(ledger_canister_id: Principal) {
+    LEDGER_CANISTER_ID.with_borrow_mut(|id| id.set(Some(ledger_canister_id)));
+}
+
+pub fn ledger_canister_id() -> Option<Principal> {
+    LEDGER_CANISTER_ID.with_borrow(|id| *id.get())
+}
+
+// Deterministic 32 byte memo, so that the same logical operation always
+// produces the same ledger transaction and gets deduplicated on retry.
+pub fn memo_for(reference: &str) -> Vec<u8> {
+    Sha256::digest(reference.as_bytes()).to_vec()
+}
@@ -0,0 +1,340 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use candid::{Nat, Principal};
+
+use super::{
+    Account, Allowance, AllowanceArgs, ApproveArgs, ApproveError, Ledger, LedgerError, TransferArg,
+    TransferError, TransferFromArgs, TransferFromError, approve_result, transfer_from_result,
+    transfer_result,
+};
+
+pub const TRANSACTION_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const PERMITTED_DRIFT_NANOS: u64 = 60 * 1_000_000_000;
+
+type AccountKey = (Principal, [u8; 32]);
+
+fn key(account: &Account) -> AccountKey {
+    (account.owner, account.effective_subaccount())
+}
+
+// In-memory stand-in for the ICRC-1/ICRC-2 ledger, following the same fee,
+// allowance and deduplication rules. Used by tests and local tooling.
+#[derive(Default)]
+pub struct LocalLedger {
+    pub fee: Nat,
+    time: Cell<u64>,
+    balances: RefCell<HashMap<AccountKey, Nat>>,
+    allowances: RefCell<HashMap<(AccountKey, AccountKey), Allowance>>,
+    transactions: RefCell<HashMap<String, Nat>>,
+    block_count: Cell<u64>,
+    // Principal the `Ledger` trait acts on behalf of.
+    caller: Option<Principal>,
+}
+
+impl LocalLedger {
+    pub fn new(caller: Principal, fee: u64) -> Self {
+        Self {
+            fee: Nat::from(fee),
+            caller: Some(caller),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_time(&self, nanos: u64) {
+        self.time.set(nanos);
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time.get()
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.block_count.get()
+    }
+
+    pub fn mint(&self, to: &Account, amount: u64) -> Nat {
+        self.credit(key(to), Nat::from(amount));
+        self.next_block()
+    }
+
+    pub fn icrc1_balance_of(&self, account: &Account) -> Nat {
+        self.balances
+            .borrow()
+            .get(&key(account))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn icrc2_allowance(&self, arg: &AllowanceArgs) -> Allowance {
+        self.allowances
+            .borrow()
+            .get(&(key(&arg.account), key(&arg.spender)))
+            .filter(|allowance| !self.is_expired(allowance))
+            .cloned()
+            .unwrap_or(Allowance {
+                allowance: Nat::from(0u64),
+                expires_at: None,
+            })
+    }
+
+    pub fn icrc1_transfer(
+        &self,
+        caller: Principal,
+        arg: TransferArg,
+    ) -> Result<Nat, TransferError> {
+        let from = Account {
+            owner: caller,
+            subaccount: arg.from_subaccount.clone(),
+        };
+
+        if let Some(fee) = &arg.fee
+            && *fee != self.fee
+        {
+            return Err(TransferError::BadFee {
+                expected_fee: self.fee.clone(),
+            });
+        }
+
+        let tx = format!("transfer:{caller}:{arg:?}");
+        if let Some(created_at_time) = arg.created_at_time {
+            self.check_time(created_at_time).map_err(|err| match err {
+                TimeError::TooOld => TransferError::TooOld,
+                TimeError::CreatedInFuture(ledger_time) => {
+                    TransferError::CreatedInFuture { ledger_time }
+                }
+            })?;
+
+            if let Some(duplicate_of) = self.transactions.borrow().get(&tx) {
+                return Err(TransferError::Duplicate {
+                    duplicate_of: duplicate_of.clone(),
+                });
+            }
+        }
+
+        let total = arg.amount.clone() + self.fee.clone();
+        let balance = self.icrc1_balance_of(&from);
+        if balance < total {
+            return Err(TransferError::InsufficientFunds { balance });
+        }
+
+        self.debit(key(&from), total);
+        self.credit(key(&arg.to), arg.amount.clone());
+
+        Ok(self.record(arg.created_at_time.map(|_| tx)))
+    }
+
+    pub fn icrc2_approve(&self, caller: Principal, arg: ApproveArgs) -> Result<Nat, ApproveError> {
+        let from = Account {
+            owner: caller,
+            subaccount: arg.from_subaccount.clone(),
+        };
+
+        if let Some(fee) = &arg.fee
+            && *fee != self.fee
+        {
+            return Err(ApproveError::BadFee {
+                expected_fee: self.fee.clone(),
+            });
+        }
+
+        if let Some(expires_at) = arg.expires_at
+            && expires_at <= self.time()
+        {
+            return Err(ApproveError::Expired {
+                ledger_time: self.time(),
+            });
+        }
+
+        let tx = format!("approve:{caller}:{arg:?}");
+        if let Some(created_at_time) = arg.created_at_time {
+            self.check_time(created_at_time).map_err(|err| match err {
+                TimeError::TooOld => ApproveError::TooOld,
+                TimeError::CreatedInFuture(ledger_time) => {
+                    ApproveError::CreatedInFuture { ledger_time }
+                }
+            })?;
+
+            if let Some(duplicate_of) = self.transactions.borrow().get(&tx) {
+                return Err(ApproveError::Duplicate {
+                    duplicate_of: duplicate_of.clone(),
+                });
+            }
+        }
+
+        let allowance_key = (key(&from), key(&arg.spender));
+        let current = self.icrc2_allowance(&AllowanceArgs {
+            account: from.clone(),
+            spender: arg.spender.clone(),
+        });
+
+        if let Some(expected) = &arg.expected_allowance
+            && *expected != current.allowance
+        {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: current.allowance,
+            });
+        }
+
+        let balance = self.icrc1_balance_of(&from);
+        if balance < self.fee {
+            return Err(ApproveError::InsufficientFunds { balance });
+        }
+
+        self.debit(key(&from), self.fee.clone());
+        self.allowances.borrow_mut().insert(
+            allowance_key,
+            Allowance {
+                allowance: arg.amount.clone(),
+                expires_at: arg.expires_at,
+            },
+        );
+
+        Ok(self.record(arg.created_at_time.map(|_| tx)))
+    }
+
+    pub fn icrc2_transfer_from(
+        &self,
+        caller: Principal,
+        arg: TransferFromArgs,
+    ) -> Result<Nat, TransferFromError> {
+        let spender = Account {
+            owner: caller,
+            subaccount: arg.spender_subaccount.clone(),
+        };
+
+        if let Some(fee) = &arg.fee
+            && *fee != self.fee
+        {
+            return Err(TransferFromError::BadFee {
+                expected_fee: self.fee.clone(),
+            });
+        }
+
+        let tx = format!("transfer_from:{caller}:{arg:?}");
+        if let Some(created_at_time) = arg.created_at_time {
+            self.check_time(created_at_time).map_err(|err| match err {
+                TimeError::TooOld => TransferFromError::TooOld,
+                TimeError::CreatedInFuture(ledger_time) => {
+                    TransferFromError::CreatedInFuture { ledger_time }
+                }
+            })?;
+
+            if let Some(duplicate_of) = self.transactions.borrow().get(&tx) {
+                return Err(TransferFromError::Duplicate {
+                    duplicate_of: duplicate_of.clone(),
+                });
+            }
+        }
+
+        let total = arg.amount.clone() + self.fee.clone();
+
+        let allowance = self.icrc2_allowance(&AllowanceArgs {
+            account: arg.from.clone(),
+            spender: spender.clone(),
+        });
+        if allowance.allowance < total {
+            return Err(TransferFromError::InsufficientAllowance {
+                allowance: allowance.allowance,
+            });
+        }
+
+        let balance = self.icrc1_balance_of(&arg.from);
+        if balance < total {
+            return Err(TransferFromError::InsufficientFunds { balance });
+        }
+
+        self.debit(key(&arg.from), total.clone());
+        self.credit(key(&arg.to), arg.amount.clone());
+        self.allowances.borrow_mut().insert(
+            (key(&arg.from), key(&spender)),
+            Allowance {
+                allowance: allowance.allowance - total,
+                expires_at: allowance.expires_at,
+            },
+        );
+
+        Ok(self.record(arg.created_at_time.map(|_| tx)))
+    }
+
+    fn caller(&self) -> Result<Principal, LedgerError> {
+        self.caller.ok_or(LedgerError::NotConfigured)
+    }
+
+    fn is_expired(&self, allowance: &Allowance) -> bool {
+        allowance
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= self.time())
+    }
+
+    fn check_time(&self, created_at_time: u64) -> Result<(), TimeError> {
+        let now = self.time();
+
+        if created_at_time + TRANSACTION_WINDOW_NANOS + PERMITTED_DRIFT_NANOS < now {
+            return Err(TimeError::TooOld);
+        }
+
+        if created_at_time > now + PERMITTED_DRIFT_NANOS {
+            return Err(TimeError::CreatedInFuture(now));
+        }
+
+        Ok(())
+    }
+
+    fn credit(&self, account: AccountKey, amount: Nat) {
+        let mut balances = self.balances.borrow_mut();
+        let balance = balances.entry(account).or_default();
+        *balance += amount;
+    }
+
+    fn debit(&self, account: AccountKey, amount: Nat) {
+        let mut balances = self.balances.borrow_mut();
+        let balance = balances.entry(account).or_default();
+        *balance -= amount;
+    }
+
+    fn next_block(&self) -> Nat {
+        let block = self.block_count.get();
+        self.block_count.set(block + 1);
+        Nat::from(block)
+    }
+
+    fn record(&self, transaction: Option<String>) -> Nat {
+        let block = self.next_block();
+        if let Some(tx) = transaction {
+            self.transactions.borrow_mut().insert(tx, block.clone());
+        }
+        block
+    }
+}
+
+enum TimeError {
+    TooOld,
+    CreatedInFuture(u64),
+}
+
+impl Ledger for LocalLedger {
+    async fn balance_of(&self, account: Account) -> Result<Nat, LedgerError> {
+        Ok(self.icrc1_balance_of(&account))
+    }
+
+    async fn transfer(&self, arg: TransferArg) -> Result<Nat, LedgerError> {
+        transfer_result(self.icrc1_transfer(self.caller()?, arg))
+    }
+
+    async fn approve(&self, arg: ApproveArgs) -> Result<Nat, LedgerError> {
+        approve_result(self.icrc2_approve(self.caller()?, arg))
+    }
+
+    async fn transfer_from(&self, arg: TransferFromArgs) -> Result<Nat, LedgerError> {
+        transfer_from_result(self.icrc2_transfer_from(self.caller()?, arg))
+    }
+
+    async fn allowance(&self, arg: AllowanceArgs) -> Result<Allowance, LedgerError> {
+        Ok(self.icrc2_allowance(&arg))
+    }
+
+    async fn fee(&self) -> Result<Nat, LedgerError> {
+        Ok(self.fee.clone())
+    }
+}
@@ -1,9 +1,22 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
 use candid::{CandidType, Principal};
 use ic_cdk::call::Call;
+use ic_stable_structures::StableCell;
 use serde::{Deserialize, Serialize};
 use time::{OffsetDateTime, UtcOffset, format_description::well_known::Rfc3339};
 use uuid::Builder;
 
+use crate::memory::{Memory, STORAGE_CANISTER_MEMORY_ID};
+
+pub mod audit;
+pub mod escrow;
+pub mod ledger;
+pub mod memory;
+pub mod notifications;
+pub mod rewards;
+
 pub fn now() -> String {
     let nanos = ic_cdk::api::time();
 
@@ -39,15 +52,25 @@ pub fn convert_to_tz(time_string: String, offset: f32) -> String {
     dt.to_offset(custom_offset).format(&Rfc3339).unwrap()
 }
 
+thread_local! {
+    static UUID_COUNTER: Cell<u64> = const { Cell::new(0) };
+}
+
+// Ids are unique within a canister: the canister time fills the high half and
+// a counter the low half, so ids made in the same round still differ. The
+// counter starts over on upgrade, by which time the clock has moved on.
 pub fn generate_uuid() -> String {
-    let timestamp = now_as_datetime().unix_timestamp_nanos();
-    let bytes = timestamp.to_be_bytes();
+    let counter = UUID_COUNTER.replace(UUID_COUNTER.get().wrapping_add(1));
+
+    uuid_from(ic_cdk::api::time(), counter)
+}
 
+fn uuid_from(nanos: u64, counter: u64) -> String {
     let mut buf = [0u8; 16];
-    buf[..16].copy_from_slice(&bytes[..16]);
+    buf[..8].copy_from_slice(&nanos.to_be_bytes());
+    buf[8..].copy_from_slice(&counter.to_be_bytes());
 
-    let builder = Builder::from_slice(&buf).expect("Error creating UUID builder.");
-    builder.into_uuid().to_string()
+    Builder::from_bytes(buf).into_uuid().to_string()
 }
 
 #[derive(Clone, Serialize, Deserialize, CandidType, Debug)]
@@ -77,10 +100,14 @@ pub struct StoredFile {
     pub mime_type: String,
     pub size: usize,
     pub data: Vec<u8>,
+    pub content_hash: String,
+    pub parent_id: Option<String>,
     pub owner: Principal,
     pub groups: Vec<Group>,
     pub allowed_users: Vec<(Principal, Access)>,
     pub public: bool,
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
     pub uploaded_at: String,
 }
 
@@ -90,25 +117,139 @@ pub enum FileUploadResolveType {
     SuccessfullyUploaded,
     FailedToUpload,
     AlreadyUploaded,
+    QuotaExceeded,
+    ContentRejected,
+}
+
+// What an upload is for, which decides the types and sizes storage accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum UploadContext {
+    General,
+    ProfilePicture,
+    PostMedia,
+    CourseResource,
+    CompetitionSubmission,
+    // Ciphertext, taken as its declared type.
+    Encrypted,
+}
+
+thread_local! {
+    // Lives in stable memory, so the configured storage canister survives upgrades.
+    static STORAGE_CANISTER_ID: RefCell<StableCell<Option<Principal>, Memory>> = RefCell::new(
+        StableCell::init(memory::get(STORAGE_CANISTER_MEMORY_ID), None),
+    );
+}
+
+pub fn configure_storage(storage_canister_id: Principal) {
+    STORAGE_CANISTER_ID.with_borrow_mut(|id| id.set(Some(storage_canister_id)));
+}
+
+pub fn storage_canister_id() -> Option<Principal> {
+    STORAGE_CANISTER_ID.with_borrow(|id| *id.get())
 }
 
-pub async fn upload_files(
+// Uploads `files` owned by `owner`. Only canisters subscribed to the storage
+// canister can do this. A failed call is an error, like a refused upload.
+pub async fn upload_files_for(
     storage_canister_id: Principal,
+    owner: Principal,
     files: Vec<StoredFile>,
-) -> Vec<(String, FileUploadResolveType, String)> {
-    Call::unbounded_wait(storage_canister_id, "upload_files")
-        .with_arg(&files)
+    context: Option<UploadContext>,
+) -> Result<Vec<(String, FileUploadResolveType, String)>, String> {
+    Call::unbounded_wait(storage_canister_id, "upload_files_for")
+        .with_args(&(owner, files, context))
         .await
-        .expect("Failed to upload files.")
-        .candid::<Vec<(String, FileUploadResolveType, String)>>()
-        .expect("Candid decoding failed.")
+        .map_err(|err| format!("Failed to upload files: {err}"))?
+        .candid::<Result<Vec<(String, FileUploadResolveType, String)>, String>>()
+        .map_err(|err| format!("Candid decoding failed: {err}"))?
+}
+
+// Sent by storage to the canisters it has as subscribers, to their
+// `storage_event` method. Entities are those the receiver referenced.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub enum StorageEvent {
+    FileDeleted {
+        file_id: String,
+        entities: Vec<String>,
+    },
+    PermissionsChanged {
+        file_id: String,
+        entities: Vec<String>,
+    },
+}
+
+// Sent by a storage bucket to its `storage_index` after files were stored or
+// removed. `bytes_used` is everything the bucket's blobs take.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct BucketReport {
+    pub stored: Vec<String>,
+    pub removed: Vec<String>,
+    pub bytes_used: u64,
+}
+
+// Buckets prefix the ids of their files with their own canister id, so ids
+// stay unique across the buckets of an index.
+pub fn bucket_file_id(bucket_canister_id: Principal, local_id: &str) -> String {
+    format!("{bucket_canister_id}-{local_id}")
+}
+
+// Tells storage that `entity`, acting for `user`, uses `file_ids`.
+// Fire-and-forget: storage only keeps the references of canisters it
+// registered as subscribers, to files `user` can read.
+pub fn reference_files(
+    storage_canister_id: Principal,
+    file_ids: Vec<String>,
+    entity: String,
+    user: Principal,
+) {
+    if file_ids.is_empty() {
+        return;
+    }
+
+    if let Err(err) = Call::unbounded_wait(storage_canister_id, "add_file_references")
+        .with_args(&(file_ids, entity, user))
+        .oneway()
+    {
+        ic_cdk::println!("Failed to reference files: {}", err);
+    }
+}
+
+// Tells storage that `entity` no longer uses `file_ids`. Fire-and-forget, like
+// `reference_files`.
+pub fn unreference_files(storage_canister_id: Principal, file_ids: Vec<String>, entity: String) {
+    if file_ids.is_empty() {
+        return;
+    }
+
+    if let Err(err) = Call::unbounded_wait(storage_canister_id, "remove_file_references")
+        .with_args(&(file_ids, entity))
+        .oneway()
+    {
+        ic_cdk::println!("Failed to unreference files: {}", err);
+    }
 }
 
+// Listings only carry metadata, contents come from `get_files_content`.
 pub async fn get_files(storage_canister_id: Principal, files_ids: Vec<String>) -> Vec<StoredFile> {
-    Call::unbounded_wait(storage_canister_id, "get_files_by_id")
+    Call::unbounded_wait(storage_canister_id, "get_files_content")
         .with_arg(&files_ids)
         .await
-        .expect("Failed to upload files.")
+        .expect("Failed to get files.")
         .candid::<Vec<StoredFile>>()
         .expect("Candid decoding failed.")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_made_at_the_same_time_differ() {
+        let first = uuid_from(1_700_000_000_000_000_000, 0);
+        let second = uuid_from(1_700_000_000_000_000_000, 1);
+
+        assert_ne!(first, second);
+        assert_ne!(first, uuid_from(1_700_000_000_000_000_001, 0));
+        assert_eq!(first, uuid_from(1_700_000_000_000_000_000, 0));
+    }
+}
@@ -0,0 +1,53 @@
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableCell};
+use serde::de::DeserializeOwned;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Stable memory regions shared by every canister built on `utilities`.
+// Ids must never be reused for a different structure.
+pub const AUDIT_LOG_MEMORY_ID: MemoryId = MemoryId::new(0);
+pub const LEDGER_MEMORY_ID: MemoryId = MemoryId::new(1);
+pub const PAYOUTS_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const BUCKETS_MEMORY_ID: MemoryId = MemoryId::new(3);
+pub const FILE_BUCKETS_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const INDEX_SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const PENDING_BUCKET_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const REWARD_BUDGET_MEMORY_ID: MemoryId = MemoryId::new(7);
+pub const ESCROWS_MEMORY_ID: MemoryId = MemoryId::new(8);
+pub const STORAGE_CANISTER_MEMORY_ID: MemoryId = MemoryId::new(9);
+pub const PRIZES_MEMORY_ID: MemoryId = MemoryId::new(10);
+pub const RESOURCES_MEMORY_ID: MemoryId = MemoryId::new(11);
+pub const NOTIFICATIONS_CANISTER_MEMORY_ID: MemoryId = MemoryId::new(12);
+pub const EMITTERS_MEMORY_ID: MemoryId = MemoryId::new(13);
+pub const TOP_UPS_MEMORY_ID: MemoryId = MemoryId::new(14);
+pub const FRONTENDS_MEMORY_ID: MemoryId = MemoryId::new(15);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with_borrow(|manager| manager.get(id))
+}
+
+// Heap state that a canister saves in `pre_upgrade` and loads back in
+// `post_upgrade`. `restore` empties the region, so it returns `None` until
+// the next `save`.
+pub fn save<T: CandidType>(id: MemoryId, value: &T) {
+    let bytes = Encode!(value).expect("Failed to encode saved state.");
+    StableCell::init(get(id), Vec::new()).set(bytes);
+}
+
+pub fn restore<T: CandidType + DeserializeOwned>(id: MemoryId) -> Option<T> {
+    let bytes = StableCell::init(get(id), Vec::new()).set(Vec::new());
+    if bytes.is_empty() {
+        return None;
+    }
+
+    Some(Decode!(&bytes, T).expect("Failed to decode saved state."))
+}
@@ -0,0 +1,126 @@
+use std::cell::RefCell;
+
+use candid::{CandidType, Principal};
+use ic_cdk::call::Call;
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+
+use crate::memory::{self, Memory, NOTIFICATIONS_CANISTER_MEMORY_ID};
+
+// Events emitted by the district canisters and delivered to the
+// `notifications` canister. Ids refer to records of the emitting canister.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, CandidType, Serialize, Deserialize)]
+pub enum NotificationType {
+    NewFollower,
+    FollowRequest,
+    PostLiked,
+    PostCommented,
+    CompetitionDeadline,
+    CompetitionFinalized,
+    CourseEnrollment,
+    CourseCompleted,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum NotificationEvent {
+    NewFollower {
+        account_id: String,
+        follower_id: String,
+    },
+    FollowRequest {
+        account_id: String,
+        requester_id: String,
+    },
+    PostLiked {
+        post_id: String,
+        account_id: String,
+    },
+    PostCommented {
+        post_id: String,
+        comment_id: String,
+        account_id: String,
+    },
+    CompetitionDeadline {
+        competition_id: String,
+        title: String,
+        ended_at: String,
+    },
+    CompetitionFinalized {
+        competition_id: String,
+        title: String,
+    },
+    CourseEnrollment {
+        course_id: u64,
+        enrollment_id: u64,
+    },
+    CourseCompleted {
+        course_id: u64,
+        enrollment_id: u64,
+    },
+}
+
+impl NotificationEvent {
+    pub fn kind(&self) -> NotificationType {
+        match self {
+            NotificationEvent::NewFollower { .. } => NotificationType::NewFollower,
+            NotificationEvent::FollowRequest { .. } => NotificationType::FollowRequest,
+            NotificationEvent::PostLiked { .. } => NotificationType::PostLiked,
+            NotificationEvent::PostCommented { .. } => NotificationType::PostCommented,
+            NotificationEvent::CompetitionDeadline { .. } => NotificationType::CompetitionDeadline,
+            NotificationEvent::CompetitionFinalized { .. } => {
+                NotificationType::CompetitionFinalized
+            }
+            NotificationEvent::CourseEnrollment { .. } => NotificationType::CourseEnrollment,
+            NotificationEvent::CourseCompleted { .. } => NotificationType::CourseCompleted,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct EmitEventInput {
+    pub recipients: Vec<Principal>,
+    pub event: NotificationEvent,
+}
+
+thread_local! {
+    // Lives in stable memory, so the configured canister survives upgrades.
+    static NOTIFICATIONS_CANISTER_ID: RefCell<StableCell<Option<Principal>, Memory>> = RefCell::new(
+        StableCell::init(memory::get(NOTIFICATIONS_CANISTER_MEMORY_ID), None),
+    );
+}
+
+pub fn configure(notifications_canister_id: Principal) {
+    NOTIFICATIONS_CANISTER_ID.with_borrow_mut(|id| id.set(Some(notifications_canister_id)));
+}
+
+pub fn notifications_canister_id() -> Option<Principal> {
+    NOTIFICATIONS_CANISTER_ID.with_borrow(|id| *id.get())
+}
+
+// Fire-and-forget delivery: notifications are best effort and must never make
+// the emitting endpoint fail or wait. Does nothing until `configure` was called.
+pub fn notify(recipients: Vec<Principal>, event: NotificationEvent) {
+    let Some(canister_id) = notifications_canister_id() else {
+        return;
+    };
+
+    let mut recipients = recipients;
+    recipients.sort();
+    recipients.dedup();
+    if recipients.is_empty() {
+        return;
+    }
+
+    let input = EmitEventInput { recipients, event };
+    if let Err(err) = Call::unbounded_wait(canister_id, "emit")
+        .with_arg(&input)
+        .oneway()
+    {
+        ic_cdk::println!(
+            "Failed to emit {:?} notification: {}",
+            input.event.kind(),
+            err
+        );
+    }
+}
@@ -0,0 +1,519 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::thread::LocalKey;
+
+use candid::{CandidType, Decode, Encode, Nat};
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::{
+    Account, Ledger, LedgerError, Subaccount, TransferArg, TransferFromArgs, memo_for,
+};
+use crate::memory::{self, Memory, PAYOUTS_MEMORY_ID, REWARD_BUDGET_MEMORY_ID};
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum RewardKind {
+    CompetitionPrize { competition_id: String, order: u64 },
+    CourseCompletion { course_id: u64, enrollment_id: u64 },
+    CreatorTip { post_id: String, tipper_id: String },
+    EscrowLock { escrow_id: String },
+    EscrowRelease { escrow_id: String, index: u64 },
+    EscrowRefund { escrow_id: String },
+    QuotaTopUp { gib: u64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum PayoutSource {
+    Treasury,               // the paying canister's own account
+    Allowance(Account),     // an ICRC-2 approval given to the paying canister
+    Subaccount(Subaccount), // a subaccount of the paying canister, e.g. an escrow
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum PayoutStatus {
+    Pending,
+    Paid { block_index: Nat },
+    Failed { error: LedgerError },
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Payout {
+    pub id: String,
+    pub kind: RewardKind,
+    pub source: PayoutSource,
+    pub to: Account,
+    pub amount: Nat,
+    pub memo: Vec<u8>,
+    pub created_at_time: u64,
+    pub status: PayoutStatus,
+    pub attempts: u32,
+}
+
+impl Payout {
+    // `id` must be derived from the rewarded event so that paying the same
+    // event twice resolves to the same payout.
+    pub fn new(
+        id: String,
+        kind: RewardKind,
+        source: PayoutSource,
+        to: Account,
+        amount: Nat,
+        now_nanos: u64,
+    ) -> Self {
+        Self {
+            memo: memo_for(&id),
+            id,
+            kind,
+            source,
+            to,
+            amount,
+            created_at_time: now_nanos,
+            status: PayoutStatus::Pending,
+            attempts: 0,
+        }
+    }
+
+    pub fn competition_prize(
+        competition_id: String,
+        order: u64,
+        to: Account,
+        amount: u64,
+        now_nanos: u64,
+    ) -> Self {
+        Self::new(
+            format!("prize:{competition_id}:{order}"),
+            RewardKind::CompetitionPrize {
+                competition_id,
+                order,
+            },
+            PayoutSource::Treasury,
+            to,
+            Nat::from(amount),
+            now_nanos,
+        )
+    }
+
+    pub fn course_completion(
+        course_id: u64,
+        enrollment_id: u64,
+        to: Account,
+        amount: u64,
+        now_nanos: u64,
+    ) -> Self {
+        Self::new(
+            format!("course:{course_id}:{enrollment_id}"),
+            RewardKind::CourseCompletion {
+                course_id,
+                enrollment_id,
+            },
+            PayoutSource::Treasury,
+            to,
+            Nat::from(amount),
+            now_nanos,
+        )
+    }
+
+    pub fn creator_tip(
+        tip_id: String,
+        post_id: String,
+        tipper_id: String,
+        from: Account,
+        to: Account,
+        amount: u64,
+        now_nanos: u64,
+    ) -> Self {
+        Self::new(
+            format!("tip:{tip_id}"),
+            RewardKind::CreatorTip { post_id, tipper_id },
+            PayoutSource::Allowance(from),
+            to,
+            Nat::from(amount),
+            now_nanos,
+        )
+    }
+
+    pub fn quota_top_up(
+        top_up_id: String,
+        gib: u64,
+        from: Account,
+        to: Account,
+        amount: u64,
+        now_nanos: u64,
+    ) -> Self {
+        Self::new(
+            format!("quota-top-up:{top_up_id}"),
+            RewardKind::QuotaTopUp { gib },
+            PayoutSource::Allowance(from),
+            to,
+            Nat::from(amount),
+            now_nanos,
+        )
+    }
+
+    pub fn is_paid(&self) -> bool {
+        matches!(self.status, PayoutStatus::Paid { .. })
+    }
+}
+
+pub async fn settle<L: Ledger>(ledger: &L, payout: &Payout) -> Result<Nat, LedgerError> {
+    match &payout.source {
+        PayoutSource::Treasury | PayoutSource::Subaccount(_) => {
+            let from_subaccount = match &payout.source {
+                PayoutSource::Subaccount(subaccount) => Some(subaccount.clone()),
+                _ => None,
+            };
+
+            ledger
+                .transfer(TransferArg {
+                    from_subaccount,
+                    to: payout.to.clone(),
+                    amount: payout.amount.clone(),
+                    fee: None,
+                    memo: Some(payout.memo.clone()),
+                    created_at_time: Some(payout.created_at_time),
+                })
+                .await
+        }
+        PayoutSource::Allowance(from) => {
+            ledger
+                .transfer_from(TransferFromArgs {
+                    spender_subaccount: None,
+                    from: from.clone(),
+                    to: payout.to.clone(),
+                    amount: payout.amount.clone(),
+                    fee: None,
+                    memo: Some(payout.memo.clone()),
+                    created_at_time: Some(payout.created_at_time),
+                })
+                .await
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct PayoutBook {
+    payouts: HashMap<String, Payout>,
+}
+
+impl PayoutBook {
+    // Returns the payout to settle, or `None` when the event was already paid.
+    // A payout that failed with an unknown outcome keeps its `created_at_time`
+    // so the ledger can deduplicate it; one rejected as too old is restamped.
+    pub fn prepare(&mut self, payout: Payout, now_nanos: u64) -> Option<Payout> {
+        let entry = self.payouts.entry(payout.id.clone()).or_insert(payout);
+
+        match &entry.status {
+            PayoutStatus::Paid { .. } => return None,
+            PayoutStatus::Failed { error } if error.is_too_old() => {
+                entry.created_at_time = now_nanos;
+            }
+            _ => {}
+        }
+
+        entry.attempts += 1;
+        Some(entry.clone())
+    }
+
+    pub fn record(&mut self, id: &str, result: Result<Nat, LedgerError>) -> Option<Payout> {
+        let payout = self.payouts.get_mut(id)?;
+
+        payout.status = match result {
+            Ok(block_index) => PayoutStatus::Paid { block_index },
+            Err(error) => PayoutStatus::Failed { error },
+        };
+
+        Some(payout.clone())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Payout> {
+        self.payouts.get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Payout> {
+        self.payouts.values().cloned().collect()
+    }
+
+    pub fn unpaid(&self) -> Vec<Payout> {
+        self.payouts
+            .values()
+            .filter(|payout| !payout.is_paid())
+            .cloned()
+            .collect()
+    }
+}
+
+thread_local! {
+    static SAVED_BOOK: RefCell<StableCell<Vec<u8>, Memory>> =
+        RefCell::new(StableCell::init(memory::get(PAYOUTS_MEMORY_ID), Vec::new()));
+}
+
+// Payout books live on the heap. Canisters save theirs in `pre_upgrade` and
+// load it back in `post_upgrade`, so paid events stay paid across upgrades.
+pub fn save_book(book: &'static LocalKey<RefCell<PayoutBook>>) {
+    let bytes = book.with_borrow(|book| Encode!(book).expect("Failed to encode payout book."));
+    SAVED_BOOK.with_borrow_mut(|saved| saved.set(bytes));
+}
+
+pub fn restore_book(book: &'static LocalKey<RefCell<PayoutBook>>) {
+    let bytes = SAVED_BOOK.with_borrow_mut(|saved| saved.set(Vec::new()));
+    if bytes.is_empty() {
+        return;
+    }
+
+    let restored = Decode!(&bytes, PayoutBook).expect("Failed to decode payout book.");
+    book.with_borrow_mut(|book| *book = restored);
+}
+
+thread_local! {
+    // What the treasury may still pay out in rewards, set by controllers.
+    static REWARD_BUDGET: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(memory::get(REWARD_BUDGET_MEMORY_ID), 0));
+}
+
+pub fn set_reward_budget(amount: u64) {
+    REWARD_BUDGET.with_borrow_mut(|budget| budget.set(amount));
+}
+
+pub fn reward_budget() -> u64 {
+    REWARD_BUDGET.with_borrow(|budget| *budget.get())
+}
+
+// Takes `amount` out of the reward budget. Fails, leaving the budget as it
+// was, when the budget cannot cover it.
+pub fn spend_reward_budget(amount: u64) -> Result<(), String> {
+    REWARD_BUDGET.with_borrow_mut(|budget| {
+        let left = budget
+            .get()
+            .checked_sub(amount)
+            .ok_or("The reward budget is exhausted.")?;
+        budget.set(left);
+        Ok(())
+    })
+}
+
+// Pays `payout` once, keeping `book` up to date around the ledger call. The
+// book is never borrowed across the await, so concurrent messages stay safe.
+pub async fn pay<L: Ledger>(
+    book: &'static LocalKey<RefCell<PayoutBook>>,
+    ledger: &L,
+    payout: Payout,
+    now_nanos: u64,
+) -> Payout {
+    let id = payout.id.clone();
+
+    let Some(prepared) = book.with_borrow_mut(|book| book.prepare(payout, now_nanos)) else {
+        return book
+            .with_borrow(|book| book.get(&id))
+            .expect("Payout exists.");
+    };
+
+    let result = settle(ledger, &prepared).await;
+
+    book.with_borrow_mut(|book| book.record(&id, result))
+        .expect("Payout exists.")
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::ledger::local::LocalLedger;
+    use crate::ledger::{ApproveArgs, TransferError, TransferFromError};
+
+    const FEE: u64 = 10;
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    thread_local! {
+        static BOOK: RefCell<PayoutBook> = RefCell::new(PayoutBook::default());
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn setup() -> (LocalLedger, Account) {
+        BOOK.with_borrow_mut(|book| *book = PayoutBook::default());
+
+        let treasury = Account::of(principal(1));
+        let ledger = LocalLedger::new(treasury.owner, FEE);
+        ledger.set_time(NOW);
+        ledger.mint(&treasury, 10_000);
+
+        (ledger, treasury)
+    }
+
+    #[test]
+    fn pays_competition_prize_from_treasury() {
+        let (ledger, treasury) = setup();
+        let winner = Account::of(principal(2));
+
+        let payout = block_on(pay(
+            &BOOK,
+            &ledger,
+            Payout::competition_prize("comp1".into(), 1, winner.clone(), 1_000, NOW),
+            NOW,
+        ));
+
+        assert!(payout.is_paid());
+        assert_eq!(ledger.icrc1_balance_of(&winner), Nat::from(1_000u64));
+        assert_eq!(ledger.icrc1_balance_of(&treasury), Nat::from(8_990u64));
+    }
+
+    #[test]
+    fn paying_the_same_event_twice_pays_once() {
+        let (ledger, _) = setup();
+        let winner = Account::of(principal(2));
+
+        for _ in 0..2 {
+            block_on(pay(
+                &BOOK,
+                &ledger,
+                Payout::course_completion(1, 7, winner.clone(), 500, NOW),
+                NOW,
+            ));
+        }
+
+        assert_eq!(ledger.icrc1_balance_of(&winner), Nat::from(500u64));
+        assert_eq!(BOOK.with_borrow(|book| book.list().len()), 1);
+    }
+
+    #[test]
+    fn saved_books_keep_paid_events_paid() {
+        let (ledger, _) = setup();
+        let winner = Account::of(principal(2));
+        let payout = Payout::course_completion(2, 1, winner.clone(), 500, NOW);
+
+        block_on(pay(&BOOK, &ledger, payout.clone(), NOW));
+        save_book(&BOOK);
+        BOOK.with_borrow_mut(|book| *book = PayoutBook::default());
+        restore_book(&BOOK);
+
+        assert!(block_on(pay(&BOOK, &ledger, payout, NOW)).is_paid());
+        assert_eq!(ledger.icrc1_balance_of(&winner), Nat::from(500u64));
+    }
+
+    #[test]
+    fn retry_after_unknown_outcome_is_deduplicated_by_the_ledger() {
+        let (ledger, _) = setup();
+        let winner = Account::of(principal(2));
+        let payout = Payout::competition_prize("comp1".into(), 2, winner.clone(), 300, NOW);
+
+        // The first attempt reached the ledger but its reply was lost.
+        let prepared = BOOK
+            .with_borrow_mut(|book| book.prepare(payout.clone(), NOW))
+            .unwrap();
+        let block_index = block_on(settle(&ledger, &prepared)).unwrap();
+        BOOK.with_borrow_mut(|book| {
+            book.record(
+                &payout.id,
+                Err(LedgerError::CallFailed("reply lost".into())),
+            )
+        });
+
+        let retried = block_on(pay(&BOOK, &ledger, payout, NOW + 1_000));
+
+        assert_eq!(retried.status, PayoutStatus::Paid { block_index });
+        assert_eq!(retried.attempts, 2);
+        assert_eq!(ledger.icrc1_balance_of(&winner), Nat::from(300u64));
+    }
+
+    #[test]
+    fn insufficient_treasury_marks_payout_failed_and_can_be_retried() {
+        let (ledger, treasury) = setup();
+        let winner = Account::of(principal(2));
+        let payout = Payout::competition_prize("comp2".into(), 1, winner.clone(), 50_000, NOW);
+
+        let failed = block_on(pay(&BOOK, &ledger, payout.clone(), NOW));
+        assert_eq!(
+            failed.status,
+            PayoutStatus::Failed {
+                error: LedgerError::Transfer(TransferError::InsufficientFunds {
+                    balance: Nat::from(10_000u64)
+                })
+            }
+        );
+
+        ledger.mint(&treasury, 50_000);
+        let paid = block_on(pay(&BOOK, &ledger, payout, NOW));
+
+        assert!(paid.is_paid());
+        assert_eq!(ledger.icrc1_balance_of(&winner), Nat::from(50_000u64));
+    }
+
+    #[test]
+    fn too_old_payout_is_restamped() {
+        let (ledger, _) = setup();
+        let winner = Account::of(principal(2));
+        let payout = Payout::course_completion(3, 1, winner.clone(), 100, NOW);
+
+        BOOK.with_borrow_mut(|book| {
+            book.prepare(payout.clone(), NOW);
+            book.record(
+                &payout.id,
+                Err(LedgerError::Transfer(TransferError::TooOld)),
+            );
+        });
+
+        let later = NOW + 2 * crate::ledger::local::TRANSACTION_WINDOW_NANOS;
+        ledger.set_time(later);
+        let paid = block_on(pay(&BOOK, &ledger, payout, later));
+
+        assert!(paid.is_paid());
+        assert_eq!(paid.created_at_time, later);
+    }
+
+    #[test]
+    fn creator_tip_requires_an_approval() {
+        let (ledger, _) = setup();
+        let tipper = Account::of(principal(3));
+        let creator = Account::of(principal(4));
+        let spender = Account::of(principal(1));
+        ledger.mint(&tipper, 1_000);
+
+        let tip = || {
+            Payout::creator_tip(
+                "tip1".into(),
+                "post1".into(),
+                "acc3".into(),
+                tipper.clone(),
+                creator.clone(),
+                200,
+                NOW,
+            )
+        };
+
+        let refused = block_on(pay(&BOOK, &ledger, tip(), NOW));
+        assert_eq!(
+            refused.status,
+            PayoutStatus::Failed {
+                error: LedgerError::TransferFrom(TransferFromError::InsufficientAllowance {
+                    allowance: Nat::from(0u64)
+                })
+            }
+        );
+
+        ledger
+            .icrc2_approve(
+                tipper.owner,
+                ApproveArgs {
+                    from_subaccount: None,
+                    spender,
+                    amount: Nat::from(210u64),
+                    expected_allowance: None,
+                    expires_at: None,
+                    fee: None,
+                    memo: None,
+                    created_at_time: None,
+                },
+            )
+            .unwrap();
+
+        let paid = block_on(pay(&BOOK, &ledger, tip(), NOW));
+
+        assert!(paid.is_paid());
+        assert_eq!(ledger.icrc1_balance_of(&creator), Nat::from(200u64));
+        assert_eq!(ledger.icrc1_balance_of(&tipper), Nat::from(780u64));
+    }
+}
@@ -236,9 +236,13 @@ export default function AccountCreationDialog({
                 : [],
               name: uploadedProfilePicture?.name ?? "",
               size: BigInt(uploadedProfilePicture?.size ?? 0),
+              content_hash: "",
+              parent_id: [],
               mime_type: uploadedProfilePicture?.type ?? "",
               public: true,
               allowed_users: [],
+              tags: [],
+              metadata: [],
               uploaded_at: "",
             },
           ]
@@ -3,6 +3,7 @@ import { canisterId, createActor } from "../../../declarations/storage";
 import type {
   StoredFile,
   FileUploadResolveType,
+  UploadContext,
 } from "../../../declarations/storage/storage.did";
 import StorageContext from "@/contexts/storage-context";
 
@@ -30,8 +31,11 @@ export default function StorageProvider({
 
   async function getFilesByID(file_ids: Array<string>): Promise<Array<File>> {
     try {
-      const canisterFiles: Array<StoredFile> =
-        (await actor?.get_files_by_id(file_ids)) ?? [];
+      // Listings only return metadata, contents are fetched by id.
+      const fetchedFiles: Array<[] | [StoredFile]> = await Promise.all(
+        file_ids.map(async (id) => (await actor?.get_file(id, [])) ?? []),
+      );
+      const canisterFiles: Array<StoredFile> = fetchedFiles.flat();
 
       return convertToFiles(canisterFiles);
     } catch {
@@ -42,13 +46,14 @@ export default function StorageProvider({
 
   async function uploadFiles(
     files: Array<StoredFile>,
+    context?: UploadContext,
   ): Promise<
     Array<[file_id: string, resolve_id: FileUploadResolveType, message: string]>
   > {
     try {
       const results: Array<
         [file_id: string, resolve_id: FileUploadResolveType, message: string]
-      > = (await actor?.upload_files(files)) ?? [];
+      > = (await actor?.upload_files(files, context ? [context] : [])) ?? [];
 
       return results;
     } catch {
@@ -4,6 +4,7 @@ import {
   _SERVICE,
   FileUploadResolveType,
   StoredFile,
+  UploadContext,
 } from "../../../declarations/storage/storage.did";
 
 export interface StorageContextType {
@@ -13,6 +14,7 @@ export interface StorageContextType {
   getFilesByID: (file_ids: Array<string>) => Promise<Array<File>>;
   uploadFiles: (
     files: Array<StoredFile>,
+    context?: UploadContext,
   ) => Promise<
     Array<[file_id: string, resolve_id: FileUploadResolveType, message: string]>
   >;